@@ -108,7 +108,7 @@ impl GuestFunction {
             (old_sp, old_fp)
         };
 
-        env.run_call();
+        crate::libc::setjmp::run_call(env);
 
         env.cpu.branch(old_pc);
         let regs = env.cpu.regs_mut();
@@ -135,7 +135,7 @@ impl GuestFunction {
             .cpu
             .branch_with_link(self, env.dyld.return_to_host_routine());
 
-        env.run_call();
+        crate::libc::setjmp::run_call(env);
 
         env.cpu.branch(old_pc);
         env.cpu.regs_mut()[Cpu::LR] = old_lr.addr_with_thumb_bit();
@@ -180,6 +180,7 @@ macro_rules! impl_CallFromGuest {
             // ignore warnings for the zero-argument case
             #[allow(unused_variables, unused_mut, clippy::unused_unit)]
             fn call_from_guest(&self, env: &mut Environment) {
+                crate::log::set_guest_thread(env.current_thread);
                 let mut reg_offset = 0;
                 let regs = env.cpu.regs();
                 let retval_ptr = R::SIZE_IN_MEM.map(|_| {
@@ -203,6 +204,7 @@ macro_rules! impl_CallFromGuest {
             // ignore warnings for the zero-argument case
             #[allow(unused_variables, unused_mut, clippy::unused_unit)]
             fn call_from_guest(&self, env: &mut Environment) {
+                crate::log::set_guest_thread(env.current_thread);
                 let mut reg_offset = 0;
                 let regs = env.cpu.regs();
                 let retval_ptr = R::SIZE_IN_MEM.map(|_| {
@@ -335,7 +337,7 @@ pub trait GuestArg: std::fmt::Debug + Sized {
 
 /// Read a single argument from registers or the stack. Call this for each
 /// argument in order.
-fn read_next_arg<T: GuestArg>(
+pub fn read_next_arg<T: GuestArg>(
     reg_offset: &mut usize,
     regs: &[u32],
     stack_ptr: ConstPtr<u32>,
@@ -0,0 +1,333 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Guest stack traces with symbolication, for when something goes wrong.
+//!
+//! The stack is walked by following the chain of frame records: each function
+//! that sets up a frame pushes the caller's frame pointer and its return
+//! address, then points the frame pointer at them. Apple's ABI uses r7 as the
+//! frame pointer in both Arm and Thumb code, but other toolchains use r11 in
+//! Arm code, so that's tried as a fallback. Functions that don't set up a frame
+//! (e.g. leaf functions) won't appear, except via PC or LR.
+//!
+//! Return addresses are resolved against the symbol tables of the loaded
+//! binaries and the Objective-C methods they implement, so it's only as good
+//! as the symbols an app has. Many apps are stripped, but Objective-C method
+//! names are always available.
+
+use crate::abi::FRAME_POINTER;
+use crate::cpu::Cpu;
+use crate::mach_o::MachO;
+use crate::mem::{ConstPtr, Mem, Ptr};
+use crate::objc::ObjC;
+use crate::Environment;
+
+/// Frame pointer used by some non-Apple toolchains in Arm code.
+const ARM_FRAME_POINTER: usize = 11;
+
+/// Stop walking the stack after this many frames, in case it's corrupt.
+const MAX_FRAMES: usize = 256;
+
+struct Image {
+    name: String,
+    /// Start and end of each section, sorted by address.
+    sections: Vec<(u32, u32)>,
+    /// Sorted by address.
+    symbols: Vec<(u32, String)>,
+}
+
+/// Resolves guest code addresses to `image!symbol+offset` descriptions.
+pub struct Symbolicator {
+    images: Vec<Image>,
+}
+
+impl Symbolicator {
+    pub fn new(bins: &[MachO], objc: &ObjC, mem: &Mem) -> Symbolicator {
+        let mut images: Vec<Image> = bins
+            .iter()
+            .map(|bin| {
+                let mut sections: Vec<(u32, u32)> = bin
+                    .sections
+                    .iter()
+                    .map(|section| (section.addr, section.addr + section.size))
+                    .collect();
+                sections.sort();
+                Image {
+                    name: bin.name.clone(),
+                    sections,
+                    symbols: bin.symbols.clone(),
+                }
+            })
+            .collect();
+
+        for (addr, name) in objc.guest_method_symbols(mem) {
+            if let Some(image) = images
+                .iter_mut()
+                .find(|image| image.section(addr).is_some())
+            {
+                image.symbols.push((addr, name));
+            }
+        }
+        for image in &mut images {
+            // If a method also has a regular symbol, prefer the latter, since
+            // it's the name the developer gave it.
+            image.symbols.sort_by_key(|&(addr, _)| addr);
+            image.symbols.dedup_by_key(|&mut (addr, _)| addr);
+        }
+
+        Symbolicator { images }
+    }
+
+    /// Make a symbolicator for a single image with the given sections (start
+    /// and end) and symbols, both sorted by address.
+    #[cfg(test)]
+    pub fn for_image(
+        name: &str,
+        sections: Vec<(u32, u32)>,
+        symbols: Vec<(u32, String)>,
+    ) -> Symbolicator {
+        Symbolicator {
+            images: vec![Image {
+                name: name.to_string(),
+                sections,
+                symbols,
+            }],
+        }
+    }
+
+    /// Describe a code address (with or without the Thumb bit), e.g.
+    /// `0x2f1c (MyApp!-[MyView drawRect:]+0x1a)`.
+    pub fn describe(&self, addr: u32) -> String {
+        self.describe_inner(addr & !1, addr & !1)
+    }
+
+    /// Like [Self::describe], but for a return address. The instruction before
+    /// it is used for the lookup, since a call at the end of a function would
+    /// otherwise be attributed to the next one.
+    pub fn describe_return_address(&self, addr: u32) -> String {
+        self.describe_inner(addr & !1, (addr & !1).wrapping_sub(1))
+    }
+
+    /// Name the function containing a code address (with or without the Thumb
+    /// bit), e.g. `MyApp!-[MyView drawRect:]`. Unlike [Self::describe], there's
+    /// no address or offset, so this is suitable for grouping addresses by
+    /// function. Addresses with no symbol are grouped by image, and addresses
+    /// outside any image are all `[unknown]`.
+    pub fn function_name(&self, addr: u32) -> String {
+        self.function_name_inner(addr & !1)
+    }
+
+    /// Like [Self::function_name], but for a return address (see
+    /// [Self::describe_return_address]).
+    pub fn function_name_for_return_address(&self, addr: u32) -> String {
+        self.function_name_inner((addr & !1).wrapping_sub(1))
+    }
+
+    fn function_name_inner(&self, lookup_addr: u32) -> String {
+        match self.lookup(lookup_addr) {
+            Some((image, Some((_, symbol)))) => format!("{}!{}", image.name, symbol),
+            Some((image, None)) => image.name.clone(),
+            None => "[unknown]".to_string(),
+        }
+    }
+
+    fn describe_inner(&self, addr: u32, lookup_addr: u32) -> String {
+        match self.lookup(lookup_addr) {
+            Some((image, Some((symbol_addr, symbol)))) => format!(
+                "{:#010x} ({}!{}+{:#x})",
+                addr,
+                image.name,
+                symbol,
+                addr - symbol_addr
+            ),
+            Some((image, None)) => format!("{:#010x} ({})", addr, image.name),
+            None => format!("{:#010x}", addr),
+        }
+    }
+
+    /// Find the image containing an address, and the address and name of the
+    /// symbol it's in, if there is one.
+    fn lookup(&self, lookup_addr: u32) -> Option<(&Image, Option<(u32, &str)>)> {
+        let (image, section_start) = self
+            .images
+            .iter()
+            .find_map(|image| Some((image, image.section(lookup_addr)?)))?;
+        let idx = image
+            .symbols
+            .partition_point(|&(symbol_addr, _)| symbol_addr <= lookup_addr);
+        match idx.checked_sub(1).map(|idx| &image.symbols[idx]) {
+            Some((symbol_addr, symbol)) if *symbol_addr >= section_start => {
+                // C symbols have a leading underscore that isn't part of the
+                // name used in source code.
+                let symbol = symbol.strip_prefix('_').unwrap_or(symbol);
+                Some((image, Some((*symbol_addr, symbol))))
+            }
+            _ => Some((image, None)),
+        }
+    }
+}
+
+impl Image {
+    /// Find the start of the section containing an address, if any.
+    fn section(&self, addr: u32) -> Option<u32> {
+        let idx = self.sections.partition_point(|&(start, _)| start <= addr);
+        let (start, end) = self.sections[..idx].last()?;
+        (addr < *end).then_some(*start)
+    }
+}
+
+/// Why [walk_frame_records] stopped.
+pub enum WalkEnd {
+    /// The end of the chain (a null frame pointer) was reached.
+    Done,
+    /// The current thread's stack location is unknown.
+    UnknownStack,
+    /// The next frame pointer is outside the stack.
+    OutsideStack(u32),
+    /// The next frame pointer doesn't point further up the stack.
+    Invalid(u32),
+    /// [MAX_FRAMES] was reached.
+    TooDeep,
+}
+
+/// Walk the current thread's chain of frame records, returning the return
+/// address from each one (innermost first), and why the walk stopped. This
+/// doesn't include PC or LR. It's best-effort and should be safe to call even
+/// if the stack is corrupt.
+pub fn walk_frame_records(env: &Environment) -> (Vec<u32>, WalkEnd) {
+    let mut return_addresses = Vec::new();
+
+    let Some(stack) = env.threads[env.current_thread].stack.clone() else {
+        return (return_addresses, WalkEnd::UnknownStack);
+    };
+    // A frame record is two words: the caller's frame pointer and the return
+    // address.
+    let is_frame_pointer = |fp: u32| {
+        fp % 4 == 0
+            && stack.contains(&fp)
+            && fp.checked_add(7).is_some_and(|end| stack.contains(&end))
+    };
+
+    let regs = env.cpu.regs();
+    let thumb = (env.cpu.cpsr() & Cpu::CPSR_THUMB) != 0;
+    let mut fp = regs[FRAME_POINTER];
+    if !thumb && !is_frame_pointer(fp) {
+        fp = regs[ARM_FRAME_POINTER];
+    }
+
+    // PC and LR count towards the limit.
+    for _ in 2..MAX_FRAMES {
+        if !is_frame_pointer(fp) {
+            let end = if fp == 0 {
+                WalkEnd::Done
+            } else {
+                WalkEnd::OutsideStack(fp)
+            };
+            return (return_addresses, end);
+        }
+        let frame: ConstPtr<u32> = Ptr::from_bits(fp);
+        let next_fp = env.mem.read(frame);
+        return_addresses.push(env.mem.read(frame + 1));
+        // The stack grows downwards, so callers' frames are at higher
+        // addresses. Anything else means the chain is broken.
+        if next_fp != 0 && next_fp <= fp {
+            return (return_addresses, WalkEnd::Invalid(next_fp));
+        }
+        fp = next_fp;
+    }
+    (return_addresses, WalkEnd::TooDeep)
+}
+
+/// Print a stack trace for the current thread. This is best-effort and should
+/// be safe to call even if the stack is corrupt.
+pub fn print_backtrace(env: &Environment) {
+    if env.current_thread == 0 {
+        echo!("Guest stack trace for main thread:");
+    } else {
+        echo!("Guest stack trace for thread {}:", env.current_thread);
+    }
+
+    let symbolicator = Symbolicator::new(&env.bins, &env.objc, &env.mem);
+    let return_to_host_routine = env.dyld.return_to_host_routine().addr_without_thumb_bit();
+    let describe_return_address = |lr: u32| {
+        if lr & !1 == return_to_host_routine {
+            "[host function]".to_string()
+        } else {
+            symbolicator.describe_return_address(lr)
+        }
+    };
+
+    let regs = env.cpu.regs();
+    echo!(
+        " 0. {} (PC)",
+        symbolicator.describe(env.cpu.pc_with_thumb_bit().addr_with_thumb_bit())
+    );
+    echo!(" 1. {} (LR)", describe_return_address(regs[Cpu::LR]));
+
+    let (return_addresses, end) = walk_frame_records(env);
+    for (i, &lr) in return_addresses.iter().enumerate() {
+        echo!("{:2}. {}", i + 2, describe_return_address(lr));
+    }
+    match end {
+        WalkEnd::Done => (),
+        WalkEnd::UnknownStack => echo!("The stack's location is unknown."),
+        WalkEnd::OutsideStack(fp) => {
+            echo!("Next frame pointer ({:#x}) is outside the stack.", fp)
+        }
+        WalkEnd::Invalid(fp) => echo!("Next frame pointer ({:#x}) is invalid.", fp),
+        WalkEnd::TooDeep => echo!("Stopped after {} frames.", MAX_FRAMES),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Symbolicator;
+
+    fn symbolicator() -> Symbolicator {
+        Symbolicator::for_image(
+            "MyApp",
+            vec![(0x1000, 0x1800), (0x2000, 0x2800)],
+            vec![
+                (0x1000, "_foo".to_string()),
+                (0x1100, "-[MyView drawRect:]".to_string()),
+            ],
+        )
+    }
+
+    #[test]
+    fn describe() {
+        let symbolicator = symbolicator();
+        assert_eq!(symbolicator.describe(0x1004), "0x00001004 (MyApp!foo+0x4)");
+        // The Thumb bit is ignored.
+        assert_eq!(
+            symbolicator.describe(0x1121),
+            "0x00001120 (MyApp!-[MyView drawRect:]+0x20)"
+        );
+        // Symbols don't extend past the end of their section.
+        assert_eq!(symbolicator.describe(0x2010), "0x00002010 (MyApp)");
+        assert_eq!(symbolicator.describe(0x1800), "0x00001800");
+    }
+
+    #[test]
+    fn describe_return_address() {
+        let symbolicator = symbolicator();
+        // A call at the very end of foo() returns to the start of the next
+        // function.
+        assert_eq!(
+            symbolicator.describe_return_address(0x1101),
+            "0x00001100 (MyApp!foo+0x100)"
+        );
+        assert_eq!(
+            symbolicator.function_name(0x1100),
+            "MyApp!-[MyView drawRect:]"
+        );
+        assert_eq!(
+            symbolicator.function_name_for_return_address(0x1101),
+            "MyApp!foo"
+        );
+        assert_eq!(symbolicator.function_name(0x2010), "MyApp");
+        assert_eq!(symbolicator.function_name(0x3000), "[unknown]");
+    }
+}
@@ -0,0 +1,290 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! The virtual clock that the app sees, which can run faster or slower than
+//! real time (`--time-scale=`, F7 and F8).
+//!
+//! Everything the app can use to tell the time or wait for something should go
+//! through this, rather than using [Instant] or [SystemTime] directly, so that
+//! fast-forward and slow motion are consistent: if `mach_absolute_time()` ran
+//! at double speed but `nanosleep()` didn't, an app that waits for a timer
+//! would behave quite differently from one that polls the time.
+//!
+//! Not everything goes through it yet. The C time functions (`time()`,
+//! `gettimeofday()`, `mach_absolute_time()` etc) and the CPU throttle (see
+//! [crate::cpu::throttle]) do, but sleeping, `NSDate`, `NSTimer` and the run
+//! loop, the framerate limit and audio playback still follow the host's clock,
+//! so they don't speed up or slow down with the time scale.
+//!
+//! In deterministic mode (`--deterministic`), virtual time doesn't follow the
+//! host's clock at all. Instead, it advances with the number of ticks the CPU
+//! has used, and skips ahead whenever every thread is waiting for something.
+//! Together with the tick-based thread scheduling (see
+//! [crate::environment::scheduler]), this means that the app's threads get
+//! interleaved the same way on every run, so bugs are reproducible.
+//!
+//! It isn't fully deterministic though. `NSDate`, `NSTimer`, the run loop, the
+//! framerate limit and audio playback still follow the host's clock, as noted
+//! above, so when a timer fires, when the run loop gives up waiting, when a
+//! frame is presented and how far audio has played all depend on how fast the
+//! host is.
+
+use crate::options::Options;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Slowest speed for [VirtualClock::set_time_scale].
+pub const MIN_TIME_SCALE: f64 = 0.25;
+/// Fastest speed for [VirtualClock::set_time_scale].
+pub const MAX_TIME_SCALE: f64 = 8.0;
+
+/// Parse the value of `--time-scale=`: a number from [MIN_TIME_SCALE] to
+/// [MAX_TIME_SCALE].
+pub fn parse_time_scale(value: &str) -> Result<f64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|scale| (MIN_TIME_SCALE..=MAX_TIME_SCALE).contains(scale))
+        .ok_or_else(|| {
+            format!(
+                "Expected a number from {} to {}",
+                MIN_TIME_SCALE, MAX_TIME_SCALE
+            )
+        })
+}
+
+/// Default number of ticks per second of virtual time in deterministic mode,
+/// if `--cpu-speed=` isn't used. This is the speed of the original iPhone.
+pub const DEFAULT_DETERMINISTIC_TICKS_PER_SECOND: u64 = 412_000_000;
+
+/// The virtual wall-clock time at which a deterministic clock starts:
+/// 2010-01-01 00:00:00 UTC. Starting at the host's time would make the app
+/// behave differently every time, e.g. if it seeds a random number generator
+/// with the time.
+const DETERMINISTIC_SYSTEM_START: Duration = Duration::from_secs(1_262_304_000);
+
+struct TickTime {
+    ticks_per_second: u64,
+    ticks: u64,
+}
+
+pub struct VirtualClock {
+    /// The host time at which [Self::time_scale] was last changed.
+    host_base: Instant,
+    /// The virtual time at which [Self::time_scale] was last changed.
+    virtual_base: Duration,
+    time_scale: f64,
+    /// Whether virtual time is stopped (see [Self::pause]).
+    paused: bool,
+    /// The host's wall-clock time when the clock was created.
+    system_start: SystemTime,
+    /// In deterministic mode, the ticks used so far. [Self::virtual_base] is
+    /// then the time skipped with [Self::skip_to].
+    tick_time: Option<TickTime>,
+}
+
+impl VirtualClock {
+    /// Create a clock that starts at zero now.
+    pub fn new(time_scale: f64) -> VirtualClock {
+        assert!((MIN_TIME_SCALE..=MAX_TIME_SCALE).contains(&time_scale));
+        VirtualClock {
+            host_base: Instant::now(),
+            virtual_base: Duration::ZERO,
+            time_scale,
+            paused: false,
+            system_start: SystemTime::now(),
+            tick_time: None,
+        }
+    }
+
+    /// Create the clock the options call for: deterministic if
+    /// `--deterministic` is used, otherwise at `--time-scale=`.
+    pub fn from_options(options: &Options) -> VirtualClock {
+        if options.deterministic {
+            let ticks_per_second = options
+                .cpu_speed
+                .unwrap_or(DEFAULT_DETERMINISTIC_TICKS_PER_SECOND);
+            VirtualClock::new_deterministic(ticks_per_second)
+        } else {
+            VirtualClock::new(options.time_scale)
+        }
+    }
+
+    /// Create a deterministic clock (see the module documentation) that starts
+    /// at zero, and advances by a second for every `ticks_per_second` ticks.
+    pub fn new_deterministic(ticks_per_second: u64) -> VirtualClock {
+        assert!(ticks_per_second != 0);
+        VirtualClock {
+            host_base: Instant::now(),
+            virtual_base: Duration::ZERO,
+            time_scale: 1.0,
+            paused: false,
+            system_start: UNIX_EPOCH + DETERMINISTIC_SYSTEM_START,
+            tick_time: Some(TickTime {
+                ticks_per_second,
+                ticks: 0,
+            }),
+        }
+    }
+
+    pub fn is_deterministic(&self) -> bool {
+        self.tick_time.is_some()
+    }
+
+    /// Record that `ticks` were used by [crate::cpu::Cpu::run_or_step]. This
+    /// only matters in deterministic mode.
+    pub fn ticks_used(&mut self, ticks: u64) {
+        if let Some(tick_time) = &mut self.tick_time {
+            tick_time.ticks += ticks;
+        }
+    }
+
+    /// In deterministic mode, jump forward to `deadline` if it's in the
+    /// future. This is for when no thread can run until then, and replaces
+    /// sleeping on the host. Returns [false] if not in deterministic mode.
+    pub fn skip_to(&mut self, deadline: Duration) -> bool {
+        if self.tick_time.is_none() {
+            return false;
+        }
+        if let Some(skip) = deadline.checked_sub(self.now()) {
+            self.virtual_base += skip;
+        }
+        true
+    }
+
+    /// The amount of virtual time since the clock was created. This is a
+    /// monotonic clock, so it replaces [Instant].
+    pub fn now(&self) -> Duration {
+        if let Some(TickTime {
+            ticks_per_second,
+            ticks,
+        }) = self.tick_time
+        {
+            let nanos = u128::from(ticks) * 1_000_000_000 / u128::from(ticks_per_second);
+            return self.virtual_base + Duration::from_nanos(nanos as u64);
+        }
+        self.virtual_time_at(Instant::now())
+    }
+
+    fn virtual_time_at(&self, host_time: Instant) -> Duration {
+        if self.paused {
+            return self.virtual_base;
+        }
+        let host_elapsed = host_time.saturating_duration_since(self.host_base);
+        self.virtual_base + host_elapsed.mul_f64(self.time_scale)
+    }
+
+    /// The virtual wall-clock time. This starts at the host's wall-clock time
+    /// and then advances with [Self::now], so it replaces [SystemTime].
+    pub fn system_time(&self) -> SystemTime {
+        self.system_start + self.now()
+    }
+
+    /// How long, in host time, it will take for `duration` of virtual time to
+    /// pass, if the time scale doesn't change in the meantime.
+    pub fn host_duration(&self, duration: Duration) -> Duration {
+        duration.div_f64(self.time_scale)
+    }
+
+    pub fn time_scale(&self) -> f64 {
+        self.time_scale
+    }
+
+    /// Change how fast virtual time passes relative to host time. Virtual time
+    /// continues from where it was, so it never jumps or goes backwards.
+    pub fn set_time_scale(&mut self, time_scale: f64) {
+        if self.is_deterministic() {
+            return;
+        }
+        let time_scale = time_scale.clamp(MIN_TIME_SCALE, MAX_TIME_SCALE);
+        let now = Instant::now();
+        self.virtual_base = self.virtual_time_at(now);
+        self.host_base = now;
+        self.time_scale = time_scale;
+    }
+
+    /// Stop virtual time, e.g. while the app is paused with F5. Nothing the
+    /// app is waiting for will happen until [Self::resume] is called.
+    pub fn pause(&mut self) {
+        if self.is_deterministic() {
+            // Ticks aren't used while paused anyway.
+            return;
+        }
+        self.virtual_base = self.now();
+        self.paused = true;
+    }
+
+    /// Start virtual time again after [Self::pause], from where it stopped.
+    pub fn resume(&mut self) {
+        if self.is_deterministic() {
+            return;
+        }
+        self.host_base = Instant::now();
+        self.paused = false;
+    }
+
+    /// Double the time scale, up to [MAX_TIME_SCALE] (F8).
+    pub fn speed_up(&mut self) {
+        if self.is_deterministic() {
+            echo!("The time scale can't be changed in deterministic mode.");
+            return;
+        }
+        self.set_time_scale(self.time_scale * 2.0);
+        echo!("Time scale is now {}×.", self.time_scale);
+    }
+
+    /// Halve the time scale, down to [MIN_TIME_SCALE] (F7).
+    pub fn slow_down(&mut self) {
+        if self.is_deterministic() {
+            echo!("The time scale can't be changed in deterministic mode.");
+            return;
+        }
+        self.set_time_scale(self.time_scale / 2.0);
+        echo!("Time scale is now {}×.", self.time_scale);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_virtual_clock() {
+    assert_eq!(parse_time_scale("0.25"), Ok(0.25));
+    assert_eq!(parse_time_scale("8"), Ok(8.0));
+    assert!(parse_time_scale("16").is_err());
+    assert!(parse_time_scale("fast").is_err());
+
+    let mut clock = VirtualClock::new(2.0);
+    let start = clock.host_base;
+    let second = Duration::from_secs(1);
+    assert_eq!(clock.virtual_time_at(start + second), 2 * second);
+    assert_eq!(clock.host_duration(2 * second), second);
+
+    // Changing the time scale must not make the clock jump.
+    let before = clock.now();
+    clock.set_time_scale(0.5);
+    let after = clock.now();
+    assert!(after >= before && after - before < second);
+    assert_eq!(clock.host_duration(second), 2 * second);
+
+    clock.pause();
+    let paused_at = clock.now();
+    assert_eq!(clock.virtual_time_at(Instant::now() + second), paused_at);
+    clock.resume();
+    assert!(clock.now() - paused_at < second);
+
+    let mut clock = VirtualClock::new_deterministic(1000);
+    assert_eq!(clock.now(), Duration::ZERO);
+    clock.ticks_used(1500);
+    assert_eq!(clock.now(), Duration::from_millis(1500));
+    assert!(clock.skip_to(2 * second));
+    assert_eq!(clock.now(), 2 * second);
+    // Skipping never goes backwards.
+    assert!(clock.skip_to(second));
+    assert_eq!(clock.now(), 2 * second);
+    clock.ticks_used(500);
+    assert_eq!(clock.now(), Duration::from_millis(2500));
+    assert_eq!(
+        clock.system_time(),
+        UNIX_EPOCH + DETERMINISTIC_SYSTEM_START + Duration::from_millis(2500)
+    );
+}
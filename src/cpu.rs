@@ -11,24 +11,31 @@
 //! For the moment, only ARMv6 has been tested.
 
 use crate::abi::GuestFunction;
-use crate::mem::{ConstPtr, GuestUSize, Mem, MutPtr, Ptr, SafeRead, SafeWrite};
+use crate::mem::{
+    guest_size_of, Access, ConstPtr, GuestUSize, Mem, MutPtr, Protection, Ptr, SafeRead, SafeWrite,
+    WatchpointKind,
+};
 
 // Import functions from C++
 use touchHLE_dynarmic_wrapper::*;
 
+mod throttle;
+pub use throttle::{parse_cpu_speed, CpuThrottle};
+
 type VAddr = u32;
 
 fn touchHLE_cpu_read_impl<T: SafeRead + Default>(
     mem: *mut touchHLE_Mem,
     addr: VAddr,
     error: *mut bool,
+    is_code: bool,
 ) -> T {
-    // If a panic occurs (probably due to a null-pointer access), we can't let
-    // it keep unwinding as it will hit non-Rust stack frames (dynarmic).
-    // Instead we catch the unwind and then tell the C++ code a problem occurred
-    // so it can immediately halt CPU execution and then panic itself, now
-    // with only Rust stack frames to worry about and with CPU state information
-    // available that's useful for debugging.
+    // If a panic occurs, we can't let it keep unwinding as it will hit
+    // non-Rust stack frames (dynarmic). Instead we catch the unwind and then
+    // tell the C++ code a problem occurred so it can immediately halt CPU
+    // execution and then panic itself, now with only Rust stack frames to worry
+    // about and with CPU state information available that's useful for
+    // debugging.
     //
     // TODO: Disable this in debug mode? This relies on dynarmic's
     // check_halt_on_memory_access option which surely has a significant
@@ -38,11 +45,34 @@ fn touchHLE_cpu_read_impl<T: SafeRead + Default>(
     // the emulator will crash anyway, maybe this is okay.
     let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
         let mem = unsafe { &mut *mem.cast::<Mem>() };
+        let size = guest_size_of::<T>();
+        if is_code {
+            // Instruction fetches happen when code is compiled, which isn't
+            // necessarily when it's run, so a fault isn't recorded here.
+            // Dynarmic reports it when the instruction is reached instead.
+            // They also don't trigger read watchpoints.
+            if !mem
+                .page_protections()
+                .get(addr)
+                .contains(Protection::EXECUTE)
+            {
+                return None;
+            }
+        } else {
+            // Protection faults and watchpoint hits are reported like memory
+            // errors, which Cpu::run_or_step then tells apart.
+            if mem.page_protections_mut().check(addr, size, Access::Read)
+                || mem.watchpoints_mut().check(addr, size, false)
+            {
+                return None;
+            }
+        }
         let ptr: ConstPtr<T> = Ptr::from_bits(addr);
-        mem.read(ptr)
+        Some(mem.read(ptr))
     }));
+    let res = res.ok().flatten();
     unsafe {
-        error.write(res.is_err());
+        error.write(res.is_none());
     }
     res.unwrap_or_default()
 }
@@ -51,28 +81,45 @@ fn touchHLE_cpu_write_impl<T: SafeWrite>(mem: *mut touchHLE_Mem, addr: VAddr, va
     // See comments above about catch_unwind
     let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
         let mem = unsafe { &mut *mem.cast::<Mem>() };
+        // See above about protection faults and watchpoints
+        let size = guest_size_of::<T>();
+        if mem.page_protections_mut().check(addr, size, Access::Write)
+            || mem.watchpoints_mut().check(addr, size, true)
+        {
+            return Err(());
+        }
         let ptr: MutPtr<T> = Ptr::from_bits(addr);
-        mem.write(ptr, value)
+        mem.write(ptr, value);
+        Ok(())
     }));
-    res.is_err()
+    !matches!(res, Ok(Ok(())))
 }
 
 // Export functions for use by C++
 #[no_mangle]
 extern "C" fn touchHLE_cpu_read_u8(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u8 {
-    touchHLE_cpu_read_impl(mem, addr, error)
+    touchHLE_cpu_read_impl(mem, addr, error, false)
 }
 #[no_mangle]
 extern "C" fn touchHLE_cpu_read_u16(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u16 {
-    touchHLE_cpu_read_impl(mem, addr, error)
+    touchHLE_cpu_read_impl(mem, addr, error, false)
 }
 #[no_mangle]
 extern "C" fn touchHLE_cpu_read_u32(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u32 {
-    touchHLE_cpu_read_impl(mem, addr, error)
+    touchHLE_cpu_read_impl(mem, addr, error, false)
 }
 #[no_mangle]
 extern "C" fn touchHLE_cpu_read_u64(mem: *mut touchHLE_Mem, addr: VAddr, error: *mut bool) -> u64 {
-    touchHLE_cpu_read_impl(mem, addr, error)
+    touchHLE_cpu_read_impl(mem, addr, error, false)
+}
+/// Instruction fetches don't trigger read watchpoints.
+#[no_mangle]
+extern "C" fn touchHLE_cpu_read_code_u32(
+    mem: *mut touchHLE_Mem,
+    addr: VAddr,
+    error: *mut bool,
+) -> u32 {
+    touchHLE_cpu_read_impl(mem, addr, error, true)
 }
 #[no_mangle]
 extern "C" fn touchHLE_cpu_write_u8(mem: *mut touchHLE_Mem, addr: VAddr, value: u8) -> bool {
@@ -136,12 +183,20 @@ pub enum CpuState {
 /// A reason that can cause CPU execution to be interrupted.
 #[derive(Debug)]
 pub enum CpuError {
-    /// Memory error during execution (probably a null page access).
+    /// Memory error during execution (probably a bug in touchHLE).
     MemoryError,
+    /// Memory access not allowed by the page's protection (see [Mem::protect]),
+    /// e.g. a null pointer access, a write to `__TEXT`, executing data, or a
+    /// stack overflow. For an [Access::Execute] fault, the address is PC.
+    ProtectionFault(Access, VAddr),
     /// Undefined instruction (perhaps from a GDB software breakpoint).
     UndefinedInstruction,
     /// Breakpoint (`bkpt` instruction).
     Breakpoint,
+    /// A debugger watchpoint was triggered by an access at the given address.
+    /// PC points to the instruction that made the access, which hasn't been
+    /// completed.
+    Watchpoint(WatchpointKind, VAddr),
 }
 
 impl Cpu {
@@ -163,28 +218,31 @@ impl Cpu {
     /// is provided, direct memory access is enabled, and the CPU instance
     /// becomes bound to that [Mem] instance (subsequent calls must use the same
     /// one).
-    pub fn new(direct_memory_access: Option<&mut Mem>) -> Cpu {
-        // Null page count is in pages rather than bytes. Mem ensures it is
-        // page aligned.
-        let null_page_count: usize = direct_memory_access
-            .as_ref()
-            .map_or(0, |mem| mem.null_segment_size() / 0x1000)
-            .try_into()
-            .unwrap();
+    pub fn new(mut direct_memory_access: Option<&mut Mem>) -> Cpu {
         // Safety: the direct memory access pointer will be retained directly by
         // the dynarmic wrapper and indirectly by cached JIT code, so we must
         // ensure we only execute the CPU while holding a &mut on the Mem object
         // to which that pointer belongs.
         let direct_memory_access_ptr = direct_memory_access
+            .as_deref_mut()
             .map_or(std::ptr::null_mut(), |mem| unsafe {
                 mem.direct_memory_access_ptr()
             });
-        let dynarmic_wrapper =
-            unsafe { touchHLE_DynarmicWrapper_new(direct_memory_access_ptr, null_page_count) };
-        Cpu {
+        let dynarmic_wrapper = unsafe { touchHLE_DynarmicWrapper_new(direct_memory_access_ptr) };
+        let mut cpu = Cpu {
             dynarmic_wrapper,
             direct_memory_access_ptr,
+        };
+        if let Some(mem) = direct_memory_access {
+            // All pages use direct memory access initially.
+            for page in 0..(1 << 20) {
+                let addr = page * 0x1000;
+                if !Self::page_allows_direct_access(mem, addr) {
+                    cpu.set_page_direct_access(addr, false);
+                }
+            }
         }
+        cpu
     }
 
     pub fn regs(&self) -> &[u32; 16] {
@@ -228,6 +286,29 @@ impl Cpu {
         unsafe { touchHLE_DynarmicWrapper_set_cpsr(self.dynarmic_wrapper, cpsr) }
     }
 
+    /// The VFP registers as single-precision registers (s0-s63). Each
+    /// double-precision register dN is made of sN*2 (low half) and sN*2+1
+    /// (high half). Only s0-s31 (d0-d15) exist on ARMv6.
+    pub fn ext_regs(&self) -> &[u32; 64] {
+        unsafe {
+            let ptr = touchHLE_DynarmicWrapper_ext_regs_const(self.dynarmic_wrapper);
+            &*(ptr as *const [u32; 64])
+        }
+    }
+    pub fn ext_regs_mut(&mut self) -> &mut [u32; 64] {
+        unsafe {
+            let ptr = touchHLE_DynarmicWrapper_ext_regs_mut(self.dynarmic_wrapper);
+            &mut *(ptr as *mut [u32; 64])
+        }
+    }
+
+    pub fn fpscr(&self) -> u32 {
+        unsafe { touchHLE_DynarmicWrapper_fpscr(self.dynarmic_wrapper) }
+    }
+    pub fn set_fpscr(&mut self, fpscr: u32) {
+        unsafe { touchHLE_DynarmicWrapper_set_fpscr(self.dynarmic_wrapper, fpscr) }
+    }
+
     /// Swap the current state of the CPU (registers etc) with the state stored
     /// in the context object.
     pub fn swap_context(&mut self, context: &mut CpuContext) {
@@ -272,6 +353,34 @@ impl Cpu {
         }
     }
 
+    /// Whether the page containing `addr` can be accessed directly by the CPU,
+    /// rather than through the memory callbacks, which enforce page protections
+    /// (see [Mem::protect]) and watchpoints (see [Mem::watchpoints]).
+    fn page_allows_direct_access(mem: &Mem, addr: VAddr) -> bool {
+        mem.page_protections()
+            .get(addr)
+            .contains(Protection::READ | Protection::WRITE)
+            && !mem.watchpoints().page_has_watchpoint(addr & !0xfff)
+    }
+
+    /// Update whether the page containing `addr` can be accessed directly by
+    /// the CPU, after its protection or watchpoints have changed. This has no
+    /// effect if direct memory access is not in use.
+    pub fn update_page_direct_access(&mut self, mem: &Mem, addr: VAddr) {
+        let enabled = Self::page_allows_direct_access(mem, addr);
+        self.set_page_direct_access(addr, enabled);
+    }
+
+    fn set_page_direct_access(&mut self, addr: VAddr, enabled: bool) {
+        unsafe {
+            touchHLE_DynarmicWrapper_set_page_direct_access(
+                self.dynarmic_wrapper,
+                (addr / 0x1000) as usize,
+                enabled,
+            )
+        }
+    }
+
     /// Start CPU execution.
     ///
     /// If `ticks` is [Some], it is used as an abstract time limit. The value
@@ -298,11 +407,60 @@ impl Cpu {
         };
         match res {
             -1 => CpuState::Normal,
-            -2 => CpuState::Error(CpuError::MemoryError),
+            -2 => {
+                if let Some((access, addr)) = mem.page_protections_mut().take_fault() {
+                    CpuState::Error(CpuError::ProtectionFault(access, addr))
+                } else if let Some((kind, addr)) = mem.watchpoints_mut().take_hit() {
+                    CpuState::Error(CpuError::Watchpoint(kind, addr))
+                } else {
+                    CpuState::Error(CpuError::MemoryError)
+                }
+            }
             -3 => CpuState::Error(CpuError::UndefinedInstruction),
             -4 => CpuState::Error(CpuError::Breakpoint),
-            _ if res < -4 => panic!("Unexpected CPU execution result"),
+            -5 => {
+                let pc = self.regs()[Self::PC];
+                CpuState::Error(CpuError::ProtectionFault(Access::Execute, pc))
+            }
+            _ if res < -5 => panic!("Unexpected CPU execution result"),
             svc => CpuState::Svc(svc as u32),
         }
     }
 }
+
+#[cfg(test)]
+#[test]
+fn test_memory_callbacks() {
+    let mut mem = Mem::new();
+    let data_page = 0x10000;
+    let code_page = 0x11000;
+    mem.protect(data_page, 0x1000, Protection::READ | Protection::WRITE);
+    mem.protect(code_page, 0x1000, Protection::READ | Protection::EXECUTE);
+    let mut error = false;
+
+    // Data reads need READ, instruction fetches need EXECUTE.
+    touchHLE_cpu_read_u32(std::ptr::addr_of_mut!(mem).cast(), data_page, &mut error);
+    assert!(!error);
+    touchHLE_cpu_read_code_u32(std::ptr::addr_of_mut!(mem).cast(), code_page, &mut error);
+    assert!(!error);
+    touchHLE_cpu_read_code_u32(std::ptr::addr_of_mut!(mem).cast(), data_page, &mut error);
+    assert!(error);
+
+    // Read watchpoints are triggered by data reads, but not by instruction
+    // fetches.
+    mem.watchpoints_mut()
+        .add(code_page, 4, WatchpointKind::Read);
+    touchHLE_cpu_read_code_u32(std::ptr::addr_of_mut!(mem).cast(), code_page, &mut error);
+    assert!(!error);
+    assert_eq!(mem.watchpoints_mut().take_hit(), None);
+    touchHLE_cpu_read_u8(
+        std::ptr::addr_of_mut!(mem).cast(),
+        code_page + 3,
+        &mut error,
+    );
+    assert!(error);
+    assert_eq!(
+        mem.watchpoints_mut().take_hit(),
+        Some((WatchpointKind::Read, code_page + 3))
+    );
+}
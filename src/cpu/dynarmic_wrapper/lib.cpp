@@ -21,6 +21,8 @@ std::uint8_t touchHLE_cpu_read_u8(touchHLE_Mem *mem, VAddr addr, bool *error);
 std::uint16_t touchHLE_cpu_read_u16(touchHLE_Mem *mem, VAddr addr, bool *error);
 std::uint32_t touchHLE_cpu_read_u32(touchHLE_Mem *mem, VAddr addr, bool *error);
 std::uint64_t touchHLE_cpu_read_u64(touchHLE_Mem *mem, VAddr addr, bool *error);
+std::uint32_t touchHLE_cpu_read_code_u32(touchHLE_Mem *mem, VAddr addr,
+                                         bool *error);
 bool touchHLE_cpu_write_u8(touchHLE_Mem *mem, VAddr addr, std::uint8_t value);
 bool touchHLE_cpu_write_u16(touchHLE_Mem *mem, VAddr addr, std::uint16_t value);
 bool touchHLE_cpu_write_u32(touchHLE_Mem *mem, VAddr addr, std::uint32_t value);
@@ -30,6 +32,7 @@ bool touchHLE_cpu_write_u64(touchHLE_Mem *mem, VAddr addr, std::uint64_t value);
 const auto HaltReasonSvc = Dynarmic::HaltReason::UserDefined1;
 const auto HaltReasonUndefinedInstruction = Dynarmic::HaltReason::UserDefined2;
 const auto HaltReasonBreakpoint = Dynarmic::HaltReason::UserDefined3;
+const auto HaltReasonExecuteFault = Dynarmic::HaltReason::UserDefined4;
 
 class Environment final : public Dynarmic::A32::UserCallbacks {
 public:
@@ -74,7 +77,7 @@ private:
 
   std::optional<std::uint32_t> MemoryReadCode(VAddr vaddr) override {
     bool error;
-    auto value = touchHLE_cpu_read_u32(mem, vaddr, &error);
+    auto value = touchHLE_cpu_read_code_u32(mem, vaddr, &error);
     if (error) {
       return std::nullopt;
     } else {
@@ -113,7 +116,7 @@ private:
   void ExceptionRaised(VAddr pc, Dynarmic::A32::Exception exception) override {
     // MemoryReadCode returned nullopt
     if (exception == Dynarmic::A32::Exception::NoExecuteFault) {
-      cpu->HaltExecution(Dynarmic::HaltReason::MemoryAbort);
+      cpu->HaltExecution(HaltReasonExecuteFault);
     } else if (exception == Dynarmic::A32::Exception::UndefinedInstruction) {
       cpu->HaltExecution(HaltReasonUndefinedInstruction);
     } else if (exception == Dynarmic::A32::Exception::Breakpoint) {
@@ -139,31 +142,25 @@ class DynarmicWrapper {
   std::unique_ptr<Dynarmic::A32::Jit> cpu;
   std::array<std::uint8_t *, Dynarmic::A32::UserConfig::NUM_PAGE_TABLE_ENTRIES>
       page_table;
+  std::uint8_t *direct_memory_access_ptr;
 
 public:
-  DynarmicWrapper(void *direct_memory_access_ptr, size_t null_page_count) {
+  DynarmicWrapper(void *direct_memory_access_ptr)
+      : direct_memory_access_ptr((std::uint8_t *)direct_memory_access_ptr) {
     Dynarmic::A32::UserConfig user_config;
     user_config.callbacks = &env;
     // TODO: only do this in debug builds? it's probably expensive
     user_config.check_halt_on_memory_access = true;
     if (direct_memory_access_ptr) {
-      // Allow fast accesses to all pages other than the null page, which will
-      // fall back to a memory callback, which will then abort execution.
+      // Allow fast accesses to all pages initially. The Rust side then
+      // disables them for pages that need to fall back to a memory callback,
+      // e.g. the null page, which will then abort execution.
       // TODO: Eventually we should use dynarmic's true fastmem mode, but that
       // requires using mmap/mprotect/etc on the host OS so we can still catch
       // null pointer accesses.
       page_table.fill((std::uint8_t *)direct_memory_access_ptr);
-      // Note that the null page size is also defined in src/mem.rs.
+      // Note that the page size is also defined in src/mem.rs.
       static_assert(1 << Dynarmic::A32::UserConfig::PAGE_BITS == 0x1000);
-
-      if (null_page_count > page_table.size()) {
-        printf("Too many null pages, %zu requested but maximum is %zu.",
-               null_page_count, page_table.size());
-        abort();
-      }
-      for (size_t i = 0; i < null_page_count; i++) {
-        page_table[i] = nullptr;
-      }
       user_config.page_table = &page_table;
       user_config.absolute_offset_page_table = true;
     }
@@ -177,10 +174,25 @@ public:
   std::uint32_t cpsr() const { return cpu->Cpsr(); }
   void set_cpsr(std::uint32_t cpsr) { cpu->SetCpsr(cpsr); }
 
+  const std::uint32_t *ext_regs() const { return &cpu->ExtRegs().front(); }
+  std::uint32_t *ext_regs() { return &cpu->ExtRegs().front(); }
+
+  std::uint32_t fpscr() const { return cpu->Fpscr(); }
+  void set_fpscr(std::uint32_t fpscr) { cpu->SetFpscr(fpscr); }
+
   void invalidate_cache_range(VAddr start, std::uint32_t size) {
     cpu->InvalidateCacheRange(start, size);
   }
 
+  void set_page_direct_access(size_t page, bool enabled) {
+    if (!direct_memory_access_ptr) {
+      return;
+    }
+    // The page table is read at runtime by the JIT code, so there's no need
+    // to invalidate anything.
+    page_table[page] = enabled ? direct_memory_access_ptr : nullptr;
+  }
+
   void swap_context(void *context) {
     Dynarmic::A32::Context tmp = cpu->SaveContext();
     cpu->LoadContext(*(Dynarmic::A32::Context *)context);
@@ -205,6 +217,8 @@ public:
       res = -3;
     } else if (Dynarmic::Has(hr, HaltReasonBreakpoint)) {
       res = -4;
+    } else if (Dynarmic::Has(hr, HaltReasonExecuteFault)) {
+      res = -5;
     } else if (Dynarmic::Has(hr, HaltReasonSvc)) {
       res = std::int32_t(env.halting_svc);
     } else {
@@ -221,9 +235,8 @@ public:
 
 extern "C" {
 
-DynarmicWrapper *touchHLE_DynarmicWrapper_new(void *direct_memory_access_ptr,
-                                              size_t null_page_count) {
-  return new DynarmicWrapper(direct_memory_access_ptr, null_page_count);
+DynarmicWrapper *touchHLE_DynarmicWrapper_new(void *direct_memory_access_ptr) {
+  return new DynarmicWrapper(direct_memory_access_ptr);
 }
 void touchHLE_DynarmicWrapper_delete(DynarmicWrapper *cpu) { delete cpu; }
 
@@ -243,6 +256,22 @@ void touchHLE_DynarmicWrapper_set_cpsr(DynarmicWrapper *cpu,
   cpu->set_cpsr(cpsr);
 }
 
+const std::uint32_t *
+touchHLE_DynarmicWrapper_ext_regs_const(const DynarmicWrapper *cpu) {
+  return cpu->ext_regs();
+}
+std::uint32_t *touchHLE_DynarmicWrapper_ext_regs_mut(DynarmicWrapper *cpu) {
+  return cpu->ext_regs();
+}
+
+std::uint32_t touchHLE_DynarmicWrapper_fpscr(const DynarmicWrapper *cpu) {
+  return cpu->fpscr();
+}
+void touchHLE_DynarmicWrapper_set_fpscr(DynarmicWrapper *cpu,
+                                        std::uint32_t fpscr) {
+  cpu->set_fpscr(fpscr);
+}
+
 void touchHLE_DynarmicWrapper_swap_context(DynarmicWrapper *cpu,
                                            void *context) {
   cpu->swap_context(context);
@@ -254,6 +283,12 @@ void touchHLE_DynarmicWrapper_invalidate_cache_range(DynarmicWrapper *cpu,
   cpu->invalidate_cache_range(start, size);
 }
 
+void touchHLE_DynarmicWrapper_set_page_direct_access(DynarmicWrapper *cpu,
+                                                     size_t page,
+                                                     bool enabled) {
+  cpu->set_page_direct_access(page, enabled);
+}
+
 std::int32_t touchHLE_DynarmicWrapper_run_or_step(DynarmicWrapper *cpu,
                                                   touchHLE_Mem *mem,
                                                   std::uint64_t *ticks) {
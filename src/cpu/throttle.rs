@@ -0,0 +1,153 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Limiting the speed of the emulated CPU (`--cpu-speed=`).
+//!
+//! The host CPU is far faster than the original devices' CPUs, which matters
+//! for apps that tie their game logic to how fast they run, rather than to the
+//! clock. The throttle works in terms of the ticks that
+//! [super::Cpu::run_or_step] consumes, which dynarmic counts as one per guest
+//! instruction, and assumes one instruction per clock cycle. Real devices
+//! managed less than that (especially the ARM11 ones, with their small caches),
+//! so this is a rough approximation of the device's speed, not an accurate
+//! simulation.
+//!
+//! Host sleeps aren't precise (on Windows, they're rounded up to about 15ms),
+//! so rather than sleeping after every slice of execution, the CPU is allowed
+//! to get ahead of schedule by up to [MIN_SLEEP] and then sleeps for the whole
+//! lead at once. The speed is therefore only right on average, over periods
+//! longer than that.
+//!
+//! The schedule is kept in virtual time (see [crate::clock]), so the CPU speeds
+//! up and slows down along with everything else when the time scale changes.
+//!
+//! Only time spent running guest code is limited. Time spent in host functions
+//! or waiting for something (e.g. `nanosleep()` or the next frame) doesn't
+//! count, and isn't allowed to build up into a burst of extra speed later
+//! either.
+
+use crate::clock::VirtualClock;
+use std::time::Duration;
+
+/// Device presets for `--cpu-speed=`, with their clock speeds in MHz.
+pub const CPU_SPEED_PRESETS: &[(&str, u64)] = &[
+    // ARM1176JZF-S
+    ("iphone2g", 412),
+    ("iphone3g", 412),
+    ("ipodtouch1g", 412),
+    ("ipodtouch2g", 533),
+    // Cortex-A8
+    ("iphone3gs", 600),
+    ("ipodtouch3g", 600),
+];
+
+/// Parse the value of `--cpu-speed=`: either one of [CPU_SPEED_PRESETS] or a
+/// number of MHz. Returns the number of ticks per second.
+pub fn parse_cpu_speed(value: &str) -> Result<u64, String> {
+    let mhz = match CPU_SPEED_PRESETS.iter().find(|&&(name, _)| name == value) {
+        Some(&(_, mhz)) => mhz,
+        None => value
+            .parse()
+            .ok()
+            .filter(|&mhz| mhz != 0 && mhz <= 100_000)
+            .ok_or_else(|| {
+                let presets: Vec<&str> = CPU_SPEED_PRESETS.iter().map(|&(name, _)| name).collect();
+                format!("Expected a number of MHz or one of: {}", presets.join(", "))
+            })?,
+    };
+    Ok(mhz * 1_000_000)
+}
+
+/// If the CPU falls behind schedule by more than this, it's assumed that it
+/// wasn't running guest code, and the schedule is restarted. This has to be
+/// comfortably more than a host sleep can overshoot by, so that oversleeping
+/// is made up for.
+const MAX_LAG: Duration = Duration::from_millis(50);
+
+/// Don't sleep until the CPU is at least this far ahead of schedule.
+const MIN_SLEEP: Duration = Duration::from_millis(20);
+
+/// The number of ticks [CpuThrottle::tick_limit] allows at once corresponds to
+/// this much time, so that execution is spread out evenly, rather than
+/// happening in bursts.
+const SLICE: Duration = Duration::from_millis(1);
+
+pub struct CpuThrottle {
+    ticks_per_second: u64,
+    /// Virtual time when the current schedule started.
+    start: Duration,
+    /// Ticks used since [Self::start].
+    ticks: u64,
+}
+
+impl CpuThrottle {
+    pub fn new(ticks_per_second: u64) -> CpuThrottle {
+        assert!(ticks_per_second != 0);
+        CpuThrottle {
+            ticks_per_second,
+            start: Duration::ZERO,
+            ticks: 0,
+        }
+    }
+
+    /// Limit the number of ticks to be passed to [super::Cpu::run_or_step].
+    pub fn tick_limit(&self, ticks: u64) -> u64 {
+        let slice_ticks = self.ticks_per_second * SLICE.as_micros() as u64 / 1_000_000;
+        ticks.min(slice_ticks.max(1))
+    }
+
+    /// Record that `ticks` were used by [super::Cpu::run_or_step], and sleep if
+    /// the CPU is now ahead of schedule.
+    pub fn ticks_used(&mut self, ticks: u64, clock: &VirtualClock) {
+        if let Some(delay) = self.delay_after(ticks, clock.now()) {
+            std::thread::sleep(clock.host_duration(delay));
+        }
+    }
+
+    /// How long to wait for (in virtual time), if `ticks` were used by `now`.
+    /// Delays shorter than [MIN_SLEEP] are put off until they add up.
+    fn delay_after(&mut self, ticks: u64, now: Duration) -> Option<Duration> {
+        self.ticks += ticks;
+        let due = self.start
+            + Duration::from_nanos(
+                (u128::from(self.ticks) * 1_000_000_000 / u128::from(self.ticks_per_second)) as u64,
+            );
+        if now > due + MAX_LAG {
+            self.start = now;
+            self.ticks = 0;
+            None
+        } else {
+            due.checked_sub(now).filter(|&delay| delay >= MIN_SLEEP)
+        }
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_cpu_throttle() {
+    assert_eq!(parse_cpu_speed("iphone3g"), Ok(412_000_000));
+    assert_eq!(parse_cpu_speed("100"), Ok(100_000_000));
+    assert!(parse_cpu_speed("0").is_err());
+    assert!(parse_cpu_speed("fast").is_err());
+
+    // 1MHz, so 1000 ticks is 1ms.
+    let ms = Duration::from_millis;
+    let mut throttle = CpuThrottle::new(1_000_000);
+    assert_eq!(throttle.tick_limit(u64::MAX), 1000);
+    // Small leads build up until they're worth sleeping for...
+    for _ in 0..19 {
+        assert_eq!(throttle.delay_after(1000, ms(0)), None);
+    }
+    assert_eq!(throttle.delay_after(1000, ms(0)), Some(ms(20)));
+    // ...and oversleeping is made up for...
+    assert_eq!(throttle.delay_after(1000, ms(35)), None);
+    assert_eq!(throttle.delay_after(13000, ms(35)), None);
+    assert_eq!(throttle.delay_after(21000, ms(35)), Some(ms(20)));
+    // ...but falling far behind isn't.
+    let now = ms(200);
+    assert_eq!(throttle.delay_after(1000, now), None);
+    assert_eq!(throttle.delay_after(19000, now), None);
+    assert_eq!(throttle.delay_after(1000, now), Some(ms(20)));
+}
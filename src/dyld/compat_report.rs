@@ -0,0 +1,141 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Static report of what an app binary imports that touchHLE doesn't implement
+//! (`--compat-report`).
+//!
+//! This doesn't run any of the app's code, so it can't tell whether the missing
+//! things would actually be used, but it's a quick way to triage apps and to
+//! find out what to implement next.
+//!
+//! Symbols are looked up in the dynamic libraries bundled with touchHLE (e.g.
+//! `libstdc++`) before the host implementations, the same way they are when
+//! the app is run, so that what those libraries provide isn't reported.
+
+use super::constant_lists::CONSTANT_LISTS;
+use super::function_lists::FUNCTION_LISTS;
+use super::search_lists;
+use crate::bundle::Bundle;
+use crate::fs::{Fs, GuestPath};
+use crate::mach_o::MachO;
+use crate::mem::Mem;
+use crate::objc;
+use std::collections::BTreeSet;
+
+/// Symbols that the dynamic linker resolves itself, rather than by looking
+/// them up in [FUNCTION_LISTS] or [CONSTANT_LISTS].
+const SPECIAL_SYMBOLS: &[&str] = &[
+    "___CFConstantStringClassReference",
+    "__objc_empty_cache",
+    "__objc_empty_vtable",
+    "dyld_stub_binder",
+];
+
+/// Print a report of the imported C functions and constants, Objective-C
+/// classes and selectors used by the app's binary that have no implementation
+/// in touchHLE.
+pub fn print_compat_report(bundle: &Bundle, fs: &Fs) -> Result<(), String> {
+    let mut mem = Mem::new();
+    let bin = MachO::load_from_file(bundle.executable_path(), fs, &mut mem)
+        .map_err(|e| format!("Could not load executable: {}", e))?;
+
+    // Load the bundled libraries the app links against, and the ones they
+    // link against in turn.
+    let mut dylibs: Vec<MachO> = Vec::new();
+    let mut dylib_paths = bin.dynamic_libraries.clone();
+    let mut seen_paths = BTreeSet::new();
+    while let Some(path) = dylib_paths.pop() {
+        let path = GuestPath::new(&path);
+        if !seen_paths.insert(path.as_str().to_string()) || !fs.is_file(path) {
+            continue;
+        }
+        let dylib = MachO::load_from_file(path, fs, &mut mem)
+            .map_err(|e| format!("Could not load {}: {}", path.as_str(), e))?;
+        dylib_paths.extend(dylib.dynamic_libraries.iter().cloned());
+        dylibs.push(dylib);
+    }
+
+    let mut symbols = BTreeSet::new();
+    for section in &bin.sections {
+        if let Some(ref info) = section.dyld_indirect_symbol_info {
+            symbols.extend(info.indirect_undef_symbols.iter().flatten());
+        }
+    }
+    symbols.extend(bin.external_relocations.iter().map(|(_addr, name)| name));
+
+    let mut symbol_count = 0;
+    let mut missing_symbols = Vec::new();
+    let mut classes = BTreeSet::new();
+    for &symbol in &symbols {
+        if let Some(class_name) = symbol
+            .strip_prefix("_OBJC_CLASS_$_")
+            .or_else(|| symbol.strip_prefix("_OBJC_METACLASS_$_"))
+        {
+            classes.insert(class_name);
+            continue;
+        }
+        if SPECIAL_SYMBOLS.contains(&symbol.as_str()) {
+            continue;
+        }
+        symbol_count += 1;
+        if dylibs
+            .iter()
+            .any(|dylib| dylib.exported_symbols.contains_key(symbol))
+        {
+            continue;
+        }
+        if search_lists(FUNCTION_LISTS, symbol).is_none()
+            && search_lists(CONSTANT_LISTS, symbol).is_none()
+        {
+            missing_symbols.push(symbol.as_str());
+        }
+    }
+    let missing_classes: Vec<&str> = classes
+        .iter()
+        .copied()
+        .filter(|&name| !objc::host_class_exists(name))
+        .collect();
+
+    // Selectors are checked by name only: a selector counts as implemented if
+    // any host class or any class in the app has a method for it, even if it's
+    // not the class the message will actually be sent to.
+    let selectors: BTreeSet<String> = objc::bin_selector_names(&bin, &mem).into_iter().collect();
+    let host_selectors = objc::host_selector_names();
+    let bin_methods = objc::bin_method_names(&bin, &mem);
+    let missing_selectors: Vec<&str> = selectors
+        .iter()
+        .map(|name| name.as_str())
+        .filter(|&name| !host_selectors.contains(name) && !bin_methods.contains(name))
+        .collect();
+
+    echo!("Compatibility report for {}:", bin.name);
+    echo!("- Dynamic libraries:");
+    for library in &bin.dynamic_libraries {
+        if fs.is_file(GuestPath::new(library)) {
+            echo!("    {} (bundled with touchHLE)", library);
+        } else {
+            echo!("    {}", library);
+        }
+    }
+    for (kind, count, missing) in [
+        ("C functions and constants", symbol_count, missing_symbols),
+        ("Objective-C classes", classes.len(), missing_classes),
+        ("Objective-C selectors", selectors.len(), missing_selectors),
+    ] {
+        echo!(
+            "- {}: {} referenced, {} not implemented{}",
+            kind,
+            count,
+            missing.len(),
+            if missing.is_empty() { "." } else { ":" }
+        );
+        for name in missing {
+            echo!("    {}", name);
+        }
+    }
+    echo!();
+
+    Ok(())
+}
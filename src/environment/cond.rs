@@ -0,0 +1,199 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Internal condition variable interface.
+//!
+//! A thread waiting on a condition variable is blocked until it's both been
+//! woken (by a signal, a broadcast or its deadline passing) and been able to
+//! lock the mutex again. The thread scheduler finds out when that's happened
+//! with [Environment::poll_cond_wait].
+
+use std::collections::HashMap;
+use std::num::NonZeroU32;
+use std::time::Duration;
+
+use super::{Environment, MutexId, ThreadId};
+use crate::libc::errno::{EBUSY, ETIMEDOUT};
+
+/// Stores and manages condition variables. Note that the methods for waiting
+/// and waking are on [Environment] instead, because they interact with threads.
+#[derive(Default)]
+pub struct CondState {
+    conds: HashMap<CondId, Cond>,
+    cond_count: u64,
+}
+
+/// Unique identifier for condition variables.
+pub type CondId = u64;
+
+#[derive(Default)]
+struct Cond {
+    /// Threads waiting on this condition variable, in the order they started
+    /// waiting.
+    waiters: Vec<Waiter>,
+}
+
+struct Waiter {
+    thread: ThreadId,
+    mutex_id: MutexId,
+    /// The lock count the mutex had, to be restored when it's relocked.
+    lock_count: NonZeroU32,
+    /// Virtual time (see [crate::clock]) at which the wait times out, if any.
+    deadline: Option<Duration>,
+    /// The result of the wait (as errno), once the thread has been woken.
+    result: Option<i32>,
+}
+
+impl CondState {
+    /// Initializes a condition variable and returns a handle to it. Similar to
+    /// `pthread_cond_init`, but for host code.
+    pub fn init_cond(&mut self) -> CondId {
+        let cond_id = self.cond_count;
+        self.cond_count = self.cond_count.checked_add(1).unwrap();
+        self.conds.insert(cond_id, Cond::default());
+        log_dbg!("Created condition variable #{}", cond_id);
+        cond_id
+    }
+
+    /// Destroys a condition variable and returns an error on failure (as
+    /// errno). Similar to `pthread_cond_destroy`, but for host code. Note that
+    /// the condition variable is not destroyed on an Err return.
+    pub fn destroy_cond(&mut self, cond_id: CondId) -> Result<(), i32> {
+        let cond = self.conds.get(&cond_id).unwrap();
+        if !cond.waiters.is_empty() {
+            log_dbg!(
+                "Attempted to destroy condition variable with waiting threads, returning EBUSY!"
+            );
+            return Err(EBUSY);
+        }
+        self.conds.remove(&cond_id);
+        Ok(())
+    }
+
+    /// Wakes the thread that has been waiting the longest, if any. Similar to
+    /// `pthread_cond_signal`, but for host code.
+    pub fn signal_cond(&mut self, cond_id: CondId) {
+        let cond = self.conds.get_mut(&cond_id).unwrap();
+        if let Some(waiter) = cond.waiters.iter_mut().find(|w| w.result.is_none()) {
+            log_dbg!(
+                "Signalled condition variable #{}, waking thread {}.",
+                cond_id,
+                waiter.thread
+            );
+            waiter.result = Some(0);
+        }
+    }
+
+    /// Wakes all waiting threads. Similar to `pthread_cond_broadcast`, but for
+    /// host code.
+    pub fn broadcast_cond(&mut self, cond_id: CondId) {
+        let cond = self.conds.get_mut(&cond_id).unwrap();
+        log_dbg!("Broadcast to condition variable #{}.", cond_id);
+        for waiter in cond.waiters.iter_mut() {
+            waiter.result.get_or_insert(0);
+        }
+    }
+}
+
+impl Environment {
+    /// Unlocks a mutex held by the current thread and waits on a condition
+    /// variable, optionally with a timeout in virtual time, or returns an error
+    /// (as errno). Similar to `pthread_cond_wait` and `pthread_cond_timedwait`,
+    /// but for host code.
+    /// NOTE: Like [Environment::lock_mutex], this only takes effect _after_ the
+    /// calling function returns to the host run loop. The thread will then be
+    /// blocked until [Environment::poll_cond_wait] returns a result, which
+    /// becomes the return value.
+    pub fn wait_on_cond(
+        &mut self,
+        cond_id: CondId,
+        mutex_id: MutexId,
+        timeout: Option<Duration>,
+    ) -> Result<(), i32> {
+        let thread = self.current_thread;
+        let lock_count = self.mutex_state.unlock_for_wait(mutex_id, thread)?;
+        let deadline = timeout.map(|timeout| self.clock.now() + timeout);
+        log_dbg!(
+            "Thread {} waiting on condition variable #{} (deadline: {:?}).",
+            thread,
+            cond_id,
+            deadline
+        );
+        let cond = self.cond_state.conds.get_mut(&cond_id).unwrap();
+        cond.waiters.push(Waiter {
+            thread,
+            mutex_id,
+            lock_count,
+            deadline,
+            result: None,
+        });
+        self.block_on_cond(cond_id);
+        Ok(())
+    }
+
+    /// Checks whether a thread blocked on a condition variable can continue,
+    /// and if so, relocks its mutex and returns the result of the wait (as
+    /// errno). This should probably only be used by the thread scheduler.
+    pub fn poll_cond_wait(&mut self, thread: ThreadId, cond_id: CondId) -> Option<i32> {
+        let now = self.clock.now();
+        let cond = self.cond_state.conds.get_mut(&cond_id).unwrap();
+        let index = cond
+            .waiters
+            .iter()
+            .position(|w| w.thread == thread)
+            .unwrap();
+        let waiter = &mut cond.waiters[index];
+        if waiter.result.is_none() && waiter.deadline.is_some_and(|d| now >= d) {
+            log_dbg!(
+                "Thread {} timed out waiting on condition variable #{}.",
+                thread,
+                cond_id
+            );
+            waiter.result = Some(ETIMEDOUT);
+        }
+        let result = waiter.result?;
+        if !self
+            .mutex_state
+            .relock_after_wait(waiter.mutex_id, thread, waiter.lock_count)
+        {
+            return None;
+        }
+        cond.waiters.remove(index);
+        Some(result)
+    }
+
+    /// Wakes a thread waiting on a condition variable because it has been
+    /// cancelled (see [crate::libc::pthread::thread::cancellation_requested]).
+    /// As with any other wakeup, [Environment::poll_cond_wait] only returns
+    /// once the mutex has been relocked, which POSIX requires before a thread
+    /// cancelled in `pthread_cond_wait` exits. The scheduler should then end
+    /// the thread rather than return from the wait.
+    pub fn cancel_cond_wait(&mut self, thread: ThreadId, cond_id: CondId) {
+        let cond = self.cond_state.conds.get_mut(&cond_id).unwrap();
+        let waiter = cond
+            .waiters
+            .iter_mut()
+            .find(|w| w.thread == thread)
+            .unwrap();
+        log_dbg!(
+            "Thread {} cancelled while waiting on condition variable #{}.",
+            thread,
+            cond_id
+        );
+        waiter.result.get_or_insert(0);
+    }
+
+    /// The earliest deadline of any thread waiting on a condition variable, so
+    /// the scheduler knows how long it can sleep for if no thread can run.
+    pub fn next_cond_deadline(&self) -> Option<Duration> {
+        self.cond_state
+            .conds
+            .values()
+            .flat_map(|cond| &cond.waiters)
+            .filter(|w| w.result.is_none())
+            .filter_map(|w| w.deadline)
+            .min()
+    }
+}
@@ -102,6 +102,53 @@ impl MutexState {
             .get(&mutex_id)
             .map_or(false, |mutex| mutex.locked.is_some())
     }
+
+    /// Completely unlock a mutex held by `thread`, so it can wait on a
+    /// condition variable, and return its lock count. Returns an error (as
+    /// errno) if `thread` doesn't hold the mutex.
+    pub fn unlock_for_wait(
+        &mut self,
+        mutex_id: MutexId,
+        thread: ThreadId,
+    ) -> Result<NonZeroU32, i32> {
+        let mutex = self.mutexes.get_mut(&mutex_id).unwrap();
+        match mutex.locked {
+            Some((locking_thread, lock_count)) if locking_thread == thread => {
+                log_dbg!(
+                    "Unlocked mutex #{} for thread {} to wait on a condition.",
+                    mutex_id,
+                    thread
+                );
+                mutex.locked = None;
+                Ok(lock_count)
+            }
+            _ => {
+                log_dbg!("Attempted to wait on a condition with mutex #{} not locked by thread {}, returning EPERM!", mutex_id, thread);
+                Err(EPERM)
+            }
+        }
+    }
+
+    /// Lock a mutex again for `thread` after [Self::unlock_for_wait], if it's
+    /// unlocked. Returns [false] if it's still locked by another thread.
+    pub fn relock_after_wait(
+        &mut self,
+        mutex_id: MutexId,
+        thread: ThreadId,
+        lock_count: NonZeroU32,
+    ) -> bool {
+        let mutex = self.mutexes.get_mut(&mutex_id).unwrap();
+        if mutex.locked.is_some() {
+            return false;
+        }
+        log_dbg!(
+            "Relocked mutex #{} for thread {} after waiting on a condition.",
+            mutex_id,
+            thread
+        );
+        mutex.locked = Some((thread, lock_count));
+        true
+    }
 }
 
 impl Environment {
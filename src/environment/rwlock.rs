@@ -0,0 +1,185 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Internal read-write lock interface.
+//!
+//! Writers are preferred: once a thread is waiting for a write lock, new read
+//! locks can't be taken (except by threads that already hold one, since they
+//! could otherwise deadlock), so that a steady stream of readers can't starve
+//! a writer forever.
+
+use std::collections::HashMap;
+
+use super::{Environment, ThreadId};
+use crate::libc::errno::{EBUSY, EDEADLK, EPERM};
+
+/// Stores and manages read-write locks. Note that the methods for locking and
+/// unlocking are on [Environment] instead, because they interact with threads.
+#[derive(Default)]
+pub struct RwLockState {
+    rwlocks: HashMap<RwLockId, RwLock>,
+    rwlock_count: u64,
+}
+
+/// Unique identifier for read-write locks.
+pub type RwLockId = u64;
+
+#[derive(Default)]
+struct RwLock {
+    /// The number of read locks held by each thread.
+    readers: HashMap<ThreadId, u32>,
+    writer: Option<ThreadId>,
+    /// Threads blocked waiting for this lock, and whether they want to write.
+    waiters: Vec<(ThreadId, bool)>,
+}
+
+impl RwLock {
+    fn can_lock(&self, thread: ThreadId, write: bool) -> bool {
+        if self.writer.is_some() {
+            false
+        } else if write {
+            self.readers.is_empty()
+        } else {
+            self.readers.contains_key(&thread)
+                || !self.waiters.iter().any(|&(_, waiting_write)| waiting_write)
+        }
+    }
+
+    fn lock(&mut self, thread: ThreadId, write: bool) {
+        if write {
+            self.writer = Some(thread);
+        } else {
+            *self.readers.entry(thread).or_insert(0) += 1;
+        }
+    }
+}
+
+impl RwLockState {
+    /// Initializes a read-write lock and returns a handle to it. Similar to
+    /// `pthread_rwlock_init`, but for host code.
+    pub fn init_rwlock(&mut self) -> RwLockId {
+        let rwlock_id = self.rwlock_count;
+        self.rwlock_count = self.rwlock_count.checked_add(1).unwrap();
+        self.rwlocks.insert(rwlock_id, RwLock::default());
+        log_dbg!("Created read-write lock #{}", rwlock_id);
+        rwlock_id
+    }
+
+    /// Destroys a read-write lock and returns an error on failure (as errno).
+    /// Similar to `pthread_rwlock_destroy`, but for host code. Note that the
+    /// lock is not destroyed on an Err return.
+    pub fn destroy_rwlock(&mut self, rwlock_id: RwLockId) -> Result<(), i32> {
+        let rwlock = self.rwlocks.get(&rwlock_id).unwrap();
+        if rwlock.writer.is_some() || !rwlock.readers.is_empty() {
+            log_dbg!("Attempted to destroy currently locked read-write lock, returning EBUSY!");
+            return Err(EBUSY);
+        } else if !rwlock.waiters.is_empty() {
+            log_dbg!("Attempted to destroy read-write lock with waiting threads, returning EBUSY!");
+            return Err(EBUSY);
+        }
+        self.rwlocks.remove(&rwlock_id);
+        Ok(())
+    }
+}
+
+impl Environment {
+    /// Takes a read or write lock, or returns an error (as errno). If `block`
+    /// is [false], fails with `EBUSY` rather than waiting. Similar to
+    /// `pthread_rwlock_rdlock`, `pthread_rwlock_wrlock` and their `try`
+    /// variants, but for host code.
+    /// NOTE: Like [Environment::lock_mutex], blocking only takes effect _after_
+    /// the calling function returns to the host run loop. The thread will then
+    /// be blocked until [Environment::poll_rwlock_wait] returns [true].
+    pub fn lock_rwlock(
+        &mut self,
+        rwlock_id: RwLockId,
+        write: bool,
+        block: bool,
+    ) -> Result<(), i32> {
+        let thread = self.current_thread;
+        let rwlock = self.rwlock_state.rwlocks.get_mut(&rwlock_id).unwrap();
+
+        if rwlock.writer == Some(thread) || (write && rwlock.readers.contains_key(&thread)) {
+            log_dbg!(
+                "Attempted to lock read-write lock #{} for thread {}, already locked by same thread! Returning EDEADLK.",
+                rwlock_id,
+                thread
+            );
+            return Err(EDEADLK);
+        }
+
+        if rwlock.can_lock(thread, write) {
+            log_dbg!(
+                "Locked read-write lock #{} for thread {} ({}).",
+                rwlock_id,
+                thread,
+                if write { "write" } else { "read" }
+            );
+            rwlock.lock(thread, write);
+            return Ok(());
+        }
+
+        if !block {
+            return Err(EBUSY);
+        }
+        rwlock.waiters.push((thread, write));
+        self.block_on_rwlock(rwlock_id);
+        Ok(())
+    }
+
+    /// Releases a read or write lock held by the current thread, or returns an
+    /// error (as errno). Similar to `pthread_rwlock_unlock`, but for host code.
+    pub fn unlock_rwlock(&mut self, rwlock_id: RwLockId) -> Result<(), i32> {
+        let thread = self.current_thread;
+        let rwlock = self.rwlock_state.rwlocks.get_mut(&rwlock_id).unwrap();
+
+        if rwlock.writer == Some(thread) {
+            rwlock.writer = None;
+        } else if let Some(count) = rwlock.readers.get_mut(&thread) {
+            *count -= 1;
+            if *count == 0 {
+                rwlock.readers.remove(&thread);
+            }
+        } else {
+            log_dbg!(
+                "Attempted to unlock read-write lock #{} for thread {}, not locked by that thread! Returning EPERM.",
+                rwlock_id,
+                thread
+            );
+            return Err(EPERM);
+        }
+        log_dbg!(
+            "Unlocked read-write lock #{} for thread {}.",
+            rwlock_id,
+            thread
+        );
+        Ok(())
+    }
+
+    /// Checks whether a thread blocked on a read-write lock can take it now,
+    /// and if so, takes it. This should probably only be used by the thread
+    /// scheduler.
+    pub fn poll_rwlock_wait(&mut self, thread: ThreadId, rwlock_id: RwLockId) -> bool {
+        let rwlock = self.rwlock_state.rwlocks.get_mut(&rwlock_id).unwrap();
+        let index = rwlock
+            .waiters
+            .iter()
+            .position(|&(waiter, _)| waiter == thread)
+            .unwrap();
+        let (_, write) = rwlock.waiters.remove(index);
+        if rwlock.can_lock(thread, write) {
+            log_dbg!(
+                "Locked read-write lock #{} for waiting thread {}.",
+                rwlock_id,
+                thread
+            );
+            rwlock.lock(thread, write);
+            true
+        } else {
+            rwlock.waiters.insert(index, (thread, write));
+            false
+        }
+    }
+}
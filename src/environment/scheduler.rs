@@ -0,0 +1,180 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Thread scheduling policy.
+//!
+//! Guest threads are preemptive: each one runs for a time slice, counted in
+//! the ticks used by [crate::cpu::Cpu::run_or_step], after which the scheduler
+//! can switch to another thread, even if the running thread never blocks or
+//! yields. Otherwise, a thread spinning on a flag could starve the thread that
+//! would set it. Because slices are counted in ticks, not host time, where
+//! threads are preempted depends only on the instructions they run (see also
+//! [crate::clock::VirtualClock::new_deterministic]).
+//!
+//! Threads have priorities, like those set with `pthread_setschedparam`, and
+//! the scheduler prefers to run higher-priority threads. iPhone OS uses a
+//! timesharing policy by default, where lower-priority threads still get to
+//! run eventually, so threads that have been passed over get a temporary
+//! boost. Otherwise, a high-priority thread that is busy-waiting for a
+//! low-priority thread would wait forever.
+
+use std::collections::HashMap;
+
+use super::ThreadId;
+
+/// The priority threads have if none has been set. This is the default on
+/// iPhone OS too.
+pub const DEFAULT_THREAD_PRIORITY: i32 = 31;
+
+/// Default for `--thread-time-slice=`, in ticks.
+pub const DEFAULT_TIME_SLICE: u64 = 1_000_000;
+
+/// Parse the value of `--thread-time-slice=`: a number of ticks.
+pub fn parse_time_slice(value: &str) -> Result<u64, String> {
+    value
+        .parse()
+        .ok()
+        .filter(|&ticks| ticks != 0)
+        .ok_or_else(|| "Expected a positive whole number of ticks".to_string())
+}
+
+pub struct SchedulerState {
+    /// The number of ticks each thread can run for before it's preempted.
+    time_slice: u64,
+    /// Ticks remaining in the current thread's slice.
+    slice_ticks_left: u64,
+    priorities: HashMap<ThreadId, i32>,
+    /// How many times each thread has been passed over for a thread with a
+    /// higher priority since it last ran.
+    passed_over: HashMap<ThreadId, i32>,
+}
+
+impl SchedulerState {
+    pub fn new(time_slice: u64) -> SchedulerState {
+        assert!(time_slice != 0);
+        SchedulerState {
+            time_slice,
+            slice_ticks_left: time_slice,
+            priorities: HashMap::new(),
+            passed_over: HashMap::new(),
+        }
+    }
+
+    /// Limit the number of ticks to be passed to
+    /// [crate::cpu::Cpu::run_or_step], so that it stops at the end of the
+    /// current thread's slice.
+    pub fn tick_limit(&self, ticks: u64) -> u64 {
+        ticks.min(self.slice_ticks_left)
+    }
+
+    /// Record that `ticks` were used by [crate::cpu::Cpu::run_or_step].
+    pub fn ticks_used(&mut self, ticks: u64) {
+        self.slice_ticks_left = self.slice_ticks_left.saturating_sub(ticks);
+    }
+
+    /// Whether the current thread has used up its slice, so the scheduler
+    /// should switch to another thread (with [Self::choose_next_thread]) if
+    /// one can run.
+    pub fn slice_expired(&self) -> bool {
+        self.slice_ticks_left == 0
+    }
+
+    /// Give up the rest of the current thread's slice, like `sched_yield`.
+    /// Similar to blocking, this only takes effect _after_ the calling
+    /// function returns to the host run loop.
+    pub fn end_slice(&mut self) {
+        self.slice_ticks_left = 0;
+    }
+
+    pub fn priority(&self, thread: ThreadId) -> i32 {
+        self.priorities
+            .get(&thread)
+            .copied()
+            .unwrap_or(DEFAULT_THREAD_PRIORITY)
+    }
+
+    pub fn set_priority(&mut self, thread: ThreadId, priority: i32) {
+        log_dbg!("Set priority of thread {} to {}", thread, priority);
+        self.priorities.insert(thread, priority);
+    }
+
+    /// Chooses which of the `runnable` threads should run after `current`.
+    /// The thread with the highest priority wins, and threads with equal
+    /// priority take turns, in order of thread ID after `current`.
+    pub fn choose_next_thread(
+        &mut self,
+        current: ThreadId,
+        runnable: &[ThreadId],
+    ) -> Option<ThreadId> {
+        // Sort key for round-robin order: threads after the current one come
+        // first, and the current one comes last.
+        let turn = |thread: ThreadId| {
+            if thread > current {
+                (0, thread)
+            } else {
+                (1, thread)
+            }
+        };
+        let effective_priority = |thread: ThreadId| {
+            self.priority(thread)
+                .saturating_add(self.passed_over.get(&thread).copied().unwrap_or(0))
+        };
+        let &next = runnable.iter().min_by(|&&a, &&b| {
+            effective_priority(b)
+                .cmp(&effective_priority(a))
+                .then(turn(a).cmp(&turn(b)))
+        })?;
+
+        let next_priority = self.priority(next);
+        for &thread in runnable {
+            if thread != next && self.priority(thread) < next_priority {
+                *self.passed_over.entry(thread).or_insert(0) += 1;
+            }
+        }
+        self.passed_over.remove(&next);
+        self.slice_ticks_left = self.time_slice;
+        Some(next)
+    }
+
+    /// Forgets about a thread that has exited.
+    pub fn remove_thread(&mut self, thread: ThreadId) {
+        self.priorities.remove(&thread);
+        self.passed_over.remove(&thread);
+    }
+}
+
+#[cfg(test)]
+#[test]
+fn test_scheduler() {
+    assert_eq!(parse_time_slice("1000"), Ok(1000));
+    assert!(parse_time_slice("0").is_err());
+    assert!(parse_time_slice("fast").is_err());
+
+    let mut state = SchedulerState::new(1000);
+    assert_eq!(state.tick_limit(u64::MAX), 1000);
+    state.ticks_used(600);
+    assert!(!state.slice_expired());
+    assert_eq!(state.tick_limit(u64::MAX), 400);
+    assert_eq!(state.tick_limit(100), 100);
+    state.ticks_used(400);
+    assert!(state.slice_expired());
+
+    // Equal priorities: round-robin.
+    assert_eq!(state.choose_next_thread(0, &[0, 1, 2]), Some(1));
+    assert_eq!(state.tick_limit(u64::MAX), 1000);
+    state.end_slice();
+    assert!(state.slice_expired());
+    assert_eq!(state.choose_next_thread(1, &[0, 1, 2]), Some(2));
+    assert_eq!(state.choose_next_thread(2, &[0, 1, 2]), Some(0));
+    assert_eq!(state.choose_next_thread(0, &[0]), Some(0));
+    assert_eq!(state.choose_next_thread(0, &[]), None);
+
+    // A higher-priority thread is preferred, but not forever.
+    state.set_priority(2, DEFAULT_THREAD_PRIORITY + 2);
+    assert_eq!(state.choose_next_thread(0, &[0, 1, 2]), Some(2));
+    assert_eq!(state.choose_next_thread(2, &[0, 1, 2]), Some(2));
+    assert_eq!(state.choose_next_thread(2, &[0, 1, 2]), Some(0));
+    assert_eq!(state.choose_next_thread(0, &[0, 1, 2]), Some(1));
+}
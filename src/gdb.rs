@@ -11,22 +11,196 @@
 //! - The GDB source code:
 //!   - `include/gdb/signals.def` for the meanings of signal numbers
 //!   - `gdb/arch/arm.h` for ARMv6 register numbers
+//!
+//! Guest threads are visible to the debugger, but only the current thread
+//! runs when execution is continued or stepped. touchHLE's thread IDs start
+//! from 0 (the main thread), but GDB's must be positive, so they're offset by 1
+//! in packets.
+//!
+//! Software breakpoints are left to the debugger, which writes trap
+//! instructions into memory. Watchpoints are implemented by touchHLE (see
+//! [crate::mem::Watchpoints]).
 
-use crate::cpu::{Cpu, CpuError};
-use crate::mem::{GuestUSize, Mem, Ptr};
+use crate::cpu::{Cpu, CpuContext, CpuError};
+use crate::mem::{GuestUSize, Mem, Ptr, WatchpointKind};
 use std::fmt::Write as _;
 use std::io::{BufRead, BufReader, ErrorKind, Read, Write};
 use std::net::TcpStream;
 use std::time::Duration;
 
 /// GDB target description XML.
+///
+/// GDB's default register numbering for Arm is kept for the core registers.
+/// GDB provides s0-s31 itself, based on d0-d15.
 const TARGET_XML: &str = r#"
 <target version="1.0">
     <architecture>armv6</architecture>
     <osabi>Darwin</osabi>
+    <feature name="org.gnu.gdb.arm.core">
+        <reg name="r0" bitsize="32"/>
+        <reg name="r1" bitsize="32"/>
+        <reg name="r2" bitsize="32"/>
+        <reg name="r3" bitsize="32"/>
+        <reg name="r4" bitsize="32"/>
+        <reg name="r5" bitsize="32"/>
+        <reg name="r6" bitsize="32"/>
+        <reg name="r7" bitsize="32"/>
+        <reg name="r8" bitsize="32"/>
+        <reg name="r9" bitsize="32"/>
+        <reg name="r10" bitsize="32"/>
+        <reg name="r11" bitsize="32"/>
+        <reg name="r12" bitsize="32"/>
+        <reg name="sp" bitsize="32" type="data_ptr"/>
+        <reg name="lr" bitsize="32"/>
+        <reg name="pc" bitsize="32" type="code_ptr"/>
+        <reg name="cpsr" bitsize="32" regnum="25"/>
+    </feature>
+    <feature name="org.gnu.gdb.arm.vfp">
+        <reg name="d0" bitsize="64" type="ieee_double" regnum="26"/>
+        <reg name="d1" bitsize="64" type="ieee_double"/>
+        <reg name="d2" bitsize="64" type="ieee_double"/>
+        <reg name="d3" bitsize="64" type="ieee_double"/>
+        <reg name="d4" bitsize="64" type="ieee_double"/>
+        <reg name="d5" bitsize="64" type="ieee_double"/>
+        <reg name="d6" bitsize="64" type="ieee_double"/>
+        <reg name="d7" bitsize="64" type="ieee_double"/>
+        <reg name="d8" bitsize="64" type="ieee_double"/>
+        <reg name="d9" bitsize="64" type="ieee_double"/>
+        <reg name="d10" bitsize="64" type="ieee_double"/>
+        <reg name="d11" bitsize="64" type="ieee_double"/>
+        <reg name="d12" bitsize="64" type="ieee_double"/>
+        <reg name="d13" bitsize="64" type="ieee_double"/>
+        <reg name="d14" bitsize="64" type="ieee_double"/>
+        <reg name="d15" bitsize="64" type="ieee_double"/>
+        <reg name="fpscr" bitsize="32" type="int" group="float"/>
+    </feature>
 </target>
 "#;
 
+/// Register numbers from [TARGET_XML]. r0-r15 are numbered 0-15.
+const CPSR_REGNUM: usize = 25;
+const D0_REGNUM: usize = 26;
+const D15_REGNUM: usize = D0_REGNUM + 15;
+const FPSCR_REGNUM: usize = D15_REGNUM + 1;
+
+/// The registers in `g` and `G` packets, in order.
+fn g_packet_registers() -> impl Iterator<Item = usize> {
+    (0..16)
+        .chain([CPSR_REGNUM])
+        .chain(D0_REGNUM..=D15_REGNUM)
+        .chain([FPSCR_REGNUM])
+}
+
+/// Read a register by number, returning its value and its size in bytes.
+fn read_register(cpu: &Cpu, num: usize) -> Option<(u64, usize)> {
+    match num {
+        0..=15 => Some((cpu.regs()[num].into(), 4)),
+        CPSR_REGNUM => Some((cpu.cpsr().into(), 4)),
+        D0_REGNUM..=D15_REGNUM => {
+            let s = &cpu.ext_regs()[(num - D0_REGNUM) * 2..][..2];
+            Some((u64::from(s[0]) | (u64::from(s[1]) << 32), 8))
+        }
+        FPSCR_REGNUM => Some((cpu.fpscr().into(), 4)),
+        _ => None,
+    }
+}
+
+/// Write a register by number. Returns [false] if there's no such register.
+fn write_register(cpu: &mut Cpu, num: usize, value: u64) -> bool {
+    match num {
+        0..=15 => cpu.regs_mut()[num] = value as u32,
+        CPSR_REGNUM => cpu.set_cpsr(value as u32),
+        D0_REGNUM..=D15_REGNUM => {
+            let s = &mut cpu.ext_regs_mut()[(num - D0_REGNUM) * 2..][..2];
+            s[0] = value as u32;
+            s[1] = (value >> 32) as u32;
+        }
+        FPSCR_REGNUM => cpu.set_fpscr(value as u32),
+        _ => return false,
+    }
+    true
+}
+
+/// Encode a register value for GDB, which expects little-endian hex digits.
+fn encode_register(value: u64, size: usize) -> String {
+    let mut hex = String::with_capacity(size * 2);
+    for byte in &value.to_le_bytes()[..size] {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+/// Decode a register value from GDB's little-endian hex digits.
+fn decode_register(hex: &str) -> Option<u64> {
+    if hex.len() % 2 != 0 || hex.len() > 16 {
+        return None;
+    }
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate().take(hex.len() / 2) {
+        *byte = u8::from_str_radix(&hex[i * 2..][..2], 16).ok()?;
+    }
+    Some(u64::from_le_bytes(bytes))
+}
+
+/// A guest thread, as seen by the debugger.
+pub struct GdbThread<'a> {
+    /// touchHLE's ID for the thread.
+    pub id: usize,
+    /// Human-readable description for `info threads` (`qThreadExtraInfo`),
+    /// e.g. what the thread is blocked on.
+    pub description: String,
+    /// The thread's saved CPU state, or [None] for the current thread, whose
+    /// state is in the [Cpu].
+    pub context: Option<&'a mut CpuContext>,
+}
+
+/// Tracks which thread's state is swapped into the [Cpu], so that the register
+/// packets can be used on any thread.
+struct ThreadSelection {
+    /// Index of the thread whose registers the debugger is viewing.
+    selected: usize,
+    /// Index of the thread whose saved context has been swapped with the
+    /// current thread's state, if any.
+    swapped_in: Option<usize>,
+}
+
+impl ThreadSelection {
+    fn select(&mut self, idx: usize, cpu: &mut Cpu, threads: &mut [GdbThread]) {
+        if self.selected == idx {
+            return;
+        }
+        self.restore(cpu, threads);
+        // Swapping the same context object back in later restores the current
+        // thread's state.
+        if let Some(ref mut context) = threads[idx].context {
+            cpu.swap_context(context);
+            self.swapped_in = Some(idx);
+        }
+        self.selected = idx;
+    }
+
+    fn restore(&mut self, cpu: &mut Cpu, threads: &mut [GdbThread]) {
+        if let Some(idx) = self.swapped_in.take() {
+            cpu.swap_context(threads[idx].context.as_mut().unwrap());
+        }
+    }
+}
+
+/// Parse a thread ID from a packet, returning the index in the thread list, or
+/// [None] for an unknown thread. `0` means any thread and `-1` means all
+/// threads; for simplicity, both are treated as meaning the current thread.
+fn parse_thread_id(
+    thread_id: &str,
+    threads: &[GdbThread],
+    current_thread_idx: usize,
+) -> Option<usize> {
+    if thread_id == "0" || thread_id == "-1" {
+        return Some(current_thread_idx);
+    }
+    let thread_id = usize::from_str_radix(thread_id, 16).ok()?.checked_sub(1)?;
+    threads.iter().position(|thread| thread.id == thread_id)
+}
+
 /// GDB Remote Serial Protocol handler, implementing a server.
 pub struct GdbServer {
     reader: BufReader<TcpStream>,
@@ -126,18 +300,51 @@ impl GdbServer {
         log_dbg!("Sent packet: {:?}", body);
     }
 
+    /// Send a stop reply, which tells the debugger which signal stopped which
+    /// thread, and which watchpoint was triggered, if any.
+    fn send_stop_reply(
+        &mut self,
+        signal: u8,
+        thread_id: usize,
+        watchpoint: Option<(WatchpointKind, GuestUSize)>,
+    ) {
+        let mut packet = format!("T{:02x}thread:{:x};", signal, thread_id + 1);
+        if let Some((kind, addr)) = watchpoint {
+            let name = match kind {
+                WatchpointKind::Write => "watch",
+                WatchpointKind::Read => "rwatch",
+                WatchpointKind::Access => "awatch",
+            };
+            write!(packet, "{}:{:x};", name, addr).unwrap();
+        }
+        self.send_packet(&packet);
+    }
+
     /// Communciates with the debugger, returning only once it requests
     /// execution should continue. Returns [true] if the CPU should step and
     /// then resume debugging, or [false] if it should resume normal execution.
+    ///
+    /// `threads` must include the current thread.
     #[must_use]
     pub fn wait_for_debugger(
         &mut self,
         stop_reason: Option<CpuError>,
         cpu: &mut Cpu,
         mem: &mut Mem,
+        threads: &mut [GdbThread],
     ) -> bool {
         echo!("Waiting for debugger to continue.");
 
+        let current_thread_idx = threads
+            .iter()
+            .position(|thread| thread.context.is_none())
+            .unwrap();
+        let current_thread_id = threads[current_thread_idx].id;
+        let mut selection = ThreadSelection {
+            selected: current_thread_idx,
+            swapped_in: None,
+        };
+
         // Send reply to continue/step packet that gdb sent earlier, so it knows
         // why execution was stopped.
         match stop_reason {
@@ -149,7 +356,7 @@ impl GdbServer {
                 } else {
                     // The debugger previously requested stepping and no errors
                     // occurred.
-                    self.send_packet("S05"); // SIGTRAP
+                    self.send_stop_reply(0x05, current_thread_id, None); // SIGTRAP
                 }
             }
             // GDB uses an undefined instruction for software breakpoints in
@@ -157,10 +364,14 @@ impl GdbServer {
             // It apparently expects SIGTRAP instead of SIGILL even in the
             // former case.
             Some(CpuError::UndefinedInstruction) | Some(CpuError::Breakpoint) => {
-                self.send_packet("S05"); // SIGTRAP
+                self.send_stop_reply(0x05, current_thread_id, None); // SIGTRAP
             }
-            Some(CpuError::MemoryError) => {
-                self.send_packet("S0b"); // SIGSEGV
+            Some(CpuError::MemoryError) | Some(CpuError::ProtectionFault(..)) => {
+                self.send_stop_reply(0x0b, current_thread_id, None); // SIGSEGV
+            }
+            Some(CpuError::Watchpoint(kind, addr)) => {
+                // SIGTRAP
+                self.send_stop_reply(0x05, current_thread_id, Some((kind, addr)));
             }
         }
 
@@ -177,50 +388,64 @@ impl GdbServer {
                 // Query for target halt reason when first connecting
                 b'?' => {
                     assert!(stop_reason.is_none());
-                    self.send_packet("S00"); // no signal
+                    self.send_stop_reply(0x00, current_thread_id, None); // no signal
+                }
+                // Set thread for subsequent operations
+                b'H' => {
+                    let (Some(op), Some(thread_id)) = (p.get(1..2), p.get(2..)) else {
+                        // Error 1: malformed packet
+                        self.send_packet("E01");
+                        continue;
+                    };
+                    match (op, parse_thread_id(thread_id, threads, current_thread_idx)) {
+                        // Register operations can be done on any thread.
+                        ("g", Some(idx)) => {
+                            selection.select(idx, cpu, threads);
+                            self.send_packet("OK");
+                        }
+                        // touchHLE decides which thread runs, so this is
+                        // accepted but has no effect.
+                        ("c", Some(_)) => self.send_packet("OK"),
+                        // Error 0
+                        _ => self.send_packet("E00"),
+                    }
+                }
+                // Query whether a thread is alive
+                b'T' => {
+                    if parse_thread_id(&p[1..], threads, current_thread_idx).is_some() {
+                        self.send_packet("OK");
+                    } else {
+                        // Error 0
+                        self.send_packet("E00");
+                    }
                 }
                 // Read general registers
                 b'g' => {
-                    let mut packet = String::with_capacity(16 * 4 * 2);
-                    for reg in cpu.regs() {
-                        // Rust always prints in big-endian, but GDB expects
-                        // little-endian.
-                        let reg = u32::from_be_bytes(reg.to_le_bytes());
-                        write!(packet, "{:08x}", reg).unwrap();
+                    let mut packet = String::new();
+                    for num in g_packet_registers() {
+                        let (value, size) = read_register(cpu, num).unwrap();
+                        packet.push_str(&encode_register(value, size));
                     }
                     self.send_packet(&packet);
                 }
                 // Write general registers
                 b'G' => {
-                    let data = &p[1..];
-                    let regs = cpu.regs_mut();
-                    assert!(data.len() == regs.len() * 4 * 2);
-                    for (i, reg) in regs.iter_mut().enumerate() {
-                        let word = &data[i * 4 * 2..][..4 * 2];
-                        let word = u32::from_str_radix(word, 16).unwrap();
-                        // Rust decodes in big-endian, but GDB supplies
-                        // little-endian.
-                        let word = u32::from_le_bytes(word.to_be_bytes());
-                        *reg = word;
+                    let mut data = &p[1..];
+                    for num in g_packet_registers() {
+                        let (_, size) = read_register(cpu, num).unwrap();
+                        let (value, rest) = data.split_at(size * 2);
+                        data = rest;
+                        let written = write_register(cpu, num, decode_register(value).unwrap());
+                        assert!(written);
                     }
+                    assert!(data.is_empty());
                     self.send_packet("OK");
                 }
                 // Read single register by number
                 b'p' => {
                     let num = usize::from_str_radix(&p[1..], 16).unwrap();
-                    let reg = if num < 16 {
-                        Some(cpu.regs()[num])
-                    } else if num == 25 {
-                        Some(cpu.cpsr())
-                    // TODO: FPSCR, VFP registers
-                    } else {
-                        None
-                    };
-                    if let Some(reg) = reg {
-                        // Rust always prints in big-endian, but GDB expects
-                        // little-endian.
-                        let reg = u32::from_be_bytes(reg.to_le_bytes());
-                        self.send_packet(&format!("{:08x}", reg));
+                    if let Some((value, size)) = read_register(cpu, num) {
+                        self.send_packet(&encode_register(value, size));
                     } else {
                         // Error 0
                         self.send_packet("E00");
@@ -228,19 +453,11 @@ impl GdbServer {
                 }
                 // Write single register by number
                 b'P' => {
-                    let (num, word) = p[1..].split_once('=').unwrap();
+                    let (num, value) = p[1..].split_once('=').unwrap();
                     let num = usize::from_str_radix(num, 16).unwrap();
-                    let word = u32::from_str_radix(word, 16).unwrap();
-                    // Rust decodes in big-endian, but GDB supplies
-                    // little-endian.
-                    let word = u32::from_le_bytes(word.to_be_bytes());
-                    if num < 16 {
-                        cpu.regs_mut()[num] = word;
-                        self.send_packet("OK");
-                    } else if num == 25 {
-                        cpu.set_cpsr(word);
+                    let value = decode_register(value).unwrap();
+                    if write_register(cpu, num, value) {
                         self.send_packet("OK");
-                    // TODO: FPSCR, VFP registers
                     } else {
                         // Error 0
                         self.send_packet("E00");
@@ -290,6 +507,42 @@ impl GdbServer {
                         }
                     }
                 }
+                // Insert or remove watchpoint
+                b'Z' | b'z' => {
+                    let (type_, params) = p[1..].split_once(',').unwrap();
+                    let (addr, size) = params.split_once(',').unwrap();
+                    let addr = GuestUSize::from_str_radix(addr, 16).unwrap();
+                    let size = GuestUSize::from_str_radix(size, 16).unwrap();
+                    let kind = match type_ {
+                        "2" => WatchpointKind::Write,
+                        "3" => WatchpointKind::Read,
+                        "4" => WatchpointKind::Access,
+                        // Software and hardware breakpoints aren't supported,
+                        // so GDB will implement software breakpoints for us
+                        // with trap instructions.
+                        _ => {
+                            self.send_packet("");
+                            continue;
+                        }
+                    };
+                    if size == 0 || addr.checked_add(size - 1).is_none() {
+                        // Error 0
+                        self.send_packet("E00");
+                        continue;
+                    }
+                    if p.as_bytes()[0] == b'Z' {
+                        mem.watchpoints_mut().add(addr, size, kind);
+                    } else if !mem.watchpoints_mut().remove(addr, size, kind) {
+                        // Error 0
+                        self.send_packet("E00");
+                        continue;
+                    }
+                    // Pages with watchpoints must use the memory callbacks.
+                    for page in (addr / 0x1000)..=((addr + (size - 1)) / 0x1000) {
+                        cpu.update_page_direct_access(mem, page * 0x1000);
+                    }
+                    self.send_packet("OK");
+                }
                 // Continue or Step
                 b'c' | b's' => {
                     let addr = &p[1..];
@@ -317,6 +570,33 @@ impl GdbServer {
                     if p == "qAttached" {
                         // New process
                         self.send_packet("0");
+                    // Query for the list of threads. It's short enough to be
+                    // sent all at once, so the follow-up query gets the
+                    // end-of-list marker.
+                    } else if p == "qfThreadInfo" {
+                        let ids: Vec<String> = threads
+                            .iter()
+                            .map(|thread| format!("{:x}", thread.id + 1))
+                            .collect();
+                        self.send_packet(&format!("m{}", ids.join(",")));
+                    } else if p == "qsThreadInfo" {
+                        self.send_packet("l");
+                    // Query for the current thread
+                    } else if p == "qC" {
+                        self.send_packet(&format!("QC{:x}", current_thread_id + 1));
+                    // Query for a thread's description
+                    } else if let Some(thread_id) = p.strip_prefix("qThreadExtraInfo,") {
+                        match parse_thread_id(thread_id, threads, current_thread_idx) {
+                            Some(idx) => {
+                                let mut packet = String::new();
+                                for byte in threads[idx].description.bytes() {
+                                    write!(packet, "{:02x}", byte).unwrap();
+                                }
+                                self.send_packet(&packet);
+                            }
+                            // Error 0
+                            None => self.send_packet("E00"),
+                        }
                     // Query for supported features
                     } else if p == "qSupported" || p.starts_with("qSupported:") {
                         // Tell GDB we can send it an XML target description.
@@ -352,15 +632,14 @@ impl GdbServer {
                     } else {
                         log_dbg!("Unhandled packet.");
                         // Tell GDB we don't understand this packet.
-                        // In some cases this causes convenient fallbacks:
-                        // Since we don't support 'Z', GDB will implement
-                        // software breakpoints for us with trap instructions.
                         self.send_packet("");
                     }
                 }
             }
         };
 
+        selection.restore(cpu, threads);
+
         if do_step {
             echo!("Debugger requested step, resuming execution for one instruction only.");
         } else {
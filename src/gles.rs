@@ -24,7 +24,9 @@
 //!   - [gles1_native] passes through native OpenGL ES 1.1.
 //!   - [gles1_on_gl2] provides an implementation of OpenGL ES 1.1 using OpenGL
 //!     2.1 compatibility profile.
-//!   - There might be more in future.
+//!   - [gles1_software] provides an implementation of OpenGL ES 1.1 that runs
+//!     entirely on the CPU, for when no suitable driver is available or when
+//!     output must not depend on the driver.
 //! - [gles11_raw] provides raw bindings for OpenGL ES 1.1 generated from the
 //!   Khronos API headers. **The function bindings are only for use within this
 //!   module.** The constants and types can be used outside it, however.
@@ -62,6 +64,7 @@
 
 pub mod gles1_native;
 pub mod gles1_on_gl2;
+pub mod gles1_software;
 mod gles_generic;
 pub mod present;
 mod util;
@@ -71,6 +74,7 @@ pub use touchHLE_gl_bindings::gles11 as gles11_raw;
 
 use gles1_native::GLES1Native;
 use gles1_on_gl2::GLES1OnGL2;
+use gles1_software::GLES1Software;
 pub use gles_generic::GLES;
 
 /// Labels for [GLES] implementations and an abstraction for constructing them.
@@ -80,9 +84,14 @@ pub enum GLESImplementation {
     GLES1Native,
     /// [GLES1OnGL2].
     GLES1OnGL2,
+    /// [GLES1Software].
+    GLES1Software,
 }
 impl GLESImplementation {
     /// List of OpenGL ES 1.1 implementations in order of preference.
+    ///
+    /// [Self::GLES1Software] isn't included because it's much slower than the
+    /// others, and the window must be created differently for it.
     pub const GLES1_IMPLEMENTATIONS: &'static [Self] = &[Self::GLES1Native, Self::GLES1OnGL2];
     /// Convert from short name used for command-line arguments. Returns [Err]
     /// if name is not recognized..
@@ -90,6 +99,7 @@ impl GLESImplementation {
         match name {
             "gles1_on_gl2" => Ok(Self::GLES1OnGL2),
             "gles1_native" => Ok(Self::GLES1Native),
+            "software" => Ok(Self::GLES1Software),
             _ => Err(()),
         }
     }
@@ -98,6 +108,7 @@ impl GLESImplementation {
         match self {
             Self::GLES1Native => GLES1Native::description(),
             Self::GLES1OnGL2 => GLES1OnGL2::description(),
+            Self::GLES1Software => GLES1Software::description(),
         }
     }
     /// See [GLES::new].
@@ -108,6 +119,7 @@ impl GLESImplementation {
         match self {
             Self::GLES1Native => GLES1Native::new(window).map(boxer),
             Self::GLES1OnGL2 => GLES1OnGL2::new(window).map(boxer),
+            Self::GLES1Software => GLES1Software::new(window).map(boxer),
         }
     }
 }
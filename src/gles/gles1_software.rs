@@ -0,0 +1,3891 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Pure-software implementation of OpenGL ES 1.1.
+//!
+//! The other implementations need a real graphics driver, which isn't always
+//! available (e.g. on CI machines without a GPU). This one does everything on
+//! the CPU instead: vertex transformation and lighting, clipping,
+//! rasterization, texturing and the per-fragment operations are all done in
+//! plain Rust, and all buffers, textures and renderbuffers live in host memory.
+//!
+//! It is slow and only aims to be as accurate as is needed to run apps, but its
+//! output doesn't depend on the host's driver, which makes it suitable for
+//! comparing screenshots.
+//!
+//! Since there's no window system framebuffer to draw to, framebuffer 0 is a
+//! [DefaultFramebuffer] in host memory that is shared by all the contexts
+//! created for a window. [Window::swap_window] copies it to the window.
+
+use super::gles11_raw as gles11; // constants only
+use super::gles11_raw::types::*;
+use super::util::{
+    fixed_to_float, matrix_fixed_to_float, try_decode_pvrtc, PalettedTextureFormat, ParamTable,
+    ParamType,
+};
+use super::GLES;
+use crate::window::Window;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+
+// Tokens from extensions that aren't in our generated bindings.
+const POINT_SPRITE_OES: GLenum = 0x8861;
+const COORD_REPLACE_OES: GLenum = 0x8862;
+const IMPLEMENTATION_COLOR_READ_TYPE_OES: GLenum = 0x8B9A;
+const IMPLEMENTATION_COLOR_READ_FORMAT_OES: GLenum = 0x8B9B;
+const DEPTH_COMPONENT24_OES: GLenum = 0x81A6;
+const STENCIL_INDEX8_OES: GLenum = 0x8D48;
+const DEPTH24_STENCIL8_OES: GLenum = 0x88F0;
+
+const TEXTURE_UNITS: usize = 2;
+const MAX_LIGHTS: usize = 8;
+const MAX_TEXTURE_SIZE: u32 = 2048;
+const MAX_MODELVIEW_STACK_DEPTH: usize = 16;
+const MAX_PROJECTION_STACK_DEPTH: usize = 2;
+const MAX_TEXTURE_STACK_DEPTH: usize = 2;
+const POINT_SIZE_RANGE: (f32, f32) = (1.0, 64.0);
+const LINE_WIDTH_RANGE: (f32, f32) = (1.0, 64.0);
+const MAX_TEXTURE_LOD_BIAS: f32 = 4.0;
+
+/// List of capabilities that can be used with `glEnable` and `glDisable`.
+/// `GL_TEXTURE_2D` is handled separately because it is per-texture-unit.
+const CAPABILITIES: &[GLenum] = &[
+    gles11::ALPHA_TEST,
+    gles11::BLEND,
+    gles11::COLOR_LOGIC_OP,
+    gles11::CLIP_PLANE0,
+    gles11::LIGHT0,
+    gles11::LIGHT1,
+    gles11::LIGHT2,
+    gles11::LIGHT3,
+    gles11::LIGHT4,
+    gles11::LIGHT5,
+    gles11::LIGHT6,
+    gles11::LIGHT7,
+    gles11::COLOR_MATERIAL,
+    gles11::CULL_FACE,
+    gles11::DEPTH_TEST,
+    gles11::DITHER,
+    gles11::FOG,
+    gles11::LIGHTING,
+    gles11::LINE_SMOOTH,
+    gles11::MULTISAMPLE,
+    gles11::NORMALIZE,
+    gles11::POINT_SMOOTH,
+    gles11::POLYGON_OFFSET_FILL,
+    gles11::RESCALE_NORMAL,
+    gles11::SAMPLE_ALPHA_TO_COVERAGE,
+    gles11::SAMPLE_ALPHA_TO_ONE,
+    gles11::SAMPLE_COVERAGE,
+    gles11::SCISSOR_TEST,
+    gles11::STENCIL_TEST,
+    POINT_SPRITE_OES,
+];
+
+/// Table of `glPointParameter` parameters.
+const POINT_PARAMS: ParamTable = ParamTable(&[
+    (gles11::POINT_SIZE_MIN, ParamType::Float, 1),
+    (gles11::POINT_SIZE_MAX, ParamType::Float, 1),
+    (gles11::POINT_DISTANCE_ATTENUATION, ParamType::Float, 3),
+    (gles11::POINT_FADE_THRESHOLD_SIZE, ParamType::Float, 1),
+]);
+
+/// Table of `glFog` parameters.
+const FOG_PARAMS: ParamTable = ParamTable(&[
+    // Despite only having f, fv, x and xv setters in OpenGL ES 1.1, this is
+    // an integer! (You're meant to use the x/xv setter.)
+    (gles11::FOG_MODE, ParamType::Int, 1),
+    (gles11::FOG_DENSITY, ParamType::Float, 1),
+    (gles11::FOG_START, ParamType::Float, 1),
+    (gles11::FOG_END, ParamType::Float, 1),
+    (gles11::FOG_COLOR, ParamType::FloatSpecial, 4),
+]);
+
+/// Table of `glLight` parameters.
+const LIGHT_PARAMS: ParamTable = ParamTable(&[
+    (gles11::AMBIENT, ParamType::Float, 4),
+    (gles11::DIFFUSE, ParamType::Float, 4),
+    (gles11::SPECULAR, ParamType::Float, 4),
+    (gles11::POSITION, ParamType::Float, 4),
+    (gles11::SPOT_CUTOFF, ParamType::Float, 1),
+    (gles11::SPOT_DIRECTION, ParamType::Float, 3),
+    (gles11::SPOT_EXPONENT, ParamType::Float, 1),
+    (gles11::CONSTANT_ATTENUATION, ParamType::Float, 1),
+    (gles11::LINEAR_ATTENUATION, ParamType::Float, 1),
+    (gles11::QUADRATIC_ATTENUATION, ParamType::Float, 1),
+]);
+
+/// Table of `glLightModel` parameters.
+const LIGHT_MODEL_PARAMS: ParamTable = ParamTable(&[
+    (gles11::LIGHT_MODEL_AMBIENT, ParamType::FloatSpecial, 4),
+    (gles11::LIGHT_MODEL_TWO_SIDE, ParamType::Boolean, 1),
+]);
+
+/// Table of `glMaterial` parameters.
+const MATERIAL_PARAMS: ParamTable = ParamTable(&[
+    (gles11::AMBIENT, ParamType::Float, 4),
+    (gles11::DIFFUSE, ParamType::Float, 4),
+    (gles11::SPECULAR, ParamType::Float, 4),
+    (gles11::EMISSION, ParamType::Float, 4),
+    (gles11::SHININESS, ParamType::Float, 1),
+    // Not a true parameter: it's equivalent to calling glMaterial twice, once
+    // for GL_AMBIENT and once for GL_DIFFUSE.
+    (gles11::AMBIENT_AND_DIFFUSE, ParamType::Float, 4),
+]);
+
+/// Table of `glTexEnv` parameters for the `GL_TEXTURE_ENV` target.
+const TEX_ENV_PARAMS: ParamTable = ParamTable(&[
+    (gles11::TEXTURE_ENV_MODE, ParamType::Int, 1),
+    (gles11::COMBINE_RGB, ParamType::Int, 1),
+    (gles11::COMBINE_ALPHA, ParamType::Int, 1),
+    (gles11::SRC0_RGB, ParamType::Int, 1),
+    (gles11::SRC1_RGB, ParamType::Int, 1),
+    (gles11::SRC2_RGB, ParamType::Int, 1),
+    (gles11::SRC0_ALPHA, ParamType::Int, 1),
+    (gles11::SRC1_ALPHA, ParamType::Int, 1),
+    (gles11::SRC2_ALPHA, ParamType::Int, 1),
+    (gles11::OPERAND0_RGB, ParamType::Int, 1),
+    (gles11::OPERAND1_RGB, ParamType::Int, 1),
+    (gles11::OPERAND2_RGB, ParamType::Int, 1),
+    (gles11::OPERAND0_ALPHA, ParamType::Int, 1),
+    (gles11::OPERAND1_ALPHA, ParamType::Int, 1),
+    (gles11::OPERAND2_ALPHA, ParamType::Int, 1),
+    (gles11::TEXTURE_ENV_COLOR, ParamType::Float, 4),
+    (gles11::RGB_SCALE, ParamType::Float, 1),
+    (gles11::ALPHA_SCALE, ParamType::Float, 1),
+]);
+
+/// Table of `glTexParameter` parameters.
+const TEX_PARAMS: ParamTable = ParamTable(&[
+    (gles11::TEXTURE_MIN_FILTER, ParamType::Int, 1),
+    (gles11::TEXTURE_MAG_FILTER, ParamType::Int, 1),
+    (gles11::TEXTURE_WRAP_S, ParamType::Int, 1),
+    (gles11::TEXTURE_WRAP_T, ParamType::Int, 1),
+    (gles11::GENERATE_MIPMAP, ParamType::Int, 1),
+    (gles11::TEXTURE_MAX_ANISOTROPY_EXT, ParamType::Float, 1),
+]);
+
+/// Column-major 4-by-4 matrix, like OpenGL's.
+type Mat4 = [f32; 16];
+
+const IDENTITY: Mat4 = [
+    1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 0.0, 1.0,
+];
+
+fn mat4_mul(a: &Mat4, b: &Mat4) -> Mat4 {
+    let mut res = [0f32; 16];
+    for col in 0..4 {
+        for row in 0..4 {
+            res[col * 4 + row] = (0..4).map(|k| a[k * 4 + row] * b[col * 4 + k]).sum();
+        }
+    }
+    res
+}
+
+fn mat4_transform(m: &Mat4, v: [f32; 4]) -> [f32; 4] {
+    let mut res = [0f32; 4];
+    for (row, cell) in res.iter_mut().enumerate() {
+        *cell = (0..4).map(|k| m[k * 4 + row] * v[k]).sum();
+    }
+    res
+}
+
+fn dot3(a: [f32; 3], b: [f32; 3]) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+
+fn cross3(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+
+fn normalize3(v: [f32; 3]) -> [f32; 3] {
+    let length = dot3(v, v).sqrt();
+    if length == 0.0 {
+        v
+    } else {
+        [v[0] / length, v[1] / length, v[2] / length]
+    }
+}
+
+/// Transform a normal by the inverse-transpose of the upper-left 3-by-3 part
+/// of the modelview matrix.
+fn transform_normal(m: &Mat4, n: [f32; 3]) -> [f32; 3] {
+    let c0 = [m[0], m[1], m[2]];
+    let c1 = [m[4], m[5], m[6]];
+    let c2 = [m[8], m[9], m[10]];
+    let (r0, r1, r2) = (cross3(c1, c2), cross3(c2, c0), cross3(c0, c1));
+    let det = dot3(c0, r0);
+    if det == 0.0 {
+        return [0.0; 3];
+    }
+    let mut res = [0f32; 3];
+    for (i, cell) in res.iter_mut().enumerate() {
+        *cell = (r0[i] * n[0] + r1[i] * n[1] + r2[i] * n[2]) / det;
+    }
+    res
+}
+
+fn compare(func: GLenum, incoming: f32, reference: f32) -> bool {
+    match func {
+        gles11::NEVER => false,
+        gles11::LESS => incoming < reference,
+        gles11::EQUAL => incoming == reference,
+        gles11::LEQUAL => incoming <= reference,
+        gles11::GREATER => incoming > reference,
+        gles11::NOTEQUAL => incoming != reference,
+        gles11::GEQUAL => incoming >= reference,
+        gles11::ALWAYS => true,
+        _ => unreachable!(),
+    }
+}
+
+fn color_to_float(color: [u8; 4]) -> [f32; 4] {
+    color.map(|c| f32::from(c) / 255.0)
+}
+
+fn color_to_u8(color: [f32; 4]) -> [u8; 4] {
+    color.map(|c| (c.clamp(0.0, 1.0) * 255.0).round() as u8)
+}
+
+/// A 2D array of RGBA8 pixels, with rows ordered bottom-to-top like OpenGL.
+#[derive(Clone, Default)]
+struct Image {
+    width: u32,
+    height: u32,
+    pixels: Vec<[u8; 4]>,
+}
+impl Image {
+    fn new(width: u32, height: u32) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![[0, 0, 0, 0]; width as usize * height as usize],
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Half-size box-filtered copy of the image, for mipmap generation.
+    fn downsample(&self) -> Image {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut res = Image::new(width, height);
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = [0u32; 4];
+                for (sx, sy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                    let sx = (x * 2 + sx).min(self.width - 1);
+                    let sy = (y * 2 + sy).min(self.height - 1);
+                    let texel = self.pixels[(sy * self.width + sx) as usize];
+                    for i in 0..4 {
+                        sum[i] += u32::from(texel[i]);
+                    }
+                }
+                res.pixels[(y * width + x) as usize] = sum.map(|c| ((c + 2) / 4) as u8);
+            }
+        }
+        res
+    }
+}
+
+/// The window system framebuffer (framebuffer 0) for [GLES1Software]
+/// contexts. There is one of these per window, shared between all of its
+/// contexts, like the window system framebuffer for a real driver.
+pub struct DefaultFramebuffer {
+    width: u32,
+    height: u32,
+    /// RGBA8, rows ordered bottom-to-top.
+    color: Vec<[u8; 4]>,
+    depth: Vec<f32>,
+    stencil: Vec<u8>,
+}
+impl DefaultFramebuffer {
+    pub fn new(width: u32, height: u32) -> Self {
+        let size = width as usize * height as usize;
+        DefaultFramebuffer {
+            width,
+            height,
+            color: vec![[0, 0, 0, 255]; size],
+            depth: vec![1.0; size],
+            stencil: vec![0; size],
+        }
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.width, self.height)
+    }
+
+    /// Change the size of the framebuffer. The contents are lost.
+    pub fn resize(&mut self, width: u32, height: u32) {
+        if (width, height) != (self.width, self.height) {
+            *self = Self::new(width, height);
+        }
+    }
+
+    /// Get the contents of the color buffer as RGBA8 pixel data, with rows
+    /// ordered top-to-bottom like in most image formats.
+    pub fn read_rgba8_top_down(&self) -> Vec<u8> {
+        let mut res = Vec::with_capacity(self.color.len() * 4);
+        for row in self.color.chunks(self.width as usize).rev() {
+            for pixel in row {
+                res.extend_from_slice(pixel);
+            }
+        }
+        res
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ArrayPointer {
+    size: GLint,
+    type_: GLenum,
+    stride: GLsizei,
+    pointer: *const GLvoid,
+    /// Buffer object that was bound to `GL_ARRAY_BUFFER` when the pointer was
+    /// set. If it's not zero, `pointer` is an offset into that buffer.
+    buffer: GLuint,
+}
+
+#[derive(Copy, Clone)]
+struct ArrayState {
+    enabled: bool,
+    pointer: ArrayPointer,
+}
+impl ArrayState {
+    fn new(size: GLint) -> Self {
+        ArrayState {
+            enabled: false,
+            pointer: ArrayPointer {
+                size,
+                type_: gles11::FLOAT,
+                stride: 0,
+                pointer: std::ptr::null(),
+                buffer: 0,
+            },
+        }
+    }
+}
+
+#[derive(Copy, Clone)]
+struct Light {
+    ambient: [f32; 4],
+    diffuse: [f32; 4],
+    specular: [f32; 4],
+    /// In eye co-ordinates.
+    position: [f32; 4],
+    /// In eye co-ordinates.
+    spot_direction: [f32; 3],
+    spot_exponent: f32,
+    spot_cutoff: f32,
+    /// Constant, linear and quadratic.
+    attenuation: [f32; 3],
+}
+impl Light {
+    fn new(is_light0: bool) -> Self {
+        let color = if is_light0 {
+            [1.0, 1.0, 1.0, 1.0]
+        } else {
+            [0.0, 0.0, 0.0, 1.0]
+        };
+        Light {
+            ambient: [0.0, 0.0, 0.0, 1.0],
+            diffuse: color,
+            specular: color,
+            position: [0.0, 0.0, 1.0, 0.0],
+            spot_direction: [0.0, 0.0, -1.0],
+            spot_exponent: 0.0,
+            spot_cutoff: 180.0,
+            attenuation: [1.0, 0.0, 0.0],
+        }
+    }
+}
+
+/// OpenGL ES 1.1 only supports `GL_FRONT_AND_BACK` for materials, so there's
+/// only one of these.
+struct Material {
+    ambient: [f32; 4],
+    diffuse: [f32; 4],
+    specular: [f32; 4],
+    emission: [f32; 4],
+    shininess: f32,
+}
+
+#[derive(Copy, Clone)]
+struct TexEnv {
+    mode: GLenum,
+    color: [f32; 4],
+    combine_rgb: GLenum,
+    combine_alpha: GLenum,
+    src_rgb: [GLenum; 3],
+    src_alpha: [GLenum; 3],
+    operand_rgb: [GLenum; 3],
+    operand_alpha: [GLenum; 3],
+    rgb_scale: f32,
+    alpha_scale: f32,
+    /// `GL_COORD_REPLACE_OES` (`GL_POINT_SPRITE_OES` target)
+    coord_replace: bool,
+    /// `GL_TEXTURE_LOD_BIAS_EXT` (`GL_TEXTURE_FILTER_CONTROL_EXT` target)
+    lod_bias: f32,
+}
+impl TexEnv {
+    fn new() -> Self {
+        TexEnv {
+            mode: gles11::MODULATE,
+            color: [0.0; 4],
+            combine_rgb: gles11::MODULATE,
+            combine_alpha: gles11::MODULATE,
+            src_rgb: [gles11::TEXTURE, gles11::PREVIOUS, gles11::CONSTANT],
+            src_alpha: [gles11::TEXTURE, gles11::PREVIOUS, gles11::CONSTANT],
+            operand_rgb: [gles11::SRC_COLOR, gles11::SRC_COLOR, gles11::SRC_ALPHA],
+            operand_alpha: [gles11::SRC_ALPHA, gles11::SRC_ALPHA, gles11::SRC_ALPHA],
+            rgb_scale: 1.0,
+            alpha_scale: 1.0,
+            coord_replace: false,
+            lod_bias: 0.0,
+        }
+    }
+
+    /// Get a mutable reference to an integer parameter.
+    fn int_param_mut(&mut self, pname: GLenum) -> &mut GLenum {
+        match pname {
+            gles11::TEXTURE_ENV_MODE => &mut self.mode,
+            gles11::COMBINE_RGB => &mut self.combine_rgb,
+            gles11::COMBINE_ALPHA => &mut self.combine_alpha,
+            gles11::SRC0_RGB => &mut self.src_rgb[0],
+            gles11::SRC1_RGB => &mut self.src_rgb[1],
+            gles11::SRC2_RGB => &mut self.src_rgb[2],
+            gles11::SRC0_ALPHA => &mut self.src_alpha[0],
+            gles11::SRC1_ALPHA => &mut self.src_alpha[1],
+            gles11::SRC2_ALPHA => &mut self.src_alpha[2],
+            gles11::OPERAND0_RGB => &mut self.operand_rgb[0],
+            gles11::OPERAND1_RGB => &mut self.operand_rgb[1],
+            gles11::OPERAND2_RGB => &mut self.operand_rgb[2],
+            gles11::OPERAND0_ALPHA => &mut self.operand_alpha[0],
+            gles11::OPERAND1_ALPHA => &mut self.operand_alpha[1],
+            gles11::OPERAND2_ALPHA => &mut self.operand_alpha[2],
+            _ => panic!("Unhandled parameter name: {:#x}", pname),
+        }
+    }
+
+    fn set_param(&mut self, pname: GLenum, params: &[f32]) {
+        match pname {
+            gles11::TEXTURE_ENV_COLOR => self.color.copy_from_slice(&params[..4]),
+            gles11::RGB_SCALE => self.rgb_scale = params[0],
+            gles11::ALPHA_SCALE => self.alpha_scale = params[0],
+            _ => *self.int_param_mut(pname) = params[0] as GLenum,
+        }
+    }
+}
+
+struct Texture {
+    /// Base internal format of level 0. This decides which components of the
+    /// texture take part in texture environment calculations.
+    format: GLenum,
+    levels: Vec<Image>,
+    min_filter: GLenum,
+    mag_filter: GLenum,
+    wrap_s: GLenum,
+    wrap_t: GLenum,
+    generate_mipmap: bool,
+    max_anisotropy: f32,
+}
+impl Texture {
+    fn new() -> Self {
+        Texture {
+            format: gles11::RGBA,
+            levels: Vec::new(),
+            min_filter: gles11::NEAREST_MIPMAP_LINEAR,
+            mag_filter: gles11::LINEAR,
+            wrap_s: gles11::REPEAT,
+            wrap_t: gles11::REPEAT,
+            generate_mipmap: false,
+            max_anisotropy: 1.0,
+        }
+    }
+
+    fn uses_mipmaps(&self) -> bool {
+        self.min_filter != gles11::NEAREST && self.min_filter != gles11::LINEAR
+    }
+
+    /// Textures that aren't complete can't be sampled from, and texturing is
+    /// effectively disabled for any texture unit they're bound to.
+    fn is_complete(&self) -> bool {
+        let Some(base) = self.levels.first() else {
+            return false;
+        };
+        if base.is_empty() {
+            return false;
+        }
+        if !self.uses_mipmaps() {
+            return true;
+        }
+        let (mut width, mut height) = (base.width, base.height);
+        for level in 1.. {
+            if width == 1 && height == 1 {
+                return true;
+            }
+            width = (width / 2).max(1);
+            height = (height / 2).max(1);
+            match self.levels.get(level) {
+                Some(image) if image.width == width && image.height == height => (),
+                _ => return false,
+            }
+        }
+        unreachable!()
+    }
+
+    fn set_level(&mut self, level: usize, image: Image) {
+        if self.levels.len() <= level {
+            self.levels.resize_with(level + 1, Image::default);
+        }
+        self.levels[level] = image;
+    }
+
+    fn generate_mipmaps(&mut self) {
+        let Some(base) = self.levels.first() else {
+            return;
+        };
+        if base.is_empty() {
+            return;
+        }
+        self.levels.truncate(1);
+        while {
+            let last = self.levels.last().unwrap();
+            last.width > 1 || last.height > 1
+        } {
+            let next = self.levels.last().unwrap().downsample();
+            self.levels.push(next);
+        }
+    }
+
+    fn fetch(&self, level: usize, x: i32, y: i32) -> [f32; 4] {
+        let image = &self.levels[level];
+        let wrap = |coord: i32, size: u32, mode: GLenum| -> u32 {
+            match mode {
+                gles11::REPEAT => coord.rem_euclid(size as i32) as u32,
+                _ => coord.clamp(0, size as i32 - 1) as u32,
+            }
+        };
+        let x = wrap(x, image.width, self.wrap_s);
+        let y = wrap(y, image.height, self.wrap_t);
+        color_to_float(image.pixels[(y * image.width + x) as usize])
+    }
+
+    fn sample_level(&self, level: usize, s: f32, t: f32, linear: bool) -> [f32; 4] {
+        let image = &self.levels[level];
+        let u = s * image.width as f32;
+        let v = t * image.height as f32;
+        if !linear {
+            return self.fetch(level, u.floor() as i32, v.floor() as i32);
+        }
+        let (u, v) = (u - 0.5, v - 0.5);
+        let (x0, y0) = (u.floor(), v.floor());
+        let (fx, fy) = (u - x0, v - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+        let texels = [
+            self.fetch(level, x0, y0),
+            self.fetch(level, x0 + 1, y0),
+            self.fetch(level, x0, y0 + 1),
+            self.fetch(level, x0 + 1, y0 + 1),
+        ];
+        let mut res = [0f32; 4];
+        for (i, cell) in res.iter_mut().enumerate() {
+            let bottom = texels[0][i] * (1.0 - fx) + texels[1][i] * fx;
+            let top = texels[2][i] * (1.0 - fx) + texels[3][i] * fx;
+            *cell = bottom * (1.0 - fy) + top * fy;
+        }
+        res
+    }
+
+    fn sample(&self, s: f32, t: f32, lod: f32) -> [f32; 4] {
+        if lod <= 0.0 || !self.uses_mipmaps() {
+            let filter = if lod <= 0.0 {
+                self.mag_filter
+            } else {
+                self.min_filter
+            };
+            return self.sample_level(0, s, t, filter == gles11::LINEAR);
+        }
+        let max_level = (self.levels.len() - 1) as f32;
+        let lod = lod.min(max_level);
+        let linear = matches!(
+            self.min_filter,
+            gles11::LINEAR_MIPMAP_NEAREST | gles11::LINEAR_MIPMAP_LINEAR
+        );
+        match self.min_filter {
+            gles11::NEAREST_MIPMAP_NEAREST | gles11::LINEAR_MIPMAP_NEAREST => {
+                let level = (lod + 0.5).ceil() - 1.0;
+                self.sample_level(level.max(0.0) as usize, s, t, linear)
+            }
+            _ => {
+                let level0 = lod.floor();
+                let level1 = (level0 + 1.0).min(max_level);
+                let frac = lod - level0;
+                let a = self.sample_level(level0 as usize, s, t, linear);
+                let b = self.sample_level(level1 as usize, s, t, linear);
+                let mut res = [0f32; 4];
+                for i in 0..4 {
+                    res[i] = a[i] * (1.0 - frac) + b[i] * frac;
+                }
+                res
+            }
+        }
+    }
+}
+
+/// Storage for a renderbuffer. Only the vectors relevant to the internal
+/// format are non-empty.
+struct Renderbuffer {
+    internalformat: GLenum,
+    width: u32,
+    height: u32,
+    color: Vec<[u8; 4]>,
+    depth: Vec<f32>,
+    stencil: Vec<u8>,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum Attachment {
+    Renderbuffer(GLuint),
+    /// Texture name and mipmap level.
+    Texture(GLuint, usize),
+}
+
+#[derive(Default)]
+struct Framebuffer {
+    color: Option<Attachment>,
+    depth: Option<Attachment>,
+    stencil: Option<Attachment>,
+}
+
+/// The buffers of the framebuffer being drawn to or read from. These are
+/// temporarily moved out of their owners while in use (see
+/// [GLES1Software::swap_target_buffers]), so that the rest of the context
+/// state (e.g. textures) can be borrowed at the same time. An empty vector
+/// means there is no such buffer.
+#[derive(Default)]
+struct Target {
+    width: u32,
+    height: u32,
+    color: Vec<[u8; 4]>,
+    color_has_alpha: bool,
+    depth: Vec<f32>,
+    stencil: Vec<u8>,
+}
+
+/// Per-vertex data after transformation and lighting, in clip co-ordinates.
+#[derive(Copy, Clone)]
+struct ClipVertex {
+    position: [f32; 4],
+    front_color: [f32; 4],
+    back_color: [f32; 4],
+    tex_coords: [[f32; 4]; TEXTURE_UNITS],
+    /// Eye-space distance from the viewer, used for fog.
+    fog_coord: f32,
+    point_size: f32,
+}
+impl ClipVertex {
+    fn lerp(&self, other: &Self, t: f32) -> Self {
+        let lerp4 = |a: [f32; 4], b: [f32; 4]| {
+            let mut res = a;
+            for i in 0..4 {
+                res[i] = a[i] + (b[i] - a[i]) * t;
+            }
+            res
+        };
+        let mut tex_coords = self.tex_coords;
+        for (unit, tex_coord) in tex_coords.iter_mut().enumerate() {
+            *tex_coord = lerp4(self.tex_coords[unit], other.tex_coords[unit]);
+        }
+        ClipVertex {
+            position: lerp4(self.position, other.position),
+            front_color: lerp4(self.front_color, other.front_color),
+            back_color: lerp4(self.back_color, other.back_color),
+            tex_coords,
+            fog_coord: self.fog_coord + (other.fog_coord - self.fog_coord) * t,
+            point_size: self.point_size + (other.point_size - self.point_size) * t,
+        }
+    }
+
+    /// Signed distances from the six clip planes. Positive means inside.
+    fn clip_distances(&self) -> [f32; 6] {
+        let [x, y, z, w] = self.position;
+        [w + x, w - x, w + y, w - y, w + z, w - z]
+    }
+}
+
+/// Attributes interpolated across primitives: color, texture co-ordinates for
+/// each unit, and fog co-ordinate.
+const VARYING_COUNT: usize = 4 + 4 * TEXTURE_UNITS + 1;
+type Varyings = [f32; VARYING_COUNT];
+const FOG_VARYING: usize = VARYING_COUNT - 1;
+
+fn tex_coord_varying(unit: usize) -> usize {
+    4 + unit * 4
+}
+
+/// A vertex in window co-ordinates, ready for rasterization.
+#[derive(Copy, Clone)]
+struct WindowVertex {
+    x: f32,
+    y: f32,
+    z: f32,
+    /// Reciprocal of the clip-space `w`, for perspective-correct
+    /// interpolation.
+    inv_w: f32,
+    varyings: Varyings,
+}
+
+/// Texture co-ordinate derivatives in window space for a texture unit:
+/// ds/dx, dt/dx, ds/dy, dt/dy. Used to choose a mipmap level.
+type TexCoordDerivatives = [[f32; 4]; TEXTURE_UNITS];
+
+/// Linear function over window co-ordinates, for interpolation within a
+/// triangle.
+#[derive(Copy, Clone, Default)]
+struct Plane {
+    base: f32,
+    dx: f32,
+    dy: f32,
+}
+impl Plane {
+    fn new(v: [&WindowVertex; 3], values: [f32; 3], area: f32) -> Self {
+        let (x1, y1) = (v[1].x - v[0].x, v[1].y - v[0].y);
+        let (x2, y2) = (v[2].x - v[0].x, v[2].y - v[0].y);
+        let (q1, q2) = (values[1] - values[0], values[2] - values[0]);
+        let dx = (q1 * y2 - q2 * y1) / area;
+        let dy = (q2 * x1 - q1 * x2) / area;
+        Plane {
+            base: values[0] - dx * v[0].x - dy * v[0].y,
+            dx,
+            dy,
+        }
+    }
+
+    fn at(&self, x: f32, y: f32) -> f32 {
+        self.base + self.dx * x + self.dy * y
+    }
+}
+
+/// Edge function: positive if `p` is to the left of the line from `a` to `b`.
+fn edge(a: &WindowVertex, b: &WindowVertex, p: (f32, f32)) -> f32 {
+    (b.x - a.x) * (p.1 - a.y) - (b.y - a.y) * (p.0 - a.x)
+}
+
+/// Fill rule tie-breaker for pixels exactly on an edge of a counter-clockwise
+/// triangle: only top and left edges own such pixels, so that pixels on edges
+/// shared by two triangles are drawn exactly once.
+fn is_top_left(a: &WindowVertex, b: &WindowVertex) -> bool {
+    let (dx, dy) = (b.x - a.x, b.y - a.y);
+    dy < 0.0 || (dy == 0.0 && dx < 0.0)
+}
+
+/// Number of bytes per pixel for a `glTexImage2D`-style format and type.
+fn bytes_per_pixel(format: GLenum, type_: GLenum) -> usize {
+    match type_ {
+        gles11::UNSIGNED_BYTE => match format {
+            gles11::ALPHA | gles11::LUMINANCE => 1,
+            gles11::LUMINANCE_ALPHA => 2,
+            gles11::RGB => 3,
+            gles11::RGBA | gles11::BGRA_EXT => 4,
+            _ => panic!("Unhandled format {:#x}", format),
+        },
+        gles11::UNSIGNED_SHORT_5_6_5
+        | gles11::UNSIGNED_SHORT_4_4_4_4
+        | gles11::UNSIGNED_SHORT_5_5_5_1 => 2,
+        _ => panic!("Unhandled type {:#x}", type_),
+    }
+}
+
+/// Size in bytes of a row of pixels, taking `GL_PACK_ALIGNMENT` or
+/// `GL_UNPACK_ALIGNMENT` into account.
+fn row_stride(width: usize, bytes_per_pixel: usize, alignment: GLint) -> usize {
+    let alignment = alignment as usize;
+    (width * bytes_per_pixel).div_ceil(alignment) * alignment
+}
+
+/// Convert one pixel in a `glTexImage2D`-style format and type to RGBA8.
+fn unpack_pixel(format: GLenum, type_: GLenum, bytes: &[u8]) -> [u8; 4] {
+    let expand = |value: u16, bits: u32| -> u8 {
+        let max = (1u32 << bits) - 1;
+        ((u32::from(value) * 255 + max / 2) / max) as u8
+    };
+    match type_ {
+        gles11::UNSIGNED_BYTE => match format {
+            gles11::ALPHA => [0, 0, 0, bytes[0]],
+            gles11::LUMINANCE => [bytes[0], bytes[0], bytes[0], 255],
+            gles11::LUMINANCE_ALPHA => [bytes[0], bytes[0], bytes[0], bytes[1]],
+            gles11::RGB => [bytes[0], bytes[1], bytes[2], 255],
+            gles11::RGBA => [bytes[0], bytes[1], bytes[2], bytes[3]],
+            gles11::BGRA_EXT => [bytes[2], bytes[1], bytes[0], bytes[3]],
+            _ => unreachable!(),
+        },
+        gles11::UNSIGNED_SHORT_5_6_5 => {
+            let v = u16::from_ne_bytes([bytes[0], bytes[1]]);
+            [
+                expand(v >> 11, 5),
+                expand((v >> 5) & 0x3f, 6),
+                expand(v & 0x1f, 5),
+                255,
+            ]
+        }
+        gles11::UNSIGNED_SHORT_4_4_4_4 => {
+            let v = u16::from_ne_bytes([bytes[0], bytes[1]]);
+            [
+                expand(v >> 12, 4),
+                expand((v >> 8) & 0xf, 4),
+                expand((v >> 4) & 0xf, 4),
+                expand(v & 0xf, 4),
+            ]
+        }
+        gles11::UNSIGNED_SHORT_5_5_5_1 => {
+            let v = u16::from_ne_bytes([bytes[0], bytes[1]]);
+            [
+                expand(v >> 11, 5),
+                expand((v >> 6) & 0x1f, 5),
+                expand((v >> 1) & 0x1f, 5),
+                expand(v & 0x1, 1),
+            ]
+        }
+        _ => unreachable!(),
+    }
+}
+
+/// Drop the components of a color that a base internal format doesn't have,
+/// e.g. when copying from the framebuffer to a texture.
+fn convert_to_base_format(format: GLenum, c: [u8; 4]) -> [u8; 4] {
+    match format {
+        gles11::ALPHA => [0, 0, 0, c[3]],
+        gles11::LUMINANCE => [c[0], c[0], c[0], 255],
+        gles11::LUMINANCE_ALPHA => [c[0], c[0], c[0], c[3]],
+        gles11::RGB => [c[0], c[1], c[2], 255],
+        gles11::RGBA => c,
+        _ => unreachable!(),
+    }
+}
+
+fn assert_internal_format(internalformat: GLenum) {
+    assert!(
+        internalformat == gles11::ALPHA
+            || internalformat == gles11::RGB
+            || internalformat == gles11::RGBA
+            || internalformat == gles11::LUMINANCE
+            || internalformat == gles11::LUMINANCE_ALPHA
+            || internalformat == gles11::BGRA_EXT
+    );
+}
+
+fn assert_format_and_type(format: GLenum, type_: GLenum) {
+    assert!(
+        format == gles11::ALPHA
+            || format == gles11::RGB
+            || format == gles11::RGBA
+            || format == gles11::LUMINANCE
+            || format == gles11::LUMINANCE_ALPHA
+            || format == gles11::BGRA_EXT
+    );
+    assert!(
+        type_ == gles11::UNSIGNED_BYTE
+            || type_ == gles11::UNSIGNED_SHORT_5_6_5
+            || type_ == gles11::UNSIGNED_SHORT_4_4_4_4
+            || type_ == gles11::UNSIGNED_SHORT_5_5_5_1
+    );
+}
+
+fn texture_unit_index(texture: GLenum) -> usize {
+    let unit = texture.wrapping_sub(gles11::TEXTURE0) as usize;
+    assert!(unit < TEXTURE_UNITS);
+    unit
+}
+
+/// Find unused names for `glGen*` functions.
+fn gen_names<T>(
+    objects: &HashMap<GLuint, T>,
+    next_name: &mut GLuint,
+    n: GLsizei,
+    out: *mut GLuint,
+) {
+    assert!(n >= 0);
+    for i in 0..(n as usize) {
+        while *next_name == 0 || objects.contains_key(next_name) {
+            *next_name = next_name.wrapping_add(1);
+        }
+        unsafe { out.add(i).write(*next_name) };
+        *next_name = next_name.wrapping_add(1);
+    }
+}
+
+/// Convert fixed-point parameters to floats with the same rules as
+/// [ParamTable::setxv], for setters that store every parameter as a float.
+unsafe fn fixed_params_to_float(
+    table: &ParamTable,
+    pname: GLenum,
+    params: *const GLfixed,
+) -> Vec<GLfloat> {
+    let (type_, count) = table.get_type_info(pname);
+    (0..usize::from(count))
+        .map(|i| {
+            let param = params.add(i).read();
+            match type_ {
+                ParamType::Float | ParamType::FloatSpecial => fixed_to_float(param),
+                _ => param as GLfloat,
+            }
+        })
+        .collect()
+}
+
+unsafe fn names_slice<'a>(n: GLsizei, names: *const GLuint) -> &'a [GLuint] {
+    assert!(n >= 0);
+    if n == 0 {
+        &[]
+    } else {
+        std::slice::from_raw_parts(names, n as usize)
+    }
+}
+
+pub struct GLES1Software {
+    default_framebuffer: Rc<RefCell<DefaultFramebuffer>>,
+    error: GLenum,
+    capabilities: HashSet<GLenum>,
+    texture_2d_enabled: [bool; TEXTURE_UNITS],
+    hints: HashMap<GLenum, GLenum>,
+
+    // Client state
+    client_active_texture: usize,
+    vertex_array: ArrayState,
+    normal_array: ArrayState,
+    color_array: ArrayState,
+    tex_coord_arrays: [ArrayState; TEXTURE_UNITS],
+    array_buffer_binding: GLuint,
+    element_array_buffer_binding: GLuint,
+    pack_alignment: GLint,
+    unpack_alignment: GLint,
+
+    // Current vertex attribute values
+    current_color: [f32; 4],
+    current_normal: [f32; 3],
+    current_tex_coords: [[f32; 4]; TEXTURE_UNITS],
+
+    // Transformation
+    matrix_mode: GLenum,
+    modelview_stack: Vec<Mat4>,
+    projection_stack: Vec<Mat4>,
+    texture_stacks: [Vec<Mat4>; TEXTURE_UNITS],
+    viewport: (GLint, GLint, GLsizei, GLsizei),
+    depth_range: (f32, f32),
+
+    // Lighting
+    lights: [Light; MAX_LIGHTS],
+    light_model_ambient: [f32; 4],
+    light_model_two_side: bool,
+    material: Material,
+    shade_model: GLenum,
+
+    // Rasterization
+    cull_face_mode: GLenum,
+    front_face: GLenum,
+    line_width: f32,
+    point_size: f32,
+    point_size_min: f32,
+    point_size_max: f32,
+    point_distance_attenuation: [f32; 3],
+    point_fade_threshold_size: f32,
+    polygon_offset: (f32, f32),
+
+    // Texturing
+    active_texture: usize,
+    texture_bindings: [GLuint; TEXTURE_UNITS],
+    tex_envs: [TexEnv; TEXTURE_UNITS],
+
+    // Fog
+    fog_mode: GLenum,
+    fog_density: f32,
+    fog_start: f32,
+    fog_end: f32,
+    fog_color: [f32; 4],
+
+    // Per-fragment operations
+    scissor_box: (GLint, GLint, GLsizei, GLsizei),
+    alpha_func: (GLenum, f32),
+    depth_func: GLenum,
+    depth_mask: bool,
+    blend_func: (GLenum, GLenum),
+    color_mask: [bool; 4],
+    clear_color: [f32; 4],
+    clear_depth: f32,
+    clear_stencil: GLint,
+
+    // Objects
+    buffers: HashMap<GLuint, Vec<u8>>,
+    next_buffer: GLuint,
+    textures: HashMap<GLuint, Texture>,
+    next_texture: GLuint,
+    framebuffers: HashMap<GLuint, Framebuffer>,
+    next_framebuffer: GLuint,
+    framebuffer_binding: GLuint,
+    renderbuffers: HashMap<GLuint, Renderbuffer>,
+    next_renderbuffer: GLuint,
+    renderbuffer_binding: GLuint,
+}
+
+impl GLES1Software {
+    fn new_with_framebuffer(default_framebuffer: Rc<RefCell<DefaultFramebuffer>>) -> Self {
+        let (width, height) = default_framebuffer.borrow().size();
+        let (width, height) = (width as GLsizei, height as GLsizei);
+        let mut textures = HashMap::new();
+        // The default texture, used when texture 0 is bound.
+        textures.insert(0, Texture::new());
+        GLES1Software {
+            default_framebuffer,
+            error: gles11::NO_ERROR,
+            capabilities: HashSet::from([gles11::DITHER, gles11::MULTISAMPLE]),
+            texture_2d_enabled: [false; TEXTURE_UNITS],
+            hints: HashMap::new(),
+            client_active_texture: 0,
+            vertex_array: ArrayState::new(4),
+            normal_array: ArrayState::new(3),
+            color_array: ArrayState::new(4),
+            tex_coord_arrays: [ArrayState::new(4); TEXTURE_UNITS],
+            array_buffer_binding: 0,
+            element_array_buffer_binding: 0,
+            pack_alignment: 4,
+            unpack_alignment: 4,
+            current_color: [1.0, 1.0, 1.0, 1.0],
+            current_normal: [0.0, 0.0, 1.0],
+            current_tex_coords: [[0.0, 0.0, 0.0, 1.0]; TEXTURE_UNITS],
+            matrix_mode: gles11::MODELVIEW,
+            modelview_stack: vec![IDENTITY],
+            projection_stack: vec![IDENTITY],
+            texture_stacks: std::array::from_fn(|_| vec![IDENTITY]),
+            viewport: (0, 0, width, height),
+            depth_range: (0.0, 1.0),
+            lights: std::array::from_fn(|i| Light::new(i == 0)),
+            light_model_ambient: [0.2, 0.2, 0.2, 1.0],
+            light_model_two_side: false,
+            material: Material {
+                ambient: [0.2, 0.2, 0.2, 1.0],
+                diffuse: [0.8, 0.8, 0.8, 1.0],
+                specular: [0.0, 0.0, 0.0, 1.0],
+                emission: [0.0, 0.0, 0.0, 1.0],
+                shininess: 0.0,
+            },
+            shade_model: gles11::SMOOTH,
+            cull_face_mode: gles11::BACK,
+            front_face: gles11::CCW,
+            line_width: 1.0,
+            point_size: 1.0,
+            point_size_min: 0.0,
+            point_size_max: POINT_SIZE_RANGE.1,
+            point_distance_attenuation: [1.0, 0.0, 0.0],
+            point_fade_threshold_size: 1.0,
+            polygon_offset: (0.0, 0.0),
+            active_texture: 0,
+            texture_bindings: [0; TEXTURE_UNITS],
+            tex_envs: [TexEnv::new(); TEXTURE_UNITS],
+            fog_mode: gles11::EXP,
+            fog_density: 1.0,
+            fog_start: 0.0,
+            fog_end: 1.0,
+            fog_color: [0.0; 4],
+            scissor_box: (0, 0, width, height),
+            alpha_func: (gles11::ALWAYS, 0.0),
+            depth_func: gles11::LESS,
+            depth_mask: true,
+            blend_func: (gles11::ONE, gles11::ZERO),
+            color_mask: [true; 4],
+            clear_color: [0.0; 4],
+            clear_depth: 1.0,
+            clear_stencil: 0,
+            buffers: HashMap::new(),
+            next_buffer: 1,
+            textures,
+            next_texture: 1,
+            framebuffers: HashMap::new(),
+            next_framebuffer: 1,
+            framebuffer_binding: 0,
+            renderbuffers: HashMap::new(),
+            next_renderbuffer: 1,
+            renderbuffer_binding: 0,
+        }
+    }
+
+    fn set_error(&mut self, error: GLenum) {
+        // Only the first error is recorded until glGetError is called.
+        if self.error == gles11::NO_ERROR {
+            self.error = error;
+        }
+    }
+
+    fn is_enabled(&self, cap: GLenum) -> bool {
+        self.capabilities.contains(&cap)
+    }
+
+    fn array_mut(&mut self, array: GLenum) -> &mut ArrayState {
+        match array {
+            gles11::VERTEX_ARRAY => &mut self.vertex_array,
+            gles11::NORMAL_ARRAY => &mut self.normal_array,
+            gles11::COLOR_ARRAY => &mut self.color_array,
+            gles11::TEXTURE_COORD_ARRAY => &mut self.tex_coord_arrays[self.client_active_texture],
+            _ => panic!("Unhandled client state {:#x}", array),
+        }
+    }
+
+    fn matrix_stack_mut(&mut self) -> (&mut Vec<Mat4>, usize) {
+        match self.matrix_mode {
+            gles11::MODELVIEW => (&mut self.modelview_stack, MAX_MODELVIEW_STACK_DEPTH),
+            gles11::PROJECTION => (&mut self.projection_stack, MAX_PROJECTION_STACK_DEPTH),
+            gles11::TEXTURE => (
+                &mut self.texture_stacks[self.active_texture],
+                MAX_TEXTURE_STACK_DEPTH,
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    fn current_matrix_mut(&mut self) -> &mut Mat4 {
+        self.matrix_stack_mut().0.last_mut().unwrap()
+    }
+
+    fn mult_matrix(&mut self, m: &Mat4) {
+        let current = self.current_matrix_mut();
+        *current = mat4_mul(current, m);
+    }
+
+    fn modelview(&self) -> &Mat4 {
+        self.modelview_stack.last().unwrap()
+    }
+
+    fn bound_texture_mut(&mut self) -> &mut Texture {
+        let name = self.texture_bindings[self.active_texture];
+        self.textures.get_mut(&name).unwrap()
+    }
+
+    fn bound_renderbuffer_mut(&mut self) -> &mut Renderbuffer {
+        assert!(self.renderbuffer_binding != 0);
+        self.renderbuffers
+            .get_mut(&self.renderbuffer_binding)
+            .unwrap()
+    }
+
+    /// Get the texture bound to a texture unit, if texturing is enabled for
+    /// that unit and the texture is complete.
+    fn active_texture_for_unit(&self, unit: usize) -> Option<&Texture> {
+        if !self.texture_2d_enabled[unit] {
+            return None;
+        }
+        let texture = self.textures.get(&self.texture_bindings[unit])?;
+        texture.is_complete().then_some(texture)
+    }
+
+    // Framebuffer access
+
+    fn attachment_size(&self, attachment: Attachment) -> (u32, u32) {
+        match attachment {
+            Attachment::Renderbuffer(name) => self
+                .renderbuffers
+                .get(&name)
+                .map_or((0, 0), |rb| (rb.width, rb.height)),
+            Attachment::Texture(name, level) => self
+                .textures
+                .get(&name)
+                .and_then(|texture| texture.levels.get(level))
+                .map_or((0, 0), |image| (image.width, image.height)),
+        }
+    }
+
+    /// Move the buffers of the currently bound framebuffer into `target`, or
+    /// move them back if they've already been moved there. It's important that
+    /// this is called exactly twice with the same context state.
+    fn swap_target_buffers(&mut self, target: &mut Target) {
+        use std::mem::swap;
+
+        if self.framebuffer_binding == 0 {
+            let mut framebuffer = self.default_framebuffer.borrow_mut();
+            target.width = framebuffer.width;
+            target.height = framebuffer.height;
+            target.color_has_alpha = true;
+            swap(&mut framebuffer.color, &mut target.color);
+            swap(&mut framebuffer.depth, &mut target.depth);
+            swap(&mut framebuffer.stencil, &mut target.stencil);
+            return;
+        }
+
+        let Framebuffer {
+            color,
+            depth,
+            stencil,
+        } = *self.framebuffers.get(&self.framebuffer_binding).unwrap();
+        let size = color
+            .or(depth)
+            .map_or((0, 0), |attachment| self.attachment_size(attachment));
+        (target.width, target.height) = size;
+        target.color_has_alpha = true;
+
+        if let Some(attachment) = color {
+            match attachment {
+                Attachment::Renderbuffer(name) => {
+                    if let Some(rb) = self.renderbuffers.get_mut(&name) {
+                        target.color_has_alpha =
+                            ![gles11::RGB565_OES, gles11::RGB8_OES].contains(&rb.internalformat);
+                        swap(&mut rb.color, &mut target.color);
+                    }
+                }
+                Attachment::Texture(name, level) => {
+                    if let Some(texture) = self.textures.get_mut(&name) {
+                        target.color_has_alpha =
+                            [gles11::ALPHA, gles11::LUMINANCE_ALPHA, gles11::RGBA]
+                                .contains(&texture.format);
+                        swap(&mut texture.levels[level].pixels, &mut target.color);
+                    }
+                }
+            }
+        }
+        // Only renderbuffers can be depth or stencil attachments. Mismatched
+        // sizes make the framebuffer incomplete, so such buffers are ignored.
+        if let Some(Attachment::Renderbuffer(name)) = depth {
+            if self.attachment_size(Attachment::Renderbuffer(name)) == size {
+                let rb = self.renderbuffers.get_mut(&name).unwrap();
+                swap(&mut rb.depth, &mut target.depth);
+            }
+        }
+        if let Some(Attachment::Renderbuffer(name)) = stencil {
+            if self.attachment_size(Attachment::Renderbuffer(name)) == size {
+                let rb = self.renderbuffers.get_mut(&name).unwrap();
+                swap(&mut rb.stencil, &mut target.stencil);
+            }
+        }
+    }
+
+    fn with_target<R>(&mut self, f: impl FnOnce(&Self, &mut Target) -> R) -> R {
+        let mut target = Target::default();
+        self.swap_target_buffers(&mut target);
+        let res = f(self, &mut target);
+        self.swap_target_buffers(&mut target);
+        res
+    }
+
+    /// Get the window-space rectangle (x0, y0, x1, y1) that drawing and
+    /// clearing is restricted to.
+    fn draw_bounds(&self, target: &Target) -> (i32, i32, i32, i32) {
+        let (mut x0, mut y0) = (0, 0);
+        let (mut x1, mut y1) = (target.width as i32, target.height as i32);
+        if self.is_enabled(gles11::SCISSOR_TEST) {
+            let (x, y, width, height) = self.scissor_box;
+            x0 = x0.max(x);
+            y0 = y0.max(y);
+            x1 = x1.min(x.saturating_add(width));
+            y1 = y1.min(y.saturating_add(height));
+        }
+        (x0, y0, x1, y1)
+    }
+
+    /// Read a rectangle of pixels from the color buffer of the current
+    /// framebuffer. Pixels outside the buffer are transparent black.
+    fn read_color_rect(&mut self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) -> Image {
+        assert!(width >= 0 && height >= 0);
+        self.with_target(|_, target| {
+            let mut image = Image::new(width as u32, height as u32);
+            if target.color.is_empty() {
+                return image;
+            }
+            for row in 0..height {
+                for col in 0..width {
+                    let (sx, sy) = (x + col, y + row);
+                    if sx < 0 || sy < 0 || sx >= target.width as i32 || sy >= target.height as i32 {
+                        continue;
+                    }
+                    image.pixels[(row * width + col) as usize] =
+                        target.color[(sy as u32 * target.width + sx as u32) as usize];
+                }
+            }
+            image
+        })
+    }
+
+    // Vertex processing
+
+    unsafe fn fetch_attribute(
+        &self,
+        array: &ArrayPointer,
+        index: u32,
+        normalized: bool,
+        default: [f32; 4],
+    ) -> [f32; 4] {
+        let component_size = match array.type_ {
+            gles11::BYTE | gles11::UNSIGNED_BYTE => 1,
+            gles11::SHORT | gles11::UNSIGNED_SHORT => 2,
+            gles11::FIXED | gles11::FLOAT => 4,
+            _ => panic!("Unhandled array type {:#x}", array.type_),
+        };
+        let stride = if array.stride == 0 {
+            array.size as usize * component_size
+        } else {
+            array.stride as usize
+        };
+        let base: *const u8 = if array.buffer != 0 {
+            let buffer = self.buffers.get(&array.buffer).unwrap();
+            let offset = array.pointer as usize + index as usize * stride;
+            assert!(offset + array.size as usize * component_size <= buffer.len());
+            buffer.as_ptr().add(offset)
+        } else {
+            array.pointer.cast::<u8>().add(index as usize * stride)
+        };
+        let mut res = default;
+        for (i, cell) in res.iter_mut().take(array.size as usize).enumerate() {
+            let ptr = base.add(i * component_size);
+            *cell = match array.type_ {
+                gles11::BYTE => {
+                    let c = f32::from(ptr.cast::<i8>().read());
+                    if normalized {
+                        (2.0 * c + 1.0) / 255.0
+                    } else {
+                        c
+                    }
+                }
+                gles11::UNSIGNED_BYTE => {
+                    let c = f32::from(ptr.read());
+                    if normalized {
+                        c / 255.0
+                    } else {
+                        c
+                    }
+                }
+                gles11::SHORT => {
+                    let c = f32::from(ptr.cast::<i16>().read_unaligned());
+                    if normalized {
+                        (2.0 * c + 1.0) / 65535.0
+                    } else {
+                        c
+                    }
+                }
+                gles11::UNSIGNED_SHORT => {
+                    let c = f32::from(ptr.cast::<u16>().read_unaligned());
+                    if normalized {
+                        c / 65535.0
+                    } else {
+                        c
+                    }
+                }
+                gles11::FIXED => fixed_to_float(ptr.cast::<GLfixed>().read_unaligned()),
+                gles11::FLOAT => ptr.cast::<f32>().read_unaligned(),
+                _ => unreachable!(),
+            };
+        }
+        res
+    }
+
+    /// Fetch a vertex's attributes and do the per-vertex operations:
+    /// transformation, lighting, texture co-ordinate transformation and point
+    /// size calculation.
+    unsafe fn process_vertex(&self, index: u32) -> ClipVertex {
+        let position = self.fetch_attribute(
+            &self.vertex_array.pointer,
+            index,
+            false,
+            [0.0, 0.0, 0.0, 1.0],
+        );
+        let color = if self.color_array.enabled {
+            self.fetch_attribute(&self.color_array.pointer, index, true, [0.0, 0.0, 0.0, 1.0])
+        } else {
+            self.current_color
+        };
+        let mut tex_coords = self.current_tex_coords;
+        for (unit, tex_coord) in tex_coords.iter_mut().enumerate() {
+            let array = &self.tex_coord_arrays[unit];
+            if array.enabled {
+                *tex_coord =
+                    self.fetch_attribute(&array.pointer, index, false, [0.0, 0.0, 0.0, 1.0]);
+            }
+            *tex_coord = mat4_transform(self.texture_stacks[unit].last().unwrap(), *tex_coord);
+        }
+
+        let eye = mat4_transform(self.modelview(), position);
+        let clip = mat4_transform(self.projection_stack.last().unwrap(), eye);
+        let eye_pos = if eye[3] != 0.0 {
+            [eye[0] / eye[3], eye[1] / eye[3], eye[2] / eye[3]]
+        } else {
+            [eye[0], eye[1], eye[2]]
+        };
+
+        let (front_color, back_color) = if self.is_enabled(gles11::LIGHTING) {
+            let normal = if self.normal_array.enabled {
+                let n = self.fetch_attribute(&self.normal_array.pointer, index, true, [0.0; 4]);
+                [n[0], n[1], n[2]]
+            } else {
+                self.current_normal
+            };
+            let mut normal = transform_normal(self.modelview(), normal);
+            if self.is_enabled(gles11::NORMALIZE) || self.is_enabled(gles11::RESCALE_NORMAL) {
+                normal = normalize3(normal);
+            }
+            let front = self.light_vertex(eye_pos, normal, color);
+            let back = if self.light_model_two_side {
+                self.light_vertex(eye_pos, normal.map(|c| -c), color)
+            } else {
+                front
+            };
+            (front, back)
+        } else {
+            (color, color)
+        };
+
+        let distance = dot3(eye_pos, eye_pos).sqrt();
+        let [a, b, c] = self.point_distance_attenuation;
+        let point_size =
+            self.point_size * (1.0 / (a + b * distance + c * distance * distance)).sqrt();
+        let point_size = point_size
+            .clamp(self.point_size_min, self.point_size_max)
+            .clamp(POINT_SIZE_RANGE.0, POINT_SIZE_RANGE.1);
+
+        ClipVertex {
+            position: clip,
+            front_color,
+            back_color,
+            tex_coords,
+            fog_coord: eye_pos[2].abs(),
+            point_size,
+        }
+    }
+
+    /// Lighting equation from section 2.12.1 of the OpenGL ES 1.1 spec.
+    fn light_vertex(&self, vertex: [f32; 3], normal: [f32; 3], color: [f32; 4]) -> [f32; 4] {
+        let (ambient_m, diffuse_m) = if self.is_enabled(gles11::COLOR_MATERIAL) {
+            (color, color)
+        } else {
+            (self.material.ambient, self.material.diffuse)
+        };
+        let mut res = [0f32; 3];
+        for i in 0..3 {
+            res[i] = self.material.emission[i] + ambient_m[i] * self.light_model_ambient[i];
+        }
+        for (i, light) in self.lights.iter().enumerate() {
+            if !self.is_enabled(gles11::LIGHT0 + i as GLenum) {
+                continue;
+            }
+            let [px, py, pz, pw] = light.position;
+            let (to_light, attenuation) = if pw != 0.0 {
+                let v = [
+                    px / pw - vertex[0],
+                    py / pw - vertex[1],
+                    pz / pw - vertex[2],
+                ];
+                let d = dot3(v, v).sqrt();
+                let [k0, k1, k2] = light.attenuation;
+                (normalize3(v), 1.0 / (k0 + k1 * d + k2 * d * d))
+            } else {
+                (normalize3([px, py, pz]), 1.0)
+            };
+            let spot = if light.spot_cutoff != 180.0 {
+                let cos = dot3(to_light.map(|c| -c), normalize3(light.spot_direction));
+                if cos >= light.spot_cutoff.to_radians().cos() {
+                    cos.max(0.0).powf(light.spot_exponent)
+                } else {
+                    0.0
+                }
+            } else {
+                1.0
+            };
+            let n_dot_l = dot3(normal, to_light).max(0.0);
+            let specular = if n_dot_l > 0.0 {
+                let half = normalize3([to_light[0], to_light[1], to_light[2] + 1.0]);
+                dot3(normal, half).max(0.0).powf(self.material.shininess)
+            } else {
+                0.0
+            };
+            for c in 0..3 {
+                res[c] += attenuation
+                    * spot
+                    * (ambient_m[c] * light.ambient[c]
+                        + n_dot_l * diffuse_m[c] * light.diffuse[c]
+                        + specular * self.material.specular[c] * light.specular[c]);
+            }
+        }
+        [
+            res[0].clamp(0.0, 1.0),
+            res[1].clamp(0.0, 1.0),
+            res[2].clamp(0.0, 1.0),
+            diffuse_m[3].clamp(0.0, 1.0),
+        ]
+    }
+
+    fn to_window(&self, v: &ClipVertex, color: [f32; 4]) -> WindowVertex {
+        let [x, y, z, w] = v.position;
+        let inv_w = 1.0 / w;
+        let (vx, vy, vw, vh) = self.viewport;
+        let (near, far) = self.depth_range;
+        let mut varyings = [0f32; VARYING_COUNT];
+        varyings[..4].copy_from_slice(&color);
+        for unit in 0..TEXTURE_UNITS {
+            let start = tex_coord_varying(unit);
+            varyings[start..start + 4].copy_from_slice(&v.tex_coords[unit]);
+        }
+        varyings[FOG_VARYING] = v.fog_coord;
+        WindowVertex {
+            x: (x * inv_w + 1.0) * (vw as f32 / 2.0) + vx as f32,
+            y: (y * inv_w + 1.0) * (vh as f32 / 2.0) + vy as f32,
+            z: (z * inv_w) * ((far - near) / 2.0) + (far + near) / 2.0,
+            inv_w,
+            varyings,
+        }
+    }
+
+    // Primitive assembly and clipping
+
+    unsafe fn draw(&mut self, mode: GLenum, indices: &[u32]) {
+        if !self.vertex_array.enabled || indices.is_empty() {
+            return;
+        }
+        let min = *indices.iter().min().unwrap();
+        let max = *indices.iter().max().unwrap();
+        let vertices: Vec<ClipVertex> = (min..=max).map(|i| self.process_vertex(i)).collect();
+        let v = |i: usize| &vertices[(indices[i] - min) as usize];
+        let n = indices.len();
+
+        self.with_target(|this, target| match mode {
+            gles11::POINTS => {
+                for i in 0..n {
+                    this.rasterize_point(target, v(i));
+                }
+            }
+            gles11::LINES => {
+                for i in (0..n.saturating_sub(1)).step_by(2) {
+                    this.rasterize_line(target, v(i), v(i + 1));
+                }
+            }
+            gles11::LINE_STRIP | gles11::LINE_LOOP => {
+                for i in 0..n.saturating_sub(1) {
+                    this.rasterize_line(target, v(i), v(i + 1));
+                }
+                if mode == gles11::LINE_LOOP && n > 2 {
+                    this.rasterize_line(target, v(n - 1), v(0));
+                }
+            }
+            gles11::TRIANGLES => {
+                for i in (0..n.saturating_sub(2)).step_by(3) {
+                    this.rasterize_triangle(target, [v(i), v(i + 1), v(i + 2)], v(i + 2));
+                }
+            }
+            gles11::TRIANGLE_STRIP => {
+                for i in 0..n.saturating_sub(2) {
+                    // Every other triangle has its winding reversed so that
+                    // all of them face the same way.
+                    let tri = if i % 2 == 0 {
+                        [v(i), v(i + 1), v(i + 2)]
+                    } else {
+                        [v(i + 1), v(i), v(i + 2)]
+                    };
+                    this.rasterize_triangle(target, tri, v(i + 2));
+                }
+            }
+            gles11::TRIANGLE_FAN => {
+                for i in 1..n.saturating_sub(1) {
+                    this.rasterize_triangle(target, [v(0), v(i), v(i + 1)], v(i + 1));
+                }
+            }
+            _ => unreachable!(),
+        });
+    }
+
+    /// Clip a polygon against the view volume (Sutherland-Hodgman).
+    fn clip_polygon(mut polygon: Vec<ClipVertex>) -> Vec<ClipVertex> {
+        for plane in 0..6 {
+            if polygon.is_empty() {
+                break;
+            }
+            let mut clipped = Vec::with_capacity(polygon.len() + 1);
+            for i in 0..polygon.len() {
+                let a = &polygon[i];
+                let b = &polygon[(i + 1) % polygon.len()];
+                let da = a.clip_distances()[plane];
+                let db = b.clip_distances()[plane];
+                if da >= 0.0 {
+                    clipped.push(*a);
+                }
+                if (da >= 0.0) != (db >= 0.0) {
+                    clipped.push(a.lerp(b, da / (da - db)));
+                }
+            }
+            polygon = clipped;
+        }
+        polygon
+    }
+
+    fn rasterize_triangle(
+        &self,
+        target: &mut Target,
+        vertices: [&ClipVertex; 3],
+        provoking: &ClipVertex,
+    ) {
+        let polygon = Self::clip_polygon(vertices.map(|v| *v).to_vec());
+        if polygon.len() < 3 {
+            return;
+        }
+
+        // Facing is determined from the window-space signed area.
+        let positions: Vec<WindowVertex> = polygon
+            .iter()
+            .map(|v| self.to_window(v, v.front_color))
+            .collect();
+        let mut area = 0.0;
+        for i in 0..positions.len() {
+            let a = &positions[i];
+            let b = &positions[(i + 1) % positions.len()];
+            area += a.x * b.y - b.x * a.y;
+        }
+        let front_facing = (area > 0.0) == (self.front_face == gles11::CCW);
+        if self.is_enabled(gles11::CULL_FACE) {
+            let culled = match self.cull_face_mode {
+                gles11::FRONT => front_facing,
+                gles11::BACK => !front_facing,
+                _ => true, // GL_FRONT_AND_BACK
+            };
+            if culled {
+                return;
+            }
+        }
+
+        let use_back_color =
+            !front_facing && self.is_enabled(gles11::LIGHTING) && self.light_model_two_side;
+        let flat = self.shade_model == gles11::FLAT;
+        let color_of = |v: &ClipVertex| {
+            let v = if flat { provoking } else { v };
+            if use_back_color {
+                v.back_color
+            } else {
+                v.front_color
+            }
+        };
+        let window: Vec<WindowVertex> = polygon
+            .iter()
+            .map(|v| self.to_window(v, color_of(v)))
+            .collect();
+
+        for i in 1..window.len() - 1 {
+            self.fill_triangle(target, [&window[0], &window[i], &window[i + 1]]);
+        }
+    }
+
+    fn fill_triangle(&self, target: &mut Target, v: [&WindowVertex; 3]) {
+        let area = edge(v[0], v[1], (v[2].x, v[2].y));
+        if area == 0.0 || !area.is_finite() {
+            return;
+        }
+        // Make the winding counter-clockwise so all edge functions are
+        // positive inside the triangle.
+        let v = if area < 0.0 { [v[0], v[2], v[1]] } else { v };
+        let area = area.abs();
+
+        let z_plane = Plane::new(v, v.map(|v| v.z), area);
+        let depth_offset = if self.is_enabled(gles11::POLYGON_OFFSET_FILL) {
+            let (factor, units) = self.polygon_offset;
+            let max_slope = z_plane.dx.abs().max(z_plane.dy.abs());
+            factor * max_slope + units / (1 << 24) as f32
+        } else {
+            0.0
+        };
+        let inv_w_plane = Plane::new(v, v.map(|v| v.inv_w), area);
+        let mut varying_planes = [Plane::default(); VARYING_COUNT];
+        for (i, plane) in varying_planes.iter_mut().enumerate() {
+            *plane = Plane::new(v, v.map(|v| v.varyings[i] * v.inv_w), area);
+        }
+        let textured_units: [bool; TEXTURE_UNITS] =
+            std::array::from_fn(|unit| self.active_texture_for_unit(unit).is_some());
+
+        let (bx0, by0, bx1, by1) = self.draw_bounds(target);
+        let min_x = (v.iter().map(|v| v.x).fold(f32::MAX, f32::min).floor() as i32).max(bx0);
+        let max_x = (v.iter().map(|v| v.x).fold(f32::MIN, f32::max).ceil() as i32).min(bx1);
+        let min_y = (v.iter().map(|v| v.y).fold(f32::MAX, f32::min).floor() as i32).max(by0);
+        let max_y = (v.iter().map(|v| v.y).fold(f32::MIN, f32::max).ceil() as i32).min(by1);
+
+        let edges = [(v[1], v[2]), (v[2], v[0]), (v[0], v[1])];
+        let owns_edge = edges.map(|(a, b)| is_top_left(a, b));
+
+        for py in min_y..max_y {
+            for px in min_x..max_x {
+                let p = (px as f32 + 0.5, py as f32 + 0.5);
+                let inside = edges.iter().zip(owns_edge).all(|(&(a, b), owns)| {
+                    let w = edge(a, b, p);
+                    w > 0.0 || (w == 0.0 && owns)
+                });
+                if !inside {
+                    continue;
+                }
+
+                let inv_w = inv_w_plane.at(p.0, p.1);
+                let mut varyings = [0f32; VARYING_COUNT];
+                for (i, varying) in varyings.iter_mut().enumerate() {
+                    *varying = varying_planes[i].at(p.0, p.1) / inv_w;
+                }
+                let mut derivatives: TexCoordDerivatives = [[0.0; 4]; TEXTURE_UNITS];
+                for unit in (0..TEXTURE_UNITS).filter(|&unit| textured_units[unit]) {
+                    let s = tex_coord_varying(unit);
+                    let (ps, pt) = (&varying_planes[s], &varying_planes[s + 1]);
+                    let (s, t) = (varyings[s], varyings[s + 1]);
+                    // Quotient rule for (S/W)' where S = s*W.
+                    derivatives[unit] = [
+                        (ps.dx - s * inv_w_plane.dx) / inv_w,
+                        (pt.dx - t * inv_w_plane.dx) / inv_w,
+                        (ps.dy - s * inv_w_plane.dy) / inv_w,
+                        (pt.dy - t * inv_w_plane.dy) / inv_w,
+                    ];
+                }
+
+                let z = z_plane.at(p.0, p.1) + depth_offset;
+                if let Some(color) = self.shade_fragment(&varyings, &derivatives, None) {
+                    self.write_fragment(target, px as u32, py as u32, z, color);
+                }
+            }
+        }
+    }
+
+    fn rasterize_line(&self, target: &mut Target, a: &ClipVertex, b: &ClipVertex) {
+        // Parametric clipping against each plane of the view volume.
+        let (mut t0, mut t1) = (0.0f32, 1.0f32);
+        let (da, db) = (a.clip_distances(), b.clip_distances());
+        for plane in 0..6 {
+            let (da, db) = (da[plane], db[plane]);
+            if da < 0.0 && db < 0.0 {
+                return;
+            } else if da < 0.0 {
+                t0 = t0.max(da / (da - db));
+            } else if db < 0.0 {
+                t1 = t1.min(da / (da - db));
+            }
+        }
+        if t0 > t1 {
+            return;
+        }
+        let (ca, cb) = (a.lerp(b, t0), a.lerp(b, t1));
+        let flat = self.shade_model == gles11::FLAT;
+        let wa = self.to_window(&ca, if flat { b.front_color } else { ca.front_color });
+        let wb = self.to_window(&cb, cb.front_color);
+
+        let (dx, dy) = (wb.x - wa.x, wb.y - wa.y);
+        let x_major = dx.abs() >= dy.abs();
+        let (start, end) = if x_major { (wa.x, wb.x) } else { (wa.y, wb.y) };
+        let (wa, wb, start, end) = if start > end {
+            (wb, wa, end, start)
+        } else {
+            (wa, wb, start, end)
+        };
+        let length = end - start;
+        if length == 0.0 {
+            return;
+        }
+        let width = self.line_width.round().max(1.0) as i32;
+        let (bx0, by0, bx1, by1) = self.draw_bounds(target);
+
+        let first = (start - 0.5).ceil() as i32;
+        let last = (end - 0.5).ceil() as i32;
+        for major in first..last {
+            let t = (major as f32 + 0.5 - start) / length;
+            let minor = if x_major {
+                wa.y + (wb.y - wa.y) * t
+            } else {
+                wa.x + (wb.x - wa.x) * t
+            };
+            let inv_w = wa.inv_w + (wb.inv_w - wa.inv_w) * t;
+            let mut varyings = [0f32; VARYING_COUNT];
+            for (i, varying) in varyings.iter_mut().enumerate() {
+                let (va, vb) = (wa.varyings[i] * wa.inv_w, wb.varyings[i] * wb.inv_w);
+                *varying = (va + (vb - va) * t) / inv_w;
+            }
+            let z = wa.z + (wb.z - wa.z) * t;
+            let Some(color) = self.shade_fragment(&varyings, &[[0.0; 4]; TEXTURE_UNITS], None)
+            else {
+                continue;
+            };
+            let minor_start = (minor - (width - 1) as f32 / 2.0).floor() as i32;
+            for minor in minor_start..minor_start + width {
+                let (px, py) = if x_major {
+                    (major, minor)
+                } else {
+                    (minor, major)
+                };
+                if px >= bx0 && px < bx1 && py >= by0 && py < by1 {
+                    self.write_fragment(target, px as u32, py as u32, z, color);
+                }
+            }
+        }
+    }
+
+    fn rasterize_point(&self, target: &mut Target, v: &ClipVertex) {
+        if v.clip_distances().iter().any(|&d| d < 0.0) {
+            return;
+        }
+        let w = self.to_window(v, v.front_color);
+        let size = v.point_size.round().max(1.0);
+        let x0 = (w.x - size / 2.0 + 0.5).floor() as i32;
+        let y0 = (w.y - size / 2.0 + 0.5).floor() as i32;
+        let size_int = size as i32;
+        let point_sprite = self.is_enabled(POINT_SPRITE_OES);
+        let (bx0, by0, bx1, by1) = self.draw_bounds(target);
+        for py in y0.max(by0)..(y0 + size_int).min(by1) {
+            for px in x0.max(bx0)..(x0 + size_int).min(bx1) {
+                let point_coord = point_sprite.then(|| {
+                    (
+                        0.5 + (px as f32 + 0.5 - w.x) / size,
+                        0.5 - (py as f32 + 0.5 - w.y) / size,
+                    )
+                });
+                if let Some(color) =
+                    self.shade_fragment(&w.varyings, &[[0.0; 4]; TEXTURE_UNITS], point_coord)
+                {
+                    self.write_fragment(target, px as u32, py as u32, w.z, color);
+                }
+            }
+        }
+    }
+
+    // Per-fragment operations
+
+    /// Texturing, fog and the alpha test. Returns [None] if the fragment is
+    /// discarded.
+    fn shade_fragment(
+        &self,
+        varyings: &Varyings,
+        derivatives: &TexCoordDerivatives,
+        point_coord: Option<(f32, f32)>,
+    ) -> Option<[f32; 4]> {
+        let primary: [f32; 4] = varyings[..4].try_into().unwrap();
+        let mut color = primary;
+
+        for (unit, &[dsdx, dtdx, dsdy, dtdy]) in derivatives.iter().enumerate() {
+            let Some(texture) = self.active_texture_for_unit(unit) else {
+                continue;
+            };
+            let env = &self.tex_envs[unit];
+            let start = tex_coord_varying(unit);
+            let [s, t, _r, q] = varyings[start..start + 4].try_into().unwrap();
+            let (s, t) = match point_coord {
+                Some(coord) if env.coord_replace => coord,
+                _ => (s / q, t / q),
+            };
+            let base = &texture.levels[0];
+            let (w, h) = (base.width as f32, base.height as f32);
+            let rho = ((dsdx * w).hypot(dtdx * h)).max((dsdy * w).hypot(dtdy * h));
+            let lod = if rho > 0.0 { rho.log2() } else { f32::MIN };
+            let lod = lod
+                + env
+                    .lod_bias
+                    .clamp(-MAX_TEXTURE_LOD_BIAS, MAX_TEXTURE_LOD_BIAS);
+            let texel = texture.sample(s, t, lod);
+            color = Self::apply_tex_env(env, texture.format, color, texel, primary);
+        }
+
+        if self.is_enabled(gles11::FOG) {
+            let c = varyings[FOG_VARYING];
+            let f = match self.fog_mode {
+                gles11::LINEAR => (self.fog_end - c) / (self.fog_end - self.fog_start),
+                gles11::EXP => (-self.fog_density * c).exp(),
+                gles11::EXP2 => (-(self.fog_density * c).powi(2)).exp(),
+                _ => unreachable!(),
+            };
+            let f = f.clamp(0.0, 1.0);
+            for (i, c) in color[..3].iter_mut().enumerate() {
+                *c = f * *c + (1.0 - f) * self.fog_color[i];
+            }
+        }
+
+        if self.is_enabled(gles11::ALPHA_TEST) {
+            let (func, reference) = self.alpha_func;
+            if !compare(func, color[3].clamp(0.0, 1.0), reference.clamp(0.0, 1.0)) {
+                return None;
+            }
+        }
+
+        Some(color)
+    }
+
+    /// Texture environment function for one texture unit (section 3.7.12 of
+    /// the OpenGL ES 1.1 spec). `cf` is the result from the previous unit, `cs`
+    /// the texel, and `primary` the fragment's color before texturing.
+    fn apply_tex_env(
+        env: &TexEnv,
+        format: GLenum,
+        cf: [f32; 4],
+        cs: [f32; 4],
+        primary: [f32; 4],
+    ) -> [f32; 4] {
+        let has_rgb = format != gles11::ALPHA;
+        let has_alpha = matches!(
+            format,
+            gles11::ALPHA | gles11::LUMINANCE_ALPHA | gles11::RGBA
+        );
+        let mut res = cf;
+        match env.mode {
+            gles11::REPLACE => {
+                if has_rgb {
+                    res[..3].copy_from_slice(&cs[..3]);
+                }
+                if has_alpha {
+                    res[3] = cs[3];
+                }
+            }
+            gles11::MODULATE => {
+                if has_rgb {
+                    for i in 0..3 {
+                        res[i] = cf[i] * cs[i];
+                    }
+                }
+                if has_alpha {
+                    res[3] = cf[3] * cs[3];
+                }
+            }
+            gles11::DECAL => {
+                // Undefined for formats other than RGB and RGBA.
+                if format == gles11::RGB {
+                    res[..3].copy_from_slice(&cs[..3]);
+                } else if format == gles11::RGBA {
+                    for i in 0..3 {
+                        res[i] = cf[i] * (1.0 - cs[3]) + cs[i] * cs[3];
+                    }
+                }
+            }
+            gles11::BLEND => {
+                if has_rgb {
+                    for i in 0..3 {
+                        res[i] = cf[i] * (1.0 - cs[i]) + env.color[i] * cs[i];
+                    }
+                }
+                if has_alpha {
+                    res[3] = cf[3] * cs[3];
+                }
+            }
+            gles11::ADD => {
+                if has_rgb {
+                    for i in 0..3 {
+                        res[i] = (cf[i] + cs[i]).min(1.0);
+                    }
+                }
+                if has_alpha {
+                    res[3] = cf[3] * cs[3];
+                }
+            }
+            gles11::COMBINE => {
+                let source = |src: GLenum| match src {
+                    gles11::TEXTURE => cs,
+                    gles11::CONSTANT => env.color,
+                    gles11::PRIMARY_COLOR => primary,
+                    gles11::PREVIOUS => cf,
+                    _ => panic!("Unhandled combiner source {:#x}", src),
+                };
+                let rgb_args: [[f32; 3]; 3] = std::array::from_fn(|i| {
+                    let c = source(env.src_rgb[i]);
+                    match env.operand_rgb[i] {
+                        gles11::SRC_COLOR => [c[0], c[1], c[2]],
+                        gles11::ONE_MINUS_SRC_COLOR => [1.0 - c[0], 1.0 - c[1], 1.0 - c[2]],
+                        gles11::SRC_ALPHA => [c[3]; 3],
+                        gles11::ONE_MINUS_SRC_ALPHA => [1.0 - c[3]; 3],
+                        operand => panic!("Unhandled combiner operand {:#x}", operand),
+                    }
+                });
+                let alpha_args: [f32; 3] = std::array::from_fn(|i| {
+                    let a = source(env.src_alpha[i])[3];
+                    match env.operand_alpha[i] {
+                        gles11::SRC_ALPHA => a,
+                        gles11::ONE_MINUS_SRC_ALPHA => 1.0 - a,
+                        operand => panic!("Unhandled combiner operand {:#x}", operand),
+                    }
+                });
+                let combine = |func: GLenum, a0: f32, a1: f32, a2: f32| match func {
+                    gles11::REPLACE => a0,
+                    gles11::MODULATE => a0 * a1,
+                    gles11::ADD => a0 + a1,
+                    gles11::ADD_SIGNED => a0 + a1 - 0.5,
+                    gles11::INTERPOLATE => a0 * a2 + a1 * (1.0 - a2),
+                    gles11::SUBTRACT => a0 - a1,
+                    _ => panic!("Unhandled combiner function {:#x}", func),
+                };
+                let [r0, r1, r2] = rgb_args;
+                if matches!(env.combine_rgb, gles11::DOT3_RGB | gles11::DOT3_RGBA) {
+                    let dot = 4.0
+                        * ((r0[0] - 0.5) * (r1[0] - 0.5)
+                            + (r0[1] - 0.5) * (r1[1] - 0.5)
+                            + (r0[2] - 0.5) * (r1[2] - 0.5));
+                    res[..3].fill(dot);
+                } else {
+                    for i in 0..3 {
+                        res[i] = combine(env.combine_rgb, r0[i], r1[i], r2[i]);
+                    }
+                }
+                for c in &mut res[..3] {
+                    *c = (*c * env.rgb_scale).clamp(0.0, 1.0);
+                }
+                res[3] = if env.combine_rgb == gles11::DOT3_RGBA {
+                    res[0]
+                } else {
+                    let [a0, a1, a2] = alpha_args;
+                    (combine(env.combine_alpha, a0, a1, a2) * env.alpha_scale).clamp(0.0, 1.0)
+                };
+            }
+            _ => panic!("Unhandled texture environment mode {:#x}", env.mode),
+        }
+        res
+    }
+
+    fn blend_factor(factor: GLenum, src: [f32; 4], dst: [f32; 4]) -> [f32; 4] {
+        match factor {
+            gles11::ZERO => [0.0; 4],
+            gles11::ONE => [1.0; 4],
+            gles11::SRC_COLOR => src,
+            gles11::ONE_MINUS_SRC_COLOR => src.map(|c| 1.0 - c),
+            gles11::DST_COLOR => dst,
+            gles11::ONE_MINUS_DST_COLOR => dst.map(|c| 1.0 - c),
+            gles11::SRC_ALPHA => [src[3]; 4],
+            gles11::ONE_MINUS_SRC_ALPHA => [1.0 - src[3]; 4],
+            gles11::DST_ALPHA => [dst[3]; 4],
+            gles11::ONE_MINUS_DST_ALPHA => [1.0 - dst[3]; 4],
+            gles11::SRC_ALPHA_SATURATE => {
+                let f = src[3].min(1.0 - dst[3]);
+                [f, f, f, 1.0]
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Depth test, blending, masking and writing to the framebuffer.
+    ///
+    /// The stencil test is skipped: the `GLES` trait doesn't (yet) have
+    /// `glStencilFunc` or `glStencilOp`, so it would always pass with
+    /// `GL_KEEP`, which leaves the stencil buffer untouched.
+    fn write_fragment(&self, target: &mut Target, x: u32, y: u32, z: f32, color: [f32; 4]) {
+        let index = (y * target.width + x) as usize;
+
+        if self.is_enabled(gles11::DEPTH_TEST) && !target.depth.is_empty() {
+            let z = z.clamp(0.0, 1.0);
+            if !compare(self.depth_func, z, target.depth[index]) {
+                return;
+            }
+            if self.depth_mask {
+                target.depth[index] = z;
+            }
+        }
+
+        if target.color.is_empty() {
+            return;
+        }
+        let mut dst = color_to_float(target.color[index]);
+        if !target.color_has_alpha {
+            dst[3] = 1.0;
+        }
+        let src = color.map(|c| c.clamp(0.0, 1.0));
+        let mut res = if self.is_enabled(gles11::BLEND) {
+            let (sfactor, dfactor) = self.blend_func;
+            let sf = Self::blend_factor(sfactor, src, dst);
+            let df = Self::blend_factor(dfactor, src, dst);
+            std::array::from_fn(|i| src[i] * sf[i] + dst[i] * df[i])
+        } else {
+            src
+        };
+        for i in 0..4 {
+            if !self.color_mask[i] {
+                res[i] = dst[i];
+            }
+        }
+        if !target.color_has_alpha {
+            res[3] = 1.0;
+        }
+        target.color[index] = color_to_u8(res);
+    }
+
+    // glGet
+
+    /// Get the value of a `glGet` parameter and its natural type. Booleans and
+    /// integers are returned as floats too, which is lossless for all of them.
+    fn get_param(&self, pname: GLenum) -> (ParamType, Vec<GLfloat>) {
+        use ParamType::{Boolean, Float, FloatSpecial, Int};
+        let boolean = |value: bool| (Boolean, vec![if value { 1.0 } else { 0.0 }]);
+        let int = |value: u32| (Int, vec![value as f32]);
+        let array_info = |array: &ArrayState, pname: GLenum| -> Option<(ParamType, Vec<f32>)> {
+            Some(match pname {
+                gles11::VERTEX_ARRAY
+                | gles11::NORMAL_ARRAY
+                | gles11::COLOR_ARRAY
+                | gles11::TEXTURE_COORD_ARRAY => boolean(array.enabled),
+                gles11::VERTEX_ARRAY_SIZE
+                | gles11::COLOR_ARRAY_SIZE
+                | gles11::TEXTURE_COORD_ARRAY_SIZE => int(array.pointer.size as u32),
+                gles11::VERTEX_ARRAY_TYPE
+                | gles11::NORMAL_ARRAY_TYPE
+                | gles11::COLOR_ARRAY_TYPE
+                | gles11::TEXTURE_COORD_ARRAY_TYPE => int(array.pointer.type_),
+                gles11::VERTEX_ARRAY_STRIDE
+                | gles11::NORMAL_ARRAY_STRIDE
+                | gles11::COLOR_ARRAY_STRIDE
+                | gles11::TEXTURE_COORD_ARRAY_STRIDE => int(array.pointer.stride as u32),
+                gles11::VERTEX_ARRAY_BUFFER_BINDING
+                | gles11::NORMAL_ARRAY_BUFFER_BINDING
+                | gles11::COLOR_ARRAY_BUFFER_BINDING
+                | gles11::TEXTURE_COORD_ARRAY_BUFFER_BINDING => int(array.pointer.buffer),
+                _ => return None,
+            })
+        };
+        let array = match pname {
+            gles11::VERTEX_ARRAY
+            | gles11::VERTEX_ARRAY_SIZE
+            | gles11::VERTEX_ARRAY_TYPE
+            | gles11::VERTEX_ARRAY_STRIDE
+            | gles11::VERTEX_ARRAY_BUFFER_BINDING => Some(&self.vertex_array),
+            gles11::NORMAL_ARRAY
+            | gles11::NORMAL_ARRAY_TYPE
+            | gles11::NORMAL_ARRAY_STRIDE
+            | gles11::NORMAL_ARRAY_BUFFER_BINDING => Some(&self.normal_array),
+            gles11::COLOR_ARRAY
+            | gles11::COLOR_ARRAY_SIZE
+            | gles11::COLOR_ARRAY_TYPE
+            | gles11::COLOR_ARRAY_STRIDE
+            | gles11::COLOR_ARRAY_BUFFER_BINDING => Some(&self.color_array),
+            gles11::TEXTURE_COORD_ARRAY
+            | gles11::TEXTURE_COORD_ARRAY_SIZE
+            | gles11::TEXTURE_COORD_ARRAY_TYPE
+            | gles11::TEXTURE_COORD_ARRAY_STRIDE
+            | gles11::TEXTURE_COORD_ARRAY_BUFFER_BINDING => {
+                Some(&self.tex_coord_arrays[self.client_active_texture])
+            }
+            _ => None,
+        };
+        if let Some(array) = array {
+            return array_info(array, pname).unwrap();
+        }
+
+        if CAPABILITIES.contains(&pname) {
+            return boolean(self.is_enabled(pname));
+        }
+
+        let (color_bits, depth_bits, stencil_bits) = if self.framebuffer_binding == 0 {
+            ([8, 8, 8, 8], 24, 8)
+        } else {
+            let framebuffer = &self.framebuffers[&self.framebuffer_binding];
+            let rb_format = |attachment: Option<Attachment>| match attachment {
+                Some(Attachment::Renderbuffer(name)) => {
+                    self.renderbuffers.get(&name).map(|rb| rb.internalformat)
+                }
+                Some(Attachment::Texture(..)) => Some(gles11::RGBA8_OES),
+                None => None,
+            };
+            (
+                rb_format(framebuffer.color).map_or([0; 4], Self::color_bits),
+                rb_format(framebuffer.depth).map_or(0, Self::depth_bits),
+                rb_format(framebuffer.stencil).map_or(0, Self::stencil_bits),
+            )
+        };
+
+        match pname {
+            gles11::ACTIVE_TEXTURE => int(gles11::TEXTURE0 + self.active_texture as u32),
+            gles11::CLIENT_ACTIVE_TEXTURE => {
+                int(gles11::TEXTURE0 + self.client_active_texture as u32)
+            }
+            gles11::ALIASED_POINT_SIZE_RANGE | gles11::SMOOTH_POINT_SIZE_RANGE => {
+                (Float, vec![POINT_SIZE_RANGE.0, POINT_SIZE_RANGE.1])
+            }
+            gles11::ALIASED_LINE_WIDTH_RANGE | gles11::SMOOTH_LINE_WIDTH_RANGE => {
+                (Float, vec![LINE_WIDTH_RANGE.0, LINE_WIDTH_RANGE.1])
+            }
+            gles11::RED_BITS => int(color_bits[0]),
+            gles11::GREEN_BITS => int(color_bits[1]),
+            gles11::BLUE_BITS => int(color_bits[2]),
+            gles11::ALPHA_BITS => int(color_bits[3]),
+            gles11::DEPTH_BITS => int(depth_bits),
+            gles11::STENCIL_BITS => int(stencil_bits),
+            gles11::ALPHA_TEST_FUNC => int(self.alpha_func.0),
+            gles11::ALPHA_TEST_REF => (FloatSpecial, vec![self.alpha_func.1]),
+            gles11::ARRAY_BUFFER_BINDING => int(self.array_buffer_binding),
+            gles11::ELEMENT_ARRAY_BUFFER_BINDING => int(self.element_array_buffer_binding),
+            gles11::BLEND_SRC => int(self.blend_func.0),
+            gles11::BLEND_DST => int(self.blend_func.1),
+            gles11::COLOR_CLEAR_VALUE => (FloatSpecial, self.clear_color.to_vec()),
+            gles11::COLOR_WRITEMASK => (
+                Boolean,
+                self.color_mask.map(|m| if m { 1.0 } else { 0.0 }).to_vec(),
+            ),
+            gles11::CULL_FACE_MODE => int(self.cull_face_mode),
+            gles11::CURRENT_COLOR => (FloatSpecial, self.current_color.to_vec()),
+            gles11::CURRENT_NORMAL => (FloatSpecial, self.current_normal.to_vec()),
+            gles11::CURRENT_TEXTURE_COORDS => {
+                (Float, self.current_tex_coords[self.active_texture].to_vec())
+            }
+            gles11::DEPTH_CLEAR_VALUE => (FloatSpecial, vec![self.clear_depth]),
+            gles11::DEPTH_FUNC => int(self.depth_func),
+            gles11::DEPTH_RANGE => (FloatSpecial, vec![self.depth_range.0, self.depth_range.1]),
+            gles11::DEPTH_WRITEMASK => boolean(self.depth_mask),
+            gles11::FOG_COLOR => (FloatSpecial, self.fog_color.to_vec()),
+            gles11::FOG_DENSITY => (Float, vec![self.fog_density]),
+            gles11::FOG_END => (Float, vec![self.fog_end]),
+            gles11::FOG_MODE => int(self.fog_mode),
+            gles11::FOG_START => (Float, vec![self.fog_start]),
+            gles11::FOG_HINT
+            | gles11::GENERATE_MIPMAP_HINT
+            | gles11::LINE_SMOOTH_HINT
+            | gles11::PERSPECTIVE_CORRECTION_HINT
+            | gles11::POINT_SMOOTH_HINT => {
+                int(*self.hints.get(&pname).unwrap_or(&gles11::DONT_CARE))
+            }
+            gles11::FRONT_FACE => int(self.front_face),
+            IMPLEMENTATION_COLOR_READ_FORMAT_OES => int(gles11::RGBA),
+            IMPLEMENTATION_COLOR_READ_TYPE_OES => int(gles11::UNSIGNED_BYTE),
+            gles11::LIGHT_MODEL_AMBIENT => (FloatSpecial, self.light_model_ambient.to_vec()),
+            gles11::LIGHT_MODEL_TWO_SIDE => boolean(self.light_model_two_side),
+            gles11::LINE_WIDTH => (Float, vec![self.line_width]),
+            gles11::MATRIX_MODE => int(self.matrix_mode),
+            gles11::MAX_CLIP_PLANES => int(1),
+            gles11::MAX_LIGHTS => int(MAX_LIGHTS as u32),
+            gles11::MAX_MODELVIEW_STACK_DEPTH => int(MAX_MODELVIEW_STACK_DEPTH as u32),
+            gles11::MAX_PROJECTION_STACK_DEPTH => int(MAX_PROJECTION_STACK_DEPTH as u32),
+            gles11::MAX_TEXTURE_STACK_DEPTH => int(MAX_TEXTURE_STACK_DEPTH as u32),
+            gles11::MAX_TEXTURE_MAX_ANISOTROPY_EXT => (Float, vec![1.0]),
+            gles11::MAX_TEXTURE_LOD_BIAS_EXT => (Float, vec![MAX_TEXTURE_LOD_BIAS]),
+            gles11::MAX_TEXTURE_SIZE => int(MAX_TEXTURE_SIZE),
+            gles11::MAX_RENDERBUFFER_SIZE_OES => int(MAX_TEXTURE_SIZE),
+            gles11::MAX_TEXTURE_UNITS => int(TEXTURE_UNITS as u32),
+            gles11::MAX_VIEWPORT_DIMS => (Int, vec![MAX_TEXTURE_SIZE as f32; 2]),
+            gles11::MODELVIEW_MATRIX => (Float, self.modelview().to_vec()),
+            gles11::MODELVIEW_STACK_DEPTH => int(self.modelview_stack.len() as u32),
+            gles11::PROJECTION_MATRIX => (Float, self.projection_stack.last().unwrap().to_vec()),
+            gles11::PROJECTION_STACK_DEPTH => int(self.projection_stack.len() as u32),
+            gles11::TEXTURE_MATRIX => (
+                Float,
+                self.texture_stacks[self.active_texture]
+                    .last()
+                    .unwrap()
+                    .to_vec(),
+            ),
+            gles11::TEXTURE_STACK_DEPTH => {
+                int(self.texture_stacks[self.active_texture].len() as u32)
+            }
+            gles11::NUM_COMPRESSED_TEXTURE_FORMATS => int(0),
+            gles11::PACK_ALIGNMENT => int(self.pack_alignment as u32),
+            gles11::UNPACK_ALIGNMENT => int(self.unpack_alignment as u32),
+            gles11::POINT_DISTANCE_ATTENUATION => (Float, self.point_distance_attenuation.to_vec()),
+            gles11::POINT_FADE_THRESHOLD_SIZE => (Float, vec![self.point_fade_threshold_size]),
+            gles11::POINT_SIZE => (Float, vec![self.point_size]),
+            gles11::POINT_SIZE_MAX => (Float, vec![self.point_size_max]),
+            gles11::POINT_SIZE_MIN => (Float, vec![self.point_size_min]),
+            gles11::POLYGON_OFFSET_FACTOR => (Float, vec![self.polygon_offset.0]),
+            gles11::POLYGON_OFFSET_UNITS => (Float, vec![self.polygon_offset.1]),
+            gles11::SAMPLE_BUFFERS | gles11::SAMPLES => int(0),
+            gles11::SCISSOR_BOX => {
+                let (x, y, w, h) = self.scissor_box;
+                (Int, vec![x as f32, y as f32, w as f32, h as f32])
+            }
+            gles11::SHADE_MODEL => int(self.shade_model),
+            gles11::STENCIL_CLEAR_VALUE => int(self.clear_stencil as u32),
+            gles11::STENCIL_FAIL
+            | gles11::STENCIL_PASS_DEPTH_FAIL
+            | gles11::STENCIL_PASS_DEPTH_PASS => int(gles11::KEEP),
+            gles11::STENCIL_FUNC => int(gles11::ALWAYS),
+            gles11::STENCIL_REF => int(0),
+            gles11::STENCIL_VALUE_MASK | gles11::STENCIL_WRITEMASK => int(0xff),
+            gles11::SUBPIXEL_BITS => int(4),
+            gles11::TEXTURE_2D => boolean(self.texture_2d_enabled[self.active_texture]),
+            gles11::TEXTURE_BINDING_2D => int(self.texture_bindings[self.active_texture]),
+            gles11::VIEWPORT => {
+                let (x, y, w, h) = self.viewport;
+                (Int, vec![x as f32, y as f32, w as f32, h as f32])
+            }
+            gles11::FRAMEBUFFER_BINDING_OES => int(self.framebuffer_binding),
+            gles11::RENDERBUFFER_BINDING_OES => int(self.renderbuffer_binding),
+            _ => panic!("Unhandled parameter name: {:#x}", pname),
+        }
+    }
+
+    fn color_bits(internalformat: GLenum) -> [u32; 4] {
+        match internalformat {
+            gles11::RGBA8_OES => [8, 8, 8, 8],
+            gles11::RGB8_OES => [8, 8, 8, 0],
+            gles11::RGB565_OES => [5, 6, 5, 0],
+            gles11::RGBA4_OES => [4, 4, 4, 4],
+            gles11::RGB5_A1_OES => [5, 5, 5, 1],
+            _ => [0; 4],
+        }
+    }
+    fn depth_bits(internalformat: GLenum) -> u32 {
+        match internalformat {
+            gles11::DEPTH_COMPONENT16_OES => 16,
+            DEPTH_COMPONENT24_OES | DEPTH24_STENCIL8_OES => 24,
+            _ => 0,
+        }
+    }
+    fn stencil_bits(internalformat: GLenum) -> u32 {
+        match internalformat {
+            STENCIL_INDEX8_OES | DEPTH24_STENCIL8_OES => 8,
+            _ => 0,
+        }
+    }
+
+    /// Shared part of `glTexImage2D` and `glCopyTexImage2D`.
+    fn tex_image(&mut self, level: GLint, internalformat: GLenum, image: Image) {
+        assert!(level >= 0);
+        assert!(image.width <= MAX_TEXTURE_SIZE && image.height <= MAX_TEXTURE_SIZE);
+        let texture = self.bound_texture_mut();
+        if level == 0 {
+            texture.format = if internalformat == gles11::BGRA_EXT {
+                gles11::RGBA
+            } else {
+                internalformat
+            };
+        }
+        texture.set_level(level as usize, image);
+        if level == 0 && texture.generate_mipmap {
+            texture.generate_mipmaps();
+        }
+    }
+
+    /// Shared part of `glTexSubImage2D` and `glCopyTexSubImage2D`.
+    fn tex_sub_image(&mut self, level: GLint, xoffset: GLint, yoffset: GLint, image: &Image) {
+        assert!(level >= 0);
+        let texture = self.bound_texture_mut();
+        let format = texture.format;
+        let dst = &mut texture.levels[level as usize];
+        assert!(xoffset >= 0 && yoffset >= 0);
+        assert!(xoffset as u32 + image.width <= dst.width);
+        assert!(yoffset as u32 + image.height <= dst.height);
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let texel = image.pixels[(y * image.width + x) as usize];
+                let (dx, dy) = (x + xoffset as u32, y + yoffset as u32);
+                dst.pixels[(dy * dst.width + dx) as usize] = convert_to_base_format(format, texel);
+            }
+        }
+        if level == 0 && texture.generate_mipmap {
+            texture.generate_mipmaps();
+        }
+    }
+
+    unsafe fn unpack_image(
+        &self,
+        width: GLsizei,
+        height: GLsizei,
+        format: GLenum,
+        type_: GLenum,
+        pixels: *const GLvoid,
+    ) -> Image {
+        assert!(width >= 0 && height >= 0);
+        let mut image = Image::new(width as u32, height as u32);
+        if pixels.is_null() {
+            return image;
+        }
+        let (width, height) = (width as usize, height as usize);
+        let bpp = bytes_per_pixel(format, type_);
+        let stride = row_stride(width, bpp, self.unpack_alignment);
+        let data = std::slice::from_raw_parts(
+            pixels.cast::<u8>(),
+            stride * height.saturating_sub(1) + width * bpp,
+        );
+        for y in 0..height {
+            for x in 0..width {
+                let offset = y * stride + x * bpp;
+                image.pixels[y * width + x] =
+                    unpack_pixel(format, type_, &data[offset..offset + bpp]);
+            }
+        }
+        image
+    }
+
+    fn set_light_param(&mut self, light: GLenum, pname: GLenum, params: &[f32]) {
+        let index = light.wrapping_sub(gles11::LIGHT0) as usize;
+        assert!(index < MAX_LIGHTS);
+        let modelview = *self.modelview();
+        let light = &mut self.lights[index];
+        match pname {
+            gles11::AMBIENT => light.ambient.copy_from_slice(&params[..4]),
+            gles11::DIFFUSE => light.diffuse.copy_from_slice(&params[..4]),
+            gles11::SPECULAR => light.specular.copy_from_slice(&params[..4]),
+            gles11::POSITION => {
+                light.position = mat4_transform(&modelview, params[..4].try_into().unwrap())
+            }
+            gles11::SPOT_DIRECTION => {
+                let d = mat4_transform(&modelview, [params[0], params[1], params[2], 0.0]);
+                light.spot_direction = [d[0], d[1], d[2]];
+            }
+            gles11::SPOT_EXPONENT => light.spot_exponent = params[0],
+            gles11::SPOT_CUTOFF => light.spot_cutoff = params[0],
+            gles11::CONSTANT_ATTENUATION => light.attenuation[0] = params[0],
+            gles11::LINEAR_ATTENUATION => light.attenuation[1] = params[0],
+            gles11::QUADRATIC_ATTENUATION => light.attenuation[2] = params[0],
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_material_param(&mut self, face: GLenum, pname: GLenum, params: &[f32]) {
+        assert!(face == gles11::FRONT_AND_BACK);
+        let material = &mut self.material;
+        match pname {
+            gles11::AMBIENT => material.ambient.copy_from_slice(&params[..4]),
+            gles11::DIFFUSE => material.diffuse.copy_from_slice(&params[..4]),
+            gles11::SPECULAR => material.specular.copy_from_slice(&params[..4]),
+            gles11::EMISSION => material.emission.copy_from_slice(&params[..4]),
+            gles11::SHININESS => material.shininess = params[0],
+            gles11::AMBIENT_AND_DIFFUSE => {
+                material.ambient.copy_from_slice(&params[..4]);
+                material.diffuse.copy_from_slice(&params[..4]);
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_fog_param(&mut self, pname: GLenum, params: &[f32]) {
+        match pname {
+            gles11::FOG_MODE => {
+                let mode = params[0] as GLenum;
+                assert!([gles11::LINEAR, gles11::EXP, gles11::EXP2].contains(&mode));
+                self.fog_mode = mode;
+            }
+            gles11::FOG_DENSITY => self.fog_density = params[0],
+            gles11::FOG_START => self.fog_start = params[0],
+            gles11::FOG_END => self.fog_end = params[0],
+            gles11::FOG_COLOR => self.fog_color.copy_from_slice(&params[..4]),
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_point_param(&mut self, pname: GLenum, params: &[f32]) {
+        match pname {
+            gles11::POINT_SIZE_MIN => self.point_size_min = params[0],
+            gles11::POINT_SIZE_MAX => self.point_size_max = params[0],
+            gles11::POINT_DISTANCE_ATTENUATION => self
+                .point_distance_attenuation
+                .copy_from_slice(&params[..3]),
+            gles11::POINT_FADE_THRESHOLD_SIZE => self.point_fade_threshold_size = params[0],
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_tex_param(&mut self, pname: GLenum, param: f32) {
+        let texture = self.bound_texture_mut();
+        match pname {
+            gles11::TEXTURE_MIN_FILTER => {
+                let filter = param as GLenum;
+                assert!([
+                    gles11::NEAREST,
+                    gles11::LINEAR,
+                    gles11::NEAREST_MIPMAP_NEAREST,
+                    gles11::LINEAR_MIPMAP_NEAREST,
+                    gles11::NEAREST_MIPMAP_LINEAR,
+                    gles11::LINEAR_MIPMAP_LINEAR
+                ]
+                .contains(&filter));
+                texture.min_filter = filter;
+            }
+            gles11::TEXTURE_MAG_FILTER => {
+                let filter = param as GLenum;
+                assert!(filter == gles11::NEAREST || filter == gles11::LINEAR);
+                texture.mag_filter = filter;
+            }
+            gles11::TEXTURE_WRAP_S | gles11::TEXTURE_WRAP_T => {
+                let wrap = param as GLenum;
+                assert!(wrap == gles11::REPEAT || wrap == gles11::CLAMP_TO_EDGE);
+                if pname == gles11::TEXTURE_WRAP_S {
+                    texture.wrap_s = wrap;
+                } else {
+                    texture.wrap_t = wrap;
+                }
+            }
+            gles11::GENERATE_MIPMAP => texture.generate_mipmap = param != 0.0,
+            gles11::TEXTURE_MAX_ANISOTROPY_EXT => texture.max_anisotropy = param,
+            _ => unreachable!(),
+        }
+    }
+
+    fn set_tex_env_param(&mut self, target: GLenum, pname: GLenum, params: &[f32]) {
+        let env = &mut self.tex_envs[self.active_texture];
+        match target {
+            gles11::TEXTURE_ENV => {
+                TEX_ENV_PARAMS.assert_known_param(pname);
+                env.set_param(pname, params);
+            }
+            gles11::TEXTURE_FILTER_CONTROL_EXT => {
+                assert!(pname == gles11::TEXTURE_LOD_BIAS_EXT);
+                env.lod_bias = params[0];
+            }
+            POINT_SPRITE_OES => {
+                assert!(pname == COORD_REPLACE_OES);
+                env.coord_replace = params[0] != 0.0;
+            }
+            gles11::TEXTURE_2D => {
+                // This is not a valid target, but it's tolerated for a
+                // Rayman 2 case (see the GLES1-on-GL2 layer).
+                assert!(pname == gles11::TEXTURE_ENV_MODE);
+                log_dbg!(
+                    "Tolerating glTexEnv(GL_TEXTURE_2D, TEXTURE_ENV_MODE, {})",
+                    params[0]
+                );
+                env.mode = params[0] as GLenum;
+            }
+            _ => unimplemented!("target 0x{:X}, pname 0x{:X}", target, pname),
+        }
+    }
+}
+
+impl GLES for GLES1Software {
+    fn description() -> &'static str {
+        "OpenGL ES 1.1 via touchHLE software rasterizer"
+    }
+
+    fn new(window: &mut Window) -> Result<Self, String> {
+        Ok(Self::new_with_framebuffer(window.software_framebuffer()))
+    }
+
+    fn make_current(&self, _window: &Window) {
+        // There's no global state, so nothing to do.
+    }
+
+    unsafe fn driver_description(&self) -> String {
+        "touchHLE software rasterizer".to_string()
+    }
+
+    // Generic state manipulation
+    unsafe fn GetError(&mut self) -> GLenum {
+        std::mem::replace(&mut self.error, gles11::NO_ERROR)
+    }
+    unsafe fn Enable(&mut self, cap: GLenum) {
+        if cap == gles11::TEXTURE_2D {
+            self.texture_2d_enabled[self.active_texture] = true;
+        } else if [
+            gles11::VERTEX_ARRAY,
+            gles11::NORMAL_ARRAY,
+            gles11::COLOR_ARRAY,
+            gles11::TEXTURE_COORD_ARRAY,
+        ]
+        .contains(&cap)
+        {
+            log_dbg!("Tolerating glEnable({:#x}) of client state", cap);
+            self.array_mut(cap).enabled = true;
+        } else {
+            assert!(CAPABILITIES.contains(&cap));
+            self.capabilities.insert(cap);
+        }
+    }
+    unsafe fn IsEnabled(&mut self, cap: GLenum) -> GLboolean {
+        let (type_, values) = self.get_param(cap);
+        assert!(type_ == ParamType::Boolean);
+        if values[0] != 0.0 {
+            gles11::TRUE
+        } else {
+            gles11::FALSE
+        }
+    }
+    unsafe fn Disable(&mut self, cap: GLenum) {
+        if cap == gles11::TEXTURE_2D {
+            self.texture_2d_enabled[self.active_texture] = false;
+        } else if [
+            gles11::VERTEX_ARRAY,
+            gles11::NORMAL_ARRAY,
+            gles11::COLOR_ARRAY,
+            gles11::TEXTURE_COORD_ARRAY,
+        ]
+        .contains(&cap)
+        {
+            log_dbg!("Tolerating glDisable({:#x}) of client state", cap);
+            self.array_mut(cap).enabled = false;
+        } else {
+            assert!(CAPABILITIES.contains(&cap));
+            self.capabilities.remove(&cap);
+        }
+    }
+    unsafe fn ClientActiveTexture(&mut self, texture: GLenum) {
+        self.client_active_texture = texture_unit_index(texture);
+    }
+    unsafe fn EnableClientState(&mut self, array: GLenum) {
+        self.array_mut(array).enabled = true;
+    }
+    unsafe fn DisableClientState(&mut self, array: GLenum) {
+        self.array_mut(array).enabled = false;
+    }
+    unsafe fn GetBooleanv(&mut self, pname: GLenum, params: *mut GLboolean) {
+        let (_type, values) = self.get_param(pname);
+        for (i, value) in values.into_iter().enumerate() {
+            params.add(i).write(if value != 0.0 {
+                gles11::TRUE
+            } else {
+                gles11::FALSE
+            });
+        }
+    }
+    unsafe fn GetFloatv(&mut self, pname: GLenum, params: *mut GLfloat) {
+        let (_type, values) = self.get_param(pname);
+        for (i, value) in values.into_iter().enumerate() {
+            params.add(i).write(value);
+        }
+    }
+    unsafe fn GetIntegerv(&mut self, pname: GLenum, params: *mut GLint) {
+        let (type_, values) = self.get_param(pname);
+        for (i, value) in values.into_iter().enumerate() {
+            let value = match type_ {
+                // Colors and similar values are mapped from [-1, 1] to the
+                // full integer range.
+                ParamType::FloatSpecial => (f64::from(value) * f64::from(i32::MAX)) as GLint,
+                ParamType::Float => value.round() as GLint,
+                _ => value as u32 as GLint,
+            };
+            params.add(i).write(value);
+        }
+    }
+    unsafe fn GetTexEnviv(&mut self, target: GLenum, pname: GLenum, params: *mut GLint) {
+        let (type_, _count) = TEX_ENV_PARAMS.get_type_info(pname);
+        assert!(type_ == ParamType::Int);
+        assert_eq!(target, gles11::TEXTURE_ENV);
+        let env = &mut self.tex_envs[self.active_texture];
+        params.write(*env.int_param_mut(pname) as GLint);
+    }
+    unsafe fn GetPointerv(&mut self, pname: GLenum, params: *mut *const GLvoid) {
+        let array = match pname {
+            gles11::VERTEX_ARRAY_POINTER => &self.vertex_array,
+            gles11::NORMAL_ARRAY_POINTER => &self.normal_array,
+            gles11::COLOR_ARRAY_POINTER => &self.color_array,
+            gles11::TEXTURE_COORD_ARRAY_POINTER => {
+                &self.tex_coord_arrays[self.client_active_texture]
+            }
+            _ => panic!("Unhandled pointer name: {:#x}", pname),
+        };
+        params.write(array.pointer.pointer);
+    }
+    unsafe fn Hint(&mut self, target: GLenum, mode: GLenum) {
+        assert!([
+            gles11::FOG_HINT,
+            gles11::GENERATE_MIPMAP_HINT,
+            gles11::LINE_SMOOTH_HINT,
+            gles11::PERSPECTIVE_CORRECTION_HINT,
+            gles11::POINT_SMOOTH_HINT
+        ]
+        .contains(&target));
+        assert!([gles11::FASTEST, gles11::NICEST, gles11::DONT_CARE].contains(&mode));
+        self.hints.insert(target, mode);
+    }
+    unsafe fn Flush(&mut self) {
+        // Rendering is synchronous, so there's never anything to flush.
+    }
+    unsafe fn GetString(&mut self, name: GLenum) -> *const GLubyte {
+        let string: &'static [u8] = match name {
+            gles11::VENDOR => b"touchHLE project contributors\0",
+            gles11::RENDERER => b"touchHLE software rasterizer\0",
+            gles11::VERSION => b"OpenGL ES-CM 1.1\0",
+            gles11::EXTENSIONS => concat!(
+                "GL_EXT_texture_format_BGRA8888 ",
+                "GL_EXT_texture_lod_bias ",
+                "GL_IMG_texture_compression_pvrtc ",
+                "GL_OES_compressed_paletted_texture ",
+                "GL_OES_depth24 ",
+                "GL_OES_framebuffer_object ",
+                "GL_OES_packed_depth_stencil ",
+                "GL_OES_point_sprite ",
+                "GL_OES_rgb8_rgba8 ",
+                "GL_OES_stencil8\0"
+            )
+            .as_bytes(),
+            _ => panic!("Unhandled string name: {:#x}", name),
+        };
+        string.as_ptr()
+    }
+
+    // Other state manipulation
+    unsafe fn AlphaFunc(&mut self, func: GLenum, ref_: GLclampf) {
+        assert!([
+            gles11::NEVER,
+            gles11::LESS,
+            gles11::EQUAL,
+            gles11::LEQUAL,
+            gles11::GREATER,
+            gles11::NOTEQUAL,
+            gles11::GEQUAL,
+            gles11::ALWAYS
+        ]
+        .contains(&func));
+        self.alpha_func = (func, ref_);
+    }
+    unsafe fn AlphaFuncx(&mut self, func: GLenum, ref_: GLclampx) {
+        self.AlphaFunc(func, fixed_to_float(ref_))
+    }
+    unsafe fn BlendFunc(&mut self, sfactor: GLenum, dfactor: GLenum) {
+        let factors = [
+            gles11::ZERO,
+            gles11::ONE,
+            gles11::SRC_COLOR,
+            gles11::ONE_MINUS_SRC_COLOR,
+            gles11::DST_COLOR,
+            gles11::ONE_MINUS_DST_COLOR,
+            gles11::SRC_ALPHA,
+            gles11::ONE_MINUS_SRC_ALPHA,
+            gles11::DST_ALPHA,
+            gles11::ONE_MINUS_DST_ALPHA,
+            gles11::SRC_ALPHA_SATURATE,
+        ];
+        assert!(factors.contains(&sfactor) && factors.contains(&dfactor));
+        self.blend_func = (sfactor, dfactor);
+    }
+    unsafe fn ColorMask(
+        &mut self,
+        red: GLboolean,
+        green: GLboolean,
+        blue: GLboolean,
+        alpha: GLboolean,
+    ) {
+        self.color_mask = [red, green, blue, alpha].map(|m| m != gles11::FALSE);
+    }
+    unsafe fn CullFace(&mut self, mode: GLenum) {
+        assert!([gles11::FRONT, gles11::BACK, gles11::FRONT_AND_BACK].contains(&mode));
+        self.cull_face_mode = mode;
+    }
+    unsafe fn DepthFunc(&mut self, func: GLenum) {
+        assert!([
+            gles11::NEVER,
+            gles11::LESS,
+            gles11::EQUAL,
+            gles11::LEQUAL,
+            gles11::GREATER,
+            gles11::NOTEQUAL,
+            gles11::GEQUAL,
+            gles11::ALWAYS
+        ]
+        .contains(&func));
+        self.depth_func = func;
+    }
+    unsafe fn DepthMask(&mut self, flag: GLboolean) {
+        self.depth_mask = flag != gles11::FALSE;
+    }
+    unsafe fn DepthRangef(&mut self, near: GLclampf, far: GLclampf) {
+        self.depth_range = (near.clamp(0.0, 1.0), far.clamp(0.0, 1.0));
+    }
+    unsafe fn DepthRangex(&mut self, near: GLclampx, far: GLclampx) {
+        self.DepthRangef(fixed_to_float(near), fixed_to_float(far))
+    }
+    unsafe fn FrontFace(&mut self, mode: GLenum) {
+        assert!(mode == gles11::CW || mode == gles11::CCW);
+        self.front_face = mode;
+    }
+    unsafe fn PolygonOffset(&mut self, factor: GLfloat, units: GLfloat) {
+        self.polygon_offset = (factor, units);
+    }
+    unsafe fn PolygonOffsetx(&mut self, factor: GLfixed, units: GLfixed) {
+        self.PolygonOffset(fixed_to_float(factor), fixed_to_float(units))
+    }
+    unsafe fn ShadeModel(&mut self, mode: GLenum) {
+        assert!(mode == gles11::FLAT || mode == gles11::SMOOTH);
+        self.shade_model = mode;
+    }
+    unsafe fn Scissor(&mut self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        if width < 0 || height < 0 {
+            self.set_error(gles11::INVALID_VALUE);
+            return;
+        }
+        self.scissor_box = (x, y, width, height);
+    }
+    unsafe fn Viewport(&mut self, x: GLint, y: GLint, width: GLsizei, height: GLsizei) {
+        if width < 0 || height < 0 {
+            self.set_error(gles11::INVALID_VALUE);
+            return;
+        }
+        let max = MAX_TEXTURE_SIZE as GLsizei;
+        self.viewport = (x, y, width.min(max), height.min(max));
+    }
+    unsafe fn LineWidth(&mut self, val: GLfloat) {
+        if val <= 0.0 {
+            self.set_error(gles11::INVALID_VALUE);
+            return;
+        }
+        self.line_width = val.clamp(LINE_WIDTH_RANGE.0, LINE_WIDTH_RANGE.1);
+    }
+    unsafe fn LineWidthx(&mut self, val: GLfixed) {
+        self.LineWidth(fixed_to_float(val))
+    }
+
+    // Points
+    unsafe fn PointSize(&mut self, size: GLfloat) {
+        if size <= 0.0 {
+            self.set_error(gles11::INVALID_VALUE);
+            return;
+        }
+        self.point_size = size;
+    }
+    unsafe fn PointSizex(&mut self, size: GLfixed) {
+        self.PointSize(fixed_to_float(size))
+    }
+    unsafe fn PointParameterf(&mut self, pname: GLenum, param: GLfloat) {
+        POINT_PARAMS.assert_component_count(pname, 1);
+        self.set_point_param(pname, &[param]);
+    }
+    unsafe fn PointParameterx(&mut self, pname: GLenum, param: GLfixed) {
+        POINT_PARAMS.setx(
+            |param| self.set_point_param(pname, &[param]),
+            |_| unreachable!(), // no integer parameters exist
+            pname,
+            param,
+        );
+    }
+    unsafe fn PointParameterfv(&mut self, pname: GLenum, params: *const GLfloat) {
+        let (_type, count) = POINT_PARAMS.get_type_info(pname);
+        let params = std::slice::from_raw_parts(params, count.into());
+        self.set_point_param(pname, params);
+    }
+    unsafe fn PointParameterxv(&mut self, pname: GLenum, params: *const GLfixed) {
+        let (_type, count) = POINT_PARAMS.get_type_info(pname);
+        POINT_PARAMS.setxv(
+            |params| self.set_point_param(pname, std::slice::from_raw_parts(params, count.into())),
+            |_| unreachable!(), // no integer parameters exist
+            pname,
+            params,
+        );
+    }
+
+    // Lighting and materials
+    unsafe fn Fogf(&mut self, pname: GLenum, param: GLfloat) {
+        FOG_PARAMS.assert_component_count(pname, 1);
+        self.set_fog_param(pname, &[param]);
+    }
+    unsafe fn Fogx(&mut self, pname: GLenum, param: GLfixed) {
+        FOG_PARAMS.assert_component_count(pname, 1);
+        let params = fixed_params_to_float(&FOG_PARAMS, pname, &param);
+        self.set_fog_param(pname, &params);
+    }
+    unsafe fn Fogfv(&mut self, pname: GLenum, params: *const GLfloat) {
+        let (_type, count) = FOG_PARAMS.get_type_info(pname);
+        let params = std::slice::from_raw_parts(params, count.into());
+        self.set_fog_param(pname, params);
+    }
+    unsafe fn Fogxv(&mut self, pname: GLenum, params: *const GLfixed) {
+        let params = fixed_params_to_float(&FOG_PARAMS, pname, params);
+        self.set_fog_param(pname, &params);
+    }
+    unsafe fn Lightf(&mut self, light: GLenum, pname: GLenum, param: GLfloat) {
+        LIGHT_PARAMS.assert_component_count(pname, 1);
+        self.set_light_param(light, pname, &[param]);
+    }
+    unsafe fn Lightx(&mut self, light: GLenum, pname: GLenum, param: GLfixed) {
+        LIGHT_PARAMS.setx(
+            |param| self.set_light_param(light, pname, &[param]),
+            |_| unreachable!(), // no integer parameters exist
+            pname,
+            param,
+        )
+    }
+    unsafe fn Lightfv(&mut self, light: GLenum, pname: GLenum, params: *const GLfloat) {
+        let (_type, count) = LIGHT_PARAMS.get_type_info(pname);
+        let params = std::slice::from_raw_parts(params, count.into());
+        self.set_light_param(light, pname, params);
+    }
+    unsafe fn Lightxv(&mut self, light: GLenum, pname: GLenum, params: *const GLfixed) {
+        let (_type, count) = LIGHT_PARAMS.get_type_info(pname);
+        LIGHT_PARAMS.setxv(
+            |params| {
+                self.set_light_param(
+                    light,
+                    pname,
+                    std::slice::from_raw_parts(params, count.into()),
+                )
+            },
+            |_| unreachable!(), // no integer parameters exist
+            pname,
+            params,
+        )
+    }
+    unsafe fn LightModelf(&mut self, pname: GLenum, param: GLfloat) {
+        LIGHT_MODEL_PARAMS.assert_component_count(pname, 1);
+        assert!(pname == gles11::LIGHT_MODEL_TWO_SIDE);
+        self.light_model_two_side = param != 0.0;
+    }
+    unsafe fn LightModelfv(&mut self, pname: GLenum, params: *const GLfloat) {
+        LIGHT_MODEL_PARAMS.assert_known_param(pname);
+        match pname {
+            gles11::LIGHT_MODEL_AMBIENT => self
+                .light_model_ambient
+                .copy_from_slice(std::slice::from_raw_parts(params, 4)),
+            _ => self.light_model_two_side = params.read() != 0.0,
+        }
+    }
+    unsafe fn Materialf(&mut self, face: GLenum, pname: GLenum, param: GLfloat) {
+        MATERIAL_PARAMS.assert_component_count(pname, 1);
+        self.set_material_param(face, pname, &[param]);
+    }
+    unsafe fn Materialx(&mut self, face: GLenum, pname: GLenum, param: GLfixed) {
+        MATERIAL_PARAMS.setx(
+            |param| self.set_material_param(face, pname, &[param]),
+            |_| unreachable!(), // no integer parameters exist
+            pname,
+            param,
+        )
+    }
+    unsafe fn Materialfv(&mut self, face: GLenum, pname: GLenum, params: *const GLfloat) {
+        let (_type, count) = MATERIAL_PARAMS.get_type_info(pname);
+        let params = std::slice::from_raw_parts(params, count.into());
+        self.set_material_param(face, pname, params);
+    }
+    unsafe fn Materialxv(&mut self, face: GLenum, pname: GLenum, params: *const GLfixed) {
+        let (_type, count) = MATERIAL_PARAMS.get_type_info(pname);
+        MATERIAL_PARAMS.setxv(
+            |params| {
+                self.set_material_param(
+                    face,
+                    pname,
+                    std::slice::from_raw_parts(params, count.into()),
+                )
+            },
+            |_| unreachable!(), // no integer parameters exist
+            pname,
+            params,
+        )
+    }
+
+    // Buffers
+    unsafe fn GenBuffers(&mut self, n: GLsizei, buffers: *mut GLuint) {
+        gen_names(&self.buffers, &mut self.next_buffer, n, buffers);
+        for &name in names_slice(n, buffers) {
+            self.buffers.insert(name, Vec::new());
+        }
+    }
+    unsafe fn DeleteBuffers(&mut self, n: GLsizei, buffers: *const GLuint) {
+        for &name in names_slice(n, buffers) {
+            if name == 0 {
+                continue;
+            }
+            self.buffers.remove(&name);
+            if self.array_buffer_binding == name {
+                self.array_buffer_binding = 0;
+            }
+            if self.element_array_buffer_binding == name {
+                self.element_array_buffer_binding = 0;
+            }
+        }
+    }
+    unsafe fn BindBuffer(&mut self, target: GLenum, buffer: GLuint) {
+        assert!(target == gles11::ARRAY_BUFFER || target == gles11::ELEMENT_ARRAY_BUFFER);
+        if buffer != 0 {
+            self.buffers.entry(buffer).or_default();
+        }
+        if target == gles11::ARRAY_BUFFER {
+            self.array_buffer_binding = buffer;
+        } else {
+            self.element_array_buffer_binding = buffer;
+        }
+    }
+    unsafe fn BufferData(
+        &mut self,
+        target: GLenum,
+        size: GLsizeiptr,
+        data: *const GLvoid,
+        usage: GLenum,
+    ) {
+        assert!(target == gles11::ARRAY_BUFFER || target == gles11::ELEMENT_ARRAY_BUFFER);
+        assert!(usage == gles11::STATIC_DRAW || usage == gles11::DYNAMIC_DRAW);
+        assert!(size >= 0);
+        let name = if target == gles11::ARRAY_BUFFER {
+            self.array_buffer_binding
+        } else {
+            self.element_array_buffer_binding
+        };
+        assert!(name != 0);
+        let contents = if data.is_null() {
+            vec![0; size as usize]
+        } else {
+            std::slice::from_raw_parts(data.cast::<u8>(), size as usize).to_vec()
+        };
+        self.buffers.insert(name, contents);
+    }
+    unsafe fn BufferSubData(
+        &mut self,
+        target: GLenum,
+        offset: GLintptr,
+        size: GLsizeiptr,
+        data: *const GLvoid,
+    ) {
+        assert!(target == gles11::ARRAY_BUFFER || target == gles11::ELEMENT_ARRAY_BUFFER);
+        let name = if target == gles11::ARRAY_BUFFER {
+            self.array_buffer_binding
+        } else {
+            self.element_array_buffer_binding
+        };
+        let buffer = self.buffers.get_mut(&name).unwrap();
+        assert!(offset >= 0 && size >= 0);
+        let (offset, size) = (offset as usize, size as usize);
+        if offset + size > buffer.len() {
+            self.set_error(gles11::INVALID_VALUE);
+            return;
+        }
+        buffer[offset..offset + size]
+            .copy_from_slice(std::slice::from_raw_parts(data.cast::<u8>(), size));
+    }
+
+    // Non-pointers
+    unsafe fn Color4f(&mut self, red: GLfloat, green: GLfloat, blue: GLfloat, alpha: GLfloat) {
+        self.current_color = [red, green, blue, alpha];
+    }
+    unsafe fn Color4x(&mut self, red: GLfixed, green: GLfixed, blue: GLfixed, alpha: GLfixed) {
+        self.Color4f(
+            fixed_to_float(red),
+            fixed_to_float(green),
+            fixed_to_float(blue),
+            fixed_to_float(alpha),
+        )
+    }
+    unsafe fn Color4ub(&mut self, red: GLubyte, green: GLubyte, blue: GLubyte, alpha: GLubyte) {
+        self.current_color = color_to_float([red, green, blue, alpha]);
+    }
+    unsafe fn Normal3f(&mut self, nx: GLfloat, ny: GLfloat, nz: GLfloat) {
+        self.current_normal = [nx, ny, nz];
+    }
+    unsafe fn Normal3x(&mut self, nx: GLfixed, ny: GLfixed, nz: GLfixed) {
+        self.Normal3f(fixed_to_float(nx), fixed_to_float(ny), fixed_to_float(nz))
+    }
+
+    // Pointers
+    unsafe fn ColorPointer(
+        &mut self,
+        size: GLint,
+        type_: GLenum,
+        stride: GLsizei,
+        pointer: *const GLvoid,
+    ) {
+        assert!(size == 4);
+        assert!(type_ == gles11::UNSIGNED_BYTE || type_ == gles11::FIXED || type_ == gles11::FLOAT);
+        let buffer = self.array_buffer_binding;
+        self.color_array.pointer = ArrayPointer {
+            size,
+            type_,
+            stride,
+            pointer,
+            buffer,
+        };
+    }
+    unsafe fn NormalPointer(&mut self, type_: GLenum, stride: GLsizei, pointer: *const GLvoid) {
+        assert!([gles11::BYTE, gles11::SHORT, gles11::FIXED, gles11::FLOAT].contains(&type_));
+        let buffer = self.array_buffer_binding;
+        self.normal_array.pointer = ArrayPointer {
+            size: 3,
+            type_,
+            stride,
+            pointer,
+            buffer,
+        };
+    }
+    unsafe fn TexCoordPointer(
+        &mut self,
+        size: GLint,
+        type_: GLenum,
+        stride: GLsizei,
+        pointer: *const GLvoid,
+    ) {
+        assert!(size == 2 || size == 3 || size == 4);
+        assert!([gles11::BYTE, gles11::SHORT, gles11::FIXED, gles11::FLOAT].contains(&type_));
+        let buffer = self.array_buffer_binding;
+        self.tex_coord_arrays[self.client_active_texture].pointer = ArrayPointer {
+            size,
+            type_,
+            stride,
+            pointer,
+            buffer,
+        };
+    }
+    unsafe fn VertexPointer(
+        &mut self,
+        size: GLint,
+        type_: GLenum,
+        stride: GLsizei,
+        pointer: *const GLvoid,
+    ) {
+        assert!(size == 2 || size == 3 || size == 4);
+        assert!([gles11::BYTE, gles11::SHORT, gles11::FIXED, gles11::FLOAT].contains(&type_));
+        let buffer = self.array_buffer_binding;
+        self.vertex_array.pointer = ArrayPointer {
+            size,
+            type_,
+            stride,
+            pointer,
+            buffer,
+        };
+    }
+
+    // Drawing
+    unsafe fn DrawArrays(&mut self, mode: GLenum, first: GLint, count: GLsizei) {
+        assert!([
+            gles11::POINTS,
+            gles11::LINE_STRIP,
+            gles11::LINE_LOOP,
+            gles11::LINES,
+            gles11::TRIANGLE_STRIP,
+            gles11::TRIANGLE_FAN,
+            gles11::TRIANGLES
+        ]
+        .contains(&mode));
+        assert!(first >= 0 && count >= 0);
+        let indices: Vec<u32> = (first as u32..(first + count) as u32).collect();
+        self.draw(mode, &indices);
+    }
+    unsafe fn DrawElements(
+        &mut self,
+        mode: GLenum,
+        count: GLsizei,
+        type_: GLenum,
+        indices: *const GLvoid,
+    ) {
+        assert!([
+            gles11::POINTS,
+            gles11::LINE_STRIP,
+            gles11::LINE_LOOP,
+            gles11::LINES,
+            gles11::TRIANGLE_STRIP,
+            gles11::TRIANGLE_FAN,
+            gles11::TRIANGLES
+        ]
+        .contains(&mode));
+        assert!(type_ == gles11::UNSIGNED_BYTE || type_ == gles11::UNSIGNED_SHORT);
+        assert!(count >= 0);
+        let count = count as usize;
+        let index_size = if type_ == gles11::UNSIGNED_BYTE { 1 } else { 2 };
+        let indices_ptr: *const u8 = if self.element_array_buffer_binding != 0 {
+            let buffer = &self.buffers[&self.element_array_buffer_binding];
+            assert!(indices as usize + count * index_size <= buffer.len());
+            buffer.as_ptr().add(indices as usize)
+        } else {
+            indices.cast()
+        };
+        let indices: Vec<u32> = (0..count)
+            .map(|i| match type_ {
+                gles11::UNSIGNED_BYTE => u32::from(indices_ptr.add(i).read()),
+                _ => u32::from(indices_ptr.cast::<u16>().add(i).read_unaligned()),
+            })
+            .collect();
+        self.draw(mode, &indices);
+    }
+
+    // Clearing
+    unsafe fn Clear(&mut self, mask: GLbitfield) {
+        assert!(
+            mask & !(gles11::COLOR_BUFFER_BIT
+                | gles11::DEPTH_BUFFER_BIT
+                | gles11::STENCIL_BUFFER_BIT)
+                == 0
+        );
+        self.with_target(|this, target| {
+            let (x0, y0, x1, y1) = this.draw_bounds(target);
+            let clear_color = color_to_u8(this.clear_color);
+            let clear_depth = this.clear_depth.clamp(0.0, 1.0);
+            let clear_stencil = this.clear_stencil as u8;
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    let index = (y as u32 * target.width + x as u32) as usize;
+                    if mask & gles11::COLOR_BUFFER_BIT != 0 && !target.color.is_empty() {
+                        let pixel = &mut target.color[index];
+                        for i in 0..4 {
+                            if this.color_mask[i] {
+                                pixel[i] = clear_color[i];
+                            }
+                        }
+                        if !target.color_has_alpha {
+                            pixel[3] = 255;
+                        }
+                    }
+                    if mask & gles11::DEPTH_BUFFER_BIT != 0
+                        && this.depth_mask
+                        && !target.depth.is_empty()
+                    {
+                        target.depth[index] = clear_depth;
+                    }
+                    if mask & gles11::STENCIL_BUFFER_BIT != 0 && !target.stencil.is_empty() {
+                        target.stencil[index] = clear_stencil;
+                    }
+                }
+            }
+        });
+    }
+    unsafe fn ClearColor(
+        &mut self,
+        red: GLclampf,
+        green: GLclampf,
+        blue: GLclampf,
+        alpha: GLclampf,
+    ) {
+        self.clear_color = [red, green, blue, alpha].map(|c| c.clamp(0.0, 1.0));
+    }
+    unsafe fn ClearColorx(
+        &mut self,
+        red: GLclampx,
+        green: GLclampx,
+        blue: GLclampx,
+        alpha: GLclampx,
+    ) {
+        self.ClearColor(
+            fixed_to_float(red),
+            fixed_to_float(green),
+            fixed_to_float(blue),
+            fixed_to_float(alpha),
+        )
+    }
+    unsafe fn ClearDepthf(&mut self, depth: GLclampf) {
+        self.clear_depth = depth.clamp(0.0, 1.0);
+    }
+    unsafe fn ClearDepthx(&mut self, depth: GLclampx) {
+        self.ClearDepthf(fixed_to_float(depth))
+    }
+    unsafe fn ClearStencil(&mut self, s: GLint) {
+        self.clear_stencil = s;
+    }
+
+    // Textures
+    unsafe fn PixelStorei(&mut self, pname: GLenum, param: GLint) {
+        assert!(pname == gles11::PACK_ALIGNMENT || pname == gles11::UNPACK_ALIGNMENT);
+        assert!(param == 1 || param == 2 || param == 4 || param == 8);
+        if pname == gles11::PACK_ALIGNMENT {
+            self.pack_alignment = param;
+        } else {
+            self.unpack_alignment = param;
+        }
+    }
+    unsafe fn ReadPixels(
+        &mut self,
+        x: GLint,
+        y: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: GLenum,
+        type_: GLenum,
+        pixels: *mut GLvoid,
+    ) {
+        assert!(format == gles11::RGBA || format == gles11::BGRA_EXT);
+        assert!(type_ == gles11::UNSIGNED_BYTE);
+        let image = self.read_color_rect(x, y, width, height);
+        let stride = row_stride(width as usize, 4, self.pack_alignment);
+        let pixels = pixels.cast::<u8>();
+        for row in 0..image.height as usize {
+            for col in 0..image.width as usize {
+                let [r, g, b, a] = image.pixels[row * image.width as usize + col];
+                let bytes = if format == gles11::RGBA {
+                    [r, g, b, a]
+                } else {
+                    [b, g, r, a]
+                };
+                let dst = pixels.add(row * stride + col * 4);
+                std::ptr::copy_nonoverlapping(bytes.as_ptr(), dst, 4);
+            }
+        }
+    }
+    unsafe fn GenTextures(&mut self, n: GLsizei, textures: *mut GLuint) {
+        gen_names(&self.textures, &mut self.next_texture, n, textures);
+        for &name in names_slice(n, textures) {
+            self.textures.insert(name, Texture::new());
+        }
+    }
+    unsafe fn DeleteTextures(&mut self, n: GLsizei, textures: *const GLuint) {
+        for &name in names_slice(n, textures) {
+            if name == 0 {
+                continue;
+            }
+            self.textures.remove(&name);
+            for binding in self.texture_bindings.iter_mut() {
+                if *binding == name {
+                    *binding = 0;
+                }
+            }
+            // Deleting a texture attached to the current framebuffer
+            // detaches it.
+            if let Some(framebuffer) = self.framebuffers.get_mut(&self.framebuffer_binding) {
+                if matches!(framebuffer.color, Some(Attachment::Texture(attached, _)) if attached == name)
+                {
+                    framebuffer.color = None;
+                }
+            }
+        }
+    }
+    unsafe fn ActiveTexture(&mut self, texture: GLenum) {
+        self.active_texture = texture_unit_index(texture);
+    }
+    unsafe fn BindTexture(&mut self, target: GLenum, texture: GLuint) {
+        assert!(target == gles11::TEXTURE_2D);
+        self.textures.entry(texture).or_insert_with(Texture::new);
+        self.texture_bindings[self.active_texture] = texture;
+    }
+    unsafe fn TexParameteri(&mut self, target: GLenum, pname: GLenum, param: GLint) {
+        assert!(target == gles11::TEXTURE_2D);
+        TEX_PARAMS.assert_known_param(pname);
+        self.set_tex_param(pname, param as f32);
+    }
+    unsafe fn TexParameterf(&mut self, target: GLenum, pname: GLenum, param: GLfloat) {
+        assert!(target == gles11::TEXTURE_2D);
+        TEX_PARAMS.assert_known_param(pname);
+        self.set_tex_param(pname, param);
+    }
+    unsafe fn TexParameterx(&mut self, target: GLenum, pname: GLenum, param: GLfixed) {
+        assert!(target == gles11::TEXTURE_2D);
+        TEX_PARAMS.assert_component_count(pname, 1);
+        let params = fixed_params_to_float(&TEX_PARAMS, pname, &param);
+        self.set_tex_param(pname, params[0]);
+    }
+    unsafe fn TexParameteriv(&mut self, target: GLenum, pname: GLenum, params: *const GLint) {
+        self.TexParameteri(target, pname, params.read())
+    }
+    unsafe fn TexParameterfv(&mut self, target: GLenum, pname: GLenum, params: *const GLfloat) {
+        self.TexParameterf(target, pname, params.read())
+    }
+    unsafe fn TexParameterxv(&mut self, target: GLenum, pname: GLenum, params: *const GLfixed) {
+        self.TexParameterx(target, pname, params.read())
+    }
+    unsafe fn TexImage2D(
+        &mut self,
+        target: GLenum,
+        level: GLint,
+        internalformat: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        border: GLint,
+        format: GLenum,
+        type_: GLenum,
+        pixels: *const GLvoid,
+    ) {
+        assert!(target == gles11::TEXTURE_2D);
+        assert_internal_format(internalformat as GLenum);
+        assert!(border == 0);
+        assert_format_and_type(format, type_);
+        let mut image = self.unpack_image(width, height, format, type_, pixels);
+        let internalformat = if internalformat as GLenum == gles11::BGRA_EXT {
+            gles11::RGBA
+        } else {
+            internalformat as GLenum
+        };
+        for pixel in image.pixels.iter_mut() {
+            *pixel = convert_to_base_format(internalformat, *pixel);
+        }
+        self.tex_image(level, internalformat, image);
+    }
+    unsafe fn TexSubImage2D(
+        &mut self,
+        target: GLenum,
+        level: GLint,
+        xoffset: GLint,
+        yoffset: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        format: GLenum,
+        type_: GLenum,
+        pixels: *const GLvoid,
+    ) {
+        assert!(target == gles11::TEXTURE_2D);
+        assert_format_and_type(format, type_);
+        let image = self.unpack_image(width, height, format, type_, pixels);
+        self.tex_sub_image(level, xoffset, yoffset, &image);
+    }
+    unsafe fn CompressedTexImage2D(
+        &mut self,
+        target: GLenum,
+        level: GLint,
+        internalformat: GLenum,
+        width: GLsizei,
+        height: GLsizei,
+        border: GLint,
+        image_size: GLsizei,
+        data: *const GLvoid,
+    ) {
+        let data = unsafe { std::slice::from_raw_parts(data.cast::<u8>(), image_size as usize) };
+        if try_decode_pvrtc(
+            self,
+            target,
+            level,
+            internalformat,
+            width,
+            height,
+            border,
+            data,
+        ) {
+            log_dbg!("Decoded PVRTC");
+        } else if let Some(format) = PalettedTextureFormat::get_info(internalformat) {
+            // This should be invalid use? (TODO)
+            assert!(border == 0);
+            // TODO: support multiple miplevels in one image
+            assert!(level == 0);
+            let decoded = format.decode(width, height, data);
+            log_dbg!("Decoded paletted texture");
+            // The decoded rows aren't padded.
+            let unpack_alignment = std::mem::replace(&mut self.unpack_alignment, 1);
+            self.TexImage2D(
+                target,
+                level,
+                format.palette_entry_format as _,
+                width,
+                height,
+                border,
+                format.palette_entry_format,
+                format.palette_entry_type,
+                decoded.as_ptr() as *const _,
+            );
+            self.unpack_alignment = unpack_alignment;
+        } else {
+            unimplemented!("CompressedTexImage2D internalformat: {:#x}", internalformat);
+        }
+    }
+    unsafe fn CopyTexImage2D(
+        &mut self,
+        target: GLenum,
+        level: GLint,
+        internalformat: GLenum,
+        x: GLint,
+        y: GLint,
+        width: GLsizei,
+        height: GLsizei,
+        border: GLint,
+    ) {
+        assert!(target == gles11::TEXTURE_2D);
+        assert!(internalformat != gles11::BGRA_EXT);
+        assert_internal_format(internalformat);
+        assert!(border == 0);
+        let mut image = self.read_color_rect(x, y, width, height);
+        for pixel in image.pixels.iter_mut() {
+            *pixel = convert_to_base_format(internalformat, *pixel);
+        }
+        self.tex_image(level, internalformat, image);
+    }
+    unsafe fn CopyTexSubImage2D(
+        &mut self,
+        target: GLenum,
+        level: GLint,
+        xoffset: GLint,
+        yoffset: GLint,
+        x: GLint,
+        y: GLint,
+        width: GLsizei,
+        height: GLsizei,
+    ) {
+        assert!(target == gles11::TEXTURE_2D);
+        let image = self.read_color_rect(x, y, width, height);
+        self.tex_sub_image(level, xoffset, yoffset, &image);
+    }
+    unsafe fn TexEnvf(&mut self, target: GLenum, pname: GLenum, param: GLfloat) {
+        if target == gles11::TEXTURE_ENV {
+            TEX_ENV_PARAMS.assert_component_count(pname, 1);
+        }
+        self.set_tex_env_param(target, pname, &[param]);
+    }
+    unsafe fn TexEnvx(&mut self, target: GLenum, pname: GLenum, param: GLfixed) {
+        match target {
+            gles11::TEXTURE_ENV => {
+                TEX_ENV_PARAMS.assert_component_count(pname, 1);
+                let params = fixed_params_to_float(&TEX_ENV_PARAMS, pname, &param);
+                self.set_tex_env_param(target, pname, &params);
+            }
+            _ => self.set_tex_env_param(target, pname, &[fixed_to_float(param)]),
+        }
+    }
+    unsafe fn TexEnvi(&mut self, target: GLenum, pname: GLenum, param: GLint) {
+        if target == gles11::TEXTURE_ENV {
+            TEX_ENV_PARAMS.assert_component_count(pname, 1);
+        }
+        self.set_tex_env_param(target, pname, &[param as f32]);
+    }
+    unsafe fn TexEnvfv(&mut self, target: GLenum, pname: GLenum, params: *const GLfloat) {
+        let count = if target == gles11::TEXTURE_ENV {
+            TEX_ENV_PARAMS.get_type_info(pname).1
+        } else {
+            1
+        };
+        let params = std::slice::from_raw_parts(params, count.into());
+        self.set_tex_env_param(target, pname, params);
+    }
+    unsafe fn TexEnvxv(&mut self, target: GLenum, pname: GLenum, params: *const GLfixed) {
+        match target {
+            gles11::TEXTURE_ENV => {
+                let params = fixed_params_to_float(&TEX_ENV_PARAMS, pname, params);
+                self.set_tex_env_param(target, pname, &params);
+            }
+            _ => self.set_tex_env_param(target, pname, &[fixed_to_float(params.read())]),
+        }
+    }
+    unsafe fn TexEnviv(&mut self, target: GLenum, pname: GLenum, params: *const GLint) {
+        let count = if target == gles11::TEXTURE_ENV {
+            TEX_ENV_PARAMS.get_type_info(pname).1
+        } else {
+            1
+        };
+        let params: Vec<f32> = std::slice::from_raw_parts(params, count.into())
+            .iter()
+            .map(|&param| param as f32)
+            .collect();
+        self.set_tex_env_param(target, pname, &params);
+    }
+
+    // Matrix stack operations
+    unsafe fn MatrixMode(&mut self, mode: GLenum) {
+        assert!(mode == gles11::MODELVIEW || mode == gles11::PROJECTION || mode == gles11::TEXTURE);
+        self.matrix_mode = mode;
+    }
+    unsafe fn LoadIdentity(&mut self) {
+        *self.current_matrix_mut() = IDENTITY;
+    }
+    unsafe fn LoadMatrixf(&mut self, m: *const GLfloat) {
+        *self.current_matrix_mut() = m.cast::<Mat4>().read_unaligned();
+    }
+    unsafe fn LoadMatrixx(&mut self, m: *const GLfixed) {
+        *self.current_matrix_mut() = matrix_fixed_to_float(m);
+    }
+    unsafe fn MultMatrixf(&mut self, m: *const GLfloat) {
+        self.mult_matrix(&m.cast::<Mat4>().read_unaligned());
+    }
+    unsafe fn MultMatrixx(&mut self, m: *const GLfixed) {
+        self.mult_matrix(&matrix_fixed_to_float(m));
+    }
+    unsafe fn PushMatrix(&mut self) {
+        let (stack, max_depth) = self.matrix_stack_mut();
+        if stack.len() == max_depth {
+            self.set_error(gles11::STACK_OVERFLOW);
+            return;
+        }
+        stack.push(*stack.last().unwrap());
+    }
+    unsafe fn PopMatrix(&mut self) {
+        let (stack, _) = self.matrix_stack_mut();
+        if stack.len() == 1 {
+            self.set_error(gles11::STACK_UNDERFLOW);
+            return;
+        }
+        stack.pop();
+    }
+    unsafe fn Orthof(
+        &mut self,
+        left: GLfloat,
+        right: GLfloat,
+        bottom: GLfloat,
+        top: GLfloat,
+        near: GLfloat,
+        far: GLfloat,
+    ) {
+        if left == right || bottom == top || near == far {
+            self.set_error(gles11::INVALID_VALUE);
+            return;
+        }
+        let (rl, tb, fn_) = (right - left, top - bottom, far - near);
+        #[rustfmt::skip]
+        let m = [
+            2.0 / rl, 0.0, 0.0, 0.0,
+            0.0, 2.0 / tb, 0.0, 0.0,
+            0.0, 0.0, -2.0 / fn_, 0.0,
+            -(right + left) / rl, -(top + bottom) / tb, -(far + near) / fn_, 1.0,
+        ];
+        self.mult_matrix(&m);
+    }
+    unsafe fn Orthox(
+        &mut self,
+        left: GLfixed,
+        right: GLfixed,
+        bottom: GLfixed,
+        top: GLfixed,
+        near: GLfixed,
+        far: GLfixed,
+    ) {
+        self.Orthof(
+            fixed_to_float(left),
+            fixed_to_float(right),
+            fixed_to_float(bottom),
+            fixed_to_float(top),
+            fixed_to_float(near),
+            fixed_to_float(far),
+        );
+    }
+    unsafe fn Frustumf(
+        &mut self,
+        left: GLfloat,
+        right: GLfloat,
+        bottom: GLfloat,
+        top: GLfloat,
+        near: GLfloat,
+        far: GLfloat,
+    ) {
+        if near <= 0.0 || far <= 0.0 || left == right || bottom == top || near == far {
+            self.set_error(gles11::INVALID_VALUE);
+            return;
+        }
+        let (rl, tb, fn_) = (right - left, top - bottom, far - near);
+        #[rustfmt::skip]
+        let m = [
+            2.0 * near / rl, 0.0, 0.0, 0.0,
+            0.0, 2.0 * near / tb, 0.0, 0.0,
+            (right + left) / rl, (top + bottom) / tb, -(far + near) / fn_, -1.0,
+            0.0, 0.0, -2.0 * far * near / fn_, 0.0,
+        ];
+        self.mult_matrix(&m);
+    }
+    unsafe fn Frustumx(
+        &mut self,
+        left: GLfixed,
+        right: GLfixed,
+        bottom: GLfixed,
+        top: GLfixed,
+        near: GLfixed,
+        far: GLfixed,
+    ) {
+        self.Frustumf(
+            fixed_to_float(left),
+            fixed_to_float(right),
+            fixed_to_float(bottom),
+            fixed_to_float(top),
+            fixed_to_float(near),
+            fixed_to_float(far),
+        );
+    }
+    unsafe fn Rotatef(&mut self, angle: GLfloat, x: GLfloat, y: GLfloat, z: GLfloat) {
+        let [x, y, z] = normalize3([x, y, z]);
+        let (s, c) = angle.to_radians().sin_cos();
+        let ic = 1.0 - c;
+        #[rustfmt::skip]
+        let m = [
+            x * x * ic + c, y * x * ic + z * s, x * z * ic - y * s, 0.0,
+            x * y * ic - z * s, y * y * ic + c, y * z * ic + x * s, 0.0,
+            x * z * ic + y * s, y * z * ic - x * s, z * z * ic + c, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        ];
+        self.mult_matrix(&m);
+    }
+    unsafe fn Rotatex(&mut self, angle: GLfixed, x: GLfixed, y: GLfixed, z: GLfixed) {
+        self.Rotatef(
+            fixed_to_float(angle),
+            fixed_to_float(x),
+            fixed_to_float(y),
+            fixed_to_float(z),
+        );
+    }
+    unsafe fn Scalef(&mut self, x: GLfloat, y: GLfloat, z: GLfloat) {
+        #[rustfmt::skip]
+        let m = [
+            x, 0.0, 0.0, 0.0,
+            0.0, y, 0.0, 0.0,
+            0.0, 0.0, z, 0.0,
+            0.0, 0.0, 0.0, 1.0,
+        ];
+        self.mult_matrix(&m);
+    }
+    unsafe fn Scalex(&mut self, x: GLfixed, y: GLfixed, z: GLfixed) {
+        self.Scalef(fixed_to_float(x), fixed_to_float(y), fixed_to_float(z));
+    }
+    unsafe fn Translatef(&mut self, x: GLfloat, y: GLfloat, z: GLfloat) {
+        #[rustfmt::skip]
+        let m = [
+            1.0, 0.0, 0.0, 0.0,
+            0.0, 1.0, 0.0, 0.0,
+            0.0, 0.0, 1.0, 0.0,
+            x, y, z, 1.0,
+        ];
+        self.mult_matrix(&m);
+    }
+    unsafe fn Translatex(&mut self, x: GLfixed, y: GLfixed, z: GLfixed) {
+        self.Translatef(fixed_to_float(x), fixed_to_float(y), fixed_to_float(z));
+    }
+
+    // OES_framebuffer_object
+    unsafe fn GenFramebuffersOES(&mut self, n: GLsizei, framebuffers: *mut GLuint) {
+        gen_names(
+            &self.framebuffers,
+            &mut self.next_framebuffer,
+            n,
+            framebuffers,
+        );
+        for &name in names_slice(n, framebuffers) {
+            self.framebuffers.insert(name, Framebuffer::default());
+        }
+    }
+    unsafe fn GenRenderbuffersOES(&mut self, n: GLsizei, renderbuffers: *mut GLuint) {
+        gen_names(
+            &self.renderbuffers,
+            &mut self.next_renderbuffer,
+            n,
+            renderbuffers,
+        );
+        for &name in names_slice(n, renderbuffers) {
+            self.renderbuffers.insert(
+                name,
+                Renderbuffer {
+                    internalformat: gles11::RGBA4_OES,
+                    width: 0,
+                    height: 0,
+                    color: Vec::new(),
+                    depth: Vec::new(),
+                    stencil: Vec::new(),
+                },
+            );
+        }
+    }
+    unsafe fn BindFramebufferOES(&mut self, target: GLenum, framebuffer: GLuint) {
+        assert!(target == gles11::FRAMEBUFFER_OES);
+        if framebuffer != 0 {
+            self.framebuffers.entry(framebuffer).or_default();
+        }
+        self.framebuffer_binding = framebuffer;
+    }
+    unsafe fn BindRenderbufferOES(&mut self, target: GLenum, renderbuffer: GLuint) {
+        assert!(target == gles11::RENDERBUFFER_OES);
+        if renderbuffer != 0 && !self.renderbuffers.contains_key(&renderbuffer) {
+            // Reserve the name the same way glGenRenderbuffersOES would.
+            self.next_renderbuffer = renderbuffer;
+            let mut name = 0;
+            self.GenRenderbuffersOES(1, &mut name);
+            assert_eq!(name, renderbuffer);
+        }
+        self.renderbuffer_binding = renderbuffer;
+    }
+    unsafe fn RenderbufferStorageOES(
+        &mut self,
+        target: GLenum,
+        internalformat: GLenum,
+        width: GLsizei,
+        height: GLsizei,
+    ) {
+        assert!(target == gles11::RENDERBUFFER_OES);
+        assert!(width >= 0 && height >= 0);
+        let (width, height) = (width as u32, height as u32);
+        assert!(width <= MAX_TEXTURE_SIZE && height <= MAX_TEXTURE_SIZE);
+        let size = width as usize * height as usize;
+        let rb = self.bound_renderbuffer_mut();
+        rb.internalformat = internalformat;
+        rb.width = width;
+        rb.height = height;
+        rb.color = Vec::new();
+        rb.depth = Vec::new();
+        rb.stencil = Vec::new();
+        match internalformat {
+            gles11::RGBA8_OES
+            | gles11::RGB8_OES
+            | gles11::RGB565_OES
+            | gles11::RGBA4_OES
+            | gles11::RGB5_A1_OES => rb.color = vec![[0, 0, 0, 0]; size],
+            gles11::DEPTH_COMPONENT16_OES | DEPTH_COMPONENT24_OES => rb.depth = vec![0.0; size],
+            STENCIL_INDEX8_OES => rb.stencil = vec![0; size],
+            DEPTH24_STENCIL8_OES => {
+                rb.depth = vec![0.0; size];
+                rb.stencil = vec![0; size];
+            }
+            _ => unimplemented!(
+                "RenderbufferStorageOES internalformat: {:#x}",
+                internalformat
+            ),
+        }
+    }
+    unsafe fn FramebufferRenderbufferOES(
+        &mut self,
+        target: GLenum,
+        attachment: GLenum,
+        renderbuffertarget: GLenum,
+        renderbuffer: GLuint,
+    ) {
+        assert!(target == gles11::FRAMEBUFFER_OES);
+        assert!(renderbuffertarget == gles11::RENDERBUFFER_OES);
+        assert!(self.framebuffer_binding != 0);
+        let new = (renderbuffer != 0).then_some(Attachment::Renderbuffer(renderbuffer));
+        let framebuffer = self
+            .framebuffers
+            .get_mut(&self.framebuffer_binding)
+            .unwrap();
+        match attachment {
+            gles11::COLOR_ATTACHMENT0_OES => framebuffer.color = new,
+            gles11::DEPTH_ATTACHMENT_OES => framebuffer.depth = new,
+            gles11::STENCIL_ATTACHMENT_OES => framebuffer.stencil = new,
+            _ => panic!("Unhandled attachment {:#x}", attachment),
+        }
+    }
+    unsafe fn FramebufferTexture2DOES(
+        &mut self,
+        target: GLenum,
+        attachment: GLenum,
+        textarget: GLenum,
+        texture: GLuint,
+        level: i32,
+    ) {
+        assert!(target == gles11::FRAMEBUFFER_OES);
+        assert!(textarget == gles11::TEXTURE_2D);
+        assert!(attachment == gles11::COLOR_ATTACHMENT0_OES);
+        assert!(level == 0);
+        assert!(self.framebuffer_binding != 0);
+        let new = (texture != 0).then_some(Attachment::Texture(texture, 0));
+        self.framebuffers
+            .get_mut(&self.framebuffer_binding)
+            .unwrap()
+            .color = new;
+    }
+    unsafe fn GetRenderbufferParameterivOES(
+        &mut self,
+        target: GLenum,
+        pname: GLenum,
+        params: *mut GLint,
+    ) {
+        assert!(target == gles11::RENDERBUFFER_OES);
+        let rb = self.bound_renderbuffer_mut();
+        let format = rb.internalformat;
+        let [red, green, blue, alpha] = Self::color_bits(format);
+        let value = match pname {
+            gles11::RENDERBUFFER_WIDTH_OES => rb.width,
+            gles11::RENDERBUFFER_HEIGHT_OES => rb.height,
+            gles11::RENDERBUFFER_INTERNAL_FORMAT_OES => format,
+            gles11::RENDERBUFFER_RED_SIZE_OES => red,
+            gles11::RENDERBUFFER_GREEN_SIZE_OES => green,
+            gles11::RENDERBUFFER_BLUE_SIZE_OES => blue,
+            gles11::RENDERBUFFER_ALPHA_SIZE_OES => alpha,
+            gles11::RENDERBUFFER_DEPTH_SIZE_OES => Self::depth_bits(format),
+            gles11::RENDERBUFFER_STENCIL_SIZE_OES => Self::stencil_bits(format),
+            _ => panic!("Unhandled parameter name: {:#x}", pname),
+        };
+        params.write(value as GLint);
+    }
+    unsafe fn CheckFramebufferStatusOES(&mut self, target: GLenum) -> GLenum {
+        assert!(target == gles11::FRAMEBUFFER_OES);
+        if self.framebuffer_binding == 0 {
+            return gles11::FRAMEBUFFER_COMPLETE_OES;
+        }
+        let framebuffer = &self.framebuffers[&self.framebuffer_binding];
+        let attachments = [framebuffer.color, framebuffer.depth, framebuffer.stencil];
+        if attachments.iter().all(|a| a.is_none()) {
+            return gles11::FRAMEBUFFER_INCOMPLETE_MISSING_ATTACHMENT_OES;
+        }
+        let mut size = None;
+        for attachment in attachments.into_iter().flatten() {
+            let attachment_size = self.attachment_size(attachment);
+            if attachment_size.0 == 0 || attachment_size.1 == 0 {
+                return gles11::FRAMEBUFFER_INCOMPLETE_ATTACHMENT_OES;
+            }
+            if *size.get_or_insert(attachment_size) != attachment_size {
+                return gles11::FRAMEBUFFER_INCOMPLETE_DIMENSIONS_OES;
+            }
+        }
+        gles11::FRAMEBUFFER_COMPLETE_OES
+    }
+    unsafe fn DeleteFramebuffersOES(&mut self, n: GLsizei, framebuffers: *const GLuint) {
+        for &name in names_slice(n, framebuffers) {
+            if name == 0 {
+                continue;
+            }
+            self.framebuffers.remove(&name);
+            if self.framebuffer_binding == name {
+                self.framebuffer_binding = 0;
+            }
+        }
+    }
+    unsafe fn DeleteRenderbuffersOES(&mut self, n: GLsizei, renderbuffers: *const GLuint) {
+        for &name in names_slice(n, renderbuffers) {
+            if name == 0 {
+                continue;
+            }
+            self.renderbuffers.remove(&name);
+            if self.renderbuffer_binding == name {
+                self.renderbuffer_binding = 0;
+            }
+            // Deleting a renderbuffer attached to the current framebuffer
+            // detaches it.
+            if let Some(framebuffer) = self.framebuffers.get_mut(&self.framebuffer_binding) {
+                let attached = Some(Attachment::Renderbuffer(name));
+                for attachment in [
+                    &mut framebuffer.color,
+                    &mut framebuffer.depth,
+                    &mut framebuffer.stencil,
+                ] {
+                    if *attachment == attached {
+                        *attachment = None;
+                    }
+                }
+            }
+        }
+    }
+    unsafe fn GenerateMipmapOES(&mut self, target: GLenum) {
+        assert!(target == gles11::TEXTURE_2D);
+        self.bound_texture_mut().generate_mipmaps();
+    }
+}
@@ -249,4 +249,49 @@ impl PalettedTextureFormat {
             _ => None,
         }
     }
+
+    /// Decode paletted texture data into a tightly-packed array of palette
+    /// entries, suitable for passing to `glTexImage2D` with
+    /// [Self::palette_entry_format] and [Self::palette_entry_type].
+    pub fn decode(&self, width: GLsizei, height: GLsizei, data: &[u8]) -> Vec<u8> {
+        let palette_entry_size = match self.palette_entry_type {
+            gles11::UNSIGNED_BYTE => match self.palette_entry_format {
+                gles11::RGB => 3,
+                gles11::RGBA => 4,
+                _ => unreachable!(),
+            },
+            gles11::UNSIGNED_SHORT_5_6_5
+            | gles11::UNSIGNED_SHORT_4_4_4_4
+            | gles11::UNSIGNED_SHORT_5_5_5_1 => 2,
+            _ => unreachable!(),
+        };
+        let palette_entry_count = match self.index_is_nibble {
+            true => 16,
+            false => 256,
+        };
+        let palette_size = palette_entry_size * palette_entry_count;
+
+        let index_count = width as usize * height as usize;
+        let (index_word_size, index_word_count) = match self.index_is_nibble {
+            true => (1, index_count.div_ceil(2)),
+            false => (4, index_count.div_ceil(4)),
+        };
+        let indices_size = index_word_size * index_word_count;
+
+        assert_eq!(data.len(), palette_size + indices_size);
+        let (palette, indices) = data.split_at(palette_size);
+
+        let mut decoded = Vec::<u8>::with_capacity(palette_entry_size * index_count);
+        for i in 0..index_count {
+            let index = if self.index_is_nibble {
+                (indices[i / 2] >> ((1 - (i % 2)) * 4)) & 0xf
+            } else {
+                indices[i]
+            } as usize;
+            let palette_entry = &palette[index * palette_entry_size..][..palette_entry_size];
+            decoded.extend_from_slice(palette_entry);
+        }
+        assert!(decoded.len() == palette_entry_size * index_count);
+        decoded
+    }
 }
@@ -0,0 +1,100 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Heap and Objective-C object statistics, for finding out why an app is
+//! running out of memory. These can be printed with a hotkey (F10) or when the
+//! app exits (`--dump-heap-stats-on-exit`).
+
+use crate::mem::AllocatorStats;
+use crate::objc::ClassObjectStats;
+use crate::Environment;
+
+/// Stop listing classes after this many, since apps can have thousands.
+const MAX_CLASSES: usize = 50;
+
+fn format_size(bytes: u64) -> String {
+    if bytes >= 1024 * 1024 {
+        format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+    } else if bytes >= 1024 {
+        format!("{:.1} KiB", bytes as f64 / 1024.0)
+    } else {
+        format!("{} B", bytes)
+    }
+}
+
+fn print_allocator_stats(stats: &AllocatorStats) {
+    echo!("Heap:");
+    echo!(
+        "  Allocated: {} in {} chunks",
+        format_size(stats.allocated_bytes),
+        stats.allocated_chunks
+    );
+    echo!(
+        "  Reserved (binaries, main thread stack, etc): {} in {} chunks",
+        format_size(stats.reserved_bytes),
+        stats.reserved_chunks
+    );
+    if stats.quarantined_chunks != 0 {
+        echo!(
+            "  Freed but quarantined by --debug-heap: {} in {} chunks",
+            format_size(stats.quarantined_bytes),
+            stats.quarantined_chunks
+        );
+    }
+    // The free chunk at the top of the address space is huge, so the bytes
+    // outside the largest free chunk are a better measure of fragmentation
+    // than a percentage would be.
+    echo!(
+        "  Free: {} in {} chunks, of which {} is outside the largest chunk",
+        format_size(stats.free_bytes),
+        stats.free_chunks,
+        format_size(stats.free_bytes - u64::from(stats.largest_free_chunk))
+    );
+    echo!("  Chunk sizes:         allocated       free");
+    for &(min_size, allocated, free) in &stats.histogram {
+        if allocated == 0 && free == 0 {
+            continue;
+        }
+        echo!(
+            "    {:>9} and up {:>10} {:>10}",
+            format_size(min_size.into()),
+            allocated,
+            free
+        );
+    }
+}
+
+fn print_object_stats(stats: &[ClassObjectStats], static_count: usize) {
+    let count: usize = stats.iter().map(|class_stats| class_stats.count).sum();
+    echo!(
+        "Objective-C objects: {} reference-counted, {} static (classes etc)",
+        count,
+        static_count
+    );
+    if stats.is_empty() {
+        return;
+    }
+    echo!("      count  total refs  max refs  class");
+    for class_stats in stats.iter().take(MAX_CLASSES) {
+        echo!(
+            "  {:>9} {:>11} {:>9}  {}",
+            class_stats.count,
+            class_stats.total_refcount,
+            class_stats.max_refcount,
+            class_stats.class_name
+        );
+    }
+    if stats.len() > MAX_CLASSES {
+        echo!("  ...and {} more classes.", stats.len() - MAX_CLASSES);
+    }
+}
+
+/// Print statistics about guest heap usage and the Objective-C objects that
+/// currently exist.
+pub fn print_heap_stats(env: &Environment) {
+    print_allocator_stats(&env.mem.allocator_stats());
+    let (object_stats, static_count) = env.objc.object_stats(&env.mem);
+    print_object_stats(&object_stats, static_count);
+}
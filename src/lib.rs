@@ -30,7 +30,9 @@ mod log;
 mod abi;
 mod app_picker;
 mod audio;
+mod backtrace;
 mod bundle;
+mod clock;
 mod cpu;
 mod debug;
 mod dyld;
@@ -40,6 +42,7 @@ mod frameworks;
 mod fs;
 mod gdb;
 mod gles;
+mod heap_stats;
 mod image;
 mod libc;
 mod licenses;
@@ -49,6 +52,8 @@ mod mem;
 mod objc;
 mod options;
 mod paths;
+mod profiler;
+mod save_state;
 mod stack;
 mod window;
 
@@ -57,7 +62,10 @@ mod window;
 // probably shouldn't be, but they need a new home (TODO).
 // Unlike its siblings, this module should be considered private and only used
 // via re-exports.
-use environment::{Environment, MutexId, MutexType, ThreadId, PTHREAD_MUTEX_DEFAULT};
+use environment::{
+    parse_time_slice, CondId, Environment, MutexId, MutexType, RwLockId, ThreadId,
+    DEFAULT_THREAD_PRIORITY, DEFAULT_TIME_SLICE, PTHREAD_MUTEX_DEFAULT,
+};
 
 use std::path::PathBuf;
 
@@ -72,7 +80,7 @@ pub extern "C" fn SDL_main(
     _argc: std::ffi::c_int,
     _argv: *const *const std::ffi::c_char,
 ) -> std::ffi::c_int {
-    unsafe { log::setup_log_file() };
+    log::setup_log_file();
 
     // Rust's default panic handler prints to stderr, but on Android that just
     // gets discarded, so we set a custom hook to make debugging easier.
@@ -114,6 +122,11 @@ Special options:
 
     --info
         Print basic information about the app bundle without running the app.
+
+    --compat-report
+        Like --info, but also list the C functions, Objective-C classes and
+        Objective-C selectors used by the app that touchHLE doesn't implement.
+        The app is not run, so some of these might not actually be needed.
 ";
 
 pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
@@ -132,6 +145,7 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
 
     let mut bundle_path: Option<PathBuf> = None;
     let mut just_info = false;
+    let mut compat_report = false;
     let mut option_args = Vec::new();
 
     for arg in args {
@@ -144,6 +158,8 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
             return Ok(());
         } else if arg == "--info" {
             just_info = true;
+        } else if arg == "--compat-report" {
+            compat_report = true;
         // Parse an option but discard the value, to test whether it's valid.
         // We don't want to apply it immediately, because then options loaded
         // from a file would take precedence over options from the command line.
@@ -158,15 +174,26 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
         }
     }
 
+    // Apply the logging options from the command line straight away, so they
+    // affect everything from here on. They're applied again once the options
+    // from files are known.
+    {
+        let mut options = options::Options::default();
+        for option_arg in &option_args {
+            options.parse_argument(option_arg)?;
+        }
+        log::configure(&options)?;
+    }
+
     let (bundle_path, env_for_salvage) = if let Some(bundle_path) = bundle_path {
         (bundle_path, None)
     } else {
         let mut options = options::Options::default();
         // Apply command-line options only (no app-specific options apply)
         for option_arg in &option_args {
-            let parse_result = options.parse_argument(option_arg);
-            assert!(parse_result == Ok(true));
+            options.parse_argument(option_arg)?;
         }
+        options.finish()?;
         if options.headless {
             return Err(
                 "No app specified. Use the --help flag to see command-line usage.".to_string(),
@@ -229,6 +256,9 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
         }
     }
 
+    if compat_report {
+        return dyld::compat_report::print_compat_report(&bundle, &fs);
+    }
     if just_info {
         return Ok(());
     }
@@ -286,11 +316,23 @@ pub fn main<T: Iterator<Item = String>>(mut args: T) -> Result<(), String> {
 
     // Apply command-line options
     for option_arg in option_args {
-        let parse_result = options.parse_argument(&option_arg);
-        assert!(parse_result == Ok(true));
+        options.parse_argument(&option_arg)?;
     }
+    options.finish()?;
+
+    log::configure(&options)?;
+    profiler::configure(&options);
 
     let mut env = Environment::new(bundle, fs, options, env_for_salvage)?;
-    env.run();
+    // A panic in a host function (including one for a CPU error) unwinds back
+    // to here, but the guest registers are left as they were, so the guest's
+    // state at the time can still be printed.
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| env.run()));
+    if let Err(payload) = result {
+        echo!("Register state immediately after panic:");
+        env.cpu.dump_regs();
+        backtrace::print_backtrace(&env);
+        std::panic::resume_unwind(payload);
+    }
     Ok(())
 }
@@ -37,6 +37,7 @@ pub mod sys;
 pub mod sysctl;
 pub mod time;
 pub mod unistd;
+pub mod unwind;
 pub mod wchar;
 
 /// Container for state of various child modules
@@ -47,9 +48,11 @@ pub struct State {
     posix_io: posix_io::State,
     pthread: pthread::State,
     pub semaphore: semaphore::State,
+    setjmp: setjmp::State,
     stdlib: stdlib::State,
     string: string::State,
     time: time::State,
+    unwind: unwind::State,
     errno: errno::State,
     clocale: clocale::State,
 }
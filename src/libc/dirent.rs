@@ -7,6 +7,8 @@
 
 use crate::dyld::FunctionExports;
 use crate::fs::GuestPath;
+use crate::libc::errno::{set_errno, ENOTDIR};
+use crate::libc::posix_io::errno_for_path;
 use crate::mem::{ConstPtr, MutPtr, Ptr, SafeRead};
 use crate::{export_c_func, impl_GuestRet_for_large_struct, Environment};
 use std::collections::HashMap;
@@ -64,6 +66,12 @@ fn opendir(env: &mut Environment, filename: ConstPtr<u8>) -> MutPtr<DIR> {
         State::get_mut(env).read_dirs.insert(dir, Vec::new());
         dir
     } else {
+        let errno = if env.fs.exists(guest_path) {
+            ENOTDIR
+        } else {
+            errno_for_path(env, guest_path, false, false)
+        };
+        set_errno(env, errno);
         Ptr::null()
     }
 }
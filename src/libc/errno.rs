@@ -3,22 +3,161 @@
  * License, v. 2.0. If a copy of the MPL was not distributed with this
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
-//! `errno.h`
+//! `errno.h`, and the functions from `string.h` and `stdio.h` for describing
+//! errors.
 
 use crate::dyld::FunctionExports;
 use crate::export_c_func;
-use crate::mem::{ConstPtr, MutPtr};
+use crate::mem::{ConstPtr, GuestUSize, MutPtr};
 use crate::Environment;
+use std::collections::HashMap;
 use std::io::Write;
 
 pub const EPERM: i32 = 1;
+pub const ENOENT: i32 = 2;
+pub const ESRCH: i32 = 3;
+pub const EIO: i32 = 5;
+pub const EBADF: i32 = 9;
 pub const EDEADLK: i32 = 11;
+pub const ENOMEM: i32 = 12;
+pub const EACCES: i32 = 13;
+pub const EFAULT: i32 = 14;
 pub const EBUSY: i32 = 16;
+pub const EEXIST: i32 = 17;
+pub const ENOTDIR: i32 = 20;
+pub const EISDIR: i32 = 21;
 pub const EINVAL: i32 = 22;
+pub const ERANGE: i32 = 34;
+pub const EAGAIN: i32 = 35;
+pub const ETIMEDOUT: i32 = 60;
+pub const ENOTEMPTY: i32 = 66;
+pub const ENOSYS: i32 = 78;
+pub const EOVERFLOW: i32 = 84;
+
+/// The messages returned by `strerror`, indexed by errno. These are the same
+/// as iPhone OS's `sys_errlist`.
+const ERROR_MESSAGES: &[&str] = &[
+    "Undefined error: 0",
+    "Operation not permitted",
+    "No such file or directory",
+    "No such process",
+    "Interrupted system call",
+    "Input/output error",
+    "Device not configured",
+    "Argument list too long",
+    "Exec format error",
+    "Bad file descriptor",
+    "No child processes",
+    "Resource deadlock avoided",
+    "Cannot allocate memory",
+    "Permission denied",
+    "Bad address",
+    "Block device required",
+    "Resource busy",
+    "File exists",
+    "Cross-device link",
+    "Operation not supported by device",
+    "Not a directory",
+    "Is a directory",
+    "Invalid argument",
+    "Too many open files in system",
+    "Too many open files",
+    "Inappropriate ioctl for device",
+    "Text file busy",
+    "File too large",
+    "No space left on device",
+    "Illegal seek",
+    "Read-only file system",
+    "Too many links",
+    "Broken pipe",
+    "Numerical argument out of domain",
+    "Result too large",
+    "Resource temporarily unavailable",
+    "Operation now in progress",
+    "Operation already in progress",
+    "Socket operation on non-socket",
+    "Destination address required",
+    "Message too long",
+    "Protocol wrong type for socket",
+    "Protocol not available",
+    "Protocol not supported",
+    "Socket type not supported",
+    "Operation not supported",
+    "Protocol family not supported",
+    "Address family not supported by protocol family",
+    "Address already in use",
+    "Can't assign requested address",
+    "Network is down",
+    "Network is unreachable",
+    "Network dropped connection on reset",
+    "Software caused connection abort",
+    "Connection reset by peer",
+    "No buffer space available",
+    "Socket is already connected",
+    "Socket is not connected",
+    "Can't send after socket shutdown",
+    "Too many references: can't splice",
+    "Operation timed out",
+    "Connection refused",
+    "Too many levels of symbolic links",
+    "File name too long",
+    "Host is down",
+    "No route to host",
+    "Directory not empty",
+    "Too many processes",
+    "Too many users",
+    "Disc quota exceeded",
+    "Stale NFS file handle",
+    "Too many levels of remote in path",
+    "RPC struct is bad",
+    "RPC version wrong",
+    "RPC prog. not avail",
+    "Program version wrong",
+    "Bad procedure for program",
+    "No locks available",
+    "Function not implemented",
+    "Inappropriate file type or format",
+    "Authentication error",
+    "Need authenticator",
+    "Device power is off",
+    "Device error",
+    "Value too large to be stored in data type",
+    "Bad executable (or shared library)",
+    "Bad CPU type in executable",
+    "Shared library version mismatch",
+    "Malformed Mach-o file",
+    "Operation canceled",
+    "Identifier removed",
+    "No message of desired type",
+    "Illegal byte sequence",
+    "Attribute not found",
+    "Bad message",
+    "EMULTIHOP (Reserved)",
+    "No message available on STREAM",
+    "ENOLINK (Reserved)",
+    "No STREAM resources",
+    "Not a STREAM",
+    "Protocol error",
+    "STREAM ioctl timeout",
+    "Operation not supported on socket",
+];
+
+fn known_error_message(errnum: i32) -> Option<&'static str> {
+    ERROR_MESSAGES.get(usize::try_from(errnum).ok()?).copied()
+}
+
+fn error_message(errnum: i32) -> String {
+    match known_error_message(errnum) {
+        Some(message) => message.to_string(),
+        None => format!("Unknown error: {}", errnum),
+    }
+}
 
 #[derive(Default)]
 pub struct State {
-    errnos: std::collections::HashMap<crate::ThreadId, MutPtr<i32>>,
+    errnos: HashMap<crate::ThreadId, MutPtr<i32>>,
+    /// Strings returned by `strerror`, allocated on first use.
+    messages: HashMap<i32, ConstPtr<u8>>,
 }
 impl State {
     fn errno_for_thread(
@@ -26,13 +165,47 @@ impl State {
         mem: &mut crate::mem::Mem,
         thread: crate::ThreadId,
     ) -> MutPtr<i32> {
-        *self.errnos.entry(thread).or_insert_with(|| {
-            log!(
-                "TODO: errno accessed on thread {} (will always be 0)",
-                thread
-            );
-            mem.alloc_and_write(0i32)
-        })
+        *self
+            .errnos
+            .entry(thread)
+            .or_insert_with(|| mem.alloc_and_write(0i32))
+    }
+}
+
+/// Get the value of `errno` for the current thread.
+pub fn get_errno(env: &mut Environment) -> i32 {
+    let ptr = __error(env);
+    env.mem.read(ptr)
+}
+
+/// Set the value of `errno` for the current thread. Host functions should call
+/// this when they fail, like their counterparts in Apple's libc do.
+pub fn set_errno(env: &mut Environment, errnum: i32) {
+    log_dbg!("Setting errno to {}", errnum);
+    let ptr = __error(env);
+    env.mem.write(ptr, errnum);
+}
+
+/// Frees a thread's `errno`, if it has been used. This should be called when
+/// the thread exits.
+pub fn thread_exited(env: &mut Environment, thread: crate::ThreadId) {
+    if let Some(ptr) = env.libc_state.errno.errnos.remove(&thread) {
+        env.mem.free(ptr.cast());
+    }
+}
+
+/// Get the errno that best matches a host I/O error. The raw OS error code
+/// can't be used directly, because it is different on each host OS.
+pub fn io_error_to_errno(err: &std::io::Error) -> i32 {
+    use std::io::ErrorKind;
+    match err.kind() {
+        ErrorKind::NotFound => ENOENT,
+        ErrorKind::PermissionDenied => EACCES,
+        ErrorKind::AlreadyExists => EEXIST,
+        ErrorKind::WouldBlock => EAGAIN,
+        ErrorKind::InvalidInput => EINVAL,
+        ErrorKind::TimedOut => ETIMEDOUT,
+        _ => EIO,
     }
 }
 
@@ -42,19 +215,78 @@ fn __error(env: &mut Environment) -> MutPtr<i32> {
         .errno_for_thread(&mut env.mem, env.current_thread)
 }
 
+fn strerror(env: &mut Environment, errnum: i32) -> ConstPtr<u8> {
+    if known_error_message(errnum).is_none() {
+        set_errno(env, EINVAL);
+    }
+    if let Some(&message) = env.libc_state.errno.messages.get(&errnum) {
+        return message;
+    }
+    let message = env
+        .mem
+        .alloc_and_write_cstr(error_message(errnum).as_bytes())
+        .cast_const();
+    env.libc_state.errno.messages.insert(errnum, message);
+    message
+}
+
+fn strerror_r(env: &mut Environment, errnum: i32, buf: MutPtr<u8>, buflen: GuestUSize) -> i32 {
+    let message = error_message(errnum);
+    if buflen == 0 {
+        return ERANGE;
+    }
+    // As on iPhone OS, the message is truncated if it doesn't fit.
+    let len: GuestUSize = message.len().try_into().unwrap();
+    let to_copy = len.min(buflen - 1);
+    env.mem
+        .bytes_at_mut(buf, to_copy)
+        .copy_from_slice(&message.as_bytes()[..to_copy as usize]);
+    env.mem.write(buf + to_copy, b'\0');
+    if to_copy < len {
+        ERANGE
+    } else if known_error_message(errnum).is_none() {
+        EINVAL
+    } else {
+        0 // success
+    }
+}
+
 fn perror(env: &mut Environment, s: ConstPtr<u8>) {
-    // TODO: errno mapping
-    let errno_msg = "<TODO: errno>\n";
-    let msg = if !s.is_null() {
-        if let Ok(str) = env.mem.cstr_at_utf8(s) {
-            format!("{}: {}", str, errno_msg)
-        } else {
-            errno_msg.to_string()
-        }
+    let errno_msg = error_message(get_errno(env));
+    let prefix: &[u8] = if !s.is_null() {
+        env.mem.cstr_at(s)
     } else {
-        errno_msg.to_string()
+        &[]
     };
-    let _ = std::io::stderr().write_all(msg.as_bytes());
+    let mut msg = Vec::new();
+    if !prefix.is_empty() {
+        msg.extend_from_slice(prefix);
+        msg.extend_from_slice(b": ");
+    }
+    msg.extend_from_slice(errno_msg.as_bytes());
+    msg.push(b'\n');
+    let _ = std::io::stderr().write_all(&msg);
 }
 
-pub const FUNCTIONS: FunctionExports = &[export_c_func!(__error()), export_c_func!(perror(_))];
+pub const FUNCTIONS: FunctionExports = &[
+    export_c_func!(__error()),
+    export_c_func!(strerror(_)),
+    export_c_func!(strerror_r(_, _, _)),
+    export_c_func!(perror(_)),
+];
+
+#[cfg(test)]
+#[test]
+fn test_error_messages() {
+    assert_eq!(error_message(0), "Undefined error: 0");
+    assert_eq!(error_message(ENOENT), "No such file or directory");
+    assert_eq!(error_message(EAGAIN), "Resource temporarily unavailable");
+    assert_eq!(error_message(ETIMEDOUT), "Operation timed out");
+    assert_eq!(
+        error_message(EOVERFLOW),
+        "Value too large to be stored in data type"
+    );
+    assert_eq!(error_message(102), "Operation not supported on socket");
+    assert_eq!(error_message(103), "Unknown error: 103");
+    assert_eq!(error_message(-1), "Unknown error: -1");
+}
@@ -7,6 +7,7 @@
 
 use crate::dyld::FunctionExports;
 use crate::export_c_func;
+use crate::libc::errno::{set_errno, ENOSYS};
 use crate::mem::MutPtr;
 use crate::Environment;
 
@@ -14,8 +15,9 @@ use crate::Environment;
 #[allow(non_camel_case_types)]
 struct ifaddrs {}
 
-fn getifaddrs(_env: &mut Environment, _ifap: MutPtr<MutPtr<ifaddrs>>) -> i32 {
+fn getifaddrs(env: &mut Environment, _ifap: MutPtr<MutPtr<ifaddrs>>) -> i32 {
     // TODO: implement
+    set_errno(env, ENOSYS);
     -1
 }
 
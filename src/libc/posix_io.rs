@@ -10,6 +10,10 @@ pub mod stat;
 use crate::abi::DotDotDot;
 use crate::dyld::{export_c_func, FunctionExports};
 use crate::fs::{GuestFile, GuestOpenOptions, GuestPath};
+use crate::libc::errno::{
+    io_error_to_errno, set_errno, EACCES, EBADF, EEXIST, EFAULT, EINVAL, EIO, EISDIR, ENOENT,
+    ENOTDIR, ERANGE,
+};
 use crate::mem::{ConstPtr, ConstVoidPtr, GuestISize, GuestUSize, MutPtr, MutVoidPtr, Ptr};
 use crate::Environment;
 use std::io::{Read, Seek, SeekFrom, Write};
@@ -75,6 +79,40 @@ pub const LOCK_NB: FLockFlag = 4;
 #[allow(dead_code)]
 pub const LOCK_UN: FLockFlag = 8;
 
+/// Work out the errno for a failed filesystem operation on `path`, since
+/// [crate::fs] only reports that an operation failed, not why. `write` is
+/// whether the operation modifies the file or directory at `path`, and
+/// `create` is whether it would create it if it didn't exist.
+pub(super) fn errno_for_path(
+    env: &Environment,
+    path: &GuestPath,
+    write: bool,
+    create: bool,
+) -> i32 {
+    let parent = path.join("..");
+    let (exists, _, writeable, is_dir) = env.fs.access(path);
+    let (parent_exists, _, parent_writeable, parent_is_dir) = env.fs.access(&parent);
+    if !parent_exists {
+        ENOENT
+    } else if !parent_is_dir {
+        ENOTDIR
+    } else if !exists {
+        if !create {
+            ENOENT
+        } else if !parent_writeable {
+            EACCES
+        } else {
+            EIO
+        }
+    } else if write && is_dir {
+        EISDIR
+    } else if write && !writeable {
+        EACCES
+    } else {
+        EIO
+    }
+}
+
 fn open(env: &mut Environment, path: ConstPtr<u8>, flags: i32, _args: DotDotDot) -> FileDescriptor {
     // TODO: parse variadic arguments and pass them on (file creation mode)
     self::open_direct(env, path, flags)
@@ -95,12 +133,11 @@ pub fn open_direct(env: &mut Environment, path: ConstPtr<u8>, flags: i32) -> Fil
                 | O_EXCL)
             == 0
     );
-    // TODO: exclusive mode not implemented yet
-    assert!(flags & O_EXCL == 0);
 
     if path.is_null() {
         log_dbg!("open({:?}, {:#x}) => -1", path, flags);
-        return -1; // TODO: set errno to EFAULT
+        set_errno(env, EFAULT);
+        return -1;
     }
 
     // TODO: respect the mode (in the variadic arguments) when creating a file
@@ -130,10 +167,24 @@ pub fn open_direct(env: &mut Environment, path: ConstPtr<u8>, flags: i32) -> Fil
                 path,
                 err
             );
-            // TODO: set errno
+            // Every path in the guest filesystem is valid UTF-8, so this one
+            // can't exist.
+            set_errno(env, ENOENT);
             return -1;
         }
     };
+    if (flags & (O_CREAT | O_EXCL)) == (O_CREAT | O_EXCL)
+        && env.fs.exists(GuestPath::new(&path_string))
+    {
+        log_dbg!(
+            "open({:?} {:?}, {:#x}) => -1, file already exists",
+            path,
+            path_string,
+            flags
+        );
+        set_errno(env, EEXIST);
+        return -1;
+    }
     // TODO: symlinks don't exist in the FS yet, so we can't "not follow" them.
     if flags & O_NOFOLLOW != 0 {
         log!("Ignoring O_NOFOLLOW when opening {:?}", path_string);
@@ -165,7 +216,13 @@ pub fn open_direct(env: &mut Environment, path: ConstPtr<u8>, flags: i32) -> Fil
             file_idx_to_fd(idx)
         }
         Err(()) => {
-            // TODO: set errno
+            let errno = errno_for_path(
+                env,
+                GuestPath::new(&path_string),
+                (flags & O_ACCMODE) != O_RDONLY,
+                (flags & O_CREAT) != 0,
+            );
+            set_errno(env, errno);
             -1
         }
     };
@@ -189,8 +246,16 @@ pub fn read(
     buffer: MutVoidPtr,
     size: GuestUSize,
 ) -> GuestISize {
-    // TODO: error handling for unknown fd?
-    let file = env.libc_state.posix_io.file_for_fd(fd).unwrap();
+    let Some(file) = env.libc_state.posix_io.file_for_fd(fd) else {
+        log!(
+            "Warning: read({:?}, {:?}, {:#x}) on unknown file descriptor, returning -1",
+            fd,
+            buffer,
+            size,
+        );
+        set_errno(env, EBADF);
+        return -1;
+    };
 
     let buffer_slice = env.mem.bytes_at_mut(buffer.cast(), size);
     match file.file.read(buffer_slice) {
@@ -219,7 +284,6 @@ pub fn read(
             bytes_read.try_into().unwrap()
         }
         Err(e) => {
-            // TODO: set errno
             log!(
                 "Warning: read({:?}, {:?}, {:#x}) encountered error {:?}, returning -1",
                 fd,
@@ -227,6 +291,7 @@ pub fn read(
                 size,
                 e,
             );
+            set_errno(env, io_error_to_errno(&e));
             -1
         }
     }
@@ -248,8 +313,16 @@ pub fn write(
     buffer: ConstVoidPtr,
     size: GuestUSize,
 ) -> GuestISize {
-    // TODO: error handling for unknown fd?
-    let file = env.libc_state.posix_io.file_for_fd(fd).unwrap();
+    let Some(file) = env.libc_state.posix_io.file_for_fd(fd) else {
+        log!(
+            "Warning: write({:?}, {:?}, {:#x}) on unknown file descriptor, returning -1",
+            fd,
+            buffer,
+            size,
+        );
+        set_errno(env, EBADF);
+        return -1;
+    };
 
     let buffer_slice = env.mem.bytes_at(buffer.cast(), size);
     match file.file.write(buffer_slice) {
@@ -274,7 +347,6 @@ pub fn write(
             bytes_written.try_into().unwrap()
         }
         Err(e) => {
-            // TODO: set errno
             log!(
                 "Warning: write({:?}, {:?}, {:#x}) encountered error {:?}, returning -1",
                 fd,
@@ -282,6 +354,7 @@ pub fn write(
                 size,
                 e,
             );
+            set_errno(env, io_error_to_errno(&e));
             -1
         }
     }
@@ -293,13 +366,26 @@ pub const SEEK_SET: i32 = 0;
 pub const SEEK_CUR: i32 = 1;
 pub const SEEK_END: i32 = 2;
 pub fn lseek(env: &mut Environment, fd: FileDescriptor, offset: off_t, whence: i32) -> off_t {
-    // TODO: error handling for unknown fd?
-    let file = env.libc_state.posix_io.file_for_fd(fd).unwrap();
+    let Some(file) = env.libc_state.posix_io.file_for_fd(fd) else {
+        log!(
+            "Warning: lseek({:?}, {:#x}, {}) on unknown file descriptor, returning -1",
+            fd,
+            offset,
+            whence
+        );
+        set_errno(env, EBADF);
+        return -1;
+    };
 
     let from = match whence {
-        // not sure whether offset is treated as signed or unsigned when using
-        // SEEK_SET, so `.try_into()` seems safer.
-        SEEK_SET => SeekFrom::Start(offset.try_into().unwrap()),
+        SEEK_SET => {
+            // A negative offset would be before the start of the file.
+            let Ok(offset) = u64::try_from(offset) else {
+                set_errno(env, EINVAL);
+                return -1;
+            };
+            SeekFrom::Start(offset)
+        }
         SEEK_CUR => SeekFrom::Current(offset),
         SEEK_END => SeekFrom::End(offset),
         _ => panic!("Unsupported \"whence\" parameter to seek(): {}", whence),
@@ -313,20 +399,30 @@ pub fn lseek(env: &mut Environment, fd: FileDescriptor, offset: off_t, whence: i
 
             new_offset.try_into().unwrap()
         }
-        // TODO: set errno
-        Err(_) => -1,
+        Err(e) => {
+            set_errno(env, io_error_to_errno(&e));
+            -1
+        }
     };
     log_dbg!("lseek({:?}, {:#x}, {}) => {}", fd, offset, whence, res);
     res
 }
 
 pub fn close(env: &mut Environment, fd: FileDescriptor) -> i32 {
-    // TODO: error handling for unknown fd?
-    if fd < 0 || matches!(fd, STDOUT_FILENO | STDERR_FILENO) {
+    if matches!(fd, STDOUT_FILENO | STDERR_FILENO) {
         return 0;
     }
 
-    match env.libc_state.posix_io.files[fd_to_file_idx(fd)].take() {
+    let file = if fd < NORMAL_FILENO_BASE {
+        None
+    } else {
+        env.libc_state
+            .posix_io
+            .files
+            .get_mut(fd_to_file_idx(fd))
+            .and_then(|file| file.take())
+    };
+    match file {
         Some(file) => {
             // The actual closing of the file happens implicitly when `file`
             // falls out of scope. The return value is about whether flushing
@@ -336,16 +432,16 @@ pub fn close(env: &mut Environment, fd: FileDescriptor) -> i32 {
                     log_dbg!("close({:?}) => 0", fd);
                     0
                 }
-                Err(_) => {
-                    // TODO: set errno
+                Err(e) => {
                     log!("Warning: close({:?}) failed, returning -1", fd);
+                    set_errno(env, io_error_to_errno(&e));
                     -1
                 }
             }
         }
         None => {
-            // TODO: set errno
             log!("Warning: close({:?}) failed, returning -1", fd);
+            set_errno(env, EBADF);
             -1
         }
     }
@@ -354,12 +450,12 @@ pub fn close(env: &mut Environment, fd: FileDescriptor) -> i32 {
 pub fn getcwd(env: &mut Environment, buf_ptr: MutPtr<u8>, buf_size: GuestUSize) -> MutPtr<u8> {
     let working_directory = env.fs.working_directory();
     if !env.fs.is_dir(working_directory) {
-        // TODO: set errno to ENOENT
         log!(
             "Warning: getcwd({:?}, {:#x}) failed, returning NULL",
             buf_ptr,
             buf_size
         );
+        set_errno(env, ENOENT);
         return Ptr::null();
     }
 
@@ -377,12 +473,12 @@ pub fn getcwd(env: &mut Environment, buf_ptr: MutPtr<u8>, buf_size: GuestUSize)
     let res_size: GuestUSize = u32::try_from(working_directory.len()).unwrap() + 1;
 
     if buf_size < res_size {
-        // TODO: set errno to EINVAL or ERANGE as appropriate
         log!(
             "Warning: getcwd({:?}, {:#x}) failed, returning NULL",
             buf_ptr,
             buf_size
         );
+        set_errno(env, if buf_size == 0 { EINVAL } else { ERANGE });
         return Ptr::null();
     }
 
@@ -414,7 +510,12 @@ fn chdir(env: &mut Environment, path_ptr: ConstPtr<u8>) -> i32 {
         }
         Err(()) => {
             log!("Warning: chdir({:?}) failed, could not change working directory to {:?}, returning -1", path_ptr, path);
-            // TODO: set errno
+            let errno = if env.fs.exists(path) {
+                ENOTDIR
+            } else {
+                errno_for_path(env, path, false, false)
+            };
+            set_errno(env, errno);
             -1
         }
     }
@@ -427,10 +528,20 @@ fn flock(_env: &mut Environment, fd: FileDescriptor, operation: FLockFlag) -> i3
 }
 
 fn ftruncate(env: &mut Environment, fd: FileDescriptor, len: off_t) -> i32 {
-    let file = env.libc_state.posix_io.file_for_fd(fd).unwrap();
-    match file.file.set_len(len as u64) {
+    let Some(file) = env.libc_state.posix_io.file_for_fd(fd) else {
+        set_errno(env, EBADF);
+        return -1;
+    };
+    let Ok(len) = u64::try_from(len) else {
+        set_errno(env, EINVAL);
+        return -1;
+    };
+    match file.file.set_len(len) {
         Ok(()) => 0,
-        Err(_) => -1, // TODO: set errno
+        Err(e) => {
+            set_errno(env, io_error_to_errno(&e));
+            -1
+        }
     }
 }
 
@@ -5,9 +5,10 @@
  */
 //! POSIX `sys/stat.h`
 
-use super::{off_t, FileDescriptor};
+use super::{errno_for_path, off_t, FileDescriptor};
 use crate::dyld::{export_c_func, FunctionExports};
 use crate::fs::GuestPath;
+use crate::libc::errno::{set_errno, EBADF, EEXIST};
 use crate::mem::{ConstPtr, MutVoidPtr};
 use crate::Environment;
 use std::io::{Seek, SeekFrom};
@@ -17,29 +18,35 @@ pub type mode_t = u16;
 
 fn mkdir(env: &mut Environment, path: ConstPtr<u8>, mode: mode_t) -> i32 {
     // TODO: respect the mode
-    match env
-        .fs
-        .create_dir(GuestPath::new(&env.mem.cstr_at_utf8(path).unwrap()))
-    {
+    let path_string = env.mem.cstr_at_utf8(path).unwrap().to_owned();
+    let guest_path = GuestPath::new(&path_string);
+    match env.fs.create_dir(guest_path) {
         Ok(()) => {
             log_dbg!("mkdir({:?}, {:#x}) => 0", path, mode);
             0
         }
         Err(()) => {
-            // TODO: set errno
             log!(
                 "Warning: mkdir({:?}, {:#x}) failed, returning -1",
                 path,
                 mode,
             );
+            let errno = if env.fs.exists(guest_path) {
+                EEXIST
+            } else {
+                errno_for_path(env, guest_path, true, true)
+            };
+            set_errno(env, errno);
             -1
         }
     }
 }
 
 fn fstat(env: &mut Environment, fd: FileDescriptor, buf: MutVoidPtr) -> i32 {
-    // TODO: error handling for unknown fd?
-    let file = env.libc_state.posix_io.file_for_fd(fd).unwrap();
+    let Some(file) = env.libc_state.posix_io.file_for_fd(fd) else {
+        set_errno(env, EBADF);
+        return -1;
+    };
 
     log!("Warning: fstat() call, this function is mostly unimplemented");
     // FIXME: This implementation is highly incomplete. fstat() returns a huge
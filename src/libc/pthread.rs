@@ -28,9 +28,11 @@ macro_rules! check_magic {
     }
 }
 
+pub mod cond;
 pub mod key;
 pub mod mutex;
 pub mod once;
+pub mod rwlock;
 pub mod thread;
 
 #[derive(Default)]
@@ -0,0 +1,213 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Guest condition variable interface.
+//!
+//! See [crate::environment::cond] for the internal implementation.
+#![allow(rustdoc::broken_intra_doc_links)] // https://github.com/rust-lang/rust/issues/83049
+
+use super::mutex::{get_mutex_id, pthread_mutex_t};
+use super::thread::test_cancel;
+use crate::dyld::{export_c_func, FunctionExports};
+use crate::libc::errno::ETIMEDOUT;
+use crate::libc::time::timespec;
+use crate::mem::{ConstPtr, MutPtr, Ptr, SafeRead};
+use crate::{CondId, Environment};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// Apple's implementation is a 4-byte magic number followed by a 4-byte opaque
+/// region. We only have to match the size theirs has.
+#[repr(C, packed)]
+pub struct pthread_condattr_t {
+    /// Magic number (must be [MAGIC_CONDATTR])
+    magic: u32,
+    _unused: u32,
+}
+unsafe impl SafeRead for pthread_condattr_t {}
+
+/// Apple's implementation is a 4-byte magic number followed by a 24-byte opaque
+/// region. We will store the actual data on the host, determined by a condition
+/// variable identifier.
+#[repr(C, packed)]
+pub struct pthread_cond_t {
+    /// Magic number (must be [MAGIC_COND])
+    magic: u32,
+    /// Unique condition variable identifier, used in matching the condition
+    /// variable to its host object.
+    cond_id: CondId,
+}
+unsafe impl SafeRead for pthread_cond_t {}
+
+/// Arbitrarily-chosen magic number for `pthread_condattr_t` (not Apple's).
+const MAGIC_CONDATTR: u32 = u32::from_be_bytes(*b"CoAt");
+/// Arbitrarily-chosen magic number for `pthread_cond_t` (not Apple's).
+const MAGIC_COND: u32 = u32::from_be_bytes(*b"COND");
+/// Magic number used by `PTHREAD_COND_INITIALIZER`. This is part of the ABI!
+const MAGIC_COND_STATIC: u32 = 0x3CB0B1BB;
+
+fn pthread_condattr_init(env: &mut Environment, attr: MutPtr<pthread_condattr_t>) -> i32 {
+    env.mem.write(
+        attr,
+        pthread_condattr_t {
+            magic: MAGIC_CONDATTR,
+            _unused: 0,
+        },
+    );
+    0 // success
+}
+fn pthread_condattr_destroy(env: &mut Environment, attr: MutPtr<pthread_condattr_t>) -> i32 {
+    check_magic!(env, attr, MAGIC_CONDATTR);
+    env.mem.write(
+        attr,
+        pthread_condattr_t {
+            magic: 0,
+            _unused: 0,
+        },
+    );
+    0 // success
+}
+
+fn pthread_cond_init(
+    env: &mut Environment,
+    cond: MutPtr<pthread_cond_t>,
+    attr: ConstPtr<pthread_condattr_t>,
+) -> i32 {
+    if !attr.is_null() {
+        check_magic!(env, attr, MAGIC_CONDATTR);
+        // There aren't any attributes we care about (only process-shared).
+    }
+    let cond_id = env.cond_state.init_cond();
+    log_dbg!(
+        "Condition variable #{} created from pthread_cond_init ({:?})",
+        cond_id,
+        cond
+    );
+    env.mem.write(
+        cond,
+        pthread_cond_t {
+            magic: MAGIC_COND,
+            cond_id,
+        },
+    );
+    0 // success
+}
+
+fn get_cond_id(env: &mut Environment, cond: MutPtr<pthread_cond_t>) -> CondId {
+    let magic: u32 = env.mem.read(cond.cast());
+    // This is a statically-initialized condition variable, we need to register
+    // it, and change the magic number in the process.
+    if magic == MAGIC_COND_STATIC {
+        log_dbg!(
+            "Detected statically-initialized condition variable at {:?}, registering.",
+            cond
+        );
+        pthread_cond_init(env, cond, Ptr::null());
+    } else {
+        // As with mutexes, a bad magic number almost certainly indicates memory
+        // corruption, so panicking is more useful than returning EINVAL.
+        assert_eq!(magic, MAGIC_COND);
+    }
+    env.mem.read(cond).cond_id
+}
+
+fn pthread_cond_wait(
+    env: &mut Environment,
+    cond: MutPtr<pthread_cond_t>,
+    mutex: MutPtr<pthread_mutex_t>,
+) -> i32 {
+    if test_cancel(env) {
+        return 0;
+    }
+    let cond_id = get_cond_id(env, cond);
+    let mutex_id = get_mutex_id(env, mutex);
+    env.wait_on_cond(cond_id, mutex_id, None).err().unwrap_or(0)
+}
+
+fn pthread_cond_timedwait(
+    env: &mut Environment,
+    cond: MutPtr<pthread_cond_t>,
+    mutex: MutPtr<pthread_mutex_t>,
+    abstime: ConstPtr<timespec>,
+) -> i32 {
+    if test_cancel(env) {
+        return 0;
+    }
+    let cond_id = get_cond_id(env, cond);
+    let mutex_id = get_mutex_id(env, mutex);
+    let abstime = env.mem.read(abstime);
+    let (tv_sec, tv_nsec) = (abstime.tv_sec, abstime.tv_nsec);
+    // The deadline is in wall-clock time, so it has to be converted to a
+    // timeout in terms of the virtual clock.
+    let deadline = UNIX_EPOCH
+        + Duration::from_secs(tv_sec.try_into().unwrap())
+        + Duration::from_nanos(tv_nsec.try_into().unwrap());
+    let Ok(timeout) = deadline.duration_since(env.clock.system_time()) else {
+        return ETIMEDOUT;
+    };
+    env.wait_on_cond(cond_id, mutex_id, Some(timeout))
+        .err()
+        .unwrap_or(0)
+}
+
+/// Undocumented Darwin function like `pthread_cond_timedwait`, but with a
+/// relative timeout.
+fn pthread_cond_timedwait_relative_np(
+    env: &mut Environment,
+    cond: MutPtr<pthread_cond_t>,
+    mutex: MutPtr<pthread_mutex_t>,
+    reltime: ConstPtr<timespec>,
+) -> i32 {
+    if test_cancel(env) {
+        return 0;
+    }
+    let cond_id = get_cond_id(env, cond);
+    let mutex_id = get_mutex_id(env, mutex);
+    let reltime = env.mem.read(reltime);
+    let (tv_sec, tv_nsec) = (reltime.tv_sec, reltime.tv_nsec);
+    let timeout = Duration::from_secs(tv_sec.try_into().unwrap())
+        + Duration::from_nanos(tv_nsec.try_into().unwrap());
+    env.wait_on_cond(cond_id, mutex_id, Some(timeout))
+        .err()
+        .unwrap_or(0)
+}
+
+fn pthread_cond_signal(env: &mut Environment, cond: MutPtr<pthread_cond_t>) -> i32 {
+    let cond_id = get_cond_id(env, cond);
+    env.cond_state.signal_cond(cond_id);
+    0 // success
+}
+
+fn pthread_cond_broadcast(env: &mut Environment, cond: MutPtr<pthread_cond_t>) -> i32 {
+    let cond_id = get_cond_id(env, cond);
+    env.cond_state.broadcast_cond(cond_id);
+    0 // success
+}
+
+fn pthread_cond_destroy(env: &mut Environment, cond: MutPtr<pthread_cond_t>) -> i32 {
+    let cond_id = get_cond_id(env, cond);
+    if let Err(err) = env.cond_state.destroy_cond(cond_id) {
+        return err;
+    }
+    env.mem.write(
+        cond,
+        pthread_cond_t {
+            magic: 0,
+            cond_id: 0xFFFFFFFFFFFFFFFF,
+        },
+    );
+    0 // success
+}
+
+pub const FUNCTIONS: FunctionExports = &[
+    export_c_func!(pthread_condattr_init(_)),
+    export_c_func!(pthread_condattr_destroy(_)),
+    export_c_func!(pthread_cond_init(_, _)),
+    export_c_func!(pthread_cond_wait(_, _)),
+    export_c_func!(pthread_cond_timedwait(_, _, _)),
+    export_c_func!(pthread_cond_timedwait_relative_np(_, _, _)),
+    export_c_func!(pthread_cond_signal(_)),
+    export_c_func!(pthread_cond_broadcast(_)),
+    export_c_func!(pthread_cond_destroy(_)),
+];
@@ -58,8 +58,18 @@ fn pthread_setspecific(env: &mut Environment, key: pthread_key_t, value: ConstVo
     0 // success
 }
 
+fn pthread_key_delete(env: &mut Environment, key: pthread_key_t) -> i32 {
+    // TODO: return error instead of panicking if key is invalid?
+    let idx: usize = key.checked_sub(1).unwrap().try_into().unwrap();
+    // Destructors aren't called when a key is deleted. Keys are never reused,
+    // so the slot just stays empty.
+    get_state(env).keys[idx].0.clear();
+    0 // success
+}
+
 pub const FUNCTIONS: FunctionExports = &[
     export_c_func!(pthread_key_create(_, _)),
     export_c_func!(pthread_getspecific(_)),
     export_c_func!(pthread_setspecific(_, _)),
+    export_c_func!(pthread_key_delete(_)),
 ];
@@ -126,6 +126,13 @@ fn check_or_register_mutex(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>
     }
 }
 
+/// Get the host mutex for a guest mutex (registering it first if it's
+/// statically-initialized), for use by other pthread objects.
+pub(super) fn get_mutex_id(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>) -> MutexId {
+    check_or_register_mutex(env, mutex);
+    env.mem.read(mutex).mutex_id
+}
+
 pub fn pthread_mutex_lock(env: &mut Environment, mutex: MutPtr<pthread_mutex_t>) -> i32 {
     check_or_register_mutex(env, mutex);
     let mutex_data = env.mem.read(mutex);
@@ -0,0 +1,160 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Guest read-write lock interface.
+//!
+//! See [crate::environment::rwlock] for the internal implementation.
+#![allow(rustdoc::broken_intra_doc_links)] // https://github.com/rust-lang/rust/issues/83049
+
+use crate::dyld::{export_c_func, FunctionExports};
+use crate::mem::{ConstPtr, MutPtr, Ptr, SafeRead};
+use crate::{Environment, RwLockId};
+
+/// Apple's implementation is a 4-byte magic number followed by a 12-byte
+/// opaque region. We only have to match the size theirs has.
+#[repr(C, packed)]
+pub struct pthread_rwlockattr_t {
+    /// Magic number (must be [MAGIC_RWLOCKATTR])
+    magic: u32,
+    _unused: [u32; 3],
+}
+unsafe impl SafeRead for pthread_rwlockattr_t {}
+
+/// Apple's implementation is a 4-byte magic number followed by a 124-byte
+/// opaque region. We will store the actual data on the host, determined by a
+/// read-write lock identifier.
+#[repr(C, packed)]
+pub struct pthread_rwlock_t {
+    /// Magic number (must be [MAGIC_RWLOCK])
+    magic: u32,
+    /// Unique read-write lock identifier, used in matching the lock to its
+    /// host object.
+    rwlock_id: RwLockId,
+}
+unsafe impl SafeRead for pthread_rwlock_t {}
+
+/// Arbitrarily-chosen magic number for `pthread_rwlockattr_t` (not Apple's).
+const MAGIC_RWLOCKATTR: u32 = u32::from_be_bytes(*b"RwAt");
+/// Arbitrarily-chosen magic number for `pthread_rwlock_t` (not Apple's).
+const MAGIC_RWLOCK: u32 = u32::from_be_bytes(*b"RWLK");
+/// Magic number used by `PTHREAD_RWLOCK_INITIALIZER`. This is part of the ABI!
+const MAGIC_RWLOCK_STATIC: u32 = 0x2DA8B3B4;
+
+fn pthread_rwlockattr_init(env: &mut Environment, attr: MutPtr<pthread_rwlockattr_t>) -> i32 {
+    env.mem.write(
+        attr,
+        pthread_rwlockattr_t {
+            magic: MAGIC_RWLOCKATTR,
+            _unused: [0; 3],
+        },
+    );
+    0 // success
+}
+fn pthread_rwlockattr_destroy(env: &mut Environment, attr: MutPtr<pthread_rwlockattr_t>) -> i32 {
+    check_magic!(env, attr, MAGIC_RWLOCKATTR);
+    env.mem.write(
+        attr,
+        pthread_rwlockattr_t {
+            magic: 0,
+            _unused: [0; 3],
+        },
+    );
+    0 // success
+}
+
+fn pthread_rwlock_init(
+    env: &mut Environment,
+    rwlock: MutPtr<pthread_rwlock_t>,
+    attr: ConstPtr<pthread_rwlockattr_t>,
+) -> i32 {
+    if !attr.is_null() {
+        check_magic!(env, attr, MAGIC_RWLOCKATTR);
+        // There aren't any attributes we care about (only process-shared).
+    }
+    let rwlock_id = env.rwlock_state.init_rwlock();
+    log_dbg!(
+        "Read-write lock #{} created from pthread_rwlock_init ({:?})",
+        rwlock_id,
+        rwlock
+    );
+    env.mem.write(
+        rwlock,
+        pthread_rwlock_t {
+            magic: MAGIC_RWLOCK,
+            rwlock_id,
+        },
+    );
+    0 // success
+}
+
+fn get_rwlock_id(env: &mut Environment, rwlock: MutPtr<pthread_rwlock_t>) -> RwLockId {
+    let magic: u32 = env.mem.read(rwlock.cast());
+    // This is a statically-initialized read-write lock, we need to register
+    // it, and change the magic number in the process.
+    if magic == MAGIC_RWLOCK_STATIC {
+        log_dbg!(
+            "Detected statically-initialized read-write lock at {:?}, registering.",
+            rwlock
+        );
+        pthread_rwlock_init(env, rwlock, Ptr::null());
+    } else {
+        // As with mutexes, a bad magic number almost certainly indicates memory
+        // corruption, so panicking is more useful than returning EINVAL.
+        assert_eq!(magic, MAGIC_RWLOCK);
+    }
+    env.mem.read(rwlock).rwlock_id
+}
+
+fn pthread_rwlock_rdlock(env: &mut Environment, rwlock: MutPtr<pthread_rwlock_t>) -> i32 {
+    let rwlock_id = get_rwlock_id(env, rwlock);
+    env.lock_rwlock(rwlock_id, false, true).err().unwrap_or(0)
+}
+
+fn pthread_rwlock_tryrdlock(env: &mut Environment, rwlock: MutPtr<pthread_rwlock_t>) -> i32 {
+    let rwlock_id = get_rwlock_id(env, rwlock);
+    env.lock_rwlock(rwlock_id, false, false).err().unwrap_or(0)
+}
+
+fn pthread_rwlock_wrlock(env: &mut Environment, rwlock: MutPtr<pthread_rwlock_t>) -> i32 {
+    let rwlock_id = get_rwlock_id(env, rwlock);
+    env.lock_rwlock(rwlock_id, true, true).err().unwrap_or(0)
+}
+
+fn pthread_rwlock_trywrlock(env: &mut Environment, rwlock: MutPtr<pthread_rwlock_t>) -> i32 {
+    let rwlock_id = get_rwlock_id(env, rwlock);
+    env.lock_rwlock(rwlock_id, true, false).err().unwrap_or(0)
+}
+
+fn pthread_rwlock_unlock(env: &mut Environment, rwlock: MutPtr<pthread_rwlock_t>) -> i32 {
+    let rwlock_id = get_rwlock_id(env, rwlock);
+    env.unlock_rwlock(rwlock_id).err().unwrap_or(0)
+}
+
+fn pthread_rwlock_destroy(env: &mut Environment, rwlock: MutPtr<pthread_rwlock_t>) -> i32 {
+    let rwlock_id = get_rwlock_id(env, rwlock);
+    if let Err(err) = env.rwlock_state.destroy_rwlock(rwlock_id) {
+        return err;
+    }
+    env.mem.write(
+        rwlock,
+        pthread_rwlock_t {
+            magic: 0,
+            rwlock_id: 0xFFFFFFFFFFFFFFFF,
+        },
+    );
+    0 // success
+}
+
+pub const FUNCTIONS: FunctionExports = &[
+    export_c_func!(pthread_rwlockattr_init(_)),
+    export_c_func!(pthread_rwlockattr_destroy(_)),
+    export_c_func!(pthread_rwlock_init(_, _)),
+    export_c_func!(pthread_rwlock_rdlock(_)),
+    export_c_func!(pthread_rwlock_tryrdlock(_)),
+    export_c_func!(pthread_rwlock_wrlock(_)),
+    export_c_func!(pthread_rwlock_trywrlock(_)),
+    export_c_func!(pthread_rwlock_unlock(_)),
+    export_c_func!(pthread_rwlock_destroy(_)),
+];
@@ -4,12 +4,18 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 //! Threads.
+//!
+//! Cancellation is always deferred: a cancelled thread exits when it next
+//! reaches a cancellation point (see [test_cancel]). A thread that is already
+//! blocked at a cancellation point when it is cancelled has to be woken by the
+//! thread scheduler, which checks [cancellation_requested] for such threads.
 
 use crate::abi::GuestFunction;
 use crate::dyld::{export_c_func, FunctionExports};
-use crate::libc::errno::{EDEADLK, EINVAL};
-use crate::mem::{ConstPtr, MutPtr, MutVoidPtr, SafeRead};
-use crate::{Environment, ThreadId};
+use crate::libc::errno::{EDEADLK, EINVAL, ESRCH};
+use crate::libc::sched::{sched_param, SCHED_OTHER};
+use crate::mem::{ConstPtr, GuestUSize, Mem, MutPtr, MutVoidPtr, Ptr, SafeRead};
+use crate::{Environment, ThreadId, DEFAULT_THREAD_PRIORITY};
 use std::collections::HashMap;
 
 #[derive(Default)]
@@ -31,14 +37,18 @@ pub struct pthread_attr_t {
     /// Magic number (must be [MAGIC_ATTR])
     magic: u32,
     detachstate: i32,
-    _unused: [u32; 8],
+    stacksize: GuestUSize,
+    sched_priority: i32,
+    _unused: [u32; 6],
 }
 unsafe impl SafeRead for pthread_attr_t {}
 
 const DEFAULT_ATTR: pthread_attr_t = pthread_attr_t {
     magic: MAGIC_ATTR,
     detachstate: PTHREAD_CREATE_JOINABLE,
-    _unused: [0; 8],
+    stacksize: Mem::SECONDARY_THREAD_STACK_SIZE,
+    sched_priority: DEFAULT_THREAD_PRIORITY,
+    _unused: [0; 6],
 };
 
 /// Apple's implementation is a 4-byte magic number followed by a massive
@@ -55,8 +65,23 @@ pub type pthread_t = MutPtr<OpaqueThread>;
 struct ThreadHostObject {
     thread_id: ThreadId,
     joined_by: Option<ThreadId>,
+    detached: bool,
+    cancel_state: CancelState,
+    cancel_pending: bool,
     _attr: pthread_attr_t,
 }
+impl ThreadHostObject {
+    fn new(thread_id: ThreadId, attr: pthread_attr_t) -> Self {
+        ThreadHostObject {
+            thread_id,
+            joined_by: None,
+            detached: attr.detachstate == PTHREAD_CREATE_DETACHED,
+            cancel_state: PTHREAD_CANCEL_ENABLE,
+            cancel_pending: false,
+            _attr: attr,
+        }
+    }
+}
 
 /// Arbitrarily-chosen magic number for `pthread_attr_t` (not Apple's).
 const MAGIC_ATTR: u32 = u32::from_be_bytes(*b"ThAt");
@@ -68,6 +93,23 @@ type DetachState = i32;
 const PTHREAD_CREATE_JOINABLE: DetachState = 1;
 pub const PTHREAD_CREATE_DETACHED: DetachState = 2;
 
+/// Custom typedef for readability (the C API just uses `int`)
+type CancelState = i32;
+const PTHREAD_CANCEL_ENABLE: CancelState = 1;
+const PTHREAD_CANCEL_DISABLE: CancelState = 0;
+
+/// Custom typedef for readability (the C API just uses `int`)
+type CancelType = i32;
+const PTHREAD_CANCEL_DEFERRED: CancelType = 2;
+const PTHREAD_CANCEL_ASYNCHRONOUS: CancelType = 0;
+
+/// Value returned to `pthread_join` for a cancelled thread.
+pub const PTHREAD_CANCELED: MutVoidPtr = Ptr::from_bits(1);
+
+/// Darwin requires stack sizes to be at least this, and a multiple of the page
+/// size.
+const PTHREAD_STACK_MIN: GuestUSize = 0x4000;
+
 pub fn pthread_attr_init(env: &mut Environment, attr: MutPtr<pthread_attr_t>) -> i32 {
     env.mem.write(attr, DEFAULT_ATTR);
     0 // success
@@ -91,11 +133,68 @@ fn pthread_attr_destroy(env: &mut Environment, attr: MutPtr<pthread_attr_t>) ->
         pthread_attr_t {
             magic: 0,
             detachstate: 0,
+            stacksize: 0,
+            sched_priority: 0,
             _unused: Default::default(),
         },
     );
     0 // success
 }
+fn pthread_attr_setstacksize(
+    env: &mut Environment,
+    attr: MutPtr<pthread_attr_t>,
+    stacksize: GuestUSize,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    if stacksize < PTHREAD_STACK_MIN || stacksize % 0x1000 != 0 {
+        log_dbg!(
+            "pthread_attr_setstacksize: invalid size {:#x}, returning EINVAL",
+            stacksize
+        );
+        return EINVAL;
+    }
+    let mut attr_copy = env.mem.read(attr);
+    attr_copy.stacksize = stacksize;
+    env.mem.write(attr, attr_copy);
+    0 // success
+}
+fn pthread_attr_getstacksize(
+    env: &mut Environment,
+    attr: ConstPtr<pthread_attr_t>,
+    stacksize: MutPtr<GuestUSize>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    let attr_copy = env.mem.read(attr);
+    env.mem.write(stacksize, attr_copy.stacksize);
+    0 // success
+}
+fn pthread_attr_setschedparam(
+    env: &mut Environment,
+    attr: MutPtr<pthread_attr_t>,
+    param: ConstPtr<sched_param>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    let mut attr_copy = env.mem.read(attr);
+    attr_copy.sched_priority = env.mem.read(param).sched_priority;
+    env.mem.write(attr, attr_copy);
+    0 // success
+}
+fn pthread_attr_getschedparam(
+    env: &mut Environment,
+    attr: ConstPtr<pthread_attr_t>,
+    param: MutPtr<sched_param>,
+) -> i32 {
+    check_magic!(env, attr, MAGIC_ATTR);
+    let attr_copy = env.mem.read(attr);
+    env.mem.write(
+        param,
+        sched_param {
+            sched_priority: attr_copy.sched_priority,
+            _opaque: [0; 4],
+        },
+    );
+    0 // success
+}
 
 pub fn pthread_create(
     env: &mut Environment,
@@ -111,7 +210,9 @@ pub fn pthread_create(
         DEFAULT_ATTR
     };
 
-    let thread_id = env.new_thread(start_routine, user_data);
+    let thread_id = env.new_thread(start_routine, user_data, attr.stacksize);
+    env.scheduler_state
+        .set_priority(thread_id, attr.sched_priority);
 
     let opaque = env.mem.alloc_and_write(OpaqueThread {
         magic: MAGIC_THREAD,
@@ -119,14 +220,9 @@ pub fn pthread_create(
     env.mem.write(thread, opaque);
 
     assert!(!State::get(env).threads.contains_key(&opaque));
-    State::get(env).threads.insert(
-        opaque,
-        ThreadHostObject {
-            thread_id,
-            joined_by: None,
-            _attr: attr,
-        },
-    );
+    State::get(env)
+        .threads
+        .insert(opaque, ThreadHostObject::new(thread_id, attr));
 
     log_dbg!("pthread_create({:?}, {:?}, {:?}, {:?}) => 0 (success), created new pthread_t {:?} (thread ID: {})", thread, attr, start_routine, user_data, opaque, thread_id);
 
@@ -146,14 +242,9 @@ fn pthread_self(env: &mut Environment) -> pthread_t {
         });
 
         assert!(!State::get(env).threads.contains_key(&opaque));
-        State::get(env).threads.insert(
-            opaque,
-            ThreadHostObject {
-                thread_id: 0,
-                joined_by: None,
-                _attr: DEFAULT_ATTR,
-            },
-        );
+        State::get(env)
+            .threads
+            .insert(opaque, ThreadHostObject::new(0, DEFAULT_ATTR));
         log_dbg!(
             "pthread_self: created pthread object {:?} for main thread",
             opaque
@@ -169,10 +260,21 @@ fn pthread_self(env: &mut Environment) -> pthread_t {
 }
 
 fn pthread_join(env: &mut Environment, thread: pthread_t, retval: MutPtr<MutVoidPtr>) -> i32 {
+    if test_cancel(env) {
+        return 0;
+    }
+
     let current_thread = env.current_thread;
     let curr_pthread_t = pthread_self(env);
     // The joinee is the thread that is being waited on.
-    let joinee_thread = State::get(env).threads.get_mut(&thread).unwrap().thread_id;
+    let Some(host_obj_joinee) = State::get(env).threads.get(&thread) else {
+        log_dbg!(
+            "pthread_join({:?}): no such thread, returning ESRCH!",
+            thread
+        );
+        return ESRCH;
+    };
+    let joinee_thread = host_obj_joinee.thread_id;
 
     // FIXME?: Blocking on the main thread is technically allowed, but
     // effectively useless (as the main thread exiting means the whole
@@ -206,7 +308,7 @@ fn pthread_join(env: &mut Environment, thread: pthread_t, retval: MutPtr<MutVoid
 
     // Deattached threads cannot be joined with.
     let host_obj_joinee = State::get(env).threads.get_mut(&thread).unwrap();
-    if host_obj_joinee._attr.detachstate == PTHREAD_CREATE_DETACHED {
+    if host_obj_joinee.detached {
         log_dbg!("Thread attempted join with deattached thread, returning EINVAL!");
         return EINVAL;
     }
@@ -217,9 +319,193 @@ fn pthread_join(env: &mut Environment, thread: pthread_t, retval: MutPtr<MutVoid
     env.join_with_thread(joinee_thread, retval);
     0
 }
-fn pthread_setcanceltype(_env: &mut Environment, _type: i32, _oldtype: MutPtr<i32>) -> i32 {
-    // TODO
-    0
+
+fn pthread_detach(env: &mut Environment, thread: pthread_t) -> i32 {
+    let Some(host_obj) = State::get(env).threads.get_mut(&thread) else {
+        log_dbg!(
+            "pthread_detach({:?}): no such thread, returning ESRCH!",
+            thread
+        );
+        return ESRCH;
+    };
+    if host_obj.detached || host_obj.joined_by.is_some() {
+        log_dbg!(
+            "pthread_detach({:?}): already detached or being joined, returning EINVAL!",
+            thread
+        );
+        return EINVAL;
+    }
+    host_obj.detached = true;
+    log_dbg!("pthread_detach({:?}) => 0 (success)", thread);
+    0 // success
+}
+
+/// Exits the current thread, as if its start routine returned `value_ptr`.
+/// NOTE: Like [Environment::join_with_thread], this only takes effect _after_
+/// the calling function returns to the host run loop.
+pub fn pthread_exit(env: &mut Environment, value_ptr: MutVoidPtr) {
+    // FIXME: The main thread is allowed to exit this way, and the process then
+    // continues until the other threads finish.
+    assert!(
+        env.current_thread != 0,
+        "pthread_exit() on the main thread is not supported"
+    );
+    log_dbg!(
+        "pthread_exit({:?}) on thread {}",
+        value_ptr,
+        env.current_thread
+    );
+    env.exit_thread(value_ptr);
+}
+
+fn pthread_cancel(env: &mut Environment, thread: pthread_t) -> i32 {
+    let Some(host_obj) = State::get(env).threads.get_mut(&thread) else {
+        log_dbg!(
+            "pthread_cancel({:?}): no such thread, returning ESRCH!",
+            thread
+        );
+        return ESRCH;
+    };
+    log_dbg!(
+        "pthread_cancel({:?}): thread {} will exit at its next cancellation point",
+        thread,
+        host_obj.thread_id
+    );
+    host_obj.cancel_pending = true;
+    0 // success
+}
+
+/// Checks if the current thread has a pending cancellation request, and if so,
+/// makes it exit. Functions that are cancellation points should call this
+/// first, and return immediately if it returns [true].
+pub fn test_cancel(env: &mut Environment) -> bool {
+    let current = pthread_self(env);
+    let host_obj = State::get(env).threads.get(&current).unwrap();
+    if !host_obj.cancel_pending || host_obj.cancel_state != PTHREAD_CANCEL_ENABLE {
+        return false;
+    }
+    log_dbg!(
+        "Thread {} reached a cancellation point, exiting",
+        env.current_thread
+    );
+    pthread_exit(env, PTHREAD_CANCELED);
+    true
+}
+
+/// Checks if a thread that is blocked at a cancellation point (joining,
+/// waiting on a condition variable or semaphore, or sleeping) has a pending
+/// cancellation request. The thread scheduler should check this for each such
+/// thread, and if it returns [true], stop the thread waiting and end it as if
+/// it had called `pthread_exit(PTHREAD_CANCELED)`. A thread waiting on a
+/// condition variable must relock the mutex first (see
+/// [Environment::cancel_cond_wait]). If the thread was joining another thread,
+/// that thread can be joined again.
+pub fn cancellation_requested(env: &mut Environment, thread: ThreadId) -> bool {
+    let threads = &mut State::get(env).threads;
+    let cancelled = threads.values().any(|host_obj| {
+        host_obj.thread_id == thread
+            && host_obj.cancel_pending
+            && host_obj.cancel_state == PTHREAD_CANCEL_ENABLE
+    });
+    if cancelled {
+        for host_obj in threads.values_mut() {
+            if host_obj.joined_by == Some(thread) {
+                host_obj.joined_by = None;
+            }
+        }
+    }
+    cancelled
+}
+
+fn pthread_testcancel(env: &mut Environment) {
+    test_cancel(env);
+}
+
+fn pthread_setcancelstate(env: &mut Environment, state: CancelState, oldstate: MutPtr<i32>) -> i32 {
+    if state != PTHREAD_CANCEL_ENABLE && state != PTHREAD_CANCEL_DISABLE {
+        return EINVAL;
+    }
+    let current = pthread_self(env);
+    let host_obj = State::get(env).threads.get_mut(&current).unwrap();
+    let old = std::mem::replace(&mut host_obj.cancel_state, state);
+    if !oldstate.is_null() {
+        env.mem.write(oldstate, old);
+    }
+    0 // success
+}
+
+fn pthread_setcanceltype(env: &mut Environment, type_: CancelType, oldtype: MutPtr<i32>) -> i32 {
+    match type_ {
+        PTHREAD_CANCEL_DEFERRED => (),
+        PTHREAD_CANCEL_ASYNCHRONOUS => {
+            log!(
+                "TODO: asynchronous cancellation requested, cancellation will be deferred instead"
+            );
+        }
+        _ => return EINVAL,
+    }
+    // Only deferred cancellation is really supported, so that's always the
+    // old type.
+    if !oldtype.is_null() {
+        env.mem.write(oldtype, PTHREAD_CANCEL_DEFERRED);
+    }
+    0 // success
+}
+
+fn pthread_equal(_env: &mut Environment, t1: pthread_t, t2: pthread_t) -> i32 {
+    (t1 == t2).into()
+}
+
+fn pthread_getschedparam(
+    env: &mut Environment,
+    thread: pthread_t,
+    policy: MutPtr<i32>,
+    param: MutPtr<sched_param>,
+) -> i32 {
+    let Some(host_obj) = State::get(env).threads.get(&thread) else {
+        return ESRCH;
+    };
+    let sched_priority = env.scheduler_state.priority(host_obj.thread_id);
+    if !policy.is_null() {
+        env.mem.write(policy, SCHED_OTHER);
+    }
+    if !param.is_null() {
+        env.mem.write(
+            param,
+            sched_param {
+                sched_priority,
+                _opaque: [0; 4],
+            },
+        );
+    }
+    0 // success
+}
+
+fn pthread_setschedparam(
+    env: &mut Environment,
+    thread: pthread_t,
+    policy: i32,
+    param: ConstPtr<sched_param>,
+) -> i32 {
+    let Some(host_obj) = State::get(env).threads.get(&thread) else {
+        log_dbg!(
+            "pthread_setschedparam({:?}): no such thread, returning ESRCH!",
+            thread
+        );
+        return ESRCH;
+    };
+    let thread_id = host_obj.thread_id;
+    // The policy is ignored: every thread is scheduled the same way, only the
+    // priority matters.
+    let sched_priority = env.mem.read(param).sched_priority;
+    log_dbg!(
+        "pthread_setschedparam({:?}, {}, priority {})",
+        thread,
+        policy,
+        sched_priority
+    );
+    env.scheduler_state.set_priority(thread_id, sched_priority);
+    0 // success
 }
 
 type mach_port_t = u32;
@@ -235,9 +521,21 @@ pub const FUNCTIONS: FunctionExports = &[
     export_c_func!(pthread_attr_init(_)),
     export_c_func!(pthread_attr_setdetachstate(_, _)),
     export_c_func!(pthread_attr_destroy(_)),
+    export_c_func!(pthread_attr_setstacksize(_, _)),
+    export_c_func!(pthread_attr_getstacksize(_, _)),
+    export_c_func!(pthread_attr_setschedparam(_, _)),
+    export_c_func!(pthread_attr_getschedparam(_, _)),
     export_c_func!(pthread_create(_, _, _, _)),
     export_c_func!(pthread_self()),
     export_c_func!(pthread_join(_, _)),
+    export_c_func!(pthread_detach(_)),
+    export_c_func!(pthread_exit(_)),
+    export_c_func!(pthread_cancel(_)),
+    export_c_func!(pthread_testcancel()),
+    export_c_func!(pthread_setcancelstate(_, _)),
     export_c_func!(pthread_setcanceltype(_, _)),
+    export_c_func!(pthread_equal(_, _)),
+    export_c_func!(pthread_getschedparam(_, _, _)),
+    export_c_func!(pthread_setschedparam(_, _, _)),
     export_c_func!(pthread_mach_thread_np(_)),
 ];
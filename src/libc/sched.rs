@@ -6,13 +6,25 @@
 //! `sched.h`.
 
 use crate::dyld::{export_c_func, FunctionExports};
+use crate::mem::SafeRead;
 use crate::Environment;
 
+#[allow(non_camel_case_types)]
+#[repr(C, packed)]
+pub struct sched_param {
+    pub sched_priority: i32,
+    pub _opaque: [u8; 4],
+}
+unsafe impl SafeRead for sched_param {}
+
+pub const SCHED_OTHER: i32 = 1;
+
 fn sched_yield(env: &mut Environment) -> i32 {
     log_dbg!(
-        "TODO: thread {} requested processor yield, ignoring",
+        "Thread {} requested processor yield, ending its time slice",
         env.current_thread
     );
+    env.scheduler_state.end_slice();
     0 // success
 }
 
@@ -6,8 +6,10 @@
 //! `semaphore.h`
 
 use crate::dyld::{export_c_func, FunctionExports};
+use crate::libc::errno::{set_errno, EAGAIN, EEXIST, ENOENT};
 use crate::libc::posix_io::stat::mode_t;
 use crate::libc::posix_io::{O_CREAT, O_EXCL};
+use crate::libc::pthread::thread::test_cancel;
 use crate::mem::{ConstPtr, MutPtr};
 use crate::{Environment, ThreadId};
 use std::cell::RefCell;
@@ -51,8 +53,8 @@ fn sem_open(
     let sem_name_str = sem_name.to_string();
     let host_sem_rc =
         if let Some(existing_host_sem_rc) = State::get(env).named_semaphores.get(sem_name) {
-            if (oflag & O_EXCL) == 0 {
-                // TODO: set errno
+            if (oflag & (O_CREAT | O_EXCL)) == (O_CREAT | O_EXCL) {
+                set_errno(env, EEXIST);
                 return SEM_FAILED;
             }
             let existing_host_sem = (*existing_host_sem_rc).borrow();
@@ -62,7 +64,7 @@ fn sem_open(
             existing_host_sem_rc.clone()
         } else {
             if (oflag & O_CREAT) == 0 {
-                // TODO: set errno
+                set_errno(env, ENOENT);
                 return SEM_FAILED;
             }
             let host_sem_rc = Rc::new(RefCell::new(SemaphoreHostObject {
@@ -89,6 +91,9 @@ fn sem_post(env: &mut Environment, sem: MutPtr<sem_t>) -> i32 {
 }
 
 fn sem_wait(env: &mut Environment, sem: MutPtr<sem_t>) -> i32 {
+    if test_cancel(env) {
+        return 0;
+    }
     env.sem_decrement(sem, true);
     0 // success
 }
@@ -97,6 +102,7 @@ fn sem_trywait(env: &mut Environment, sem: MutPtr<sem_t>) -> i32 {
     if env.sem_decrement(sem, false) {
         0 // success
     } else {
+        set_errno(env, EAGAIN);
         -1
     }
 }
@@ -116,7 +122,16 @@ fn sem_close(env: &mut Environment, sem: MutPtr<sem_t>) -> i32 {
 
 fn sem_unlink(env: &mut Environment, name: ConstPtr<u8>) -> i32 {
     let sem_name = env.mem.cstr_at_utf8(name).unwrap();
-    env.libc_state.semaphore.named_semaphores.remove(sem_name);
+    if env
+        .libc_state
+        .semaphore
+        .named_semaphores
+        .remove(sem_name)
+        .is_none()
+    {
+        set_errno(env, ENOENT);
+        return -1;
+    }
     0 // success
 }
 
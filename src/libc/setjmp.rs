@@ -5,26 +5,224 @@
  */
 //! `setjmp.h`.
 //!
-//! We don't have a real implementation for this right now. It could be quite
-//! tricky to write one, considering that we would need to unwind through host
-//! code, and somehow do so selectively since we have a mix of stack frames from
-//! different guest threads. For the moment, we simply pray the app never throws
-//! exceptions.
+//! These are host functions, so the registers they see are those of the guest
+//! code that called them: `setjmp` saves the callee-saved registers, SP and LR
+//! to the `jmp_buf`, and `longjmp` restores them, so that when the stub
+//! returns to LR, it's as if `setjmp` returned a second time. The layout of
+//! `jmp_buf` is the same as in Apple's Libc, in case an app looks inside it.
+//!
+//! The tricky part is when a `longjmp` crosses host code, e.g. when a guest
+//! callback called by a host function jumps back to before that host function
+//! was called. The host function's Rust stack frames can't just be skipped, so
+//! in that case [longjmp] unwinds them with a special panic payload, which is
+//! caught by [run_call] once it gets back to the host-to-guest call that the
+//! target is in. Jumping out of all host-to-guest calls on a thread isn't
+//! supported, and neither is a jump that would have to unwind host frames that
+//! another thread is running on top of.
 //!
 //! Note that `setjmp` and `longjmp` are defined as macros in the C standard,
-//! but it seems like the implementation of these on iPhone OS uses real
-//! functions, at least for the former.
+//! but the implementation of these on iPhone OS uses real functions.
 
+use super::unwind;
+use crate::cpu::Cpu;
 use crate::dyld::{export_c_func, FunctionExports};
+use crate::mem::{ConstPtr, MutPtr};
 use crate::Environment;
+use std::panic::{catch_unwind, resume_unwind, AssertUnwindSafe};
+
+#[allow(non_camel_case_types)]
+type jmp_buf = i32;
+
+// Word offsets within a jmp_buf. The core registers are r4-r8, r10, r11, SP
+// and LR, in that order. The signal mask isn't restored, since we don't have
+// signals, but sigsetjmp() still records whether it would be.
+const JMP_CORE_REGS: usize = 0;
+const JMP_SP: usize = 7;
+/// d8-d15, as 16 single-precision registers (s16-s31).
+const JMP_VFP: usize = 9;
+const JMP_SIG: usize = 25;
+const JMP_SIGFLAG: usize = 26;
+
+/// Core registers in the order they're saved, starting from [JMP_CORE_REGS].
+const SAVED_REGS: [usize; 9] = [4, 5, 6, 7, 8, 10, 11, Cpu::SP, Cpu::LR];
+/// The first of the VFP registers that are saved, starting from [JMP_VFP].
+const SAVED_EXT_REGS_START: usize = 16;
+const SAVED_EXT_REGS_COUNT: usize = 16;
 
-/// The signature of this is incomplete because it's a stub (see module docs).
-fn setjmp(env: &mut Environment) -> i32 {
+#[derive(Default)]
+pub struct State {
+    /// The thread and SP at the start of each host-to-guest call that's in
+    /// progress (see [run_call]), innermost last.
+    host_to_guest_calls: Vec<(usize, u32)>,
+}
+impl State {
+    fn get(env: &mut Environment) -> &mut Self {
+        &mut env.libc_state.setjmp
+    }
+}
+
+/// Panic payload used to unwind host frames that a `longjmp` crosses.
+struct LongJmp {
+    thread: usize,
+    target_sp: u32,
+}
+
+fn save_registers(env: &mut Environment, buf: MutPtr<jmp_buf>) {
+    let regs = *env.cpu.regs();
+    for (i, &reg) in SAVED_REGS.iter().enumerate() {
+        env.mem
+            .write(buf + (JMP_CORE_REGS + i) as u32, regs[reg] as i32);
+    }
+    let ext_regs = *env.cpu.ext_regs();
+    for i in 0..SAVED_EXT_REGS_COUNT {
+        let value = ext_regs[SAVED_EXT_REGS_START + i];
+        env.mem.write(buf + (JMP_VFP + i) as u32, value as i32);
+    }
+    env.mem.write(buf + JMP_SIG as u32, 0);
+}
+
+fn setjmp(env: &mut Environment, buf: MutPtr<jmp_buf>) -> i32 {
+    save_registers(env, buf);
+    0 // no longjmp() was performed
+}
+
+fn _setjmp(env: &mut Environment, buf: MutPtr<jmp_buf>) -> i32 {
+    setjmp(env, buf)
+}
+
+fn sigsetjmp(env: &mut Environment, buf: MutPtr<jmp_buf>, savemask: i32) -> i32 {
+    save_registers(env, buf);
+    env.mem.write(buf + JMP_SIGFLAG as u32, savemask);
+    0 // no siglongjmp() was performed
+}
+
+fn longjmp(env: &mut Environment, buf: ConstPtr<jmp_buf>, val: i32) {
+    let thread = env.current_thread;
+    let current_sp = env.cpu.regs()[Cpu::SP];
+    let target_sp = env.mem.read(buf + JMP_SP as u32) as u32;
     log_dbg!(
-        "TODO: setjmp() at {:#x}",
-        env.cpu.regs()[crate::cpu::Cpu::LR]
+        "longjmp({:?}, {}) from SP {:#x} to SP {:#x}",
+        buf,
+        val,
+        current_sp,
+        target_sp
     );
-    0 // no longjmp() was performed
+    // The stack grows downwards, so anything below SP has been popped.
+    assert!(
+        target_sp >= current_sp,
+        "longjmp() to a function that has already returned (SP {:#x}, target SP {:#x})",
+        current_sp,
+        target_sp
+    );
+
+    {
+        let regs = env.cpu.regs_mut();
+        for (i, &reg) in SAVED_REGS.iter().enumerate() {
+            regs[reg] = env.mem.read(buf + (JMP_CORE_REGS + i) as u32) as u32;
+        }
+        // longjmp() can't make setjmp() return 0.
+        regs[0] = if val == 0 { 1 } else { val as u32 };
+    }
+    for i in 0..SAVED_EXT_REGS_COUNT {
+        let value = env.mem.read(buf + (JMP_VFP + i) as u32) as u32;
+        env.cpu.ext_regs_mut()[SAVED_EXT_REGS_START + i] = value;
+    }
+
+    // A function that calls setjmp() must have pushed LR, so its SP is always
+    // strictly below the start of the host-to-guest call it's in.
+    let Some(innermost) = State::get(env)
+        .host_to_guest_calls
+        .iter()
+        .rev()
+        .find(|&&(call_thread, _)| call_thread == thread)
+        .map(|&(_, call_sp)| call_sp)
+    else {
+        return;
+    };
+    if target_sp < innermost {
+        // No host frames are crossed, so returning from this function is
+        // enough: the stub returns to the restored LR.
+        return;
+    }
+
+    // Unwinding stops at the innermost call on this thread that the target is
+    // in. Everything more recent than it gets unwound, so it must all belong to
+    // this thread: if another thread was switched to inside one of these host
+    // functions, its host frames are in the way too, and unwinding them would
+    // wreck that thread.
+    let calls = &State::get(env).host_to_guest_calls;
+    let Some(target_call) = calls
+        .iter()
+        .rposition(|&(call_thread, call_sp)| call_thread == thread && target_sp < call_sp)
+    else {
+        panic!("longjmp() out of all host-to-guest calls on this thread isn't supported");
+    };
+    if let Some(&(other_thread, _)) = calls[target_call + 1..]
+        .iter()
+        .find(|&&(call_thread, _)| call_thread != thread)
+    {
+        panic!(
+            "longjmp() on thread {} would unwind host frames that thread {} is using, which isn't supported",
+            thread, other_thread
+        );
+    }
+    log_dbg!("longjmp() crosses host frames, unwinding them");
+    // resume_unwind() doesn't run the panic hook, so this isn't reported as a
+    // crash.
+    resume_unwind(Box::new(LongJmp { thread, target_sp }));
+}
+
+fn _longjmp(env: &mut Environment, buf: ConstPtr<jmp_buf>, val: i32) {
+    longjmp(env, buf, val)
+}
+
+fn siglongjmp(env: &mut Environment, buf: ConstPtr<jmp_buf>, val: i32) {
+    longjmp(env, buf, val)
+}
+
+/// Run guest code for a host-to-guest call (see
+/// [crate::abi::GuestFunction::call]) until it returns, handling any
+/// `longjmp` that crosses host frames (see the module documentation). C++
+/// exceptions aren't allowed to cross host frames at all (see
+/// [super::unwind]).
+pub fn run_call(env: &mut Environment) {
+    let thread = env.current_thread;
+    let call_sp = env.cpu.regs()[Cpu::SP];
+    State::get(env).host_to_guest_calls.push((thread, call_sp));
+    unwind::enter_host_to_guest_call(env);
+    loop {
+        let Err(payload) = catch_unwind(AssertUnwindSafe(|| env.run_call())) else {
+            break;
+        };
+        match payload.downcast::<LongJmp>() {
+            Ok(long_jmp) if long_jmp.thread == thread && long_jmp.target_sp < call_sp => {
+                // The registers were already restored by longjmp(), so guest
+                // code can continue from the target. The inner calls have
+                // removed themselves from the list while unwinding.
+                crate::profiler::host_calls_unwound(env);
+                continue;
+            }
+            Ok(long_jmp) => {
+                State::get(env).host_to_guest_calls.pop();
+                unwind::leave_host_to_guest_call(env);
+                resume_unwind(long_jmp);
+            }
+            Err(payload) => {
+                State::get(env).host_to_guest_calls.pop();
+                unwind::leave_host_to_guest_call(env);
+                resume_unwind(payload);
+            }
+        }
+    }
+    State::get(env).host_to_guest_calls.pop();
+    unwind::leave_host_to_guest_call(env);
 }
 
-pub const FUNCTIONS: FunctionExports = &[export_c_func!(setjmp())];
+pub const FUNCTIONS: FunctionExports = &[
+    export_c_func!(setjmp(_)),
+    export_c_func!(_setjmp(_)),
+    export_c_func!(sigsetjmp(_, _)),
+    export_c_func!(longjmp(_, _)),
+    export_c_func!(_longjmp(_, _)),
+    export_c_func!(siglongjmp(_, _)),
+];
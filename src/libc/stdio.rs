@@ -5,9 +5,10 @@
  */
 //! `stdio.h`
 
+use super::errno::{io_error_to_errno, set_errno, EACCES, EFAULT, ENOTEMPTY};
 use super::posix_io::{
-    self, off_t, O_APPEND, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY, STDERR_FILENO,
-    STDIN_FILENO, STDOUT_FILENO,
+    self, errno_for_path, off_t, O_APPEND, O_CREAT, O_RDONLY, O_RDWR, O_TRUNC, O_WRONLY,
+    STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
 };
 use crate::dyld::{export_c_func, ConstantExports, FunctionExports, HostConstant};
 use crate::fs::GuestPath;
@@ -145,11 +146,14 @@ fn fgets(
 }
 
 fn fputs(env: &mut Environment, str: ConstPtr<u8>, stream: MutPtr<FILE>) -> i32 {
-    // TODO: this function doesn't set errno or return EOF yet
     let str_len = strlen(env, str);
-    fwrite(env, str.cast(), str_len, 1, stream)
-        .try_into()
-        .unwrap()
+    let written = fwrite(env, str.cast(), str_len, 1, stream);
+    if str_len != 0 && written == 0 {
+        // fwrite() will have set errno
+        EOF
+    } else {
+        written.try_into().unwrap()
+    }
 }
 
 fn fwrite(
@@ -173,14 +177,20 @@ fn fwrite(
             let buffer_slice = env.mem.bytes_at(buffer.cast(), total_size);
             match std::io::stdout().write(buffer_slice) {
                 Ok(bytes_written) => (bytes_written / (item_size as usize)) as GuestUSize,
-                Err(_err) => 0,
+                Err(err) => {
+                    set_errno(env, io_error_to_errno(&err));
+                    0
+                }
             }
         }
         STDERR_FILENO => {
             let buffer_slice = env.mem.bytes_at(buffer.cast(), total_size);
             match std::io::stderr().write(buffer_slice) {
                 Ok(bytes_written) => (bytes_written / (item_size as usize)) as GuestUSize,
-                Err(_err) => 0,
+                Err(err) => {
+                    set_errno(env, io_error_to_errno(&err));
+                    0
+                }
             }
         }
         _ => {
@@ -275,22 +285,34 @@ fn putchar(_env: &mut Environment, c: u8) -> i32 {
 
 fn remove(env: &mut Environment, path: ConstPtr<u8>) -> i32 {
     if Ptr::is_null(path) {
-        // TODO: set errno
         log!("remove({:?}) => -1, attempted to remove null", path);
+        set_errno(env, EFAULT);
         return -1;
     }
 
-    match env
-        .fs
-        .remove(GuestPath::new(&env.mem.cstr_at_utf8(path).unwrap()))
-    {
+    let path_string = env.mem.cstr_at_utf8(path).unwrap().to_owned();
+    let guest_path = GuestPath::new(&path_string);
+    match env.fs.remove(guest_path) {
         Ok(()) => {
             log_dbg!("remove({:?}) => 0", path);
             0
         }
         Err(_) => {
-            // TODO: set errno
             log!("Warning: remove({:?}) failed, returning -1", path);
+            let errno = if !env.fs.exists(guest_path) {
+                errno_for_path(env, guest_path, false, false)
+            } else if env
+                .fs
+                .enumerate(guest_path)
+                .is_ok_and(|mut children| children.next().is_some())
+            {
+                ENOTEMPTY
+            } else {
+                // touchHLE doesn't allow removing read-only files or anything
+                // in a read-only directory.
+                EACCES
+            };
+            set_errno(env, errno);
             -1
         }
     }
@@ -9,6 +9,7 @@ use crate::abi::{DotDotDot, VaList};
 use crate::dyld::{export_c_func, FunctionExports};
 use crate::frameworks::foundation::{ns_string, unichar};
 use crate::libc::clocale::{setlocale, LC_CTYPE};
+use crate::libc::errno::{set_errno, EOVERFLOW};
 use crate::libc::posix_io::{STDERR_FILENO, STDOUT_FILENO};
 use crate::libc::stdio::FILE;
 use crate::libc::stdlib::atoi_inner;
@@ -470,7 +471,7 @@ fn swprintf(
         env.mem.write(ws + i, res[i as usize] as wchar_t);
     }
     if to_write >= n {
-        // TODO: set errno
+        set_errno(env, EOVERFLOW);
         return -1;
     }
     env.mem.write(ws + to_write, wchar_t::default());
@@ -6,12 +6,16 @@
 //! `stdlib.h`
 
 use crate::abi::{CallFromHost, GuestFunction};
+use crate::cpu::Cpu;
 use crate::dyld::{export_c_func, export_c_func_aliased, FunctionExports};
 use crate::fs::{resolve_path, GuestPath};
+use crate::heap_stats::print_heap_stats;
 use crate::libc::clocale::{setlocale, LC_CTYPE};
+use crate::libc::errno::{set_errno, EINVAL, ENOMEM, ERANGE};
 use crate::libc::string::strlen;
 use crate::libc::wchar::wchar_t;
-use crate::mem::{ConstPtr, ConstVoidPtr, GuestUSize, MutPtr, MutVoidPtr, Ptr};
+use crate::mem::{print_leak_summary, ConstPtr, ConstVoidPtr, GuestUSize, MutPtr, MutVoidPtr, Ptr};
+use crate::profiler;
 use crate::Environment;
 use std::collections::HashMap;
 use std::str::FromStr;
@@ -29,21 +33,41 @@ pub struct State {
 // Sizes of zero are implementation-defined. macOS will happily give you back
 // an allocation for any of these, so presumably iPhone OS does too.
 // (touchHLE's allocator will round up allocations to at least 16 bytes.)
+// Like on a real device, these return NULL if there's not enough memory, i.e.
+// `--memory-limit=` would be exceeded, and set errno to ENOMEM.
 
 fn malloc(env: &mut Environment, size: GuestUSize) -> MutVoidPtr {
-    env.mem.alloc(size)
+    let caller = env.cpu.regs()[Cpu::LR];
+    let ptr = env.mem.alloc_for_guest(size, caller);
+    if ptr.is_null() {
+        set_errno(env, ENOMEM);
+    }
+    ptr
 }
 
 fn calloc(env: &mut Environment, count: GuestUSize, size: GuestUSize) -> MutVoidPtr {
-    let total = size.checked_mul(count).unwrap();
-    env.mem.alloc(total)
+    let Some(total) = size.checked_mul(count) else {
+        set_errno(env, ENOMEM);
+        return Ptr::null();
+    };
+    let caller = env.cpu.regs()[Cpu::LR];
+    let ptr = env.mem.alloc_for_guest(total, caller);
+    if ptr.is_null() {
+        set_errno(env, ENOMEM);
+    }
+    ptr
 }
 
 fn realloc(env: &mut Environment, ptr: MutVoidPtr, size: GuestUSize) -> MutVoidPtr {
     if ptr.is_null() {
         return malloc(env, size);
     }
-    env.mem.realloc(ptr, size)
+    let caller = env.cpu.regs()[Cpu::LR];
+    let new_ptr = env.mem.realloc_for_guest(ptr, size, caller);
+    if new_ptr.is_null() {
+        set_errno(env, ENOMEM);
+    }
+    new_ptr
 }
 
 fn free(env: &mut Environment, ptr: MutVoidPtr) {
@@ -98,9 +122,30 @@ fn strtod(env: &mut Environment, nptr: ConstPtr<u8>, endptr: MutPtr<MutPtr<u8>>)
     if !endptr.is_null() {
         env.mem.write(endptr, (nptr + len).cast_mut());
     }
+    if float_out_of_range(env, nptr, len, res) {
+        set_errno(env, ERANGE);
+    }
     res
 }
 
+/// Checks whether the result of parsing the first `len` bytes of `s` as a
+/// floating-point number overflowed or underflowed. [atof_inner] doesn't
+/// support `INF`, so an infinite result must be an overflow.
+fn float_out_of_range(env: &Environment, s: ConstPtr<u8>, len: GuestUSize, result: f64) -> bool {
+    if result.is_infinite() {
+        return true;
+    }
+    // The result is rounded to zero even though some digit before the
+    // exponent isn't zero.
+    result == 0.0
+        && env
+            .mem
+            .bytes_at(s, len)
+            .iter()
+            .take_while(|&&c| c.to_ascii_lowercase() != b'e')
+            .any(|&c| (b'1'..=b'9').contains(&c))
+}
+
 fn prng(state: u32) -> u32 {
     // The state must not be zero for this algorithm to work. This also makes
     // the default seed be 1, which matches the C standard.
@@ -116,6 +161,8 @@ fn prng(state: u32) -> u32 {
 
 const RAND_MAX: i32 = i32::MAX;
 const ULONG_MAX: u32 = u32::MAX;
+const LONG_MAX: i32 = i32::MAX;
+const LONG_MIN: i32 = i32::MIN;
 
 fn srand(env: &mut Environment, seed: u32) {
     env.libc_state.stdlib.rand = seed;
@@ -183,11 +230,23 @@ fn setenv(env: &mut Environment, name: ConstPtr<u8>, value: ConstPtr<u8>, overwr
     0 // success
 }
 
-fn exit(_env: &mut Environment, exit_code: i32) {
+fn exit(env: &mut Environment, exit_code: i32) {
     echo!("App called exit(), exiting.");
+    if env.options.dump_heap_stats_on_exit {
+        print_heap_stats(env);
+    }
+    print_leak_summary(env);
+    profiler::finish(env);
     std::process::exit(exit_code);
 }
 
+fn abort(_env: &mut Environment) {
+    // This is usually reached via std::terminate(), e.g. because of an uncaught
+    // C++ exception. Panicking stops the app, and the guest stack trace is
+    // printed by the panic handling around Environment::run() in lib.rs.
+    panic!("App called abort()");
+}
+
 fn bsearch(
     env: &mut Environment,
     key: ConstVoidPtr,
@@ -229,20 +288,100 @@ fn strtof(env: &mut Environment, nptr: ConstPtr<u8>, endptr: MutPtr<ConstPtr<u8>
     if !endptr.is_null() {
         env.mem.write(endptr, nptr + length);
     }
-    number as f32
+    let number = number as f32;
+    if float_out_of_range(env, nptr, length, number.into()) {
+        set_errno(env, ERANGE);
+    }
+    number
+}
+
+fn strtol(env: &mut Environment, str: ConstPtr<u8>, endptr: MutPtr<MutPtr<u8>>, base: i32) -> i32 {
+    let (magnitude, negative, len) = strtoul_inner(env, str, base);
+    if !endptr.is_null() {
+        env.mem.write(endptr, (str + len).cast_mut());
+    }
+    let res = if negative {
+        -i128::from(magnitude)
+    } else {
+        i128::from(magnitude)
+    };
+    log_dbg!("strtol({:?}, {:?}, {}) => {}", str, endptr, base, res);
+    res.try_into().unwrap_or_else(|_| {
+        set_errno(env, ERANGE);
+        if negative {
+            LONG_MIN
+        } else {
+            LONG_MAX
+        }
+    })
 }
 
 fn strtoul(env: &mut Environment, str: ConstPtr<u8>, endptr: MutPtr<MutPtr<u8>>, base: i32) -> u32 {
-    let s = env.mem.cstr_at_utf8(str).unwrap();
-    log_dbg!("strtoul '{}'", s);
-    assert_eq!(base, 16);
-    let without_prefix = s.trim_start_matches("0x");
-    let res = u32::from_str_radix(without_prefix, 16).unwrap_or(ULONG_MAX);
+    let (magnitude, negative, len) = strtoul_inner(env, str, base);
     if !endptr.is_null() {
-        let len: GuestUSize = s.len().try_into().unwrap();
         env.mem.write(endptr, (str + len).cast_mut());
     }
-    res
+    log_dbg!(
+        "strtoul({:?}, {:?}, {}) => {}{}",
+        str,
+        endptr,
+        base,
+        if negative { "-" } else { "" },
+        magnitude
+    );
+    let Ok(res) = u32::try_from(magnitude) else {
+        set_errno(env, ERANGE);
+        return ULONG_MAX;
+    };
+    // A negative number is converted as if by unsigned negation.
+    if negative {
+        res.wrapping_neg()
+    } else {
+        res
+    }
+}
+
+/// Parses an integer like `strtol` and `strtoul` do. Returns the magnitude
+/// of the number (saturated to [u64::MAX]), whether it had a minus sign, and
+/// the number of bytes parsed, which is 0 if there was no number.
+fn strtoul_inner(env: &mut Environment, s: ConstPtr<u8>, base: i32) -> (u64, bool, GuestUSize) {
+    if base != 0 && !(2..=36).contains(&base) {
+        set_errno(env, EINVAL);
+        return (0, false, 0);
+    }
+    let mut base = base as u32;
+
+    let start = skip_whitespace(env, s);
+    let mut len = Ptr::to_bits(start) - Ptr::to_bits(s);
+    let sign = env.mem.read(s + len);
+    let negative = sign == b'-';
+    if sign == b'+' || sign == b'-' {
+        len += 1;
+    }
+    // The 0x prefix only counts if there's a hex digit after it.
+    if (base == 0 || base == 16)
+        && env.mem.read(s + len) == b'0'
+        && env.mem.read(s + len + 1).to_ascii_lowercase() == b'x'
+        && env.mem.read(s + len + 2).is_ascii_hexdigit()
+    {
+        len += 2;
+        base = 16;
+    } else if base == 0 {
+        base = if env.mem.read(s + len) == b'0' { 8 } else { 10 };
+    }
+
+    let digits_start = len;
+    let mut magnitude: u64 = 0;
+    while let Some(digit) = char::from(env.mem.read(s + len)).to_digit(base) {
+        magnitude = magnitude
+            .saturating_mul(base.into())
+            .saturating_add(digit.into());
+        len += 1;
+    }
+    if len == digits_start {
+        return (0, false, 0);
+    }
+    (magnitude, negative, len)
 }
 
 fn realpath(
@@ -340,8 +479,10 @@ pub const FUNCTIONS: FunctionExports = &[
     export_c_func!(getenv(_)),
     export_c_func!(setenv(_, _, _)),
     export_c_func!(exit(_)),
+    export_c_func!(abort()),
     export_c_func!(bsearch(_, _, _, _, _)),
     export_c_func!(strtof(_, _)),
+    export_c_func!(strtol(_, _, _)),
     export_c_func!(strtoul(_, _, _)),
     export_c_func!(realpath(_, _)),
     export_c_func_aliased!("realpath$DARWIN_EXTSN", realpath(_, _)),
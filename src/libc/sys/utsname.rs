@@ -8,15 +8,16 @@
 use crate::dyld::FunctionExports;
 use crate::environment::Environment;
 use crate::export_c_func;
+use crate::libc::errno::{set_errno, ENOSYS};
 use crate::mem::MutPtr;
 
 // TODO: struct definition
 #[allow(non_camel_case_types)]
 struct utsname {}
 
-fn uname(_env: &mut Environment, name: MutPtr<utsname>) -> i32 {
+fn uname(env: &mut Environment, name: MutPtr<utsname>) -> i32 {
     log!("TODO: uname({:?}), returning -1", name);
-    // TODO: set errno
+    set_errno(env, ENOSYS);
     -1
 }
 
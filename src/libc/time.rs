@@ -6,9 +6,10 @@
 //! `time.h` (C) and `sys/time.h` (POSIX)
 
 use crate::dyld::{export_c_func, FunctionExports};
+use crate::libc::pthread::thread::test_cancel;
 use crate::mem::{guest_size_of, ConstPtr, MutPtr, Ptr, SafeRead};
 use crate::Environment;
-use std::time::{Duration, Instant, SystemTime};
+use std::time::{Duration, SystemTime};
 
 #[derive(Default)]
 pub struct State {
@@ -30,14 +31,13 @@ type clock_t = u64;
 const CLOCKS_PER_SEC: clock_t = 1000000;
 
 fn clock(env: &mut Environment) -> clock_t {
-    Instant::now()
-        .duration_since(env.startup_time)
-        .as_secs()
-        .wrapping_mul(CLOCKS_PER_SEC)
+    env.clock.now().as_secs().wrapping_mul(CLOCKS_PER_SEC)
 }
 
 fn time(env: &mut Environment, out: MutPtr<time_t>) -> time_t {
-    let time64 = SystemTime::now()
+    let time64 = env
+        .clock
+        .system_time()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap()
         .as_secs();
@@ -286,9 +286,9 @@ unsafe impl SafeRead for timeval {}
 
 #[allow(non_camel_case_types)]
 #[repr(C, packed)]
-struct timespec {
-    tv_sec: time_t,
-    tv_nsec: i32,
+pub struct timespec {
+    pub tv_sec: time_t,
+    pub tv_nsec: i32,
 }
 unsafe impl SafeRead for timespec {}
 
@@ -319,7 +319,9 @@ fn gettimeofday(
         return 0; // success
     }
 
-    let time = SystemTime::now()
+    let time = env
+        .clock
+        .system_time()
         .duration_since(SystemTime::UNIX_EPOCH)
         .unwrap();
 
@@ -337,6 +339,9 @@ fn gettimeofday(
 }
 
 fn nanosleep(env: &mut Environment, rqtp: ConstPtr<timespec>, _rmtp: MutPtr<timespec>) -> i32 {
+    if test_cancel(env) {
+        return 0;
+    }
     let t = env.mem.read(rqtp);
     let tv_sec = t.tv_sec;
     let tv_nsec = t.tv_nsec;
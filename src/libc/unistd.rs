@@ -7,7 +7,11 @@
 
 use crate::dyld::{export_c_func, FunctionExports};
 use crate::fs::GuestPath;
-use crate::libc::posix_io::{FileDescriptor, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
+use crate::libc::errno::{set_errno, EACCES};
+use crate::libc::posix_io::{
+    errno_for_path, FileDescriptor, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO,
+};
+use crate::libc::pthread::thread::test_cancel;
 use crate::mem::ConstPtr;
 use crate::Environment;
 use std::time::Duration;
@@ -19,6 +23,9 @@ const F_OK: i32 = 0;
 const R_OK: i32 = 4;
 
 fn sleep(env: &mut Environment, seconds: u32) -> u32 {
+    if test_cancel(env) {
+        return 0;
+    }
     env.sleep(Duration::from_secs(seconds.into()), true);
     // sleep() returns the amount of time remaining that should have been slept,
     // but wasn't, if the thread was woken up early by a signal.
@@ -27,6 +34,9 @@ fn sleep(env: &mut Environment, seconds: u32) -> u32 {
 }
 
 fn usleep(env: &mut Environment, useconds: useconds_t) -> i32 {
+    if test_cancel(env) {
+        return 0;
+    }
     env.sleep(Duration::from_micros(useconds.into()), true);
     0 // success
 }
@@ -53,27 +63,21 @@ fn isatty(_env: &mut Environment, fd: FileDescriptor) -> i32 {
 }
 
 fn access(env: &mut Environment, path: ConstPtr<u8>, mode: i32) -> i32 {
-    let binding = env.mem.cstr_at_utf8(path).unwrap();
+    let binding = env.mem.cstr_at_utf8(path).unwrap().to_owned();
     let guest_path = GuestPath::new(&binding);
     let (exists, r, _, _) = env.fs.access(guest_path);
-    // TODO: set errno
-    match mode {
-        F_OK => {
-            if exists {
-                0
-            } else {
-                -1
-            }
-        }
-        R_OK => {
-            if r {
-                0
-            } else {
-                -1
-            }
+    let errno = if !exists {
+        errno_for_path(env, guest_path, false, false)
+    } else {
+        match mode {
+            F_OK => return 0,
+            R_OK if r => return 0,
+            R_OK => EACCES,
+            _ => unimplemented!("{}", mode),
         }
-        _ => unimplemented!("{}", mode),
-    }
+    };
+    set_errno(env, errno);
+    -1
 }
 
 pub const FUNCTIONS: FunctionExports = &[
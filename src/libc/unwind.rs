@@ -0,0 +1,375 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! `unwind.h` (the SjLj exception unwinder normally provided by libgcc).
+//!
+//! C++ code for iPhone OS uses "setjmp/longjmp" exception handling: each
+//! function with a `try` block or a destructor to run registers a function
+//! context, which contains a `__builtin_setjmp` buffer pointing at the
+//! function's landing pad dispatcher. Throwing an exception walks the list of
+//! registered contexts, asking the personality routine in each one (usually
+//! `__gxx_personality_sj0` in libstdc++) whether it has a handler, then jumps
+//! to the landing pads one by one.
+//!
+//! The bundled `libgcc_s.1.dylib` has an implementation of this, but it keeps
+//! the list of contexts in thread-specific data and jumps straight to a landing
+//! pad, which would skip over any host frames in between and leave them in a
+//! broken state. This implementation is used instead, so that the list of
+//! contexts can be cut off whenever host code calls guest code (see
+//! [enter_host_to_guest_call]). An exception thrown by guest code can therefore
+//! only be caught by guest code on the same thread that was called by the same
+//! host function (or not by a host function at all). If there's no handler
+//! there, it's treated as uncaught and `std::terminate()` is called.
+//!
+//! Resources:
+//! - [Itanium C++ ABI: Exception Handling](https://itanium-cxx-abi.github.io/cxx-abi/abi-eh.html)
+//! - `gcc/unwind-sjlj.c` and `gcc/unwind.inc` in the libgcc sources (see
+//!   `touchHLE_dylibs/README.md`)
+
+#![allow(non_camel_case_types)]
+
+use crate::abi::{CallFromHost, GuestFunction, FRAME_POINTER};
+use crate::cpu::Cpu;
+use crate::dyld::{export_c_func, FunctionExports};
+use crate::mem::{MutPtr, MutVoidPtr, Ptr, SafeRead};
+use crate::{Environment, ThreadId};
+use std::collections::HashMap;
+
+type _Unwind_Reason_Code = i32;
+const _URC_FOREIGN_EXCEPTION_CAUGHT: _Unwind_Reason_Code = 1;
+const _URC_FATAL_PHASE2_ERROR: _Unwind_Reason_Code = 2;
+const _URC_FATAL_PHASE1_ERROR: _Unwind_Reason_Code = 3;
+const _URC_END_OF_STACK: _Unwind_Reason_Code = 5;
+const _URC_HANDLER_FOUND: _Unwind_Reason_Code = 6;
+const _URC_INSTALL_CONTEXT: _Unwind_Reason_Code = 7;
+const _URC_CONTINUE_UNWIND: _Unwind_Reason_Code = 8;
+
+type _Unwind_Action = i32;
+const _UA_SEARCH_PHASE: _Unwind_Action = 1;
+const _UA_CLEANUP_PHASE: _Unwind_Action = 2;
+const _UA_HANDLER_FRAME: _Unwind_Action = 4;
+
+#[repr(C, packed)]
+struct _Unwind_Exception {
+    exception_class: u64,
+    /// `void (*exception_cleanup)(_Unwind_Reason_Code, _Unwind_Exception *)`
+    exception_cleanup: GuestFunction,
+    /// Non-zero for a forced unwind, which isn't supported.
+    private_1: u32,
+    /// The function context with the handler, found in the search phase.
+    private_2: MutPtr<SjLj_Function_Context>,
+}
+unsafe impl SafeRead for _Unwind_Exception {}
+
+/// Registered by each function that has a landing pad. Its layout is fixed by
+/// the code the compiler generates.
+///
+/// This is also used as the `_Unwind_Context` passed to personality routines,
+/// since it has everything they can ask about.
+#[repr(C, packed)]
+struct SjLj_Function_Context {
+    prev: MutPtr<SjLj_Function_Context>,
+    /// Which call site the function is at, as an index into its LSDA's call
+    /// site table. `-1` means exceptions aren't handled at this call site.
+    call_site: i32,
+    /// Values for the landing pad, set by `_Unwind_SetGR`: the exception
+    /// object and the selector of the handler.
+    data: [u32; 4],
+    personality: GuestFunction,
+    /// Language-specific data area (LSDA).
+    lsda: MutVoidPtr,
+    /// `__builtin_setjmp` buffer: frame pointer (r7), landing pad dispatcher
+    /// address (with the Thumb bit set if needed) and stack pointer. The other
+    /// two words aren't used on ARM.
+    jbuf: [u32; 5],
+}
+unsafe impl SafeRead for SjLj_Function_Context {}
+
+#[derive(Default)]
+pub struct State {
+    /// The most recently registered function context for each thread. The rest
+    /// are found by following [SjLj_Function_Context::prev].
+    function_contexts: HashMap<ThreadId, MutPtr<SjLj_Function_Context>>,
+    /// Function contexts that are hidden because a host-to-guest call is in
+    /// progress on that thread (see [enter_host_to_guest_call]), innermost
+    /// last.
+    hidden_function_contexts: Vec<(ThreadId, MutPtr<SjLj_Function_Context>)>,
+}
+impl State {
+    fn get(env: &mut Environment) -> &mut Self {
+        &mut env.libc_state.unwind
+    }
+    fn current_context(env: &mut Environment) -> MutPtr<SjLj_Function_Context> {
+        let thread = env.current_thread;
+        State::get(env)
+            .function_contexts
+            .get(&thread)
+            .copied()
+            .unwrap_or(Ptr::null())
+    }
+    fn set_current_context(env: &mut Environment, fc: MutPtr<SjLj_Function_Context>) {
+        let thread = env.current_thread;
+        State::get(env).function_contexts.insert(thread, fc);
+    }
+}
+
+/// Hide the current thread's function contexts from guest code that host code
+/// is about to call, so that exceptions thrown by it can't be unwound through
+/// the host code's frames. [leave_host_to_guest_call] must be called once the
+/// call is over.
+pub fn enter_host_to_guest_call(env: &mut Environment) {
+    let thread = env.current_thread;
+    let fc = State::current_context(env);
+    State::get(env).hidden_function_contexts.push((thread, fc));
+    State::set_current_context(env, Ptr::null());
+}
+
+/// Undo [enter_host_to_guest_call].
+pub fn leave_host_to_guest_call(env: &mut Environment) {
+    let (thread, fc) = State::get(env).hidden_function_contexts.pop().unwrap();
+    assert_eq!(thread, env.current_thread);
+    State::set_current_context(env, fc);
+}
+
+fn _Unwind_SjLj_Register(env: &mut Environment, fc: MutPtr<SjLj_Function_Context>) {
+    let prev = State::current_context(env);
+    let prev_ptr: MutPtr<MutPtr<SjLj_Function_Context>> = fc.cast();
+    env.mem.write(prev_ptr, prev);
+    State::set_current_context(env, fc);
+}
+
+fn _Unwind_SjLj_Unregister(env: &mut Environment, fc: MutPtr<SjLj_Function_Context>) {
+    let prev = env.mem.read(fc).prev;
+    State::set_current_context(env, prev);
+}
+
+/// Call the personality routine for a function context, if there is one.
+fn call_personality(
+    env: &mut Environment,
+    fc: MutPtr<SjLj_Function_Context>,
+    actions: _Unwind_Action,
+    exc: MutPtr<_Unwind_Exception>,
+) -> _Unwind_Reason_Code {
+    let SjLj_Function_Context {
+        call_site,
+        personality,
+        ..
+    } = env.mem.read(fc);
+    if personality.addr_with_thumb_bit() == 0 {
+        return _URC_CONTINUE_UNWIND;
+    }
+    let exception_class = env.mem.read(exc).exception_class;
+    log_dbg!(
+        "Calling personality routine {:?} for {:?} (call site {}, actions {:#x})",
+        personality,
+        fc,
+        call_site,
+        actions
+    );
+    // _Unwind_Reason_Code (*personality)(int version, _Unwind_Action actions,
+    //     _Unwind_Exception_Class exceptionClass,
+    //     _Unwind_Exception *exceptionObject, _Unwind_Context *context)
+    personality.call_from_host(env, (1i32, actions, exception_class, exc, fc))
+}
+
+/// Unwind to the landing pad of `fc`: this makes the host function that called
+/// this return to the landing pad rather than to its caller.
+fn install_context(env: &mut Environment, fc: MutPtr<SjLj_Function_Context>) {
+    let [frame_pointer, landing_pad, stack_pointer, _, _] = env.mem.read(fc).jbuf;
+    log_dbg!(
+        "Installing context {:?}: landing pad {:#x}, SP {:#x}",
+        fc,
+        landing_pad,
+        stack_pointer
+    );
+    // Functions with landing pads are registered until they return, so this
+    // one stays current until its landing pad unregisters it.
+    State::set_current_context(env, fc);
+    // Only the frame and stack pointers are restored, like __builtin_longjmp:
+    // the compiler assumes every other register is clobbered.
+    let regs = env.cpu.regs_mut();
+    regs[FRAME_POINTER] = frame_pointer;
+    regs[Cpu::SP] = stack_pointer;
+    regs[Cpu::LR] = landing_pad;
+}
+
+/// The second phase of unwinding: find the next landing pad to run, starting
+/// from the current function context. Landing pads that only run cleanups call
+/// `_Unwind_SjLj_Resume` at the end, which comes back here, until the handler
+/// found in the first phase is reached.
+fn unwind_phase2(
+    env: &mut Environment,
+    exc: MutPtr<_Unwind_Exception>,
+) -> Result<MutPtr<SjLj_Function_Context>, _Unwind_Reason_Code> {
+    let handler = env.mem.read(exc).private_2;
+    let mut fc = State::current_context(env);
+    loop {
+        if fc.is_null() {
+            return Err(_URC_FATAL_PHASE2_ERROR);
+        }
+        let match_handler = if fc == handler { _UA_HANDLER_FRAME } else { 0 };
+        match call_personality(env, fc, _UA_CLEANUP_PHASE | match_handler, exc) {
+            _URC_INSTALL_CONTEXT => return Ok(fc),
+            _URC_CONTINUE_UNWIND => (),
+            _ => return Err(_URC_FATAL_PHASE2_ERROR),
+        }
+        fc = env.mem.read(fc).prev;
+    }
+}
+
+fn _Unwind_SjLj_RaiseException(
+    env: &mut Environment,
+    exc: MutPtr<_Unwind_Exception>,
+) -> _Unwind_Reason_Code {
+    log_dbg!("_Unwind_SjLj_RaiseException({:?})", exc);
+
+    // Phase 1: search for a handler, without unwinding anything yet.
+    let mut fc = State::current_context(env);
+    loop {
+        if fc.is_null() {
+            let thread = env.current_thread;
+            if State::get(env)
+                .hidden_function_contexts
+                .iter()
+                .any(|&(hidden_thread, hidden)| hidden_thread == thread && !hidden.is_null())
+            {
+                log!("Warning: C++ exception {:?} wasn't caught by the guest code that a host function called. Catching it outside that host function isn't supported, so it's treated as uncaught.", exc);
+            }
+            return _URC_END_OF_STACK;
+        }
+        match call_personality(env, fc, _UA_SEARCH_PHASE, exc) {
+            _URC_HANDLER_FOUND => break,
+            _URC_CONTINUE_UNWIND => (),
+            _ => return _URC_FATAL_PHASE1_ERROR,
+        }
+        fc = env.mem.read(fc).prev;
+    }
+    let mut exception = env.mem.read(exc);
+    exception.private_1 = 0;
+    exception.private_2 = fc;
+    env.mem.write(exc, exception);
+
+    // Phase 2: jump to the first landing pad.
+    match unwind_phase2(env, exc) {
+        Ok(fc) => {
+            install_context(env, fc);
+            // Not seen by the caller, since we return to the landing pad.
+            _URC_INSTALL_CONTEXT
+        }
+        Err(code) => code,
+    }
+}
+
+/// Called at the end of a landing pad that didn't catch the exception, to
+/// carry on unwinding.
+fn _Unwind_SjLj_Resume(env: &mut Environment, exc: MutPtr<_Unwind_Exception>) {
+    log_dbg!("_Unwind_SjLj_Resume({:?})", exc);
+    assert!(
+        env.mem.read(exc).private_1 == 0,
+        "Forced unwinding is not supported"
+    );
+    let fc = unwind_phase2(env, exc).unwrap_or_else(|code| {
+        panic!(
+            "Failed to resume unwinding of C++ exception {:?}: {}",
+            exc, code
+        )
+    });
+    install_context(env, fc);
+}
+
+fn _Unwind_SjLj_Resume_or_Rethrow(
+    env: &mut Environment,
+    exc: MutPtr<_Unwind_Exception>,
+) -> _Unwind_Reason_Code {
+    assert!(
+        env.mem.read(exc).private_1 == 0,
+        "Forced unwinding is not supported"
+    );
+    _Unwind_SjLj_RaiseException(env, exc)
+}
+
+fn _Unwind_DeleteException(env: &mut Environment, exc: MutPtr<_Unwind_Exception>) {
+    let cleanup = env.mem.read(exc).exception_cleanup;
+    if cleanup.addr_with_thumb_bit() != 0 {
+        () = cleanup.call_from_host(env, (_URC_FOREIGN_EXCEPTION_CAUGHT, exc));
+    }
+}
+
+// Only the registers used to pass values to the landing pad are available, as
+// in gcc's unwind-sjlj.c. Other indices are ignored.
+
+fn _Unwind_GetGR(env: &mut Environment, context: MutPtr<SjLj_Function_Context>, index: i32) -> u32 {
+    // The struct is packed, so the array has to be copied out of it.
+    let data = env.mem.read(context).data;
+    let Some(&value) = data.get(index as usize) else {
+        log!("Warning: _Unwind_GetGR() with invalid index {}", index);
+        return 0;
+    };
+    value
+}
+
+fn _Unwind_SetGR(
+    env: &mut Environment,
+    context: MutPtr<SjLj_Function_Context>,
+    index: i32,
+    value: u32,
+) {
+    let mut fc = env.mem.read(context);
+    let mut data = fc.data;
+    let Some(reg) = data.get_mut(index as usize) else {
+        log!("Warning: _Unwind_SetGR() with invalid index {}", index);
+        return;
+    };
+    *reg = value;
+    fc.data = data;
+    env.mem.write(context, fc);
+}
+
+fn _Unwind_GetIP(env: &mut Environment, context: MutPtr<SjLj_Function_Context>) -> u32 {
+    // The call site index is stored off by one, so that 0 can mean "none".
+    (env.mem.read(context).call_site + 1) as u32
+}
+
+fn _Unwind_SetIP(env: &mut Environment, context: MutPtr<SjLj_Function_Context>, value: u32) {
+    let mut fc = env.mem.read(context);
+    fc.call_site = value as i32 - 1;
+    env.mem.write(context, fc);
+}
+
+fn _Unwind_GetLanguageSpecificData(
+    env: &mut Environment,
+    context: MutPtr<SjLj_Function_Context>,
+) -> MutVoidPtr {
+    env.mem.read(context).lsda
+}
+
+fn _Unwind_GetRegionStart(_env: &mut Environment, _context: MutPtr<SjLj_Function_Context>) -> u32 {
+    0 // not meaningful for SjLj
+}
+
+fn _Unwind_GetDataRelBase(_env: &mut Environment, _context: MutPtr<SjLj_Function_Context>) -> u32 {
+    0 // not meaningful for SjLj
+}
+
+fn _Unwind_GetTextRelBase(_env: &mut Environment, _context: MutPtr<SjLj_Function_Context>) -> u32 {
+    0 // not meaningful for SjLj
+}
+
+pub const FUNCTIONS: FunctionExports = &[
+    export_c_func!(_Unwind_SjLj_Register(_)),
+    export_c_func!(_Unwind_SjLj_Unregister(_)),
+    export_c_func!(_Unwind_SjLj_RaiseException(_)),
+    export_c_func!(_Unwind_SjLj_Resume(_)),
+    export_c_func!(_Unwind_SjLj_Resume_or_Rethrow(_)),
+    export_c_func!(_Unwind_DeleteException(_)),
+    export_c_func!(_Unwind_GetGR(_, _)),
+    export_c_func!(_Unwind_SetGR(_, _, _)),
+    export_c_func!(_Unwind_GetIP(_)),
+    export_c_func!(_Unwind_SetIP(_, _)),
+    export_c_func!(_Unwind_GetLanguageSpecificData(_)),
+    export_c_func!(_Unwind_GetRegionStart(_)),
+    export_c_func!(_Unwind_GetDataRelBase(_)),
+    export_c_func!(_Unwind_GetTextRelBase(_)),
+];
@@ -4,46 +4,228 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 //! Logging and terminal output macros.
+//!
+//! Which messages are logged is configured at runtime with the `--log=` option
+//! (see [configure]). By default, [log] messages are printed for all modules
+//! and [log_dbg] messages for none.
+
+use crate::options::Options;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock, RwLock};
+use std::time::Instant;
+
+/// Level of logging for a module, for the `--log=` option.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Print nothing.
+    Off,
+    /// Print messages from [log] only. This is the default.
+    Info,
+    /// Print messages from both [log] and [log_dbg].
+    Debug,
+}
+
+/// A `MODULE[=LEVEL]` entry from the `--log=` option.
+#[derive(Clone, Debug)]
+pub struct LogDirective {
+    module: String,
+    level: LogLevel,
+}
+
+impl LogDirective {
+    /// Parse a comma-separated list of `MODULE[=LEVEL]` entries, where `LEVEL`
+    /// is `off`, `info` or `debug` (the default).
+    pub fn parse_list(value: &str) -> Result<Vec<LogDirective>, String> {
+        value
+            .split(',')
+            .map(|directive| {
+                let (module, level) = directive.split_once('=').unwrap_or((directive, "debug"));
+                let level = match level {
+                    "off" => LogLevel::Off,
+                    "info" => LogLevel::Info,
+                    "debug" => LogLevel::Debug,
+                    _ => return Err(format!("Invalid log level {:?}", level)),
+                };
+                if module.is_empty() {
+                    return Err(format!("Invalid entry {:?}", directive));
+                }
+                Ok(LogDirective {
+                    module: module.to_string(),
+                    level,
+                })
+            })
+            .collect()
+    }
+
+    /// Whether this directive applies to a module. `touchHLE::mem` applies to
+    /// `touchHLE::mem` and `touchHLE::mem::allocator`, but not to
+    /// `touchHLE::memx`.
+    fn applies_to(&self, module: &str) -> bool {
+        module
+            .strip_prefix(self.module.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+    }
+}
+
+struct LogConfig {
+    directives: Vec<LogDirective>,
+    timestamps: bool,
+    thread_ids: bool,
+}
+
+static CONFIG: RwLock<LogConfig> = RwLock::new(LogConfig {
+    directives: Vec::new(),
+    timestamps: false,
+    thread_ids: false,
+});
+
+/// Whether [CONFIG] has anything other than the defaults. [log_dbg] is used in
+/// some very hot code paths, so this lets the common case avoid taking a lock.
+static CONFIGURED: AtomicBool = AtomicBool::new(false);
+
+/// Destination for a copy of all output, and its path.
+static LOG_FILE: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+
+static START_TIME: OnceLock<Instant> = OnceLock::new();
+
+/// The guest thread that is running, for `--log-thread-ids` (see
+/// [set_guest_thread]).
+static GUEST_THREAD: AtomicUsize = AtomicUsize::new(0);
+
+/// Record which guest thread is running, so log messages can be prefixed with
+/// its ID. All guest threads run on the same host thread, so the host thread
+/// ID isn't useful. This is called whenever guest code calls a host function
+/// (see [crate::abi::CallFromGuest]), which is where almost all logging
+/// happens, so it's cheap enough to do unconditionally.
+#[inline(always)]
+pub fn set_guest_thread(thread: crate::ThreadId) {
+    GUEST_THREAD.store(thread, Ordering::Relaxed);
+}
+
+fn open_log_file(path: &Path) -> Result<(), String> {
+    let mut log_file = LOG_FILE.lock().unwrap();
+    if log_file
+        .as_ref()
+        .is_some_and(|(old_path, _)| old_path == path)
+    {
+        // Re-opening would truncate it and lose what's been logged so far.
+        return Ok(());
+    }
+    let file = File::create(path)
+        .map_err(|e| format!("Couldn't create log file {}: {}", path.display(), e))?;
+    *log_file = Some((path.to_owned(), file));
+    Ok(())
+}
 
 /// Accessing log output on Android is more difficult than on other platforms;
 /// logcat requires a separate device. As an alternative, let's write to a file
-/// too.
+/// too. Only call this once, right at the start of the program!
 #[cfg(target_os = "android")]
-pub static mut LOG_FILE: Option<std::fs::File> = None;
+pub fn setup_log_file() {
+    open_log_file(&crate::paths::user_data_base_path().join("log.txt")).unwrap();
+}
 
-/// Set up log file. Only call this once, right at the start of the program!
-#[cfg(target_os = "android")]
-pub unsafe fn setup_log_file() {
+/// Apply the logging options (`--log=`, `--log-timestamps`, `--log-thread-ids`
+/// and `--log-file=`). This can be called more than once, and replaces the
+/// previous configuration, except that the log file is only ever replaced by
+/// another file.
+pub fn configure(options: &Options) -> Result<(), String> {
+    START_TIME.get_or_init(Instant::now);
+
+    if let Some(ref path) = options.log_file {
+        open_log_file(path)?;
+    }
+
+    let mut config = CONFIG.write().unwrap();
+    *config = LogConfig {
+        directives: options.log_directives.clone(),
+        timestamps: options.log_timestamps,
+        thread_ids: options.log_thread_ids,
+    };
+    CONFIGURED.store(
+        !config.directives.is_empty() || config.timestamps || config.thread_ids,
+        Ordering::Relaxed,
+    );
+    Ok(())
+}
+
+/// Only for internal use by the logging macros.
+pub fn enabled(module: &str, level: LogLevel) -> bool {
+    if !CONFIGURED.load(Ordering::Relaxed) {
+        return level <= LogLevel::Info;
+    }
+    // The most specific directive wins, or the last one if there's a tie.
+    let config = CONFIG.read().unwrap();
+    let module_level = config
+        .directives
+        .iter()
+        .filter(|directive| directive.applies_to(module))
+        .max_by_key(|directive| directive.module.len())
+        .map_or(LogLevel::Info, |directive| directive.level);
+    level <= module_level
+}
+
+/// Only for internal use by the logging macros.
+pub fn write_log(module: &str, message: std::fmt::Arguments) {
+    if !CONFIGURED.load(Ordering::Relaxed) {
+        echo_line(format_args!("{}: {}", module, message));
+        return;
+    }
+
+    let mut prefix = String::new();
     {
-        LOG_FILE = Some(
-            std::fs::File::create(crate::paths::user_data_base_path().join("log.txt")).unwrap(),
-        );
+        let config = CONFIG.read().unwrap();
+        if config.timestamps {
+            let elapsed = START_TIME.get_or_init(Instant::now).elapsed();
+            prefix.push_str(&format!("[{:10.6}] ", elapsed.as_secs_f64()));
+        }
+        if config.thread_ids {
+            let thread = GUEST_THREAD.load(Ordering::Relaxed);
+            prefix.push_str(&format!("[thread {}] ", thread));
+        }
     }
+    echo_line(format_args!("{}{}: {}", prefix, module, message));
 }
 
 /// Only for internal use by the logging macros.
-#[cfg(target_os = "android")]
-pub fn get_log_file() -> &'static std::fs::File {
-    unsafe { LOG_FILE.as_ref().unwrap() }
+pub fn echo_line(line: std::fmt::Arguments) {
+    #[cfg(target_os = "android")]
+    let line = {
+        let line = line.to_string();
+        sdl2::log::log(&line);
+        line
+    };
+    #[cfg(not(target_os = "android"))]
+    eprintln!("{}", line);
+
+    if let Some((_, ref mut file)) = *LOG_FILE.lock().unwrap() {
+        let _ = writeln!(file, "{}", line);
+    }
 }
 
-/// Prints a log message unconditionally. Use this for errors or warnings.
+/// Prints a log message, unless logging has been turned off for the module
+/// where it is used. Use this for errors or warnings.
 ///
 /// The message is prefixed with the module path, so it is clear where it comes
 /// from.
 macro_rules! log {
     ($($arg:tt)+) => {
-        echo!("{}: {}", module_path!(), format_args!($($arg)+));
+        if $crate::log::enabled(module_path!(), $crate::log::LogLevel::Info) {
+            $crate::log::write_log(module_path!(), format_args!($($arg)+));
+        }
     }
 }
 
 /// Like [log], but prints the message only if debugging is enabled for the
-/// module where it is used. This can be used for verbose things only needed
-/// when debugging.
+/// module where it is used (e.g. `--log=touchHLE::mem`). This can be used for
+/// verbose things only needed when debugging.
 macro_rules! log_dbg {
     ($($arg:tt)+) => {
-        if $crate::log::ENABLED_MODULES.contains(&module_path!()) {
-            log!($($arg)*);
+        if $crate::log::enabled(module_path!(), $crate::log::LogLevel::Debug) {
+            $crate::log::write_log(module_path!(), format_args!($($arg)+));
         }
     }
 }
@@ -54,34 +236,24 @@ macro_rules! log_dbg {
 /// Prefer use [log] or [log_dbg] for errors and warnings during emulation.
 macro_rules! echo {
     ($($arg:tt)+) => {
-        {
-            #[cfg(target_os = "android")]
-            {
-                let formatted_str = format!($($arg)+);
-                sdl2::log::log(&formatted_str);
-                use std::io::Write;
-                let mut log_file = $crate::log::get_log_file();
-                let _ = log_file.write_all(formatted_str.as_bytes());
-                let _ = log_file.write_all(b"\n");
-            }
-            #[cfg(not(target_os = "android"))]
-            eprintln!($($arg)+);
-        }
+        $crate::log::echo_line(format_args!($($arg)+))
     };
     () => {
-        {
-            #[cfg(target_os = "android")]
-            {
-                sdl2::log::log("");
-                use std::io::Write;
-                let _ = $crate::log::get_log_file().write_all(b"\n");
-            }
-            #[cfg(not(target_os = "android"))]
-            eprintln!("");
-        }
+        $crate::log::echo_line(format_args!(""))
     }
 }
 
-/// Put modules to enable [log_dbg] for here, e.g. "touchHLE::mem" to see when
-/// memory is allocated and freed.
-pub const ENABLED_MODULES: &[&str] = &[];
+#[cfg(test)]
+#[test]
+fn test_log_directives() {
+    let directives = LogDirective::parse_list("touchHLE::mem,touchHLE::gdb=off").unwrap();
+    assert_eq!(directives[0].level, LogLevel::Debug);
+    assert_eq!(directives[1].level, LogLevel::Off);
+    assert!(directives[0].applies_to("touchHLE::mem"));
+    assert!(directives[0].applies_to("touchHLE::mem::allocator"));
+    assert!(!directives[0].applies_to("touchHLE::memx"));
+    assert!(!directives[0].applies_to("touchHLE"));
+
+    assert!(LogDirective::parse_list("touchHLE::mem=verbose").is_err());
+    assert!(LogDirective::parse_list("touchHLE::mem,").is_err());
+}
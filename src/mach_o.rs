@@ -20,7 +20,7 @@
 
 use crate::abi::GuestFunction;
 use crate::fs::{Fs, GuestPath};
-use crate::mem::{Mem, Ptr};
+use crate::mem::{Mem, Protection, Ptr};
 use mach_object::{
     cpu_subtype_t, vm_prot_t, DyLib, LoadCommand, MachCommand, OFile, Symbol, SymbolIter,
     ThreadState, N_ARM_THUMB_DEF, S_LAZY_SYMBOL_POINTERS, S_MOD_INIT_FUNC_POINTERS,
@@ -31,7 +31,6 @@ use std::io::{Cursor, Seek, SeekFrom};
 
 const VM_PROT_READ: vm_prot_t = 1;
 const VM_PROT_WRITE: vm_prot_t = 2;
-#[allow(dead_code)]
 const VM_PROT_EXECUTE: vm_prot_t = 4;
 
 #[derive(Debug)]
@@ -46,6 +45,10 @@ pub struct MachO {
     /// can look things up quickly. Thumb function symbols always have the Thumb
     /// bit set.
     pub exported_symbols: HashMap<String, u32>,
+    /// All symbols defined by the binary, including local (non-exported) ones,
+    /// sorted by address. This is used for symbolication. Unlike
+    /// [Self::exported_symbols], the addresses never have the Thumb bit set.
+    pub symbols: Vec<(u32, String)>,
     /// List of addresses and names of external relocations for the dynamic
     /// linker to resolve.
     pub external_relocations: Vec<(u32, String)>,
@@ -306,6 +309,7 @@ impl MachO {
         // Info used for the result
         let mut dynamic_libraries = Vec::new();
         let mut exported_symbols = HashMap::new();
+        let mut defined_symbols = Vec::new();
         let mut indirect_undef_symbols: Vec<Option<String>> = Vec::new();
         let mut external_relocations: Vec<(u32, String)> = Vec::new();
         let mut entry_point_pc: Option<u32> = None;
@@ -361,6 +365,18 @@ impl MachO {
                     if load_me {
                         into_mem.reserve(vmaddr, vmsize);
 
+                        let mut protection = Protection::NONE;
+                        if (initprot & VM_PROT_READ) != 0 {
+                            protection |= Protection::READ;
+                        }
+                        if (initprot & VM_PROT_WRITE) != 0 {
+                            protection |= Protection::WRITE;
+                        }
+                        if (initprot & VM_PROT_EXECUTE) != 0 {
+                            protection |= Protection::EXECUTE;
+                        }
+                        into_mem.protect_segment(vmaddr, vmsize, protection);
+
                         // If filesize is less than vmsize, the rest of the
                         // segment should be filled with zeroes. We are assuming
                         // the memory is already zeroed!
@@ -394,18 +410,25 @@ impl MachO {
                             is_64bit,
                         );
                         for symbol in symbols {
+                            // Debug (stabs) symbols are skipped: in a linked
+                            // binary, anything they name also has a regular
+                            // symbol.
                             if let Symbol::Debug { .. } = symbol {
                                 continue;
                             }
                             if let Symbol::Defined {
                                 name: Some(name),
-                                external: true,
+                                external,
                                 entry,
                                 desc,
                                 ..
                             } = symbol
                             {
                                 let entry: u32 = entry.try_into().unwrap();
+                                defined_symbols.push((entry, name.to_string()));
+                                if !external {
+                                    continue;
+                                }
                                 let entry = if desc & N_ARM_THUMB_DEF != 0 {
                                     entry | GuestFunction::THUMB_BIT
                                 } else {
@@ -538,7 +561,8 @@ impl MachO {
                     stacksize,
                 } => {
                     if stacksize != 0 {
-                        log!("TODO: stack size of {:#x} bytes requested", stacksize);
+                        log_dbg!("Stack size of {:#x} bytes requested", stacksize);
+                        into_mem.grow_main_thread_stack(stacksize.try_into().unwrap());
                     }
                     // There should only be a single entry point.
                     // (Presumably an executable won't use both commands?)
@@ -606,11 +630,14 @@ impl MachO {
             })
             .collect();
 
+        defined_symbols.sort();
+
         Ok(MachO {
             name,
             dynamic_libraries,
             sections,
             exported_symbols,
+            symbols: defined_symbols,
             external_relocations,
             entry_point_pc,
         })
@@ -17,6 +17,15 @@
 use crate::libc::wchar::wchar_t;
 
 mod allocator;
+mod debug_heap;
+mod protection;
+mod snapshot;
+mod watchpoints;
+
+pub use allocator::AllocatorStats;
+pub use debug_heap::print_leak_summary;
+pub use protection::{Access, PageProtections, Protection};
+pub use watchpoints::{WatchpointKind, Watchpoints};
 
 /// Equivalent of `usize` for guest memory.
 pub type GuestUSize = u32;
@@ -183,6 +192,7 @@ unsafe impl SafeRead for u64 {}
 unsafe impl SafeRead for f32 {}
 unsafe impl SafeRead for f64 {}
 unsafe impl<T, const MUT: bool> SafeRead for Ptr<T, MUT> {}
+unsafe impl<T: SafeRead, const N: usize> SafeRead for [T; N] {}
 
 /// Marker trait for types that can be written to guest memory.
 ///
@@ -234,6 +244,19 @@ pub struct Mem {
     null_segment_size: VAddr,
 
     allocator: allocator::Allocator,
+    protections: PageProtections,
+    /// See [Self::set_enforce_segment_protection].
+    enforce_segment_protection: bool,
+    watchpoints: Watchpoints,
+    /// [Some] if `--debug-heap` is enabled.
+    debug_heap: Option<debug_heap::DebugHeap>,
+
+    /// See [Self::set_memory_limit].
+    memory_limit: Option<u64>,
+    memory_warning_threshold: Option<u64>,
+    /// Set when allocated memory goes above the warning threshold, see
+    /// [Self::take_memory_warning].
+    memory_warning_pending: bool,
 }
 
 impl Drop for Mem {
@@ -256,6 +279,11 @@ impl Mem {
     /// space (see also: stack.rs), I have no idea if this matches iPhone OS.
     pub const MAIN_THREAD_STACK_LOW_END: VAddr = 0u32.wrapping_sub(Self::MAIN_THREAD_STACK_SIZE);
 
+    /// Address of the guard page below the main thread's stack, which is never
+    /// accessible, so that a stack overflow can't silently corrupt other
+    /// memory.
+    pub const MAIN_THREAD_STACK_GUARD_PAGE: VAddr = Self::MAIN_THREAD_STACK_LOW_END - 0x1000;
+
     /// iPhone OS secondary thread stack size.
     pub const SECONDARY_THREAD_STACK_SIZE: GuestUSize = 512 * 1024;
 
@@ -271,6 +299,13 @@ impl Mem {
             bytes,
             null_segment_size: 0,
             allocator,
+            protections: Self::initial_protections(),
+            enforce_segment_protection: false,
+            watchpoints: Watchpoints::default(),
+            debug_heap: None,
+            memory_limit: None,
+            memory_warning_threshold: None,
+            memory_warning_pending: false,
         }
     }
 
@@ -284,7 +319,20 @@ impl Mem {
             bytes: _,
             null_segment_size: _,
             ref mut allocator,
+            ref mut protections,
+            enforce_segment_protection: _,
+            ref mut watchpoints,
+            ref mut debug_heap,
+            memory_limit: _,
+            memory_warning_threshold: _,
+            ref mut memory_warning_pending,
         } = mem;
+        *protections = Self::initial_protections();
+        *watchpoints = Watchpoints::default();
+        *memory_warning_pending = false;
+        if let Some(debug_heap) = debug_heap {
+            *debug_heap = Default::default();
+        }
         let used_chunks = allocator.reset_and_drain_used_chunks();
         for allocator::Chunk { base, size } in used_chunks {
             mem.bytes_mut()[base as usize..][..size.get() as usize].fill(0);
@@ -293,26 +341,27 @@ impl Mem {
         mem
     }
 
+    fn initial_protections() -> PageProtections {
+        let mut protections = PageProtections::new();
+        // The allocator reserves this page.
+        protections.set(Self::MAIN_THREAD_STACK_GUARD_PAGE, 0x1000, Protection::NONE);
+        protections
+    }
+
     /// Sets up the null segment of the given size. There's no reason to call
     /// this outside of binary loading, and it won't be respected even if you
     /// do. The size must not have been set already, and must be page aligned.
     pub fn set_null_segment_size(&mut self, new_null_segment_size: VAddr) {
-        // TODO?: Maybe this should be replaced with a per-page rwx/callback
-        //        setting? Currently we don't properly follow segment
-        //        protections, which means that applications can write into
-        //        segments they shouldn't be able to. Adding that would fix
-        //        this, along with removing this special case.
+        // TODO?: Host code's accesses are only checked against the null
+        //        segment, not the page protections. Maybe they should be?
         assert!(self.null_segment_size == 0);
         assert!(new_null_segment_size % 0x1000 == 0);
         self.allocator
             .reserve(allocator::Chunk::new(0, new_null_segment_size));
+        self.protect(0, new_null_segment_size, Protection::NONE);
         self.null_segment_size = new_null_segment_size;
     }
 
-    pub fn null_segment_size(&self) -> VAddr {
-        self.null_segment_size
-    }
-
     /// Get a pointer to the full 4GiB of memory. This is only for use when
     /// setting up the CPU, never call this otherwise.
     ///
@@ -323,6 +372,108 @@ impl Mem {
         self.bytes.cast()
     }
 
+    /// Set the protection of the pages covering `size` bytes starting at
+    /// `base`, which must be page-aligned. If the CPU has already been created,
+    /// [crate::cpu::Cpu::update_page_direct_access] must then be called for
+    /// each page.
+    pub fn protect(&mut self, base: VAddr, size: GuestUSize, protection: Protection) {
+        self.protections.set(base, size, protection);
+    }
+
+    /// Set whether the app binary's segments get the protection they ask for
+    /// (`--enforce-segment-protection`). This is off by default because
+    /// direct memory access can't be used for pages that aren't both readable
+    /// and writable, and `__TEXT` contains constants that are read often, so
+    /// the segments are left readable, writable and executable. Must be
+    /// called before binaries are loaded.
+    pub fn set_enforce_segment_protection(&mut self, enforce: bool) {
+        self.enforce_segment_protection = enforce;
+    }
+
+    /// Set the protection for a segment of a binary that is being loaded. This
+    /// does nothing unless [Self::set_enforce_segment_protection] was used.
+    pub fn protect_segment(&mut self, base: VAddr, size: GuestUSize, protection: Protection) {
+        if self.enforce_segment_protection {
+            self.protect(base, size, protection);
+        }
+    }
+
+    /// Allocate a stack of `size` bytes for a secondary thread, with a guard
+    /// page below it that can never be accessed, so that a stack overflow
+    /// can't silently corrupt other memory. Returns the allocation, which must
+    /// later be freed with [Self::free_thread_stack], and the address just
+    /// above the top of the stack (the initial stack pointer).
+    ///
+    /// [crate::cpu::Cpu::update_page_direct_access] must then be called for
+    /// [Self::thread_stack_guard_page] of the allocation.
+    pub fn alloc_thread_stack(&mut self, size: GuestUSize) -> (MutVoidPtr, VAddr) {
+        let size = size.checked_add(0xfff).unwrap() & !0xfff;
+        // The allocator doesn't align to pages, so allocate an extra page to
+        // leave room for aligning the guard page.
+        let stack = self.alloc(size.checked_add(0x2000).unwrap());
+        let guard_page = Self::thread_stack_guard_page(stack);
+        self.protect(guard_page, 0x1000, Protection::NONE);
+        (stack, guard_page + 0x1000 + size)
+    }
+
+    /// Get the guard page of a stack allocated with
+    /// [Self::alloc_thread_stack].
+    pub fn thread_stack_guard_page(stack: MutVoidPtr) -> VAddr {
+        (stack.to_bits() + 0xfff) & !0xfff
+    }
+
+    /// Free a stack allocated with [Self::alloc_thread_stack].
+    /// [crate::cpu::Cpu::update_page_direct_access] must then be called for
+    /// [Self::thread_stack_guard_page] of the allocation.
+    pub fn free_thread_stack(&mut self, stack: MutVoidPtr) {
+        let guard_page = Self::thread_stack_guard_page(stack);
+        self.protect(guard_page, 0x1000, Protection::ALL);
+        self.free(stack);
+    }
+
+    pub fn page_protections(&self) -> &PageProtections {
+        &self.protections
+    }
+    pub fn page_protections_mut(&mut self) -> &mut PageProtections {
+        &mut self.protections
+    }
+
+    /// Limit the total size of allocations, like the RAM of a real device
+    /// (`--memory-limit=`), and set the size above which the app should be
+    /// sent a memory warning (`--memory-warning-threshold=`). If there's a
+    /// limit but no threshold, the threshold is 80% of the limit.
+    ///
+    /// When the limit would be exceeded, allocations by guest code fail (e.g.
+    /// `malloc()` returns `NULL`), and allocations by host code panic, much as
+    /// the OS would kill the app.
+    pub fn set_memory_limit(&mut self, limit: Option<u64>, warning_threshold: Option<u64>) {
+        self.memory_limit = limit;
+        self.memory_warning_threshold = warning_threshold.or(limit.map(|limit| limit / 5 * 4));
+    }
+
+    /// Returns [true] if allocated memory has gone above the memory warning
+    /// threshold (see [Self::set_memory_limit]) since the last call.
+    pub fn take_memory_warning(&mut self) -> bool {
+        std::mem::take(&mut self.memory_warning_pending)
+    }
+
+    /// Get statistics about heap usage, e.g. for `--dump-heap-stats-on-exit`.
+    pub fn allocator_stats(&self) -> AllocatorStats {
+        match self.debug_heap {
+            Some(ref debug_heap) => self
+                .allocator
+                .stats(|base| debug_heap.is_quarantined_chunk(base)),
+            None => self.allocator.stats(|_| false),
+        }
+    }
+
+    pub fn watchpoints(&self) -> &Watchpoints {
+        &self.watchpoints
+    }
+    pub fn watchpoints_mut(&mut self) -> &mut Watchpoints {
+        &mut self.watchpoints
+    }
+
     fn bytes(&self) -> &Bytes {
         unsafe { &*self.bytes }
     }
@@ -478,30 +629,119 @@ impl Mem {
             .copy_within(src..src.checked_add(size).unwrap(), dest)
     }
 
+    #[cold]
+    fn out_of_memory(size: GuestUSize) -> ! {
+        panic!(
+            "Out of memory: allocating {:#x} bytes would exceed the memory limit!",
+            size
+        )
+    }
+
     /// Allocate `size` bytes.
     pub fn alloc(&mut self, size: GuestUSize) -> MutVoidPtr {
-        let ptr = Ptr::from_bits(self.allocator.alloc(size));
+        self.alloc_inner(size, None)
+            .unwrap_or_else(|| Self::out_of_memory(size))
+    }
+
+    /// Like [Self::alloc], but for guest code calling an allocation function
+    /// such as `malloc()`. `caller` is the function's return address, which
+    /// `--debug-heap` remembers as where the allocation was made.
+    ///
+    /// Unlike [Self::alloc], this returns null if the memory limit (see
+    /// [Self::set_memory_limit]) would be exceeded.
+    pub fn alloc_for_guest(&mut self, size: GuestUSize, caller: VAddr) -> MutVoidPtr {
+        self.alloc_inner(size, Some(caller)).unwrap_or_default()
+    }
+
+    fn alloc_inner(&mut self, size: GuestUSize, site: Option<VAddr>) -> Option<MutVoidPtr> {
+        let allocated_before = self.allocator.allocated_bytes();
+        // This ignores rounding and red zones, but it's close enough.
+        if self
+            .memory_limit
+            .is_some_and(|limit| allocated_before + u64::from(size) > limit)
+        {
+            log!(
+                "Can't allocate {:#x} bytes, {:#x} of {:#x} bytes are in use!",
+                size,
+                allocated_before,
+                self.memory_limit.unwrap()
+            );
+            return None;
+        }
+
+        let ptr = Ptr::from_bits(if self.debug_heap.is_some() {
+            self.debug_heap_alloc(size, site)
+        } else {
+            self.allocator.alloc(size)
+        });
         log_dbg!("Allocated {:?} ({:#x} bytes)", ptr, size);
-        ptr
+
+        if let Some(threshold) = self.memory_warning_threshold {
+            let allocated = self.allocator.allocated_bytes();
+            if allocated_before < threshold && allocated >= threshold {
+                log!(
+                    "{:#x} bytes in use, above the memory warning threshold.",
+                    allocated
+                );
+                self.memory_warning_pending = true;
+            }
+        }
+
+        Some(ptr)
     }
 
     pub fn realloc(&mut self, old_ptr: MutVoidPtr, size: GuestUSize) -> MutVoidPtr {
-        // TODO: for a moment we always assume that we do not have enough size
-        //       to realloc inplace
-        let old_size = self.allocator.find_allocated_size(old_ptr.to_bits());
-        if old_size >= size {
-            return old_ptr;
-        }
-        let new_ptr = self.alloc(size);
-        self.memmove(new_ptr, old_ptr.cast_const(), old_size);
+        self.realloc_inner(old_ptr, size, None)
+            .unwrap_or_else(|| Self::out_of_memory(size))
+    }
+
+    /// Like [Self::realloc], but for guest code calling `realloc()`. See
+    /// [Self::alloc_for_guest]. If this returns null, the old allocation is
+    /// left alone.
+    pub fn realloc_for_guest(
+        &mut self,
+        old_ptr: MutVoidPtr,
+        size: GuestUSize,
+        caller: VAddr,
+    ) -> MutVoidPtr {
+        self.realloc_inner(old_ptr, size, Some(caller))
+            .unwrap_or_default()
+    }
+
+    fn realloc_inner(
+        &mut self,
+        old_ptr: MutVoidPtr,
+        size: GuestUSize,
+        site: Option<VAddr>,
+    ) -> Option<MutVoidPtr> {
+        let old_size = if self.debug_heap.is_some() {
+            self.debug_heap_allocated_size(old_ptr.to_bits())
+        } else {
+            // TODO: for a moment we always assume that we do not have enough
+            //       size to realloc inplace
+            let old_size = self.allocator.find_allocated_size(old_ptr.to_bits());
+            if old_size >= size {
+                return Some(old_ptr);
+            }
+            old_size
+        };
+        // With --debug-heap, the allocation always moves, so that stale
+        // pointers to the old one are caught.
+        let new_ptr = self.alloc_inner(size, site)?;
+        self.memmove(new_ptr, old_ptr.cast_const(), old_size.min(size));
         self.free(old_ptr);
-        new_ptr
+        Some(new_ptr)
     }
 
     /// Free an allocation made with one of the `alloc` methods on this type.
     pub fn free(&mut self, ptr: MutVoidPtr) {
-        let size = self.allocator.free(ptr.to_bits());
-        self.bytes_at_mut(ptr.cast(), size).fill(0);
+        let size = if self.debug_heap.is_some() {
+            self.debug_heap_free(ptr.to_bits())
+        } else {
+            let size = self.allocator.free(ptr.to_bits());
+            self.bytes_at_mut(ptr.cast(), size).fill(0);
+            size
+        };
         log_dbg!("Freed {:?} ({:#x} bytes)", ptr, size);
     }
 
@@ -561,4 +801,85 @@ impl Mem {
     pub fn reserve(&mut self, base: VAddr, size: GuestUSize) {
         self.allocator.reserve(allocator::Chunk::new(base, size));
     }
+
+    /// Grow the main thread's stack downwards so it's at least `size` bytes,
+    /// moving the guard page beneath it. This is for binaries that request a
+    /// bigger stack, and must happen before anything is allocated in the space
+    /// needed.
+    pub fn grow_main_thread_stack(&mut self, size: GuestUSize) {
+        let size = size.checked_add(0xfff).unwrap() & !0xfff;
+        if size <= Self::MAIN_THREAD_STACK_SIZE {
+            return;
+        }
+        let extra = size - Self::MAIN_THREAD_STACK_SIZE;
+        let new_guard_page = Self::MAIN_THREAD_STACK_GUARD_PAGE
+            .checked_sub(extra)
+            .unwrap();
+        // The old guard page becomes part of the stack, so this reserves the
+        // new guard page plus everything between it and the old one.
+        self.reserve(new_guard_page, extra);
+        self.protect(new_guard_page + 0x1000, extra, Protection::ALL);
+        self.protect(new_guard_page, 0x1000, Protection::NONE);
+        log_dbg!(
+            "Grew main thread stack to {:#x} bytes, new guard page at {:#x}",
+            size,
+            new_guard_page
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_mem() -> Mem {
+        let mut mem = Mem::new();
+        // Otherwise the first allocation could be at address 0.
+        mem.set_null_segment_size(0x1000);
+        mem
+    }
+
+    #[test]
+    fn memory_limit() {
+        let mut mem = new_mem();
+        mem.set_memory_limit(Some(1024), None);
+        let a = mem.alloc_for_guest(512, 0);
+        assert!(!a.is_null());
+        // 512 + 600 > 1024
+        assert!(mem.alloc_for_guest(600, 0).is_null());
+        // A failed realloc leaves the old allocation alone.
+        mem.write(a.cast(), 0xabu8);
+        assert!(mem.realloc_for_guest(a, 1100, 0).is_null());
+        assert_eq!(mem.read(a.cast::<u8>()), 0xab);
+        mem.free(a);
+        let b = mem.alloc_for_guest(600, 0);
+        assert!(!b.is_null());
+        mem.free(b);
+    }
+
+    #[test]
+    fn memory_warning_threshold() {
+        let mut mem = new_mem();
+        // If there's only a limit, the threshold is 80% of it.
+        mem.set_memory_limit(Some(1000), None);
+        assert_eq!(mem.memory_warning_threshold, Some(800));
+
+        mem.set_memory_limit(None, Some(256));
+        let a = mem.alloc_for_guest(128, 0);
+        assert!(!mem.take_memory_warning());
+        let b = mem.alloc_for_guest(128, 0);
+        assert!(mem.take_memory_warning());
+        assert!(!mem.take_memory_warning());
+        // Going further above the threshold doesn't warn again.
+        let c = mem.alloc_for_guest(128, 0);
+        assert!(!mem.take_memory_warning());
+        // Dropping below and crossing it again does.
+        mem.free(b);
+        mem.free(c);
+        assert!(!mem.take_memory_warning());
+        let d = mem.alloc_for_guest(256, 0);
+        assert!(mem.take_memory_warning());
+        mem.free(a);
+        mem.free(d);
+    }
 }
@@ -4,7 +4,7 @@
  * file, You can obtain one at https://mozilla.org/MPL/2.0/.
  */
 use super::{GuestUSize, Mem, VAddr};
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::num::NonZeroU32;
 
 /// iPhone OS's allocator always aligns to 16 bytes at minimum, and this
@@ -31,7 +31,7 @@ impl Chunk {
     }
 
     #[inline(always)]
-    fn last_byte(&self) -> VAddr {
+    pub(super) fn last_byte(&self) -> VAddr {
         self.base + (self.size.get() - 1)
     }
 
@@ -96,6 +96,90 @@ mod chunk_tests {
     }
 }
 
+#[cfg(test)]
+mod allocator_tests {
+    use super::{Allocator, Chunk, Mem};
+    #[test]
+    fn stats() {
+        let mut allocator = Allocator::new();
+        let stats = allocator.stats(|_| false);
+        assert_eq!(stats.allocated_chunks, 0);
+        // The main thread's stack and its guard page.
+        assert_eq!(stats.reserved_chunks, 2);
+        assert_eq!(
+            stats.reserved_bytes,
+            u64::from(Mem::MAIN_THREAD_STACK_SIZE) + 0x1000
+        );
+
+        allocator.reserve(Chunk::new(0x1000, 0x2000));
+        let a = allocator.alloc(100);
+        let b = allocator.alloc(16);
+        let stats = allocator.stats(|_| false);
+        assert_eq!(stats.reserved_chunks, 3);
+        assert_eq!(stats.allocated_chunks, 2);
+        assert_eq!(stats.allocated_bytes, 112 + 16);
+        assert_eq!(stats.histogram[0].0, 16);
+        assert_eq!(stats.histogram[0].1, 1);
+        assert_eq!(stats.histogram[2].0, 64);
+        assert_eq!(stats.histogram[2].1, 1);
+
+        let stats = allocator.stats(|base| base == a);
+        assert_eq!(stats.allocated_chunks, 1);
+        assert_eq!(stats.allocated_bytes, 16);
+        assert_eq!(stats.quarantined_chunks, 1);
+        assert_eq!(stats.quarantined_bytes, 112);
+        assert_eq!(stats.histogram[2].1, 0);
+
+        let _ = allocator.free(a);
+        let _ = allocator.free(b);
+        let stats = allocator.stats(|_| false);
+        assert_eq!(stats.allocated_chunks, 0);
+        assert_eq!(stats.allocated_bytes, 0);
+        assert_eq!(stats.reserved_chunks, 3);
+        assert_eq!(stats.free_bytes + stats.reserved_bytes, 1 << 32);
+    }
+
+    #[test]
+    fn reserved_bases() {
+        let mut allocator = Allocator::new();
+        allocator.reserve(Chunk::new(0x1000, 0x2000));
+        assert!(allocator.reserved_bases.contains(&0x1000));
+        let a = allocator.alloc(16);
+        assert!(!allocator.reserved_bases.contains(&a));
+
+        // Freeing a reserved chunk forgets that it was reserved, so the memory
+        // can be reused for an allocation.
+        assert_eq!(allocator.free(0x1000), 0x2000);
+        assert!(!allocator.reserved_bases.contains(&0x1000));
+        let stats = allocator.stats(|_| false);
+        assert_eq!(stats.reserved_chunks, 2);
+        assert_eq!(stats.allocated_chunks, 1);
+    }
+
+    #[test]
+    fn allocated_bytes() {
+        let mut allocator = Allocator::new();
+        assert_eq!(allocator.allocated_bytes(), 0);
+        // Reserved chunks don't count, whether they're reserved or freed.
+        allocator.reserve(Chunk::new(0x1000, 0x2000));
+        assert_eq!(allocator.allocated_bytes(), 0);
+        // Sizes are rounded up to the chunk size.
+        let a = allocator.alloc(100);
+        assert_eq!(allocator.allocated_bytes(), 112);
+        let b = allocator.alloc(1);
+        assert_eq!(allocator.allocated_bytes(), 112 + 16);
+        let _ = allocator.free(0x1000);
+        assert_eq!(allocator.allocated_bytes(), 112 + 16);
+        let _ = allocator.free(a);
+        assert_eq!(allocator.allocated_bytes(), 16);
+        let _ = allocator.free(b);
+        assert_eq!(allocator.allocated_bytes(), 0);
+        // Freeing something unknown changes nothing.
+        let _ = allocator.free(b);
+        assert_eq!(allocator.allocated_bytes(), 0);
+    }
+}
+
 /// Specialized collection types. They're kept in their own module so the
 /// allocator can only access them via their public methods, so that there's
 /// less places inconsistencies between the sub-collections could happen.
@@ -134,6 +218,12 @@ mod collections {
         pub fn get_size_with_base(&self, base: VAddr) -> Option<NonZeroU32> {
             self.chunks.get(&base).copied()
         }
+        /// Iterate over the chunks in address order.
+        pub fn iter(&self) -> impl Iterator<Item = Chunk> + '_ {
+            self.chunks
+                .iter()
+                .map(|(&base, &size)| Chunk { base, size })
+        }
     }
 
     #[derive(Default, Debug)]
@@ -144,7 +234,7 @@ mod collections {
     impl SizeBucketedChunkMap {
         /// Get log2 size bucket for chunk.
         #[inline(always)]
-        const fn bucket_for(size: GuestUSize) -> usize {
+        pub const fn bucket_for(size: GuestUSize) -> usize {
             (size.ilog2() - MIN_CHUNK_SIZE.ilog2()) as usize
         }
 
@@ -246,32 +336,69 @@ mod collections {
                 .flat_map(|chunks| chunks.iter())
                 .copied()
         }
+
+        /// Number of chunks in each log2 size bucket, smallest first.
+        pub fn bucket_lengths(&self) -> impl Iterator<Item = usize> + '_ {
+            self.chunks_by_log2_size.iter().map(|chunks| chunks.len())
+        }
     }
 }
 use collections::{ChunkMap, SizeBucketedChunkMap};
 
+/// Statistics about the allocator's state, see [Allocator::stats].
+pub struct AllocatorStats {
+    /// Chunks handed out by [Allocator::alloc] and not yet freed.
+    pub allocated_chunks: usize,
+    pub allocated_bytes: u64,
+    /// Chunks reserved with [Allocator::reserve], plus the main thread's stack.
+    pub reserved_chunks: usize,
+    pub reserved_bytes: u64,
+    /// Chunks that have been freed by the app but are being kept in
+    /// `--debug-heap`'s quarantine, so they aren't available yet. These are not
+    /// counted as allocated.
+    pub quarantined_chunks: usize,
+    pub quarantined_bytes: u64,
+    pub free_chunks: usize,
+    pub free_bytes: u64,
+    pub largest_free_chunk: GuestUSize,
+    /// The smallest size in each log2 size bucket (starting from
+    /// [MIN_CHUNK_SIZE]), and the number of allocated and free chunks in it.
+    pub histogram: Vec<(GuestUSize, usize, usize)>,
+}
+
 /// Tracks which memory is in use and makes allocations from it.
 #[derive(Debug)]
 pub struct Allocator {
     used_chunks: ChunkMap,
     unused_chunks: SizeBucketedChunkMap,
+    /// Bases of the chunks in `used_chunks` that were reserved rather than
+    /// allocated.
+    reserved_bases: BTreeSet<VAddr>,
+    /// Total size of the chunks in `used_chunks` that were allocated.
+    allocated_bytes: u64,
 }
 
 impl Allocator {
     pub fn new() -> Allocator {
         let main_thread_stack =
             Chunk::new(Mem::MAIN_THREAD_STACK_LOW_END, Mem::MAIN_THREAD_STACK_SIZE);
-        let rest = Chunk::new(0, Mem::MAIN_THREAD_STACK_LOW_END);
+        let main_thread_stack_guard = Chunk::new(Mem::MAIN_THREAD_STACK_GUARD_PAGE, 0x1000);
+        let rest = Chunk::new(0, Mem::MAIN_THREAD_STACK_GUARD_PAGE);
 
         let mut used_chunks: ChunkMap = Default::default();
         used_chunks.insert(main_thread_stack);
+        used_chunks.insert(main_thread_stack_guard);
 
         let mut unused_chunks: SizeBucketedChunkMap = Default::default();
         unused_chunks.insert(rest);
 
+        let reserved_bases = BTreeSet::from([main_thread_stack.base, main_thread_stack_guard.base]);
+
         Allocator {
             used_chunks,
             unused_chunks,
+            reserved_bases,
+            allocated_bytes: 0,
         }
     }
 
@@ -297,6 +424,7 @@ impl Allocator {
             self.unused_chunks.insert(after);
         }
         self.used_chunks.insert(chunk);
+        self.reserved_bases.insert(chunk.base);
     }
 
     pub fn alloc(&mut self, size: GuestUSize) -> VAddr {
@@ -314,6 +442,7 @@ impl Allocator {
             );
         };
         self.used_chunks.insert(alloc);
+        self.allocated_bytes += u64::from(alloc.size.get());
 
         alloc.base
     }
@@ -333,6 +462,9 @@ impl Allocator {
             log!("Can't free {:#x}, unknown allocation!", base);
             return 0;
         };
+        if !self.reserved_bases.remove(&base) {
+            self.allocated_bytes -= u64::from(freed.size.get());
+        }
 
         if let Some(adjacent) = self
             .unused_chunks
@@ -351,6 +483,116 @@ impl Allocator {
         freed.size.get()
     }
 
+    /// Iterate over the chunks that are in use, in address order, and whether
+    /// each one was reserved rather than allocated.
+    pub(super) fn used_chunks(&self) -> impl Iterator<Item = (Chunk, bool)> + '_ {
+        self.used_chunks
+            .iter()
+            .map(|chunk| (chunk, self.reserved_bases.contains(&chunk.base)))
+    }
+
+    /// Create an allocator where exactly the given chunks are in use, e.g.
+    /// when restoring a save state. The chunks must be in address order and
+    /// must not overlap, and each one is marked as reserved or not.
+    pub(super) fn with_used_chunks(chunks: &[(Chunk, bool)]) -> Result<Allocator, String> {
+        let mut used_chunks: ChunkMap = Default::default();
+        let mut unused_chunks: SizeBucketedChunkMap = Default::default();
+        let mut reserved_bases = BTreeSet::new();
+        let mut allocated_bytes = 0;
+        // Using u64 so the end of the address space can be represented.
+        let mut next_free: u64 = 0;
+        for &(chunk, reserved) in chunks {
+            let base = u64::from(chunk.base);
+            if base < next_free {
+                return Err(format!("{:?} overlaps the previous chunk", chunk));
+            }
+            if base > next_free {
+                if base - next_free < u64::from(MIN_CHUNK_SIZE) {
+                    return Err(format!("Gap before {:?} is too small", chunk));
+                }
+                unused_chunks.insert(Chunk::new(
+                    next_free as VAddr,
+                    (base - next_free) as GuestUSize,
+                ));
+            }
+            used_chunks.insert(chunk);
+            if reserved {
+                reserved_bases.insert(chunk.base);
+            } else {
+                allocated_bytes += u64::from(chunk.size.get());
+            }
+            next_free = base + u64::from(chunk.size.get());
+        }
+        let end = 1u64 << 32;
+        if next_free > end {
+            return Err("The last chunk extends past the end of memory".to_string());
+        }
+        if next_free < end {
+            if end - next_free < u64::from(MIN_CHUNK_SIZE) {
+                return Err("Gap after the last chunk is too small".to_string());
+            }
+            unused_chunks.insert(Chunk::new(
+                next_free as VAddr,
+                (end - next_free) as GuestUSize,
+            ));
+        }
+        Ok(Allocator {
+            used_chunks,
+            unused_chunks,
+            reserved_bases,
+            allocated_bytes,
+        })
+    }
+
+    /// Total size of the allocations made with [Self::alloc] that haven't been
+    /// freed yet.
+    pub fn allocated_bytes(&self) -> u64 {
+        self.allocated_bytes
+    }
+
+    /// Get statistics about the allocator's state. Allocated chunks for which
+    /// `is_quarantined` returns [true] when given their base are counted
+    /// separately.
+    pub fn stats(&self, is_quarantined: impl Fn(VAddr) -> bool) -> AllocatorStats {
+        let mut stats = AllocatorStats {
+            allocated_chunks: 0,
+            allocated_bytes: 0,
+            reserved_chunks: 0,
+            reserved_bytes: 0,
+            quarantined_chunks: 0,
+            quarantined_bytes: 0,
+            free_chunks: 0,
+            free_bytes: 0,
+            largest_free_chunk: 0,
+            histogram: self
+                .unused_chunks
+                .bucket_lengths()
+                .enumerate()
+                .map(|(bucket, free_chunks)| (MIN_CHUNK_SIZE << bucket, 0, free_chunks))
+                .collect(),
+        };
+        for chunk in self.used_chunks.iter() {
+            let size = chunk.size.get();
+            if self.reserved_bases.contains(&chunk.base) {
+                stats.reserved_chunks += 1;
+                stats.reserved_bytes += u64::from(size);
+            } else if is_quarantined(chunk.base) {
+                stats.quarantined_chunks += 1;
+                stats.quarantined_bytes += u64::from(size);
+            } else {
+                stats.allocated_chunks += 1;
+                stats.allocated_bytes += u64::from(size);
+                stats.histogram[SizeBucketedChunkMap::bucket_for(size)].1 += 1;
+            }
+        }
+        for chunk in self.unused_chunks.iter() {
+            stats.free_chunks += 1;
+            stats.free_bytes += u64::from(chunk.size.get());
+            stats.largest_free_chunk = stats.largest_free_chunk.max(chunk.size.get());
+        }
+        stats
+    }
+
     pub(super) fn reset_and_drain_used_chunks(&mut self) -> impl Iterator<Item = Chunk> {
         let chunks = std::mem::take(&mut self.used_chunks);
         *self = Allocator::new();
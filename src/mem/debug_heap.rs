@@ -0,0 +1,280 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Heap debugging mode (`--debug-heap`).
+//!
+//! Each allocation is surrounded by red zones filled with a known byte value,
+//! which are checked when the allocation is freed, so a buffer overflow or
+//! underflow is caught along with where the allocation was made. Freed memory
+//! is poisoned and kept in a quarantine before it can be reused, so that a
+//! use-after-free reads obviously bad data rather than some newer allocation,
+//! and a write to freed memory is caught when it leaves the quarantine.
+//! Freeing something twice, or freeing a pointer that isn't the start of an
+//! allocation, is a fatal error.
+//!
+//! Since all of this is checked on free rather than on each access, the error
+//! is reported some time after the bad access, but it's at least reported at
+//! all. The panic happens inside the host function that the guest called, so
+//! the guest stack trace printed for it shows the offending call.
+//!
+//! Allocations made with [Mem::alloc_for_guest] remember the return address of
+//! the guest code that made them (the "site"), which is used in error messages
+//! and in the leak summary ([print_leak_summary]). Everything else, including
+//! Objective-C objects, is attributed to touchHLE.
+
+use super::allocator::MIN_CHUNK_SIZE;
+use super::{ConstPtr, GuestUSize, Mem, Ptr, VAddr};
+use crate::backtrace::Symbolicator;
+use crate::Environment;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// Size of the red zone before each allocation. The red zone after it is at
+/// least this big, but also covers the allocator's rounding up of the size.
+const RED_ZONE_SIZE: GuestUSize = MIN_CHUNK_SIZE;
+
+/// Byte value for red zones. This is the same as in the Microsoft debug CRT,
+/// so it might be familiar.
+const RED_ZONE_BYTE: u8 = 0xfd;
+/// Byte value for freed memory. This is also the same as in the Microsoft
+/// debug CRT.
+const FREED_BYTE: u8 = 0xdd;
+
+/// Freed memory is returned to the allocator once the total size of the
+/// quarantined allocations exceeds this.
+const QUARANTINE_LIMIT: GuestUSize = 8 * 1024 * 1024;
+
+/// Maximum number of allocation sites listed by [print_leak_summary].
+const MAX_LEAK_SITES: usize = 32;
+
+#[derive(Copy, Clone)]
+struct Allocation {
+    /// The size that was asked for, not including red zones.
+    size: GuestUSize,
+    /// The return address of the guest code that made the allocation, or
+    /// [None] if it was made by touchHLE.
+    site: Option<VAddr>,
+}
+
+impl Allocation {
+    fn describe(&self) -> String {
+        match self.site {
+            Some(site) => format!("{}-byte allocation made by code at {:#x}", self.size, site),
+            None => format!("{}-byte allocation made by touchHLE", self.size),
+        }
+    }
+}
+
+/// State for `--debug-heap`. See the module documentation.
+#[derive(Default)]
+pub struct DebugHeap {
+    /// Allocations in use, keyed by the address given out, which is just after
+    /// the red zone.
+    allocations: BTreeMap<VAddr, Allocation>,
+    /// Freed allocations that have not been returned to the allocator yet,
+    /// keyed the same way.
+    quarantined: HashMap<VAddr, Allocation>,
+    /// The keys of [Self::quarantined], oldest first.
+    quarantine_queue: VecDeque<VAddr>,
+    /// Total size of the allocations in [Self::quarantined].
+    quarantine_size: GuestUSize,
+}
+
+impl DebugHeap {
+    /// Check whether the allocator chunk with this base holds a quarantined
+    /// allocation.
+    pub(super) fn is_quarantined_chunk(&self, base: VAddr) -> bool {
+        self.quarantined.contains_key(&(base + RED_ZONE_SIZE))
+    }
+
+    /// Explain why `addr` can't be freed or reallocated.
+    fn describe_bad_pointer(&self, addr: VAddr, operation: &str) -> String {
+        if let Some(allocation) = self.quarantined.get(&addr) {
+            return format!(
+                "Attempted to {} {:#x}, which has already been freed! It was a {}.",
+                operation,
+                addr,
+                allocation.describe()
+            );
+        }
+        if let Some((&base, allocation)) = self.allocations.range(..=addr).next_back() {
+            if addr - base < allocation.size {
+                return format!(
+                    "Attempted to {} {:#x}, which is {:#x} bytes into the {} at {:#x}!",
+                    operation,
+                    addr,
+                    addr - base,
+                    allocation.describe(),
+                    base
+                );
+            }
+        }
+        format!(
+            "Attempted to {} {:#x}, which is not a heap allocation!",
+            operation, addr
+        )
+    }
+}
+
+impl Mem {
+    /// Enable `--debug-heap` mode. This must be done before anything is
+    /// allocated, since allocations made without it can't be freed with it.
+    pub fn enable_debug_heap(&mut self) {
+        self.debug_heap.get_or_insert_with(Default::default);
+    }
+
+    pub(super) fn debug_heap_alloc(&mut self, size: GuestUSize, site: Option<VAddr>) -> VAddr {
+        let base = self
+            .allocator
+            .alloc(size.checked_add(2 * RED_ZONE_SIZE).unwrap());
+        let chunk_size = self.allocator.find_allocated_size(base);
+        let addr = base + RED_ZONE_SIZE;
+
+        // The allocator's memory is already zeroed, so only the red zones need
+        // to be filled in.
+        let chunk = self.bytes_at_mut(Ptr::from_bits(base), chunk_size);
+        let (before, rest) = chunk.split_at_mut(RED_ZONE_SIZE as usize);
+        before.fill(RED_ZONE_BYTE);
+        rest[size as usize..].fill(RED_ZONE_BYTE);
+
+        let heap = self.debug_heap.as_mut().unwrap();
+        heap.allocations.insert(addr, Allocation { size, site });
+        addr
+    }
+
+    /// Get the size of an allocation made by [Self::debug_heap_alloc]. This
+    /// panics if there's no such allocation.
+    pub(super) fn debug_heap_allocated_size(&self, addr: VAddr) -> GuestUSize {
+        let heap = self.debug_heap.as_ref().unwrap();
+        match heap.allocations.get(&addr) {
+            Some(allocation) => allocation.size,
+            None => panic!("{}", heap.describe_bad_pointer(addr, "reallocate")),
+        }
+    }
+
+    /// Free an allocation made by [Self::debug_heap_alloc], after checking its
+    /// red zones. Returns its size. This panics if anything is wrong.
+    pub(super) fn debug_heap_free(&mut self, addr: VAddr) -> GuestUSize {
+        let heap = self.debug_heap.as_mut().unwrap();
+        let Some(allocation) = heap.allocations.remove(&addr) else {
+            panic!("{}", heap.describe_bad_pointer(addr, "free"));
+        };
+
+        self.check_red_zones(addr, allocation);
+        self.bytes_at_mut(Ptr::from_bits(addr), allocation.size)
+            .fill(FREED_BYTE);
+
+        let heap = self.debug_heap.as_mut().unwrap();
+        heap.quarantined.insert(addr, allocation);
+        heap.quarantine_queue.push_back(addr);
+        heap.quarantine_size += allocation.size;
+        let mut to_release = Vec::new();
+        while heap.quarantine_size > QUARANTINE_LIMIT {
+            let oldest = heap.quarantine_queue.pop_front().unwrap();
+            let oldest_allocation = heap.quarantined.remove(&oldest).unwrap();
+            heap.quarantine_size -= oldest_allocation.size;
+            to_release.push((oldest, oldest_allocation));
+        }
+        for (oldest, oldest_allocation) in to_release {
+            self.release_quarantined(oldest, oldest_allocation);
+        }
+        allocation.size
+    }
+
+    fn check_red_zones(&mut self, addr: VAddr, allocation: Allocation) {
+        let base = addr - RED_ZONE_SIZE;
+        let chunk_size = self.allocator.find_allocated_size(base);
+        let chunk = self.bytes_at(ConstPtr::from_bits(base), chunk_size);
+        let (before, rest) = chunk.split_at(RED_ZONE_SIZE as usize);
+        let after = &rest[allocation.size as usize..];
+
+        // Report the overwritten byte furthest from the allocation, since
+        // that's the extent of the overflow.
+        if let Some(idx) = before.iter().position(|&byte| byte != RED_ZONE_BYTE) {
+            panic!(
+                "Heap corruption: {} bytes before the {} at {:#x} were overwritten!",
+                RED_ZONE_SIZE - idx as GuestUSize,
+                allocation.describe(),
+                addr
+            );
+        }
+        if let Some(idx) = after.iter().rposition(|&byte| byte != RED_ZONE_BYTE) {
+            panic!(
+                "Heap corruption: {} bytes past the end of the {} at {:#x} were overwritten!",
+                idx + 1,
+                allocation.describe(),
+                addr
+            );
+        }
+    }
+
+    /// Return a quarantined allocation to the allocator, after checking that
+    /// nothing has written to it since it was freed.
+    fn release_quarantined(&mut self, addr: VAddr, allocation: Allocation) {
+        let freed = self.bytes_at(ConstPtr::from_bits(addr), allocation.size);
+        if let Some(idx) = freed.iter().position(|&byte| byte != FREED_BYTE) {
+            panic!(
+                "Heap corruption: offset {:#x} of the freed {} at {:#x} was overwritten!",
+                idx,
+                allocation.describe(),
+                addr
+            );
+        }
+
+        let base = addr - RED_ZONE_SIZE;
+        let chunk_size = self.allocator.free(base);
+        // The allocator expects unused memory to be zeroed.
+        self.bytes_at_mut(Ptr::from_bits(base), chunk_size).fill(0);
+    }
+}
+
+/// Print a summary of the allocations still in use, grouped by where they were
+/// made, if `--debug-heap` is enabled. This is meant for when the app exits.
+///
+/// Not everything listed is a leak: memory that an app keeps around for its
+/// whole lifetime is usually never freed.
+pub fn print_leak_summary(env: &Environment) {
+    let Some(ref heap) = env.mem.debug_heap else {
+        return;
+    };
+    if heap.allocations.is_empty() {
+        echo!("Debug heap: all allocations were freed.");
+        return;
+    }
+
+    let mut sites: HashMap<Option<VAddr>, (u64, u64)> = HashMap::new();
+    for allocation in heap.allocations.values() {
+        let (count, bytes) = sites.entry(allocation.site).or_default();
+        *count += 1;
+        *bytes += u64::from(allocation.size);
+    }
+    let mut sites: Vec<_> = sites.into_iter().collect();
+    sites.sort_by_key(|&(site, (_, bytes))| (std::cmp::Reverse(bytes), site));
+
+    let total_bytes: u64 = sites.iter().map(|&(_, (_, bytes))| bytes).sum();
+    echo!(
+        "Debug heap: {} allocations ({} bytes) were not freed. By allocation site:",
+        heap.allocations.len(),
+        total_bytes
+    );
+    let symbolicator = Symbolicator::new(&env.bins, &env.objc, &env.mem);
+    for &(site, (count, bytes)) in sites.iter().take(MAX_LEAK_SITES) {
+        let site = match site {
+            Some(site) => symbolicator.describe_return_address(site),
+            None => "touchHLE".to_string(),
+        };
+        echo!(
+            "{:>10} bytes in {:>6} allocations from {}",
+            bytes,
+            count,
+            site
+        );
+    }
+    if sites.len() > MAX_LEAK_SITES {
+        echo!(
+            "...and {} more allocation sites.",
+            sites.len() - MAX_LEAK_SITES
+        );
+    }
+}
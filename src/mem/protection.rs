@@ -0,0 +1,129 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Per-page memory protection.
+//!
+//! Like watchpoints (see [super::Watchpoints]), protections are only enforced
+//! for accesses by the emulated CPU that go through the memory callbacks (see
+//! [crate::cpu]), so pages that aren't readable and writable can't use direct
+//! memory access. Host code can access any page except the null segment, so
+//! that e.g. the dynamic linker can write to `__TEXT`.
+//!
+//! Memory is readable, writable and executable by default, because touchHLE
+//! itself puts guest code on the heap. The loaded binaries' segments only get
+//! their own protection if [super::Mem::set_enforce_segment_protection] is
+//! used.
+
+use super::{GuestUSize, VAddr};
+
+/// Set of permissions for a page. Use `|` to combine them.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct Protection(u8);
+
+impl Protection {
+    pub const NONE: Protection = Protection(0);
+    pub const READ: Protection = Protection(1);
+    pub const WRITE: Protection = Protection(2);
+    pub const EXECUTE: Protection = Protection(4);
+    pub const ALL: Protection = Protection(7);
+
+    pub fn contains(self, other: Protection) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Protection {
+    type Output = Protection;
+    fn bitor(self, other: Protection) -> Protection {
+        Protection(self.0 | other.0)
+    }
+}
+impl std::ops::BitOrAssign for Protection {
+    fn bitor_assign(&mut self, other: Protection) {
+        self.0 |= other.0
+    }
+}
+
+impl std::fmt::Debug for Protection {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Same style as `ls -l` and `vmmap`.
+        for (flag, c) in [(Self::READ, 'r'), (Self::WRITE, 'w'), (Self::EXECUTE, 'x')] {
+            write!(f, "{}", if self.contains(flag) { c } else { '-' })?;
+        }
+        Ok(())
+    }
+}
+
+/// Kind of memory access, for reporting protection faults.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    Execute,
+}
+
+impl Access {
+    fn protection(self) -> Protection {
+        match self {
+            Access::Read => Protection::READ,
+            Access::Write => Protection::WRITE,
+            Access::Execute => Protection::EXECUTE,
+        }
+    }
+}
+
+pub struct PageProtections {
+    /// Protection for each 4KiB page of the address space.
+    pages: Box<[Protection]>,
+    /// The most recent access that wasn't allowed, if not yet retrieved with
+    /// [Self::take_fault].
+    fault: Option<(Access, VAddr)>,
+}
+
+impl PageProtections {
+    pub fn new() -> PageProtections {
+        PageProtections {
+            pages: vec![Protection::ALL; 1 << 20].into_boxed_slice(),
+            fault: None,
+        }
+    }
+
+    /// Set the protection of the pages covering `size` bytes starting at
+    /// `base`, which must be page-aligned.
+    pub fn set(&mut self, base: VAddr, size: GuestUSize, protection: Protection) {
+        assert!(base % 0x1000 == 0);
+        if size == 0 {
+            return;
+        }
+        let first_page = (base / 0x1000) as usize;
+        let last_page = ((base + (size - 1)) / 0x1000) as usize;
+        self.pages[first_page..=last_page].fill(protection);
+    }
+
+    /// Get the protection of the page containing `addr`.
+    pub fn get(&self, addr: VAddr) -> Protection {
+        self.pages[(addr / 0x1000) as usize]
+    }
+
+    /// Check whether an access of `size` bytes at `addr` is allowed. If it
+    /// isn't, the fault is recorded for [Self::take_fault] and [true] is
+    /// returned, in which case the access must not be done.
+    #[inline(always)]
+    pub fn check(&mut self, addr: VAddr, size: GuestUSize, access: Access) -> bool {
+        let protection = access.protection();
+        // An unaligned access can span two pages.
+        let last_addr = addr.wrapping_add(size - 1);
+        if self.get(addr).contains(protection) && self.get(last_addr).contains(protection) {
+            return false;
+        }
+        self.fault = Some((access, addr));
+        true
+    }
+
+    /// Get and clear the most recent fault recorded by [Self::check].
+    pub fn take_fault(&mut self) -> Option<(Access, VAddr)> {
+        self.fault.take()
+    }
+}
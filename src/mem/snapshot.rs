@@ -0,0 +1,108 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Saving and restoring guest memory for save states (see
+//! [crate::save_state]).
+
+use super::allocator::{Allocator, Chunk};
+use super::{GuestUSize, Mem, VAddr};
+use crate::save_state::{read_bytes, read_u32, write_u32};
+use std::collections::BTreeSet;
+
+const PAGE_SIZE: GuestUSize = 0x1000;
+
+impl Mem {
+    /// Append the allocator state and the contents of every page that is
+    /// (partly) in use and isn't all zeroes to `out`.
+    pub fn write_snapshot(&self, out: &mut Vec<u8>) {
+        write_u32(out, self.null_segment_size);
+
+        let chunks: Vec<(Chunk, bool)> = self.allocator.used_chunks().collect();
+        write_u32(out, chunks.len().try_into().unwrap());
+        for &(chunk, reserved) in &chunks {
+            write_u32(out, chunk.base);
+            write_u32(out, chunk.size.get());
+            write_u32(out, reserved.into());
+        }
+
+        let pages: BTreeSet<VAddr> = chunks
+            .iter()
+            .flat_map(|(chunk, _)| {
+                let first_page = chunk.base / PAGE_SIZE;
+                let last_page = chunk.last_byte() / PAGE_SIZE;
+                (first_page..=last_page).map(|page| page * PAGE_SIZE)
+            })
+            .filter(|&page| page >= self.null_segment_size)
+            .filter(|&page| self.page_bytes(page).iter().any(|&byte| byte != 0))
+            .collect();
+        write_u32(out, pages.len().try_into().unwrap());
+        for page in pages {
+            write_u32(out, page);
+            out.extend_from_slice(self.page_bytes(page));
+        }
+    }
+
+    /// Replace the allocator state and the contents of memory with a snapshot
+    /// written by [Self::write_snapshot], which must be all of `data`. Memory
+    /// is left unchanged if the snapshot is invalid.
+    pub fn read_snapshot(&mut self, mut data: &[u8]) -> Result<(), String> {
+        let data = &mut data;
+        if self.debug_heap.is_some() {
+            // The debug heap's records of the allocations wouldn't match.
+            return Err("Save states can't be loaded with --debug-heap".to_string());
+        }
+        let null_segment_size = read_u32(data)?;
+        if null_segment_size != self.null_segment_size {
+            // The CPU has already been set up for the current null segment,
+            // so the state must be for the same app.
+            return Err(format!(
+                "Null segment size mismatch ({:#x} in state, {:#x} in app)",
+                null_segment_size, self.null_segment_size
+            ));
+        }
+
+        let chunk_count = read_u32(data)?;
+        let mut chunks = Vec::new();
+        for _ in 0..chunk_count {
+            let base = read_u32(data)?;
+            let size = read_u32(data)?;
+            let reserved = read_u32(data)? != 0;
+            if size == 0 {
+                return Err(format!("Empty chunk at {:#x}", base));
+            }
+            chunks.push((Chunk::new(base, size), reserved));
+        }
+        let allocator = Allocator::with_used_chunks(&chunks)?;
+
+        let page_count = read_u32(data)?;
+        let mut pages = Vec::new();
+        for _ in 0..page_count {
+            let page = read_u32(data)?;
+            if page % PAGE_SIZE != 0 || page < self.null_segment_size {
+                return Err(format!("Invalid page address {:#x}", page));
+            }
+            pages.push((page, read_bytes(data, PAGE_SIZE as usize)?));
+        }
+
+        if !data.is_empty() {
+            return Err("Unexpected data at the end of the save state".to_string());
+        }
+
+        // Everything has been checked, so now memory can be replaced.
+        let old_chunks: Vec<(Chunk, bool)> = self.allocator.used_chunks().collect();
+        for (Chunk { base, size }, _) in old_chunks {
+            self.bytes_mut()[base as usize..][..size.get() as usize].fill(0);
+        }
+        self.allocator = allocator;
+        for (page, bytes) in pages {
+            self.bytes_mut()[page as usize..][..PAGE_SIZE as usize].copy_from_slice(bytes);
+        }
+        Ok(())
+    }
+
+    fn page_bytes(&self, page: VAddr) -> &[u8] {
+        &self.bytes()[page as usize..][..PAGE_SIZE as usize]
+    }
+}
@@ -0,0 +1,118 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Data watchpoints, for the debugger (see [crate::gdb]).
+//!
+//! Watchpoints are only checked for memory accesses by the emulated CPU that go
+//! through the memory callbacks (see [crate::cpu]), so when direct memory
+//! access is in use, the CPU must be told to use the callbacks for pages that
+//! contain a watchpoint. Accesses by host code are never checked.
+//!
+//! A watchpoint stops execution _before_ the access is done, with PC still
+//! pointing to the instruction, which is what GDB and LLDB expect on Arm: they
+//! remove the watchpoints, step past the instruction, and then re-insert them.
+
+use super::{GuestUSize, VAddr};
+
+/// What kind of access triggers a watchpoint.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchpointKind {
+    Write,
+    Read,
+    /// Read or write.
+    Access,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+struct Watchpoint {
+    addr: VAddr,
+    size: GuestUSize,
+    kind: WatchpointKind,
+}
+
+impl Watchpoint {
+    /// Returns the address of the first watched byte that an access touches,
+    /// if any.
+    fn overlap(&self, addr: VAddr, size: GuestUSize) -> Option<VAddr> {
+        // u64 avoids overflow at the top of the address space.
+        let start = u64::from(self.addr).max(u64::from(addr));
+        let end =
+            (u64::from(self.addr) + u64::from(self.size)).min(u64::from(addr) + u64::from(size));
+        (start < end).then_some(start as VAddr)
+    }
+}
+
+#[derive(Default)]
+pub struct Watchpoints {
+    watchpoints: Vec<Watchpoint>,
+    /// The most recently triggered watchpoint and the address it was triggered
+    /// at, if not yet retrieved with [Self::take_hit].
+    hit: Option<(WatchpointKind, VAddr)>,
+}
+
+impl Watchpoints {
+    /// Add a watchpoint covering `size` bytes starting at `addr`. Adding the
+    /// same watchpoint twice has no effect.
+    pub fn add(&mut self, addr: VAddr, size: GuestUSize, kind: WatchpointKind) {
+        assert!(size != 0);
+        let watchpoint = Watchpoint { addr, size, kind };
+        if !self.watchpoints.contains(&watchpoint) {
+            self.watchpoints.push(watchpoint);
+        }
+    }
+
+    /// Remove a watchpoint previously added with [Self::add]. Returns [false]
+    /// if there was no such watchpoint.
+    pub fn remove(&mut self, addr: VAddr, size: GuestUSize, kind: WatchpointKind) -> bool {
+        let watchpoint = Watchpoint { addr, size, kind };
+        let old_len = self.watchpoints.len();
+        self.watchpoints.retain(|&other| other != watchpoint);
+        self.watchpoints.len() != old_len
+    }
+
+    /// Whether any watchpoint covers part of the page starting at `page_addr`.
+    pub fn page_has_watchpoint(&self, page_addr: VAddr) -> bool {
+        self.watchpoints
+            .iter()
+            .any(|watchpoint| watchpoint.overlap(page_addr, 0x1000).is_some())
+    }
+
+    /// Check whether an access of `size` bytes at `addr` triggers a watchpoint.
+    /// If it does, the hit is recorded for [Self::take_hit] and [true] is
+    /// returned, in which case the access must not be done.
+    #[inline(always)]
+    pub fn check(&mut self, addr: VAddr, size: GuestUSize, is_write: bool) -> bool {
+        if self.watchpoints.is_empty() {
+            return false;
+        }
+        self.check_slow(addr, size, is_write)
+    }
+
+    #[cold]
+    fn check_slow(&mut self, addr: VAddr, size: GuestUSize, is_write: bool) -> bool {
+        for watchpoint in &self.watchpoints {
+            let triggered = match watchpoint.kind {
+                WatchpointKind::Write => is_write,
+                WatchpointKind::Read => !is_write,
+                WatchpointKind::Access => true,
+            };
+            if !triggered {
+                continue;
+            }
+            // The reported address must be within the watched range, or the
+            // debugger won't be able to tell which watchpoint was triggered.
+            if let Some(hit_addr) = watchpoint.overlap(addr, size) {
+                self.hit = Some((watchpoint.kind, hit_addr));
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Get and clear the most recent watchpoint hit recorded by [Self::check].
+    pub fn take_hit(&mut self) -> Option<(WatchpointKind, VAddr)> {
+        self.hit.take()
+    }
+}
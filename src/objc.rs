@@ -29,22 +29,27 @@ mod objects;
 mod properties;
 mod selectors;
 mod synchronization;
+mod trace;
 
-pub use classes::{objc_classes, Class, ClassExports, ClassTemplate};
+pub use classes::{
+    bin_method_names, host_class_exists, objc_classes, Class, ClassExports, ClassTemplate,
+};
 pub use messages::{
     autorelease, msg, msg_class, msg_send, msg_send_super2, msg_super, objc_super, release, retain,
 };
 pub use methods::{HostIMP, IMP};
 pub use objects::{
-    id, impl_HostObject_with_superclass, nil, AnyHostObject, HostObject, TrivialHostObject,
+    id, impl_HostObject_with_superclass, nil, AnyHostObject, ClassObjectStats, HostObject,
+    TrivialHostObject,
 };
-pub use selectors::{selector, SEL};
+pub use selectors::{bin_selector_names, host_selector_names, selector, SEL};
+pub use trace::TracePattern;
 
 use classes::{ClassHostObject, FakeClass, UnimplementedClass, CLASS_LISTS};
 use messages::{
     objc_msgSend, objc_msgSendSuper2, objc_msgSend_stret, MsgSendSignature, MsgSendSuperSignature,
 };
-use methods::method_list_t;
+use methods::{method_list_t, method_names_from_bin};
 use objects::{objc_object, HostObjectEntry};
 use properties::{objc_copyStruct, objc_setProperty};
 use selectors::sel_registerName;
@@ -78,6 +83,12 @@ pub struct ObjC {
     /// Type information isn't part of the `objc_msgSend` ABI, so an alternative
     /// channel is needed.
     message_type_info: Option<(std::any::TypeId, &'static str)>,
+
+    /// File that traced messages are written to, if `--trace-objc-file=` is
+    /// used. This is opened when the first message is traced.
+    trace_file: Option<std::io::LineWriter<std::fs::File>>,
+    /// Nesting depth of traced messages, for indentation.
+    trace_depth: usize,
 }
 
 impl ObjC {
@@ -88,6 +99,8 @@ impl ObjC {
             classes: HashMap::new(),
             sync_mutexes: HashMap::new(),
             message_type_info: None,
+            trace_file: None,
+            trace_depth: 0,
         }
     }
 }
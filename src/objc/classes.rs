@@ -14,11 +14,12 @@ mod class_lists;
 pub(super) use class_lists::CLASS_LISTS;
 
 use super::{
-    id, method_list_t, nil, objc_object, AnyHostObject, HostIMP, HostObject, ObjC, IMP, SEL,
+    id, method_list_t, method_names_from_bin, nil, objc_object, AnyHostObject, HostIMP, HostObject,
+    ObjC, IMP, SEL,
 };
 use crate::mach_o::MachO;
 use crate::mem::{guest_size_of, ConstPtr, ConstVoidPtr, GuestUSize, Mem, Ptr, SafeRead};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 /// Generic pointer to an Objective-C class or metaclass.
 ///
@@ -715,3 +716,54 @@ impl ObjC {
         }
     }
 }
+
+/// For use by [crate::dyld::compat_report]: check whether touchHLE has a host
+/// implementation of a class.
+pub fn host_class_exists(name: &str) -> bool {
+    ObjC::find_template(name).is_some()
+}
+
+/// For use by [crate::dyld::compat_report]: get the names of all the methods
+/// defined by classes and categories in the application binary. Unlike
+/// [ObjC::register_bin_classes] and [ObjC::register_bin_categories], this
+/// doesn't need the binary to have been linked.
+pub fn bin_method_names(bin: &MachO, mem: &Mem) -> HashSet<String> {
+    let mut names = HashSet::new();
+    let mut add_methods = |methods: ConstPtr<method_list_t>| {
+        if !methods.is_null() {
+            names.extend(method_names_from_bin(methods, mem));
+        }
+    };
+
+    if let Some(list) = bin.get_section("__objc_classlist") {
+        assert!(list.size % 4 == 0);
+        let base: ConstPtr<Class> = Ptr::from_bits(list.addr);
+        for i in 0..(list.size / 4) {
+            let class = mem.read(base + i);
+            let metaclass = ObjC::read_isa(class, mem);
+            for class in [class, metaclass] {
+                let class_t { data, .. } = mem.read(class.cast());
+                let class_rw_t { base_methods, .. } = mem.read(data);
+                add_methods(base_methods);
+            }
+        }
+    }
+
+    // The classes categories are attached to might not be from the binary,
+    // but that doesn't matter here.
+    if let Some(list) = bin.get_section("__objc_catlist") {
+        assert!(list.size % 4 == 0);
+        let base: ConstPtr<ConstPtr<category_t>> = Ptr::from_bits(list.addr);
+        for i in 0..(list.size / 4) {
+            let category_t {
+                instance_methods,
+                class_methods,
+                ..
+            } = mem.read(mem.read(base + i));
+            add_methods(instance_methods);
+            add_methods(class_methods);
+        }
+    }
+
+    names
+}
@@ -88,7 +88,20 @@ fn objc_msgSend_inner(env: &mut Environment, receiver: id, selector: SEL, super2
                 continue;
             }
 
-            if let Some(imp) = methods.get(&selector) {
+            if let Some(&imp) = methods.get(&selector) {
+                let traced_call = if env.options.trace_objc.is_empty() {
+                    None
+                } else {
+                    super::trace::trace_call(
+                        env,
+                        receiver,
+                        orig_class,
+                        super2.is_some(),
+                        selector,
+                        imp,
+                    )
+                };
+
                 match imp {
                     IMP::Host(host_imp) => {
                         // TODO: do type checks when calling GuestIMPs too.
@@ -112,12 +125,31 @@ Type mismatch when sending message {} to {:?}!
                                 );
                             }
                         }
-                        host_imp.call_from_guest(env)
+                        let profiled = crate::profiler::enabled();
+                        if profiled {
+                            let (class_name, is_metaclass) =
+                                super::trace::class_name_and_kind(&env.objc, class);
+                            let name = format!(
+                                "{}[{} {}]",
+                                if is_metaclass { '+' } else { '-' },
+                                class_name,
+                                selector.as_str(&env.mem)
+                            );
+                            crate::profiler::host_call_begin(env, &name);
+                        }
+                        host_imp.call_from_guest(env);
+                        if profiled {
+                            crate::profiler::host_call_end(env);
+                        }
                     }
                     // We can't create a new stack frame, because that would
                     // interfere with pass-through of stack arguments.
                     IMP::Guest(guest_imp) => guest_imp.call_without_pushing_stack_frame(env),
                 }
+
+                if let Some(traced_call) = traced_call {
+                    super::trace::trace_return(env, imp, traced_call);
+                }
                 return;
             } else {
                 class = superclass;
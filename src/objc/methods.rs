@@ -11,7 +11,8 @@
 use super::{
     id, nil, objc_super, Class, ClassHostObject, MsgSendSignature, MsgSendSuperSignature, ObjC, SEL,
 };
-use crate::abi::{CallFromGuest, DotDotDot, GuestArg, GuestFunction, GuestRet};
+use crate::abi::{read_next_arg, CallFromGuest, DotDotDot, GuestArg, GuestFunction, GuestRet};
+use crate::cpu::Cpu;
 use crate::mem::{guest_size_of, ConstPtr, GuestUSize, Mem, Ptr, SafeRead};
 use crate::Environment;
 use std::any::TypeId;
@@ -24,6 +25,7 @@ use std::any::TypeId;
 /// "guest methods" (functions in the guest app). Either way, the function needs
 /// to conform to the same ABI: [id] and [SEL] must be its first two parameters.
 #[allow(clippy::upper_case_acronyms)]
+#[derive(Copy, Clone)]
 pub enum IMP {
     Host(&'static dyn HostIMP),
     Guest(GuestIMP),
@@ -33,6 +35,52 @@ pub enum IMP {
 pub trait HostIMP: CallFromGuest {
     /// See [MsgSendSignature::type_info].
     fn type_info(&self) -> (TypeId, &'static str);
+
+    /// For `--trace-objc=`: format the arguments (other than `self` and
+    /// `_cmd`) this method is being called with, from the guest CPU state at
+    /// the start of the call.
+    fn describe_args(&self, env: &Environment) -> String;
+
+    /// For `--trace-objc=`: format the value this method returned, from the
+    /// guest CPU state at the end of the call. `r0_at_call` is the value r0 had
+    /// at the start of the call, which is the pointer to the return value if
+    /// it's a struct returned via memory.
+    fn describe_return(&self, env: &Environment, r0_at_call: u32) -> String;
+}
+
+/// Shared part of the [HostIMP::describe_args] implementations.
+fn describe_host_imp_args<R: GuestRet>(
+    env: &Environment,
+    describe_next: &[fn(&mut usize, &[u32], ConstPtr<u32>, &Mem) -> String],
+) -> String {
+    let regs = env.cpu.regs();
+    let stack_ptr = Ptr::from_bits(regs[Cpu::SP]);
+    // Skip the pointer for the return value (if any), `self` and `_cmd`.
+    let mut reg_offset = if R::SIZE_IN_MEM.is_some() { 3 } else { 2 };
+    describe_next
+        .iter()
+        .map(|describe| describe(&mut reg_offset, regs, stack_ptr, &env.mem))
+        .collect::<Vec<String>>()
+        .join(", ")
+}
+
+fn describe_next_arg<P: GuestArg>(
+    reg_offset: &mut usize,
+    regs: &[u32],
+    stack_ptr: ConstPtr<u32>,
+    mem: &Mem,
+) -> String {
+    format!("{:?}", read_next_arg::<P>(reg_offset, regs, stack_ptr, mem))
+}
+
+/// Shared part of the [HostIMP::describe_return] implementations.
+fn describe_host_imp_return<R: GuestRet>(env: &Environment, r0_at_call: u32) -> String {
+    let retval = if R::SIZE_IN_MEM.is_some() {
+        R::from_mem(Ptr::from_bits(r0_at_call), &env.mem)
+    } else {
+        R::from_regs(env.cpu.regs())
+    };
+    format!("{:?}", retval)
 }
 
 macro_rules! impl_HostIMP {
@@ -45,6 +93,12 @@ macro_rules! impl_HostIMP {
             fn type_info(&self) -> (TypeId, &'static str) {
                 <(R, (id, SEL, $($P,)*)) as MsgSendSignature>::type_info()
             }
+            fn describe_args(&self, env: &Environment) -> String {
+                describe_host_imp_args::<R>(env, &[$(describe_next_arg::<$P>,)*])
+            }
+            fn describe_return(&self, env: &Environment, r0_at_call: u32) -> String {
+                describe_host_imp_return::<R>(env, r0_at_call)
+            }
         }
         impl<R, $($P,)*> HostIMP for fn(&mut Environment, id, SEL, $($P,)* DotDotDot) -> R
         where
@@ -54,6 +108,17 @@ macro_rules! impl_HostIMP {
             fn type_info(&self) -> (TypeId, &'static str) {
                 todo!("host-to-host message calls with var-args"); // TODO
             }
+            fn describe_args(&self, env: &Environment) -> String {
+                let args = describe_host_imp_args::<R>(env, &[$(describe_next_arg::<$P>,)*]);
+                if args.is_empty() {
+                    "...".to_string()
+                } else {
+                    format!("{}, ...", args)
+                }
+            }
+            fn describe_return(&self, env: &Environment, r0_at_call: u32) -> String {
+                describe_host_imp_return::<R>(env, r0_at_call)
+            }
         }
 
         // Currently there is a one-to-one mapping between valid host IMP
@@ -108,6 +173,27 @@ struct method_t {
 }
 unsafe impl SafeRead for method_t {}
 
+/// Get the names of the methods in a method list in an app binary, without
+/// registering them as selectors.
+pub(super) fn method_names_from_bin(
+    method_list_ptr: ConstPtr<method_list_t>,
+    mem: &Mem,
+) -> Vec<String> {
+    let method_list_t { entsize, count } = mem.read(method_list_ptr);
+    assert!(entsize >= guest_size_of::<method_t>());
+
+    let methods_base_ptr: ConstPtr<method_t> = (method_list_ptr + 1).cast();
+
+    (0..count)
+        .map(|i| {
+            let method_ptr: ConstPtr<method_t> =
+                Ptr::from_bits(methods_base_ptr.to_bits() + i * entsize);
+            let method_t { name, .. } = mem.read(method_ptr);
+            mem.cstr_at_utf8(name).unwrap().to_string()
+        })
+        .collect()
+}
+
 impl ClassHostObject {
     // See classes.rs for host method parsing
 
@@ -219,4 +305,41 @@ impl ObjC {
             }
         }
     }
+
+    /// For use by [crate::backtrace]: get the addresses (without the Thumb bit)
+    /// and names (e.g. `-[UIView initWithFrame:]`) of all the methods
+    /// implemented by guest code, including ones added by categories.
+    pub fn guest_method_symbols(&self, mem: &Mem) -> Vec<(u32, String)> {
+        let mut symbols = Vec::new();
+        for &class in self.classes.values() {
+            for class in [class, ObjC::read_isa(class, mem)] {
+                let Some(ClassHostObject {
+                    name,
+                    is_metaclass,
+                    methods,
+                    ..
+                }) = self
+                    .get_host_object(class)
+                    .and_then(|host_object| host_object.as_any().downcast_ref())
+                else {
+                    continue;
+                };
+                for (&sel, &imp) in methods {
+                    let IMP::Guest(imp) = imp else {
+                        continue;
+                    };
+                    symbols.push((
+                        imp.addr_without_thumb_bit(),
+                        format!(
+                            "{}[{} {}]",
+                            if *is_metaclass { '+' } else { '-' },
+                            name,
+                            sel.as_str(mem)
+                        ),
+                    ));
+                }
+            }
+        }
+        symbols
+    }
 }
@@ -23,6 +23,7 @@
 use super::{Class, ClassHostObject};
 use crate::mem::{guest_size_of, GuestUSize, Mem, MutPtr, Ptr, SafeRead};
 use std::any::Any;
+use std::collections::HashMap;
 use std::num::NonZeroU32;
 
 /// Memory layout of a minimal Objective-C object. See [id].
@@ -64,6 +65,15 @@ pub(super) struct HostObjectEntry {
     refcount: Option<NonZeroU32>,
 }
 
+/// Statistics about the reference-counted objects of one class, see
+/// [super::ObjC::object_stats].
+pub struct ClassObjectStats {
+    pub class_name: String,
+    pub count: usize,
+    pub total_refcount: u64,
+    pub max_refcount: u32,
+}
+
 /// Type for host objects.
 pub trait HostObject: Any + 'static {
     /// Upcast to the superclass's host object type, if any.
@@ -348,4 +358,38 @@ impl super::ObjC {
 
         mem.free(object.cast());
     }
+
+    /// Get statistics about the reference-counted objects that currently
+    /// exist, grouped by class, with the most numerous classes first. The
+    /// number of static-lifetime objects (mostly classes) is returned too.
+    pub fn object_stats(&self, mem: &Mem) -> (Vec<ClassObjectStats>, usize) {
+        let mut by_class: HashMap<Class, ClassObjectStats> = HashMap::new();
+        let mut static_count = 0;
+        for (&object, entry) in &self.objects {
+            let Some(refcount) = entry.refcount else {
+                static_count += 1;
+                continue;
+            };
+            let class = Self::read_isa(object, mem);
+            let stats = by_class.entry(class).or_insert_with(|| ClassObjectStats {
+                class_name: match self.get_host_object(class) {
+                    Some(_) => self.get_class_name(class).to_string(),
+                    None => format!("(unknown class {:?})", class),
+                },
+                count: 0,
+                total_refcount: 0,
+                max_refcount: 0,
+            });
+            stats.count += 1;
+            stats.total_refcount += u64::from(refcount.get());
+            stats.max_refcount = stats.max_refcount.max(refcount.get());
+        }
+        let mut stats: Vec<ClassObjectStats> = by_class.into_values().collect();
+        stats.sort_by(|a, b| {
+            b.count
+                .cmp(&a.count)
+                .then_with(|| a.class_name.cmp(&b.class_name))
+        });
+        (stats, static_count)
+    }
 }
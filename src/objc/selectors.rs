@@ -19,6 +19,7 @@ use crate::abi::{GuestArg, GuestRet};
 use crate::mach_o::MachO;
 use crate::mem::{ConstPtr, Mem, MutPtr, Ptr};
 use crate::Environment;
+use std::collections::HashSet;
 
 /// Create a string literal for a selector from Objective-C message syntax
 /// components. Useful for [super::objc_classes] and for [super::msg].
@@ -140,6 +141,34 @@ impl ObjC {
     }
 }
 
+/// For use by [crate::dyld::compat_report]: get the names of all the selectors
+/// that at least one host class has a method for.
+pub fn host_selector_names() -> HashSet<&'static str> {
+    let mut names = HashSet::new();
+    for &class_list in super::CLASS_LISTS {
+        for (_name, template) in class_list {
+            for method_list in [template.class_methods, template.instance_methods] {
+                names.extend(method_list.iter().map(|&(name, _imp)| name));
+            }
+        }
+    }
+    names
+}
+
+/// For use by [crate::dyld::compat_report]: get the names of all the selectors
+/// referenced in the application binary, without registering them.
+pub fn bin_selector_names(bin: &MachO, mem: &Mem) -> Vec<String> {
+    let Some(selrefs) = bin.get_section("__objc_selrefs") else {
+        return Vec::new();
+    };
+
+    assert!(selrefs.size % 4 == 0);
+    let base: ConstPtr<ConstPtr<u8>> = Ptr::from_bits(selrefs.addr);
+    (0..(selrefs.size / 4))
+        .map(|i| mem.cstr_at_utf8(mem.read(base + i)).unwrap().to_string())
+        .collect()
+}
+
 /// Standard Objective-C runtime function for selector registration.
 pub(super) fn sel_registerName(env: &mut Environment, name: ConstPtr<u8>) -> SEL {
     let name = env.mem.cstr_at_utf8(name).unwrap();
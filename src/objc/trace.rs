@@ -0,0 +1,209 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Tracing of Objective-C messages (`--trace-objc=`).
+//!
+//! [super::messages::objc_msgSend_inner] calls into this for every message
+//! that is sent to an implemented method. Messages whose receiver class and
+//! selector match one of the patterns are logged together with their return
+//! value. Nested messages are indented, so it's easy to see which message
+//! caused which.
+
+use super::{id, Class, ClassHostObject, FakeClass, ObjC, UnimplementedClass, IMP, SEL};
+use crate::Environment;
+use std::fs::File;
+use std::io::{LineWriter, Write};
+
+/// A pattern from `--trace-objc=`.
+#[derive(Clone, Debug)]
+pub struct TracePattern {
+    class: String,
+    selector: String,
+}
+
+impl TracePattern {
+    /// Parse a comma-separated list of patterns, each of which is either
+    /// `CLASS` or `CLASS:SELECTOR`, where `CLASS` and `SELECTOR` can contain
+    /// `*` and `?` wildcards.
+    pub fn parse_list(value: &str) -> Result<Vec<TracePattern>, String> {
+        value
+            .split(',')
+            .map(|pattern| {
+                // Class names can't contain colons, but selectors can.
+                let (class, selector) = pattern.split_once(':').unwrap_or((pattern, "*"));
+                if class.is_empty() || selector.is_empty() {
+                    return Err(format!("Invalid pattern {:?}", pattern));
+                }
+                Ok(TracePattern {
+                    class: class.to_string(),
+                    selector: selector.to_string(),
+                })
+            })
+            .collect()
+    }
+
+    fn matches(&self, class: &str, selector: &str) -> bool {
+        glob_match(&self.class, class) && glob_match(&self.selector, selector)
+    }
+}
+
+/// Match `text` against `pattern`, where `*` in the pattern matches any
+/// sequence of characters and `?` matches any single character.
+fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+
+    let mut p = 0;
+    let mut t = 0;
+    // Pattern position just after the most recent `*`, and the text position
+    // that `*` is currently matched up to. On a mismatch, the `*` is made to
+    // match one more character and matching resumes from there.
+    let mut backtrack: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, t));
+        } else if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p;
+            t = star_t + 1;
+            backtrack = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+#[test]
+fn test_glob_match() {
+    assert!(glob_match("*", ""));
+    assert!(glob_match("*", "UIView"));
+    assert!(glob_match("UIView", "UIView"));
+    assert!(!glob_match("UIView", "UIViewController"));
+    assert!(glob_match("UI*", "UIViewController"));
+    assert!(glob_match("*Controller", "UIViewController"));
+    assert!(glob_match("UI*View*", "UIImageViewFoo"));
+    assert!(!glob_match("UI*View", "UIImageViewFoo"));
+    assert!(glob_match("init?ith*:", "initWithFrame:"));
+    assert!(glob_match("*:*:", "touchesBegan:withEvent:"));
+    assert!(!glob_match("*:*:*:", "touchesBegan:withEvent:"));
+    assert!(glob_match("a*b*c", "aXbYbZc"));
+}
+
+/// State needed to log the return value of a traced message.
+pub(super) struct TracedCall {
+    description: String,
+    r0_at_call: u32,
+}
+
+pub(super) fn class_name_and_kind(objc: &ObjC, class: Class) -> (&str, bool) {
+    let host_object = objc.get_host_object(class).unwrap();
+    let any = host_object.as_any();
+    if let Some(ClassHostObject {
+        name, is_metaclass, ..
+    }) = any.downcast_ref()
+    {
+        (name, *is_metaclass)
+    } else if let Some(UnimplementedClass { name, is_metaclass }) = any.downcast_ref() {
+        (name, *is_metaclass)
+    } else if let Some(FakeClass { name, is_metaclass }) = any.downcast_ref() {
+        (name, *is_metaclass)
+    } else {
+        panic!("{:?} is not a class, so its name can't be traced", class);
+    }
+}
+
+fn write_trace_line(env: &mut Environment, line: &str) {
+    let Some(ref path) = env.options.trace_objc_file else {
+        log!("{}", line);
+        return;
+    };
+    let file = env.objc.trace_file.get_or_insert_with(|| {
+        let file = File::create(path).unwrap_or_else(|e| {
+            panic!(
+                "Couldn't create Objective-C trace file {}: {}",
+                path.display(),
+                e
+            )
+        });
+        LineWriter::new(file)
+    });
+    writeln!(file, "{}", line).expect("Couldn't write to Objective-C trace file");
+}
+
+/// Log a message about to be sent, if it matches the `--trace-objc=` patterns.
+/// `class` is the class the method lookup started from. If [Some] is returned,
+/// [trace_return] must be called once the method returns.
+pub(super) fn trace_call(
+    env: &mut Environment,
+    receiver: id,
+    class: Class,
+    is_super: bool,
+    selector: SEL,
+    imp: IMP,
+) -> Option<TracedCall> {
+    let (class_name, is_metaclass) = class_name_and_kind(&env.objc, class);
+    let selector_name = selector.as_str(&env.mem);
+    if !env
+        .options
+        .trace_objc
+        .iter()
+        .any(|pattern| pattern.matches(class_name, selector_name))
+    {
+        return None;
+    }
+
+    let description = format!(
+        "{}[{}{} {}]",
+        if is_metaclass { '+' } else { '-' },
+        class_name,
+        if is_super { "(super)" } else { "" },
+        selector_name
+    );
+    let (kind, args) = match imp {
+        IMP::Host(host_imp) => ("host", host_imp.describe_args(env)),
+        // Guest methods' argument types aren't known.
+        IMP::Guest(guest_imp) => ("guest", format!("{:?}", guest_imp)),
+    };
+    let line = format!(
+        "{:indent$}{} receiver {:?} ({} method): {}",
+        "",
+        description,
+        receiver,
+        kind,
+        args,
+        indent = env.objc.trace_depth * 2
+    );
+    write_trace_line(env, &line);
+    env.objc.trace_depth += 1;
+
+    Some(TracedCall {
+        description,
+        r0_at_call: env.cpu.regs()[0],
+    })
+}
+
+/// Log the return value of a message traced by [trace_call].
+pub(super) fn trace_return(env: &mut Environment, imp: IMP, call: TracedCall) {
+    env.objc.trace_depth = env.objc.trace_depth.saturating_sub(1);
+
+    let retval = match imp {
+        IMP::Host(host_imp) => host_imp.describe_return(env, call.r0_at_call),
+        // Guest methods' return types aren't known, but it's probably in r0.
+        IMP::Guest(_) => format!("r0 = {:#x}", env.cpu.regs()[0]),
+    };
+    let line = format!(
+        "{:indent$}{} returned {}",
+        "",
+        call.description,
+        retval,
+        indent = env.objc.trace_depth * 2
+    );
+    write_trace_line(env, &line);
+}
@@ -5,16 +5,24 @@
  */
 //! Parsing and management of user-configurable options, e.g. for input methods.
 
+use crate::clock::parse_time_scale;
+use crate::cpu::parse_cpu_speed;
 use crate::gles::GLESImplementation;
+use crate::log::LogDirective;
+use crate::objc::TracePattern;
 use crate::window::DeviceOrientation;
+use crate::{parse_time_slice, DEFAULT_TIME_SLICE};
 use std::collections::HashMap;
 use std::io::{BufRead, BufReader, Read};
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::num::NonZeroU32;
+use std::path::PathBuf;
 
 pub const OPTIONS_HELP: &str =
     include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/OPTIONS_HELP.txt"));
 
+const HEADLESS_GLES1_ERROR: &str = "--headless can only be used with --gles1=software";
+
 /// Game controller button for `--button-to-touch=` option.
 #[derive(Copy, Clone, Hash, PartialEq, Eq, Debug)]
 pub enum Button {
@@ -44,11 +52,33 @@ pub struct Options {
     pub stabilize_virtual_cursor: Option<(f32, f32)>,
     pub gles1_implementation: Option<GLESImplementation>,
     pub direct_memory_access: bool,
+    pub enforce_segment_protection: bool,
     pub gdb_listen_addrs: Option<Vec<SocketAddr>>,
     pub preferred_languages: Option<Vec<String>>,
     pub headless: bool,
     pub print_fps: bool,
     pub fps_limit: Option<f64>,
+    pub cpu_speed: Option<u64>,
+    pub time_scale: f64,
+    pub thread_time_slice: u64,
+    pub deterministic: bool,
+    pub record_input: Option<PathBuf>,
+    pub replay_input: Option<PathBuf>,
+    pub screenshot_frames: Vec<u64>,
+    pub screenshot_dir: Option<PathBuf>,
+    pub exit_after_frames: Option<u64>,
+    pub trace_objc: Vec<TracePattern>,
+    pub trace_objc_file: Option<PathBuf>,
+    pub log_directives: Vec<LogDirective>,
+    pub log_timestamps: bool,
+    pub log_thread_ids: bool,
+    pub log_file: Option<PathBuf>,
+    pub load_state: Option<PathBuf>,
+    pub debug_heap: bool,
+    pub dump_heap_stats_on_exit: bool,
+    pub memory_limit: Option<u64>,
+    pub memory_warning_threshold: Option<u64>,
+    pub profile: Option<PathBuf>,
 }
 
 impl Default for Options {
@@ -66,11 +96,33 @@ impl Default for Options {
             stabilize_virtual_cursor: None,
             gles1_implementation: None,
             direct_memory_access: true,
+            enforce_segment_protection: false,
             gdb_listen_addrs: None,
             preferred_languages: None,
             headless: false,
             print_fps: false,
             fps_limit: Some(60.0), // Original iPhone is 60Hz and uses v-sync
+            cpu_speed: None,
+            time_scale: 1.0,
+            thread_time_slice: DEFAULT_TIME_SLICE,
+            deterministic: false,
+            record_input: None,
+            replay_input: None,
+            screenshot_frames: Vec::new(),
+            screenshot_dir: None,
+            exit_after_frames: None,
+            trace_objc: Vec::new(),
+            trace_objc_file: None,
+            log_directives: Vec::new(),
+            log_timestamps: false,
+            log_thread_ids: false,
+            log_file: None,
+            load_state: None,
+            debug_heap: false,
+            dump_heap_stats_on_exit: false,
+            memory_limit: None,
+            memory_warning_threshold: None,
+            profile: None,
         }
     }
 }
@@ -80,6 +132,15 @@ impl Options {
     /// if the option was valid and has been applied, or `Ok(false)` if the
     /// option was not recognized.
     pub fn parse_argument(&mut self, arg: &str) -> Result<bool, String> {
+        fn parse_mebibytes(arg: &str, name: &str) -> Result<u64, String> {
+            let mebibytes: u64 = arg
+                .parse()
+                .ok()
+                .filter(|&mebibytes| mebibytes != 0 && mebibytes < 4096)
+                .ok_or_else(|| format!("Invalid value for {}", name))?;
+            Ok(mebibytes * 1024 * 1024)
+        }
+
         fn parse_degrees(arg: &str, name: &str) -> Result<f32, String> {
             let arg: f32 = arg
                 .parse()
@@ -163,6 +224,8 @@ impl Options {
             );
         } else if arg == "--disable-direct-memory-access" {
             self.direct_memory_access = false;
+        } else if arg == "--enforce-segment-protection" {
+            self.enforce_segment_protection = true;
         } else if let Some(address) = arg.strip_prefix("--gdb=") {
             let addrs = address
                 .to_socket_addrs()
@@ -186,11 +249,98 @@ impl Options {
                     .ok_or_else(|| "Invalid value for --fps-limit=".to_string())?;
                 self.fps_limit = Some(limit);
             }
+        } else if let Some(value) = arg.strip_prefix("--cpu-speed=") {
+            self.cpu_speed = if value == "off" {
+                None
+            } else {
+                Some(
+                    parse_cpu_speed(value)
+                        .map_err(|e| format!("Invalid value for --cpu-speed=: {}", e))?,
+                )
+            };
+        } else if let Some(value) = arg.strip_prefix("--time-scale=") {
+            self.time_scale = parse_time_scale(value)
+                .map_err(|e| format!("Invalid value for --time-scale=: {}", e))?;
+        } else if let Some(value) = arg.strip_prefix("--thread-time-slice=") {
+            self.thread_time_slice = parse_time_slice(value)
+                .map_err(|e| format!("Invalid value for --thread-time-slice=: {}", e))?;
+        } else if arg == "--deterministic" {
+            self.deterministic = true;
+        } else if let Some(path) = arg.strip_prefix("--record-input=") {
+            self.record_input = Some(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix("--replay-input=") {
+            self.replay_input = Some(PathBuf::from(path));
+        } else if let Some(values) = arg.strip_prefix("--screenshot-frames=") {
+            self.screenshot_frames = values
+                .split(',')
+                .map(|value| value.parse().ok().filter(|&frame| frame != 0))
+                .collect::<Option<Vec<u64>>>()
+                .ok_or_else(|| "Invalid value for --screenshot-frames=".to_string())?;
+        } else if let Some(path) = arg.strip_prefix("--screenshot-dir=") {
+            self.screenshot_dir = Some(PathBuf::from(path));
+        } else if let Some(value) = arg.strip_prefix("--exit-after-frames=") {
+            let frames: u64 = value
+                .parse()
+                .ok()
+                .filter(|&frames| frames != 0)
+                .ok_or_else(|| "Invalid value for --exit-after-frames=".to_string())?;
+            self.exit_after_frames = Some(frames);
+        } else if let Some(patterns) = arg.strip_prefix("--trace-objc=") {
+            self.trace_objc = TracePattern::parse_list(patterns)
+                .map_err(|e| format!("Invalid value for --trace-objc=: {}", e))?;
+        } else if let Some(path) = arg.strip_prefix("--trace-objc-file=") {
+            self.trace_objc_file = Some(PathBuf::from(path));
+        } else if let Some(directives) = arg.strip_prefix("--log=") {
+            // Appended rather than replaced, so options from a file and from
+            // the command line can be combined.
+            self.log_directives.extend(
+                LogDirective::parse_list(directives)
+                    .map_err(|e| format!("Invalid value for --log=: {}", e))?,
+            );
+        } else if arg == "--log-timestamps" {
+            self.log_timestamps = true;
+        } else if arg == "--log-thread-ids" {
+            self.log_thread_ids = true;
+        } else if let Some(path) = arg.strip_prefix("--log-file=") {
+            self.log_file = Some(PathBuf::from(path));
+        } else if let Some(path) = arg.strip_prefix("--load-state=") {
+            self.load_state = Some(PathBuf::from(path));
+        } else if arg == "--debug-heap" {
+            self.debug_heap = true;
+        } else if arg == "--dump-heap-stats-on-exit" {
+            self.dump_heap_stats_on_exit = true;
+        } else if let Some(value) = arg.strip_prefix("--memory-limit=") {
+            self.memory_limit = Some(parse_mebibytes(value, "--memory-limit=")?);
+        } else if let Some(value) = arg.strip_prefix("--memory-warning-threshold=") {
+            self.memory_warning_threshold =
+                Some(parse_mebibytes(value, "--memory-warning-threshold=")?);
+        } else if let Some(path) = arg.strip_prefix("--profile=") {
+            self.profile = Some(PathBuf::from(path));
         } else {
             return Ok(false);
         };
         Ok(true)
     }
+
+    /// Check that the options that have been applied can be used together, and
+    /// fill in defaults that depend on other options. This must be called once
+    /// all the options (from files and from the command line) have been
+    /// applied, so that the result doesn't depend on the order they came in.
+    pub fn finish(&mut self) -> Result<(), String> {
+        if self.headless {
+            // There's no display to create an OpenGL context for, so only the
+            // software rasterizer can work.
+            match self.gles1_implementation {
+                None => self.gles1_implementation = Some(GLESImplementation::GLES1Software),
+                Some(GLESImplementation::GLES1Software) => (),
+                Some(_) => return Err(HEADLESS_GLES1_ERROR.to_string()),
+            }
+        }
+        if self.record_input.is_some() && self.replay_input.is_some() {
+            return Err("--record-input= can't be used with --replay-input=".to_string());
+        }
+        Ok(())
+    }
 }
 
 /// Try to get app-specific options from a file.
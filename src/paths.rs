@@ -80,6 +80,10 @@ pub const USER_OPTIONS_FILE: &str = "touchHLE_options.txt";
 /// the `Documents` directory.
 pub const SANDBOX_DIR: &str = "touchHLE_sandbox";
 
+/// Name of the directory where save states are stored by default (see
+/// [crate::save_state]).
+pub const SAVE_STATES_DIR: &str = "touchHLE_save_states";
+
 /// Get a platform-specific base path needed for accessing touchHLE's
 /// user-modifiable files. This is empty on platforms other than Android.
 pub fn user_data_base_path() -> &'static Path {
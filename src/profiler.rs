@@ -0,0 +1,565 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Sampling profiler for guest code (`--profile=`).
+//!
+//! Rather than interrupting the CPU, the profiler takes samples at convenient
+//! points: whenever the CPU returns control to the run loop ([sample]), and
+//! whenever a host function is called from guest code or returns to it
+//! ([host_call_begin] and [host_call_end]). Each sample is weighted by the
+//! number of [SAMPLE_INTERVAL]s since the previous one, and is attributed to
+//! whatever was running in between: guest code, or the innermost host
+//! function. The guest stack is found the same way as for stack traces (see
+//! [crate::backtrace]), so it's only as complete as the app's frame pointers.
+//!
+//! This means the sampling rate depends on how long the CPU runs before it
+//! returns to the run loop (its time slice). Guest code that calls host
+//! functions often is sampled about once per [SAMPLE_INTERVAL], but a tight
+//! loop of pure guest code is only sampled at the end of each time slice, with
+//! all the time since the previous sample going to wherever the CPU stopped.
+//! Over many samples this still shows roughly where the time goes, but short
+//! runs and individual stacks shouldn't be trusted too much.
+//!
+//! When the app exits ([finish]), the samples are symbolicated and written out
+//! in the "folded stacks" format used by flame graph tools such as
+//! [inferno](https://github.com/jonhoo/inferno) and
+//! [FlameGraph](https://github.com/brendangregg/FlameGraph), and a table of how
+//! often each host function was called and how long it took is printed.
+//!
+//! The profiler's state is global, like the logging configuration, so that
+//! the hooks in hot code paths only need to check an atomic when it's off.
+
+use crate::backtrace::{walk_frame_records, Symbolicator};
+use crate::cpu::Cpu;
+use crate::options::Options;
+use crate::Environment;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How often a sample should be taken. Samples can only be taken at certain
+/// points (see the module documentation), so this is really a minimum.
+const SAMPLE_INTERVAL: Duration = Duration::from_millis(1);
+
+/// Stop listing host functions after this many.
+const MAX_HOST_FUNCTIONS: usize = 50;
+
+/// Whether [PROFILER] is in use. The hooks are used in some very hot code
+/// paths, so this lets them avoid taking a lock when profiling is off.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+static PROFILER: Mutex<Option<Profiler>> = Mutex::new(None);
+
+#[derive(Copy, Clone, Hash, PartialEq, Eq)]
+enum Frame {
+    /// The address of the guest instruction being executed (PC).
+    Guest(u32),
+    /// The LR of the guest function being executed. This is only a real frame
+    /// if that function hasn't set up a frame record and called something,
+    /// which is decided when symbolicating.
+    Link(u32),
+    /// A guest return address.
+    Return(u32),
+    /// A host function, by index into [Profiler::host_functions].
+    Host(usize),
+    /// A return to host code that doesn't match an active host function.
+    UnknownHost,
+}
+
+struct HostFunction {
+    name: String,
+    calls: u64,
+    /// Time spent in the function, including any guest code it calls.
+    total_time: Duration,
+    /// Number of calls to the function that haven't returned yet, so that
+    /// recursive calls aren't counted twice in [Self::total_time].
+    active: u32,
+}
+
+/// A call to a host function that hasn't returned yet.
+struct HostCall {
+    function: usize,
+    start: Instant,
+    thread: usize,
+    /// SP and LR when the function was called. A host function that calls
+    /// another directly (e.g. `objc_msgSend` calling a method) doesn't change
+    /// these, which is how such calls are grouped together.
+    sp: u32,
+    lr: u32,
+}
+
+struct Profiler {
+    path: PathBuf,
+    /// When the previous sample was taken. This is [None] until the first
+    /// hook is called, so the time spent starting up isn't counted.
+    last_sample: Option<Instant>,
+    /// Sample counts for each distinct stack. Stacks are innermost first.
+    stacks: HashMap<Vec<Frame>, u64>,
+    host_functions: Vec<HostFunction>,
+    host_function_indices: HashMap<String, usize>,
+    /// Innermost last.
+    host_calls: Vec<HostCall>,
+}
+
+impl Profiler {
+    fn new(path: PathBuf) -> Profiler {
+        Profiler {
+            path,
+            last_sample: None,
+            stacks: HashMap::new(),
+            host_functions: Vec::new(),
+            host_function_indices: HashMap::new(),
+            host_calls: Vec::new(),
+        }
+    }
+
+    /// Record a sample if one is due, attributing the time since the last one
+    /// to the current state of the CPU. `pc_is_guest` should be [true] if PC
+    /// points to guest code, rather than to a host function's stub.
+    fn sample(&mut self, env: &Environment, now: Instant, pc_is_guest: bool) {
+        let Some(last_sample) = self.last_sample else {
+            self.last_sample = Some(now);
+            return;
+        };
+        let elapsed = now.saturating_duration_since(last_sample);
+        let intervals = (elapsed.as_nanos() / SAMPLE_INTERVAL.as_nanos()) as u32;
+        if intervals == 0 {
+            return;
+        }
+        self.last_sample = Some(last_sample + SAMPLE_INTERVAL * intervals);
+
+        let stack = self.current_stack(env, pc_is_guest);
+        *self.stacks.entry(stack).or_default() += u64::from(intervals);
+    }
+
+    fn current_stack(&self, env: &Environment, pc_is_guest: bool) -> Vec<Frame> {
+        let regs = env.cpu.regs();
+        let return_to_host_routine = env.dyld.return_to_host_routine().addr_without_thumb_bit();
+        let (return_addresses, _) = walk_frame_records(env);
+
+        // Only host calls made by the current thread are relevant.
+        let mut host_calls = self
+            .host_calls
+            .iter()
+            .rev()
+            .filter(|call| call.thread == env.current_thread)
+            .peekable();
+        let in_host = host_calls
+            .peek()
+            .is_some_and(|call| call.sp == regs[Cpu::SP] && call.lr == regs[Cpu::LR]);
+        // Each time guest code returns to host code, the host function that
+        // called it is the next one out, along with any host functions that
+        // called that one directly. The return address of the outermost of
+        // those isn't in a frame record, because it was in LR at the time.
+        let mut push_host_calls = |stack: &mut Vec<Frame>| {
+            let Some(first) = host_calls.next() else {
+                stack.push(Frame::UnknownHost);
+                return;
+            };
+            stack.push(Frame::Host(first.function));
+            while let Some(next) =
+                host_calls.next_if(|next| next.sp == first.sp && next.lr == first.lr)
+            {
+                stack.push(Frame::Host(next.function));
+            }
+            stack.push(Frame::Return(first.lr));
+        };
+
+        let mut stack = Vec::with_capacity(return_addresses.len() + 2);
+        let lr = regs[Cpu::LR];
+        if in_host {
+            push_host_calls(&mut stack);
+        } else if !pc_is_guest {
+            // A host function is about to be called, so the caller is what was
+            // running.
+            stack.push(Frame::Return(lr));
+        } else {
+            stack.push(Frame::Guest(
+                env.cpu.pc_with_thumb_bit().addr_with_thumb_bit(),
+            ));
+            if lr & !1 == return_to_host_routine {
+                // If the function has set up a frame record, LR is also in it.
+                if return_addresses.first() != Some(&lr) {
+                    push_host_calls(&mut stack);
+                }
+            } else {
+                stack.push(Frame::Link(lr));
+            }
+        }
+        for return_address in return_addresses {
+            if return_address & !1 == return_to_host_routine {
+                push_host_calls(&mut stack);
+            } else {
+                stack.push(Frame::Return(return_address));
+            }
+        }
+        stack
+    }
+
+    /// Record that `thread` is calling the host function `name`, with the
+    /// given SP and LR.
+    fn begin_host_call(&mut self, name: &str, thread: usize, sp: u32, lr: u32, now: Instant) {
+        let function = match self.host_function_indices.get(name) {
+            Some(&function) => function,
+            None => {
+                let function = self.host_functions.len();
+                self.host_functions.push(HostFunction {
+                    name: name.to_string(),
+                    calls: 0,
+                    total_time: Duration::ZERO,
+                    active: 0,
+                });
+                self.host_function_indices
+                    .insert(name.to_string(), function);
+                function
+            }
+        };
+        let host_function = &mut self.host_functions[function];
+        host_function.calls += 1;
+        host_function.active += 1;
+
+        self.host_calls.push(HostCall {
+            function,
+            start: now,
+            thread,
+            sp,
+            lr,
+        });
+    }
+
+    /// Find the host call made most recently by `thread` that hasn't returned
+    /// yet. Other threads' calls may be more recent, since threads can switch
+    /// while a host function is calling guest code.
+    fn innermost_host_call(&self, thread: usize) -> Option<usize> {
+        self.host_calls
+            .iter()
+            .rposition(|call| call.thread == thread)
+    }
+
+    /// Record that the host call at `index` in [Self::host_calls] has
+    /// returned.
+    fn end_host_call(&mut self, index: usize, now: Instant) {
+        let call = self.host_calls.remove(index);
+        let host_function = &mut self.host_functions[call.function];
+        host_function.active -= 1;
+        if host_function.active == 0 {
+            host_function.total_time += now.saturating_duration_since(call.start);
+        }
+    }
+
+    /// Symbolicate the samples and merge them into folded stacks (the frames'
+    /// names, outermost first, separated by semicolons), sorted.
+    fn fold_stacks(&self, symbolicator: &Symbolicator) -> Vec<(String, u64)> {
+        // Different addresses in the same function should be merged.
+        let mut folded_stacks: HashMap<String, u64> = HashMap::new();
+        for (stack, &count) in &self.stacks {
+            let mut names: Vec<String> = Vec::with_capacity(stack.len());
+            for (i, &frame) in stack.iter().enumerate() {
+                let name = match frame {
+                    Frame::Guest(addr) => symbolicator.function_name(addr),
+                    Frame::Link(addr) => {
+                        // A stale LR points into the function being executed,
+                        // or is the same as the first frame record's.
+                        let name = symbolicator.function_name_for_return_address(addr);
+                        if names.last() == Some(&name)
+                            || stack.get(i + 1) == Some(&Frame::Return(addr))
+                        {
+                            continue;
+                        }
+                        name
+                    }
+                    Frame::Return(addr) => symbolicator.function_name_for_return_address(addr),
+                    Frame::Host(function) => {
+                        let name = &self.host_functions[function].name;
+                        // C symbols have a leading underscore that isn't part
+                        // of the name used in source code.
+                        format!("touchHLE!{}", name.strip_prefix('_').unwrap_or(name))
+                    }
+                    Frame::UnknownHost => "[host function]".to_string(),
+                };
+                names.push(name);
+            }
+            // Semicolons separate the frames and can't be escaped.
+            let folded = names
+                .iter()
+                .rev()
+                .map(|name| name.replace(';', ":"))
+                .collect::<Vec<_>>()
+                .join(";");
+            *folded_stacks.entry(folded).or_default() += count;
+        }
+
+        let mut folded_stacks: Vec<_> = folded_stacks.into_iter().collect();
+        folded_stacks.sort();
+        folded_stacks
+    }
+
+    fn write_folded_stacks(&self, symbolicator: &Symbolicator) -> std::io::Result<()> {
+        let mut file = BufWriter::new(File::create(&self.path)?);
+        for (folded, count) in self.fold_stacks(symbolicator) {
+            writeln!(file, "{} {}", folded, count)?;
+        }
+        file.flush()
+    }
+
+    fn print_host_functions(&self) {
+        let mut host_functions: Vec<&HostFunction> = self.host_functions.iter().collect();
+        host_functions.sort_by_key(|function| std::cmp::Reverse(function.total_time));
+        echo!("Host functions, by time taken (including any guest code they call):");
+        echo!("       calls  total (ms)  average (µs)  function");
+        for function in host_functions.iter().take(MAX_HOST_FUNCTIONS) {
+            echo!(
+                "  {:>10} {:>11.1} {:>13.1}  {}",
+                function.calls,
+                function.total_time.as_secs_f64() * 1e3,
+                function.total_time.as_secs_f64() * 1e6 / function.calls as f64,
+                function.name
+            );
+        }
+        if host_functions.len() > MAX_HOST_FUNCTIONS {
+            echo!(
+                "  ...and {} more host functions.",
+                host_functions.len() - MAX_HOST_FUNCTIONS
+            );
+        }
+    }
+}
+
+/// Apply the `--profile=` option. This starts the profiler if it's used.
+pub fn configure(options: &Options) {
+    let Some(ref path) = options.profile else {
+        return;
+    };
+    *PROFILER.lock().unwrap() = Some(Profiler::new(path.clone()));
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Whether the profiler is running. Callers of [host_call_begin] can use this
+/// to avoid working out a function's name when it isn't needed.
+#[inline(always)]
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Take a sample if one is due. This should be called when the CPU stops
+/// running guest code, with PC pointing to the next guest instruction.
+#[inline(always)]
+pub fn sample(env: &Environment) {
+    if enabled() {
+        sample_slow(env);
+    }
+}
+
+#[cold]
+fn sample_slow(env: &Environment) {
+    if let Some(ref mut profiler) = *PROFILER.lock().unwrap() {
+        profiler.sample(env, Instant::now(), true);
+    }
+}
+
+/// Record that guest code is calling a host function. This must be followed
+/// by a call to [host_call_end] when the host function returns, before any
+/// change to the guest registers other than r0-r3.
+///
+/// `name` is the name of the function as seen by guest code, e.g. `_malloc` or
+/// `-[NSObject init]`.
+#[inline(always)]
+pub fn host_call_begin(env: &Environment, name: &str) {
+    if enabled() {
+        host_call_begin_slow(env, name);
+    }
+}
+
+#[cold]
+fn host_call_begin_slow(env: &Environment, name: &str) {
+    let now = Instant::now();
+    let Some(ref mut profiler) = *PROFILER.lock().unwrap() else {
+        return;
+    };
+    profiler.sample(env, now, false);
+
+    let regs = env.cpu.regs();
+    profiler.begin_host_call(name, env.current_thread, regs[Cpu::SP], regs[Cpu::LR], now);
+}
+
+/// Record that the host function most recently passed to [host_call_begin]
+/// by the current thread has returned.
+#[inline(always)]
+pub fn host_call_end(env: &Environment) {
+    if enabled() {
+        host_call_end_slow(env);
+    }
+}
+
+#[cold]
+fn host_call_end_slow(env: &Environment) {
+    let now = Instant::now();
+    let Some(ref mut profiler) = *PROFILER.lock().unwrap() else {
+        return;
+    };
+    profiler.sample(env, now, false);
+
+    let index = profiler.innermost_host_call(env.current_thread).unwrap();
+    profiler.end_host_call(index, now);
+}
+
+/// Forget about host functions that were called from guest code at or below
+/// the current stack pointer, because `longjmp()` unwound them without
+/// [host_call_end] being called.
+pub fn host_calls_unwound(env: &Environment) {
+    if !enabled() {
+        return;
+    }
+    let now = Instant::now();
+    let Some(ref mut profiler) = *PROFILER.lock().unwrap() else {
+        return;
+    };
+    let sp = env.cpu.regs()[Cpu::SP];
+    while let Some(index) = profiler.innermost_host_call(env.current_thread) {
+        if profiler.host_calls[index].sp > sp {
+            break;
+        }
+        profiler.end_host_call(index, now);
+    }
+}
+
+/// Stop the profiler, if it's running, and write out the results. This is
+/// meant for when the app exits.
+pub fn finish(env: &Environment) {
+    let Some(profiler) = PROFILER.lock().unwrap().take() else {
+        return;
+    };
+    ENABLED.store(false, Ordering::Relaxed);
+
+    let samples: u64 = profiler.stacks.values().sum();
+    let symbolicator = Symbolicator::new(&env.bins, &env.objc, &env.mem);
+    match profiler.write_folded_stacks(&symbolicator) {
+        Ok(()) => echo!(
+            "Profiler: wrote {} samples ({} distinct stacks) to {}.",
+            samples,
+            profiler.stacks.len(),
+            profiler.path.display()
+        ),
+        Err(e) => echo!(
+            "Profiler: couldn't write {}: {}",
+            profiler.path.display(),
+            e
+        ),
+    }
+    profiler.print_host_functions();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbolicator() -> Symbolicator {
+        Symbolicator::for_image(
+            "MyApp",
+            vec![(0x1000, 0x2000)],
+            vec![
+                (0x1000, "_leaf".to_string()),
+                (0x1100, "_middle".to_string()),
+                (0x1200, "_main".to_string()),
+            ],
+        )
+    }
+
+    #[test]
+    fn fold_stacks() {
+        let mut profiler = Profiler::new(PathBuf::new());
+        profiler.begin_host_call("_malloc", 0, 0, 0, Instant::now());
+        let stacks = [
+            // leaf() was called by middle() (in LR), which was called by main()
+            // (in a frame record).
+            (
+                vec![
+                    Frame::Guest(0x1010),
+                    Frame::Link(0x1105),
+                    Frame::Return(0x1205),
+                ],
+                2,
+            ),
+            // Different addresses in the same functions are merged.
+            (
+                vec![
+                    Frame::Guest(0x1020),
+                    Frame::Link(0x1109),
+                    Frame::Return(0x1209),
+                ],
+                3,
+            ),
+            // A stale LR that points into the function being executed.
+            (
+                vec![
+                    Frame::Guest(0x1110),
+                    Frame::Link(0x1105),
+                    Frame::Return(0x1205),
+                ],
+                1,
+            ),
+            // An LR that is also in the first frame record.
+            (
+                vec![
+                    Frame::Guest(0x1010),
+                    Frame::Link(0x1205),
+                    Frame::Return(0x1205),
+                ],
+                4,
+            ),
+            (
+                vec![Frame::Host(0), Frame::Return(0x1105), Frame::Return(0x1205)],
+                6,
+            ),
+            (vec![Frame::Guest(0x3000), Frame::UnknownHost], 7),
+        ];
+        profiler.stacks.extend(stacks);
+
+        assert_eq!(
+            profiler.fold_stacks(&symbolicator()),
+            [
+                ("MyApp!main;MyApp!leaf", 4),
+                ("MyApp!main;MyApp!middle", 1),
+                ("MyApp!main;MyApp!middle;MyApp!leaf", 5),
+                ("MyApp!main;MyApp!middle;touchHLE!malloc", 6),
+                ("[host function];[unknown]", 7),
+            ]
+            .map(|(folded, count)| (folded.to_string(), count))
+        );
+    }
+
+    #[test]
+    fn host_calls_on_different_threads() {
+        let mut profiler = Profiler::new(PathBuf::new());
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        profiler.begin_host_call("_a", 0, 0x1000, 0x2000, start);
+        profiler.begin_host_call("_b", 1, 0x3000, 0x4000, start + ms(1));
+        profiler.begin_host_call("_a", 0, 0x0f00, 0x2100, start + ms(2));
+
+        // The innermost call for thread 1 isn't the innermost overall.
+        let index = profiler.innermost_host_call(1).unwrap();
+        assert_eq!(index, 1);
+        profiler.end_host_call(index, start + ms(4));
+        assert_eq!(profiler.host_functions[1].total_time, ms(3));
+        assert_eq!(profiler.host_calls.len(), 2);
+        assert!(profiler.host_calls.iter().all(|call| call.thread == 0));
+
+        // Recursive calls are only timed once, from the outermost call.
+        let index = profiler.innermost_host_call(0).unwrap();
+        assert_eq!(profiler.host_calls[index].sp, 0x0f00);
+        profiler.end_host_call(index, start + ms(5));
+        assert_eq!(profiler.host_functions[0].total_time, Duration::ZERO);
+        let index = profiler.innermost_host_call(0).unwrap();
+        profiler.end_host_call(index, start + ms(6));
+        assert_eq!(profiler.host_functions[0].total_time, ms(6));
+        assert_eq!(profiler.host_functions[0].calls, 2);
+        assert!(profiler.innermost_host_call(0).is_none());
+    }
+}
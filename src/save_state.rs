@@ -0,0 +1,231 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Save states: snapshots of a running app that can be loaded again later
+//! (F2 and F3, `--load-state=`).
+//!
+//! A save state contains the guest memory that is in use, the allocator's
+//! record of which memory that is, and the registers of every guest thread.
+//! Only pages that aren't all zeroes are stored, so a state is usually much
+//! smaller than the memory the app is using. The format is a simple versioned
+//! little-endian binary one, see [save] and [load].
+//!
+//! That is enough for most of what a game keeps track of (the level, the
+//! score, the positions of things), because the app keeps it in guest memory.
+//! It is not everything though, and these are not covered:
+//!
+//! - Objective-C host objects, and the class and selector tables. Objects
+//!   that are implemented by touchHLE keep their state on the host, not in
+//!   guest memory.
+//! - Other framework state, e.g. OpenGL ES contexts and textures, audio
+//!   players and the OpenAL state.
+//! - Open guest file descriptors.
+//!
+//! Guest code runs nested inside host functions, e.g. the run loop started by
+//! `UIApplicationMain` calls back into the app, so the guest stack contains
+//! return addresses into host frames that can't be saved. A state can
+//! therefore only be loaded at the same point in the host call stack as where
+//! it was saved. The environment saves and loads states at the start of an
+//! iteration of the main thread's run loop, and a state loaded with
+//! `--load-state=` is applied when the run loop first starts. Host objects
+//! created since the app was launched won't match the restored memory, so
+//! this works best for apps that create most of their objects at launch and
+//! afterwards mostly change guest memory.
+
+use crate::cpu::Cpu;
+use crate::mem::Mem;
+use std::path::{Path, PathBuf};
+
+const MAGIC: &[u8; 8] = b"tHLEstat";
+
+/// Version of the save state format. This must be incremented whenever the
+/// format changes, so that older states are rejected rather than misread.
+const FORMAT_VERSION: u32 = 1;
+
+/// The file F2 and F3 use if `--load-state=` isn't used.
+pub fn default_path(bundle_id: &str) -> PathBuf {
+    crate::paths::user_data_base_path()
+        .join(crate::paths::SAVE_STATES_DIR)
+        .join(format!("{}.touchHLEstate", bundle_id))
+}
+
+/// The registers of a guest thread.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CpuRegisters {
+    pub regs: [u32; 16],
+    pub cpsr: u32,
+    pub ext_regs: [u32; 64],
+    pub fpscr: u32,
+}
+
+impl CpuRegisters {
+    /// Get the registers of the thread that is currently running on the CPU.
+    /// For other threads, swap their context in first with
+    /// [Cpu::swap_context].
+    pub fn from_cpu(cpu: &Cpu) -> CpuRegisters {
+        CpuRegisters {
+            regs: *cpu.regs(),
+            cpsr: cpu.cpsr(),
+            ext_regs: *cpu.ext_regs(),
+            fpscr: cpu.fpscr(),
+        }
+    }
+
+    pub fn apply_to_cpu(&self, cpu: &mut Cpu) {
+        *cpu.regs_mut() = self.regs;
+        cpu.set_cpsr(self.cpsr);
+        *cpu.ext_regs_mut() = self.ext_regs;
+        cpu.set_fpscr(self.fpscr);
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        for &reg in self.regs.iter() {
+            write_u32(out, reg);
+        }
+        write_u32(out, self.cpsr);
+        for &reg in self.ext_regs.iter() {
+            write_u32(out, reg);
+        }
+        write_u32(out, self.fpscr);
+    }
+
+    fn read(data: &mut &[u8]) -> Result<CpuRegisters, String> {
+        let mut registers = CpuRegisters {
+            regs: [0; 16],
+            cpsr: 0,
+            ext_regs: [0; 64],
+            fpscr: 0,
+        };
+        for reg in registers.regs.iter_mut() {
+            *reg = read_u32(data)?;
+        }
+        registers.cpsr = read_u32(data)?;
+        for reg in registers.ext_regs.iter_mut() {
+            *reg = read_u32(data)?;
+        }
+        registers.fpscr = read_u32(data)?;
+        Ok(registers)
+    }
+}
+
+pub(crate) fn write_u32(out: &mut Vec<u8>, value: u32) {
+    out.extend_from_slice(&value.to_le_bytes());
+}
+
+pub(crate) fn read_bytes<'a>(data: &mut &'a [u8], count: usize) -> Result<&'a [u8], String> {
+    if data.len() < count {
+        return Err("Save state is truncated".to_string());
+    }
+    let (bytes, rest) = data.split_at(count);
+    *data = rest;
+    Ok(bytes)
+}
+
+pub(crate) fn read_u32(data: &mut &[u8]) -> Result<u32, String> {
+    let bytes = read_bytes(data, 4)?;
+    Ok(u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+fn encode(mem: &Mem, threads: &[CpuRegisters]) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.extend_from_slice(MAGIC);
+    write_u32(&mut out, FORMAT_VERSION);
+    write_u32(&mut out, threads.len().try_into().unwrap());
+    for registers in threads {
+        registers.write(&mut out);
+    }
+    mem.write_snapshot(&mut out);
+    out
+}
+
+fn decode(mut data: &[u8], mem: &mut Mem) -> Result<Vec<CpuRegisters>, String> {
+    let data = &mut data;
+    if read_bytes(data, MAGIC.len())? != MAGIC {
+        return Err("Not a touchHLE save state".to_string());
+    }
+    let version = read_u32(data)?;
+    if version != FORMAT_VERSION {
+        return Err(format!(
+            "Save state has format version {}, but this version of touchHLE only supports version {}",
+            version, FORMAT_VERSION
+        ));
+    }
+    let thread_count = read_u32(data)?;
+    let threads = (0..thread_count)
+        .map(|_| CpuRegisters::read(data))
+        .collect::<Result<Vec<_>, _>>()?;
+    mem.read_snapshot(data)?;
+    Ok(threads)
+}
+
+/// Save a state to a file. `threads` are the registers of each guest thread,
+/// indexed by thread ID.
+pub fn save(path: &Path, mem: &Mem, threads: &[CpuRegisters]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .map_err(|e| format!("Couldn't create {}: {}", parent.display(), e))?;
+    }
+    std::fs::write(path, encode(mem, threads))
+        .map_err(|e| format!("Couldn't write {}: {}", path.display(), e))?;
+    log!("Saved state to {}", path.display());
+    Ok(())
+}
+
+/// Load a state from a file, replacing the contents of guest memory, and
+/// return the registers of each guest thread, indexed by thread ID. The caller
+/// is responsible for giving those to the threads. Guest memory is left
+/// unchanged if the state can't be loaded.
+pub fn load(path: &Path, mem: &mut Mem, cpu: &mut Cpu) -> Result<Vec<CpuRegisters>, String> {
+    let data =
+        std::fs::read(path).map_err(|e| format!("Couldn't read {}: {}", path.display(), e))?;
+    let threads = decode(&data, mem)
+        .map_err(|e| format!("Couldn't load state from {}: {}", path.display(), e))?;
+    // The code in memory may have changed.
+    cpu.invalidate_cache_range(0, u32::MAX);
+    log!("Loaded state from {}", path.display());
+    Ok(threads)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_mem() -> Mem {
+        let mut mem = Mem::new();
+        mem.set_null_segment_size(0x1000);
+        mem
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut mem = new_mem();
+        let ptr = mem.alloc(0x2000);
+        mem.bytes_at_mut(ptr.cast(), 4).copy_from_slice(b"save");
+        let mut registers = CpuRegisters {
+            regs: [0; 16],
+            cpsr: Cpu::CPSR_USER_MODE,
+            ext_regs: [0; 64],
+            fpscr: 0x0300_0000,
+        };
+        registers.regs[Cpu::PC] = 0x2000;
+        registers.ext_regs[63] = 0xffff_ffff;
+
+        let data = encode(&mem, std::slice::from_ref(&registers));
+
+        let mut mem2 = new_mem();
+        let _ = mem2.alloc(0x4000);
+        let other = mem2.alloc(0x10);
+        mem2.write(other.cast(), 0xffu8);
+        assert_eq!(decode(&data, &mut mem2), Ok(vec![registers]));
+        assert_eq!(mem2.bytes_at(ptr.cast(), 4), b"save");
+        // The allocation made before loading is gone, and zeroed.
+        assert_eq!(mem2.read(other.cast::<u8>()), 0);
+
+        assert!(decode(&data[..data.len() - 1], &mut new_mem()).is_err());
+        let mut wrong_version = data.clone();
+        wrong_version[MAGIC.len()] += 1;
+        assert!(decode(&wrong_version, &mut new_mem()).is_err());
+    }
+}
@@ -12,18 +12,28 @@
 //! window system interaction in general, because it is assumed only one window
 //! will be needed for the runtime of the app.
 
+mod input_recording;
+mod screenshot;
+
+use crate::clock::VirtualClock;
+use crate::gles::gles1_software::DefaultFramebuffer;
 use crate::gles::present::present_frame;
-use crate::gles::{create_gles1_ctx, GLES};
+use crate::gles::{create_gles1_ctx, GLESImplementation, GLES};
 use crate::image::Image;
 use crate::matrix::Matrix;
 use crate::options::Options;
+use input_recording::InputRecording;
 use sdl2::mouse::MouseButton;
 use sdl2::pixels::PixelFormatEnum;
+use sdl2::render::BlendMode;
 use sdl2::surface::Surface;
+use std::cell::{Cell, RefCell};
 use std::collections::{HashMap, VecDeque};
 use std::env;
 use std::f32::consts::FRAC_PI_2;
 use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::rc::Rc;
 use std::time::{Duration, Instant};
 
 #[derive(Copy, Clone, Eq, PartialEq)]
@@ -94,12 +104,30 @@ pub enum Event {
     /// OS has informed touchHLE it will soon terminate.
     /// (iOS `applicationWillTerminate:`, Android `onDestroy()`)
     AppWillTerminate,
+    /// User pressed F9, requesting that the app be sent a memory warning as if
+    /// the device was low on memory.
+    /// (iOS `applicationDidReceiveMemoryWarning:`)
+    SimulateMemoryWarning,
     TouchesDown(HashMap<FingerId, Coords>),
     TouchesMove(HashMap<FingerId, Coords>),
     TouchesUp(HashMap<FingerId, Coords>),
     /// User pressed F12, requesting that execution be paused and the debugger
     /// take over.
     EnterDebugger,
+    /// User pressed F2, requesting that the app's state be saved (see
+    /// [crate::save_state]).
+    SaveState,
+    /// User pressed F3, requesting that the last saved state be loaded.
+    LoadState,
+    /// User pressed F10, requesting that heap and Objective-C object
+    /// statistics be printed (see [crate::heap_stats]).
+    PrintHeapStats,
+    /// User pressed F7, requesting that virtual time run at half the current
+    /// speed (see [crate::clock]).
+    DecreaseTimeScale,
+    /// User pressed F8, requesting that virtual time run at double the current
+    /// speed (see [crate::clock]).
+    IncreaseTimeScale,
     TextInput(TextInputEvent),
 }
 
@@ -153,6 +181,9 @@ pub struct Window {
     fullscreen: bool,
     scale_hack: NonZeroU32,
     internal_gl_ctx: Option<Box<dyn GLES>>,
+    /// Framebuffer shared by all software rasterizer contexts. If this exists,
+    /// it's what gets presented, rather than an OpenGL framebuffer.
+    software_framebuffer: Option<Rc<RefCell<DefaultFramebuffer>>>,
     splash_image: Option<Image>,
     device_orientation: DeviceOrientation,
     app_gl_ctx_no_longer_current: bool,
@@ -162,6 +193,18 @@ pub struct Window {
     accelerometer: Option<sdl2::sensor::Sensor>,
     virtual_cursor_last: Option<(f32, f32, bool, bool)>,
     virtual_cursor_last_unsticky: Option<(f32, f32, Instant)>,
+    /// Number of frames presented so far, used to timestamp recorded input.
+    frame_number: Cell<u64>,
+    input_recording: Option<RefCell<InputRecording>>,
+    /// Copy of `screenshot_frames` on [Options].
+    screenshot_frames: Vec<u64>,
+    screenshot_dir: PathBuf,
+    /// Copy of `exit_after_frames` on [Options].
+    exit_after_frames: Option<u64>,
+    /// Whether the app has been paused with F5 (see [Self::wait_while_paused]).
+    paused: bool,
+    /// Whether F6 was pressed while paused, so one more frame should be run.
+    frame_step_requested: bool,
 }
 impl Window {
     /// Returns [true] if touchHLE is running on a device where we should always
@@ -177,6 +220,20 @@ impl Window {
         launch_image: Option<Image>,
         options: &Options,
     ) -> Window {
+        let software_rendering = matches!(
+            options.gles1_implementation,
+            Some(GLESImplementation::GLES1Software)
+        );
+
+        if options.headless {
+            // The software rasterizer doesn't need a display, so it can still
+            // draw to an invisible window. The other implementations need an
+            // OpenGL window, which the dummy driver can't provide, so they
+            // can't be used with --headless (see [Options::finish]).
+            assert!(software_rendering);
+            sdl2::hint::set("SDL_VIDEODRIVER", "dummy");
+        }
+
         let sdl_ctx = sdl2::init().unwrap();
         let video_ctx = sdl_ctx.video().unwrap();
 
@@ -212,39 +269,36 @@ impl Window {
         let device_orientation = options.initial_orientation;
         let fullscreen = options.fullscreen;
 
-        let mut window = if Self::rotatable_fullscreen() {
+        let mut window_builder = if Self::rotatable_fullscreen() {
             // Without this, SDL will force fullscreen mode to be portrait.
             set_sdl2_orientation(device_orientation);
             let screen_size = video_ctx.display_bounds(0).unwrap().size();
             let (width, height) = rotate_fullscreen_size(device_orientation, screen_size);
-            let window = video_ctx
-                .window(title, width, height)
-                .fullscreen()
-                .opengl()
-                .build()
-                .unwrap();
-            window
+            let mut window_builder = video_ctx.window(title, width, height);
+            window_builder.fullscreen();
+            window_builder
         } else if fullscreen {
             let (width, height) = video_ctx.display_bounds(0).unwrap().size();
-            let window = video_ctx
-                .window(title, width, height)
-                .fullscreen_desktop()
-                .opengl()
-                .build()
-                .unwrap();
-            window
+            let mut window_builder = video_ctx.window(title, width, height);
+            window_builder.fullscreen_desktop();
+            window_builder
         } else {
             let (width, height) = size_for_orientation(device_orientation, scale_hack);
-            let window = video_ctx
-                .window(title, width, height)
-                .position_centered()
-                .opengl()
-                .build()
-                .unwrap();
-            window
+            let mut window_builder = video_ctx.window(title, width, height);
+            window_builder.position_centered();
+            window_builder
         };
+        // The software rasterizer presents via the window surface instead,
+        // which SDL2 doesn't allow for OpenGL windows.
+        if !software_rendering {
+            window_builder.opengl();
+        }
+        if options.headless {
+            window_builder.hidden();
+        }
+        let mut window = window_builder.build().unwrap();
 
-        if env::consts::OS == "android" {
+        if env::consts::OS == "android" && !software_rendering {
             // Sanity check
             let gl_attr = video_ctx.gl_attr();
             debug_assert_eq!(gl_attr.context_profile(), sdl2::video::GLProfile::GLES);
@@ -276,6 +330,26 @@ impl Window {
         #[cfg(target_os = "macos")]
         let max_height = window.size().1;
 
+        let input_recording = if let Some(ref path) = options.record_input {
+            let recording = InputRecording::record(path).unwrap_or_else(|e| {
+                panic!(
+                    "Couldn't start recording input to {}: {}",
+                    path.display(),
+                    e
+                )
+            });
+            log!("Recording input to {}.", path.display());
+            Some(RefCell::new(recording))
+        } else if let Some(ref path) = options.replay_input {
+            let recording = InputRecording::replay(path).unwrap_or_else(|e| {
+                panic!("Couldn't load input recording {}: {}", path.display(), e)
+            });
+            log!("Replaying input from {}.", path.display());
+            Some(RefCell::new(recording))
+        } else {
+            None
+        };
+
         let mut window = Window {
             _sdl_ctx: sdl_ctx,
             video_ctx,
@@ -292,6 +366,7 @@ impl Window {
             fullscreen,
             scale_hack,
             internal_gl_ctx: None,
+            software_framebuffer: None,
             splash_image: launch_image,
             device_orientation,
             app_gl_ctx_no_longer_current: false,
@@ -301,6 +376,16 @@ impl Window {
             accelerometer,
             virtual_cursor_last: None,
             virtual_cursor_last_unsticky: None,
+            frame_number: Cell::new(0),
+            input_recording,
+            screenshot_frames: options.screenshot_frames.clone(),
+            screenshot_dir: options
+                .screenshot_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(".")),
+            exit_after_frames: options.exit_after_frames,
+            paused: false,
+            frame_step_requested: false,
         };
 
         // Set up OpenGL ES context used for splash screen and app UI rendering
@@ -583,6 +668,63 @@ impl Window {
                     echo!("F12 pressed, EnterDebugger event queued.");
                     Event::EnterDebugger
                 }
+                E::KeyDown {
+                    keycode: Some(sdl2::keyboard::Keycode::F2),
+                    ..
+                } => {
+                    echo!("F2 pressed, SaveState event queued.");
+                    Event::SaveState
+                }
+                E::KeyDown {
+                    keycode: Some(sdl2::keyboard::Keycode::F3),
+                    ..
+                } => {
+                    echo!("F3 pressed, LoadState event queued.");
+                    Event::LoadState
+                }
+                E::KeyDown {
+                    keycode: Some(sdl2::keyboard::Keycode::F10),
+                    ..
+                } => Event::PrintHeapStats,
+                // Pausing is handled here rather than by the app's run loop,
+                // since the app isn't running while it's paused.
+                E::KeyDown {
+                    keycode: Some(sdl2::keyboard::Keycode::F5),
+                    ..
+                } => {
+                    self.paused = !self.paused;
+                    if self.paused {
+                        echo!("F5 pressed, pausing after the next frame.");
+                    }
+                    continue;
+                }
+                E::KeyDown {
+                    keycode: Some(sdl2::keyboard::Keycode::F6),
+                    ..
+                } => {
+                    if self.paused {
+                        self.frame_step_requested = true;
+                    } else {
+                        echo!("F6 pressed, pausing after the next frame.");
+                        self.paused = true;
+                    }
+                    continue;
+                }
+                E::KeyDown {
+                    keycode: Some(sdl2::keyboard::Keycode::F7),
+                    ..
+                } => Event::DecreaseTimeScale,
+                E::KeyDown {
+                    keycode: Some(sdl2::keyboard::Keycode::F8),
+                    ..
+                } => Event::IncreaseTimeScale,
+                E::KeyDown {
+                    keycode: Some(sdl2::keyboard::Keycode::F9),
+                    ..
+                } => {
+                    echo!("F9 pressed, sending a memory warning.");
+                    Event::SimulateMemoryWarning
+                }
                 E::KeyDown {
                     keycode: Some(sdl2::keyboard::Keycode::Backspace),
                     ..
@@ -627,12 +769,48 @@ impl Window {
         }
     }
 
+    fn is_replaying_input(&self) -> bool {
+        self.input_recording
+            .as_ref()
+            .is_some_and(|recording| recording.borrow().is_replaying())
+    }
+
     /// Pop an event from the queue (in FIFO order, except for high priority
     /// events)
     pub fn pop_event(&mut self) -> Option<Event> {
-        self.high_priority_event
+        let frame = self.frame_number.get();
+
+        if self.is_replaying_input() {
+            // Input from the host is replaced by the recording, but the user
+            // should still be able to quit or use the debugging hotkeys.
+            self.event_queue.retain(|event| {
+                matches!(
+                    event,
+                    Event::Quit
+                        | Event::EnterDebugger
+                        | Event::PrintHeapStats
+                        | Event::DecreaseTimeScale
+                        | Event::IncreaseTimeScale
+                )
+            });
+            return self
+                .high_priority_event
+                .take()
+                .or_else(|| self.event_queue.pop_front())
+                .or_else(|| {
+                    let recording = self.input_recording.as_ref().unwrap();
+                    recording.borrow_mut().next_event(frame)
+                });
+        }
+
+        let event = self
+            .high_priority_event
             .take()
-            .or_else(|| self.event_queue.pop_front())
+            .or_else(|| self.event_queue.pop_front())?;
+        if let Some(ref recording) = self.input_recording {
+            recording.borrow_mut().record_event(frame, &event);
+        }
+        Some(event)
     }
 
     fn controller_added(&mut self, joystick_idx: u32) {
@@ -675,6 +853,20 @@ impl Window {
     /// Get the real or simulated accelerometer output.
     /// See also [crate::frameworks::uikit::ui_accelerometer].
     pub fn get_acceleration(&self, options: &Options) -> (f32, f32, f32) {
+        let Some(ref recording) = self.input_recording else {
+            return self.get_acceleration_from_host(options);
+        };
+        let frame = self.frame_number.get();
+        let mut recording = recording.borrow_mut();
+        if let Some(acceleration) = recording.next_acceleration(frame) {
+            return acceleration;
+        }
+        let acceleration = self.get_acceleration_from_host(options);
+        recording.record_acceleration(frame, acceleration);
+        acceleration
+    }
+
+    fn get_acceleration_from_host(&self, options: &Options) -> (f32, f32, f32) {
         if self.controllers.is_empty() {
             if let Some(ref accelerometer) = self.accelerometer {
                 let data = accelerometer.get_data().unwrap();
@@ -981,16 +1173,112 @@ impl Window {
             gl_ctx.DeleteTextures(1, &texture);
         };
 
-        self.window.gl_swap_window();
+        self.swap_window();
 
         // hold onto GL context so the image doesn't disappear, and hold
         // onto image so we can rotate later if necessary
     }
 
+    /// Get the framebuffer used as framebuffer 0 by software rasterizer
+    /// contexts, creating it if it doesn't exist yet.
+    pub fn software_framebuffer(&mut self) -> Rc<RefCell<DefaultFramebuffer>> {
+        let (width, height) = self.window.size();
+        self.software_framebuffer
+            .get_or_insert_with(|| Rc::new(RefCell::new(DefaultFramebuffer::new(width, height))))
+            .clone()
+    }
+
     /// Swap front-buffer and back-buffer so the result of OpenGL rendering is
     /// presented.
     pub fn swap_window(&self) {
-        self.window.gl_swap_window();
+        let frame = self.frame_number.get() + 1;
+        self.frame_number.set(frame);
+        let take_screenshot = self.screenshot_frames.contains(&frame);
+
+        if let Some(ref framebuffer) = self.software_framebuffer {
+            let framebuffer = framebuffer.borrow();
+            let (width, height) = framebuffer.size();
+            let mut pixels = framebuffer.read_rgba8_top_down();
+            if take_screenshot {
+                self.save_screenshot(frame, width, height, &pixels);
+            }
+            let mut src = Surface::from_data(
+                &mut pixels,
+                width,
+                height,
+                width * 4,
+                PixelFormatEnum::RGBA32,
+            )
+            .unwrap();
+            // The framebuffer's alpha channel isn't meaningful for
+            // presentation.
+            src.set_blend_mode(BlendMode::None).unwrap();
+            let mut dst = self.window.surface(&self.event_pump).unwrap();
+            src.blit(None, &mut dst, None).unwrap();
+            dst.update_window().unwrap();
+        } else {
+            if take_screenshot {
+                let (width, height) = self.window.drawable_size();
+                let pixels = screenshot::read_gl_window_pixels(&self.video_ctx, width, height);
+                self.save_screenshot(frame, width, height, &pixels);
+            }
+            self.window.gl_swap_window();
+        }
+
+        if self.exit_after_frames == Some(frame) {
+            log!("Presented {} frames, exiting as requested.", frame);
+            std::process::exit(0);
+        }
+    }
+
+    /// If the app has been paused with F5 or F6, wait until it's resumed with
+    /// F5, or until F6 is pressed to let it run for one more frame. This should
+    /// be called after each frame is presented (see [Self::swap_window]), so
+    /// the frame stays on screen while paused. Virtual time is stopped while
+    /// waiting (see [VirtualClock::pause]), but audio isn't paused, and
+    /// anything that still uses the host's clock will see the pause.
+    ///
+    /// Events continue to be polled while waiting, and any that are meant for
+    /// the app are queued for it as usual. Quitting ends the pause.
+    pub fn wait_while_paused(&mut self, options: &Options, clock: &mut VirtualClock) {
+        if !self.paused || !self.enable_event_polling {
+            return;
+        }
+
+        echo!(
+            "Paused at frame {}. Press F5 to resume or F6 to advance one frame.",
+            self.frame_number.get()
+        );
+        clock.pause();
+        loop {
+            if !self.paused {
+                echo!("Resuming.");
+                break;
+            }
+            if std::mem::take(&mut self.frame_step_requested) {
+                break;
+            }
+            if self.high_priority_event.is_some()
+                || self
+                    .event_queue
+                    .iter()
+                    .any(|event| matches!(event, Event::Quit))
+            {
+                self.paused = false;
+                break;
+            }
+            std::thread::sleep(Duration::from_secs_f64(1.0 / 120.0));
+            self.poll_for_events(options);
+        }
+        clock.resume();
+    }
+
+    fn save_screenshot(&self, frame: u64, width: u32, height: u32, pixels: &[u8]) {
+        let path = self.screenshot_dir.join(format!("frame_{}.png", frame));
+        if let Err(e) = screenshot::write_png(&path, width, height, pixels) {
+            panic!("Couldn't save screenshot to {}: {}", path.display(), e);
+        }
+        log!("Saved screenshot of frame {} to {}.", frame, path.display());
     }
 
     /// Consider the emulated device to be rotated to a particular orientation.
@@ -1051,6 +1339,11 @@ impl Window {
 
         self.device_orientation = new_orientation;
 
+        if let Some(ref framebuffer) = self.software_framebuffer {
+            let (width, height) = self.window.size();
+            framebuffer.borrow_mut().resize(width, height);
+        }
+
         if self.splash_image.is_some() {
             self.display_splash();
         }
@@ -1112,6 +1405,11 @@ impl Window {
 
     /// Special offset to add to y co-ordinates, only when drawing to screen.
     pub fn viewport_y_offset(&self) -> u32 {
+        // The software framebuffer is always resized to match the window, so
+        // the macOS quirk doesn't apply.
+        if self.software_framebuffer.is_some() {
+            return 0;
+        }
         #[cfg(target_os = "macos")]
         return self.viewport_y_offset;
         #[cfg(not(target_os = "macos"))]
@@ -0,0 +1,279 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Recording and replaying of input (`--record-input=` and `--replay-input=`).
+//!
+//! What gets recorded is the stream of [Event]s delivered to the app and the
+//! accelerometer samples it reads, each tagged with the number of frames that
+//! had been presented at that point. Events are recorded after translation to
+//! the app's co-ordinate space, so a recording doesn't depend on the window
+//! size, scale hack or input device used to make it.
+//!
+//! The file format is plain text with one entry per line, so it's easy to
+//! inspect or edit by hand:
+//!
+//! ```text
+//! touchHLE input recording v1
+//! 120 touches_down mouse=160,240
+//! 123 touches_up mouse=160,240
+//! 130 accel 0,0,-1
+//! 200 text 68656c6c6f
+//! ```
+//!
+//! Multi-touch events have several space-separated finger entries. Finger IDs
+//! are `mouse`, `cursor`, `touch:<id>` or `button:<name>`. Text is hex-encoded
+//! UTF-8.
+
+use super::{Coords, Event, FingerId, TextInputEvent};
+use crate::options::Button;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{BufRead, BufReader, LineWriter, Write};
+use std::path::Path;
+
+const HEADER: &str = "touchHLE input recording v1";
+
+pub enum InputRecording {
+    Record {
+        file: LineWriter<File>,
+    },
+    Replay {
+        events: VecDeque<(u64, Event)>,
+        accelerations: VecDeque<(u64, (f32, f32, f32))>,
+        last_acceleration: (f32, f32, f32),
+        finished: bool,
+    },
+}
+
+impl InputRecording {
+    /// Create a new recording file, replacing any existing one.
+    pub fn record(path: &Path) -> Result<Self, String> {
+        let file = File::create(path).map_err(|e| format!("Couldn't create file: {}", e))?;
+        let mut file = LineWriter::new(file);
+        writeln!(file, "{}", HEADER).map_err(|e| format!("Couldn't write to file: {}", e))?;
+        Ok(InputRecording::Record { file })
+    }
+
+    /// Load a recording file for replay.
+    pub fn replay(path: &Path) -> Result<Self, String> {
+        let file = File::open(path).map_err(|e| format!("Couldn't open file: {}", e))?;
+        let mut lines = BufReader::new(file).lines();
+        match lines.next() {
+            Some(Ok(line)) if line == HEADER => (),
+            _ => return Err("Not a touchHLE input recording".to_string()),
+        }
+
+        let mut events = VecDeque::new();
+        let mut accelerations = VecDeque::new();
+        for (line_no, line) in lines.enumerate() {
+            // Line numbering usually starts from 1, and the header is line 1.
+            let line_no = line_no + 2;
+            let line = line.map_err(|e| format!("Error while reading line {}: {}", line_no, e))?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let (frame, rest) = line
+                .split_once(' ')
+                .and_then(|(frame, rest)| Some((frame.parse::<u64>().ok()?, rest)))
+                .ok_or_else(|| format!("Line {} is missing a frame number", line_no))?;
+            let (kind, args) = rest.split_once(' ').unwrap_or((rest, ""));
+            if kind == "accel" {
+                let acceleration = parse_acceleration(args)
+                    .ok_or_else(|| format!("Line {} has an invalid acceleration", line_no))?;
+                accelerations.push_back((frame, acceleration));
+            } else {
+                let event = parse_event(kind, args)
+                    .ok_or_else(|| format!("Line {} has an invalid event", line_no))?;
+                events.push_back((frame, event));
+            }
+        }
+
+        Ok(InputRecording::Replay {
+            events,
+            accelerations,
+            // Device lying flat on its back.
+            last_acceleration: (0.0, 0.0, -1.0),
+            finished: false,
+        })
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        matches!(self, InputRecording::Replay { .. })
+    }
+
+    /// Record an event delivered to the app. Does nothing when replaying.
+    pub fn record_event(&mut self, frame: u64, event: &Event) {
+        let InputRecording::Record { ref mut file } = self else {
+            return;
+        };
+        let Some(line) = format_event(event) else {
+            return;
+        };
+        writeln!(file, "{} {}", frame, line).expect("Couldn't write to input recording");
+    }
+
+    /// Record an accelerometer sample read by the app. Does nothing when
+    /// replaying.
+    pub fn record_acceleration(&mut self, frame: u64, (x, y, z): (f32, f32, f32)) {
+        let InputRecording::Record { ref mut file } = self else {
+            return;
+        };
+        writeln!(file, "{} accel {},{},{}", frame, x, y, z)
+            .expect("Couldn't write to input recording");
+    }
+
+    /// Get the next recorded event, if it was delivered no later than the
+    /// current frame. Returns [None] when recording.
+    pub fn next_event(&mut self, frame: u64) -> Option<Event> {
+        let InputRecording::Replay { events, .. } = self else {
+            return None;
+        };
+        if events.front()?.0 > frame {
+            return None;
+        }
+        let (_frame, event) = events.pop_front().unwrap();
+        self.check_finished();
+        Some(event)
+    }
+
+    /// Get the next recorded accelerometer sample, or the most recent one if
+    /// the next sample is for a later frame. Returns [None] when recording.
+    pub fn next_acceleration(&mut self, frame: u64) -> Option<(f32, f32, f32)> {
+        let InputRecording::Replay {
+            accelerations,
+            last_acceleration,
+            ..
+        } = self
+        else {
+            return None;
+        };
+        if accelerations.front().is_some_and(|&(f, _)| f <= frame) {
+            *last_acceleration = accelerations.pop_front().unwrap().1;
+        }
+        let acceleration = *last_acceleration;
+        self.check_finished();
+        Some(acceleration)
+    }
+
+    fn check_finished(&mut self) {
+        let InputRecording::Replay {
+            events,
+            accelerations,
+            finished,
+            ..
+        } = self
+        else {
+            return;
+        };
+        if !*finished && events.is_empty() && accelerations.is_empty() {
+            log!("Input replay finished.");
+            *finished = true;
+        }
+    }
+}
+
+fn format_event(event: &Event) -> Option<String> {
+    fn format_touches(kind: &str, map: &HashMap<FingerId, Coords>) -> String {
+        let mut line = kind.to_string();
+        for (finger, &(x, y)) in map {
+            let finger = match finger {
+                FingerId::Mouse => "mouse".to_string(),
+                FingerId::VirtualCursor => "cursor".to_string(),
+                FingerId::Touch(id) => format!("touch:{}", id),
+                FingerId::ButtonToTouch(button) => format!("button:{:?}", button),
+            };
+            line.push_str(&format!(" {}={},{}", finger, x, y));
+        }
+        line
+    }
+
+    Some(match event {
+        Event::Quit => "quit".to_string(),
+        Event::AppWillResignActive => "app_will_resign_active".to_string(),
+        Event::AppWillTerminate => "app_will_terminate".to_string(),
+        Event::SimulateMemoryWarning => "memory_warning".to_string(),
+        Event::TouchesDown(map) => format_touches("touches_down", map),
+        Event::TouchesMove(map) => format_touches("touches_move", map),
+        Event::TouchesUp(map) => format_touches("touches_up", map),
+        // Debugging hotkeys aren't input to the app.
+        Event::EnterDebugger
+        | Event::PrintHeapStats
+        | Event::DecreaseTimeScale
+        | Event::IncreaseTimeScale => return None,
+        Event::TextInput(TextInputEvent::Text(text)) => {
+            let hex: String = text.bytes().map(|b| format!("{:02x}", b)).collect();
+            format!("text {}", hex)
+        }
+        Event::TextInput(TextInputEvent::Backspace) => "backspace".to_string(),
+        Event::TextInput(TextInputEvent::Return) => "return".to_string(),
+    })
+}
+
+fn parse_event(kind: &str, args: &str) -> Option<Event> {
+    fn parse_touches(args: &str) -> Option<HashMap<FingerId, Coords>> {
+        let mut map = HashMap::new();
+        for finger in args.split_whitespace() {
+            let (finger, coords) = finger.split_once('=')?;
+            let finger = match finger.split_once(':') {
+                None if finger == "mouse" => FingerId::Mouse,
+                None if finger == "cursor" => FingerId::VirtualCursor,
+                Some(("touch", id)) => FingerId::Touch(id.parse().ok()?),
+                Some(("button", button)) => FingerId::ButtonToTouch(parse_button(button)?),
+                _ => return None,
+            };
+            let (x, y) = coords.split_once(',')?;
+            map.insert(finger, (x.parse().ok()?, y.parse().ok()?));
+        }
+        Some(map)
+    }
+
+    Some(match kind {
+        "quit" => Event::Quit,
+        "app_will_resign_active" => Event::AppWillResignActive,
+        "app_will_terminate" => Event::AppWillTerminate,
+        "memory_warning" => Event::SimulateMemoryWarning,
+        "touches_down" => Event::TouchesDown(parse_touches(args)?),
+        "touches_move" => Event::TouchesMove(parse_touches(args)?),
+        "touches_up" => Event::TouchesUp(parse_touches(args)?),
+        "text" => {
+            // An odd number of digits makes the last get() return None.
+            let bytes = (0..args.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(args.get(i..i + 2)?, 16).ok())
+                .collect::<Option<Vec<u8>>>()?;
+            Event::TextInput(TextInputEvent::Text(String::from_utf8(bytes).ok()?))
+        }
+        "backspace" => Event::TextInput(TextInputEvent::Backspace),
+        "return" => Event::TextInput(TextInputEvent::Return),
+        _ => return None,
+    })
+}
+
+fn parse_button(name: &str) -> Option<Button> {
+    Some(match name {
+        "DPadLeft" => Button::DPadLeft,
+        "DPadUp" => Button::DPadUp,
+        "DPadRight" => Button::DPadRight,
+        "DPadDown" => Button::DPadDown,
+        "Start" => Button::Start,
+        "A" => Button::A,
+        "B" => Button::B,
+        "X" => Button::X,
+        "Y" => Button::Y,
+        "LeftShoulder" => Button::LeftShoulder,
+        _ => return None,
+    })
+}
+
+fn parse_acceleration(args: &str) -> Option<(f32, f32, f32)> {
+    let mut components = args.split(',').map(|c| c.parse::<f32>().ok());
+    let x = components.next()??;
+    let y = components.next()??;
+    let z = components.next()??;
+    if components.next().is_some() {
+        return None;
+    }
+    Some((x, y, z))
+}
@@ -0,0 +1,79 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+//! Saving screenshots of presented frames (`--screenshot-frames=`).
+
+use crate::gles::gles11_raw as gles11; // constants and types only
+use std::ffi::c_void;
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::Path;
+
+/// Read the contents of the window's framebuffer using the current OpenGL
+/// (ES) context. This must be done before swapping, since the back buffer's
+/// contents are undefined afterwards. Returns RGBA8 pixels with top-to-bottom
+/// row order.
+pub fn read_gl_window_pixels(video_ctx: &sdl2::VideoSubsystem, width: u32, height: u32) -> Vec<u8> {
+    use gles11::types::*;
+
+    // The current context might be an OpenGL ES 1.1 context or an OpenGL 2.1
+    // context, but glReadPixels() has the same signature in both, so this
+    // avoids having to care which one it is.
+    let read_pixels = video_ctx.gl_get_proc_address("glReadPixels");
+    assert!(!read_pixels.is_null());
+    let read_pixels: unsafe extern "system" fn(
+        GLint,
+        GLint,
+        GLsizei,
+        GLsizei,
+        GLenum,
+        GLenum,
+        *mut c_void,
+    ) = unsafe { std::mem::transmute(read_pixels) };
+
+    let row_size = width as usize * 4;
+    let mut pixels = vec![0u8; row_size * height as usize];
+    unsafe {
+        read_pixels(
+            0,
+            0,
+            width as _,
+            height as _,
+            gles11::RGBA,
+            gles11::UNSIGNED_BYTE,
+            pixels.as_mut_ptr() as *mut _,
+        );
+    }
+
+    // OpenGL's row order is bottom-to-top.
+    let mut flipped = Vec::with_capacity(pixels.len());
+    for row in pixels.chunks(row_size).rev() {
+        flipped.extend_from_slice(row);
+    }
+    flipped
+}
+
+/// Write RGBA8 pixel data with top-to-bottom row order to a PNG file. The
+/// alpha channel is ignored, since it isn't meaningful for presentation.
+pub fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) -> Result<(), String> {
+    assert!(pixels.len() == width as usize * height as usize * 4);
+
+    let rgb8_pixels: Vec<u8> = pixels
+        .chunks(4)
+        .flat_map(|rgba| &rgba[..3])
+        .copied()
+        .collect();
+
+    let file = File::create(path).map_err(|e| format!("Couldn't create file: {}", e))?;
+    let mut encoder = png::Encoder::new(BufWriter::new(file), width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder
+        .write_header()
+        .map_err(|e| format!("Couldn't write PNG header: {}", e))?;
+    writer
+        .write_image_data(&rgb8_pixels)
+        .map_err(|e| format!("Couldn't write PNG data: {}", e))
+}
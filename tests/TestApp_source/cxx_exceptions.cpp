@@ -0,0 +1,190 @@
+/*
+ * This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at https://mozilla.org/MPL/2.0/.
+ */
+
+// Tests for C++ exception handling.
+// Unlike the other source files, this is C++, so it's compiled separately
+// rather than being included by main.c. The C++ runtime support comes from the
+// libstdc++ and libgcc_s dylibs bundled with touchHLE (see integration.rs).
+
+// === Declarations ===
+
+extern "C" {
+
+// <stddef.h>
+#define NULL 0
+typedef unsigned long size_t;
+
+// <stdlib.h>
+void qsort(void *, size_t, size_t, int (*)(const void *, const void *));
+
+// <pthread.h>
+typedef struct opaque_pthread_t *pthread_t;
+typedef struct opaque_pthread_attr_t *pthread_attr_t;
+int pthread_create(pthread_t *, const pthread_attr_t *, void *(*)(void *),
+                   void *);
+int pthread_join(pthread_t, void **);
+}
+
+// === Helpers ===
+
+struct Base {
+  int value;
+  Base(int value) : value(value) {}
+  virtual ~Base() {}
+};
+
+struct Derived : Base {
+  Derived(int value) : Base(value) {}
+};
+
+// Counts how many of these have been destroyed.
+static int destructor_count;
+struct Guard {
+  ~Guard() { destructor_count++; }
+};
+
+__attribute__((noinline)) static void throw_int(int value) { throw value; }
+
+__attribute__((noinline)) static void throw_derived_with_guards(int value) {
+  Guard guard1;
+  Guard guard2;
+  throw Derived(value);
+}
+
+__attribute__((noinline)) static void throw_through_guard(int value) {
+  Guard guard;
+  throw_int(value);
+}
+
+// === Tests ===
+
+extern "C" int test_cxx_throw_catch() {
+  try {
+    throw_int(42);
+  } catch (int value) {
+    return value == 42 ? 0 : -1;
+  }
+  return -2;
+}
+
+extern "C" int test_cxx_catch_by_base_class() {
+  try {
+    throw_int(1);
+  } catch (Base &) {
+    // The wrong handler.
+    return -1;
+  } catch (...) {
+  }
+
+  try {
+    throw Derived(7);
+  } catch (int) {
+    return -2;
+  } catch (Base &base) {
+    return base.value == 7 ? 0 : -3;
+  }
+  return -4;
+}
+
+extern "C" int test_cxx_cleanups() {
+  destructor_count = 0;
+  try {
+    throw_derived_with_guards(3);
+  } catch (Derived &derived) {
+    if (derived.value != 3)
+      return -1;
+  }
+  if (destructor_count != 2)
+    return -2;
+
+  // The guard's landing pad has to resume unwinding afterwards.
+  destructor_count = 0;
+  try {
+    throw_through_guard(4);
+  } catch (int value) {
+    if (value != 4)
+      return -3;
+  }
+  if (destructor_count != 1)
+    return -4;
+  return 0;
+}
+
+extern "C" int test_cxx_rethrow() {
+  int caught = 0;
+  try {
+    try {
+      throw_int(5);
+    } catch (...) {
+      caught++;
+      throw;
+    }
+  } catch (int value) {
+    if (value == 5)
+      caught++;
+  }
+  return caught == 2 ? 0 : -1;
+}
+
+// Throws and catches inside a callback called by touchHLE's qsort(), while the
+// test function has its own handler outside the qsort() call.
+static int callback_catches;
+static int throwing_int_compar(const void *a, const void *b) {
+  try {
+    throw_int(*(int *)a - *(int *)b);
+  } catch (int difference) {
+    callback_catches++;
+    return difference;
+  }
+  return 0;
+}
+
+extern "C" int test_cxx_exception_in_callback() {
+  callback_catches = 0;
+  int arr[] = {3, 1, 2};
+  try {
+    qsort(arr, 3, sizeof(int), &throwing_int_compar);
+  } catch (...) {
+    return -1;
+  }
+  if (callback_catches == 0)
+    return -2;
+  if (arr[0] != 1 || arr[1] != 2 || arr[2] != 3)
+    return -3;
+  // Unwinding still works after the callbacks.
+  try {
+    throw_int(6);
+  } catch (int value) {
+    return value == 6 ? 0 : -4;
+  }
+  return -5;
+}
+
+static void *exception_thread_func(void *arg) {
+  try {
+    throw_through_guard(*(int *)arg);
+  } catch (int value) {
+    *(int *)arg = value + 1;
+  }
+  return NULL;
+}
+
+// Each thread has its own list of handlers.
+extern "C" int test_cxx_exception_in_thread() {
+  int value = 8;
+  destructor_count = 0;
+  try {
+    pthread_t thread;
+    if (pthread_create(&thread, NULL, &exception_thread_func, &value) != 0)
+      return -1;
+    pthread_join(thread, NULL);
+  } catch (...) {
+    return -2;
+  }
+  if (value != 9 || destructor_count != 1)
+    return -3;
+  return 0;
+}
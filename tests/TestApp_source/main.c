@@ -27,6 +27,13 @@ typedef int wchar_t;
 // <errno.h>
 int *__error(void);
 #define errno (*__error())
+#define ENOENT 2
+#define ENOMEM 12
+#define EBUSY 16
+#define EEXIST 17
+#define EINVAL 22
+#define ERANGE 34
+#define ETIMEDOUT 60
 
 // <stdarg.h>
 typedef __builtin_va_list va_list;
@@ -49,15 +56,23 @@ int swprintf(wchar_t *, size_t, const wchar_t *, ...);
 void exit(int);
 void free(void *);
 void *malloc(size_t);
+void *calloc(size_t, size_t);
 void qsort(void *, size_t, size_t, int (*)(const void *, const void *));
 void *realloc(void *, size_t);
 double atof(const char *);
+double strtod(const char *, char **);
 float strtof(const char *, char **);
+long strtol(const char *, char **, int);
 unsigned long strtoul(const char *, char **, int);
 char *realpath(const char *, char *);
 size_t mbstowcs(wchar_t *, const char *, size_t);
 size_t wcstombs(char *, const wchar_t *, size_t);
 
+// <setjmp.h>
+typedef int jmp_buf[28];
+int setjmp(jmp_buf);
+void longjmp(jmp_buf, int) __attribute__((noreturn));
+
 // <string.h>
 void *memset(void *, int, size_t);
 int memcmp(const void *, const void *, size_t);
@@ -71,6 +86,8 @@ char *strrchr(const char *s, int c);
 size_t strlen(const char *);
 int strncmp(const char *, const char *, size_t);
 size_t strcspn(const char *, const char *);
+char *strerror(int);
+int strerror_r(int, char *, size_t);
 
 // <unistd.h>
 typedef unsigned int __uint32_t;
@@ -81,6 +98,14 @@ int usleep(useconds_t);
 
 // <fcntl.h>
 #define O_CREAT 0x00000200
+#define O_EXCL 0x00000800
+
+// <time.h>
+typedef long time_t;
+struct timespec {
+  time_t tv_sec;
+  long tv_nsec;
+};
 
 // <pthread.h>
 typedef struct opaque_pthread_t opaque_pthread_t;
@@ -91,6 +116,45 @@ typedef struct opaque_pthread_attr_t *__pthread_attr_t;
 typedef __pthread_attr_t pthread_attr_t;
 int pthread_create(pthread_t *, const pthread_attr_t *, void *(*)(void *),
                    void *);
+int pthread_join(pthread_t, void **);
+pthread_t pthread_self(void);
+int pthread_equal(pthread_t, pthread_t);
+void pthread_exit(void *);
+int pthread_cancel(pthread_t);
+void pthread_testcancel(void);
+#define PTHREAD_CANCELED ((void *)1)
+int pthread_attr_init(pthread_attr_t *);
+int pthread_attr_destroy(pthread_attr_t *);
+int pthread_attr_setstacksize(pthread_attr_t *, size_t);
+int pthread_attr_getstacksize(const pthread_attr_t *, size_t *);
+typedef struct {
+  long __sig;
+  char __opaque[40];
+} pthread_mutex_t;
+#define PTHREAD_MUTEX_INITIALIZER {0x32AAABA7, {0}}
+int pthread_mutex_lock(pthread_mutex_t *);
+int pthread_mutex_unlock(pthread_mutex_t *);
+typedef struct {
+  long __sig;
+  char __opaque[24];
+} pthread_cond_t;
+#define PTHREAD_COND_INITIALIZER {0x3CB0B1BB, {0}}
+int pthread_cond_wait(pthread_cond_t *, pthread_mutex_t *);
+int pthread_cond_timedwait(pthread_cond_t *, pthread_mutex_t *,
+                           const struct timespec *);
+int pthread_cond_signal(pthread_cond_t *);
+int pthread_cond_destroy(pthread_cond_t *);
+typedef struct {
+  long __sig;
+  char __opaque[124];
+} pthread_rwlock_t;
+#define PTHREAD_RWLOCK_INITIALIZER {0x2DA8B3B4, {0}}
+int pthread_rwlock_rdlock(pthread_rwlock_t *);
+int pthread_rwlock_tryrdlock(pthread_rwlock_t *);
+int pthread_rwlock_wrlock(pthread_rwlock_t *);
+int pthread_rwlock_trywrlock(pthread_rwlock_t *);
+int pthread_rwlock_unlock(pthread_rwlock_t *);
+int pthread_rwlock_destroy(pthread_rwlock_t *);
 
 // <semaphore.h>
 #define SEM_FAILED ((sem_t *)-1)
@@ -153,6 +217,14 @@ CFComparisonResult CFStringCompare(CFStringRef a, CFStringRef b,
 CFRange CFStringFind(CFStringRef theString, CFStringRef stringToFind,
                      CFOptionFlags compareOptions);
 
+// cxx_exceptions.cpp
+int test_cxx_throw_catch();
+int test_cxx_catch_by_base_class();
+int test_cxx_cleanups();
+int test_cxx_rethrow();
+int test_cxx_exception_in_callback();
+int test_cxx_exception_in_thread();
+
 // === Main code ===
 
 int int_compar(const void *a, const void *b) { return *(int *)a - *(int *)b; }
@@ -492,6 +564,21 @@ int test_strtoul() {
   if (strtoul(text, &endptr, 16) != 3435973836 || endptr != text + 10) {
     return -1;
   }
+  text = " -0755 ";
+  if (strtol(text, &endptr, 0) != -493 || endptr != text + 6) {
+    return -2;
+  }
+  text = "z";
+  if (strtol(text, &endptr, 36) != 35 || endptr != text + 1) {
+    return -3;
+  }
+  text = "0x";
+  if (strtoul(text, &endptr, 16) != 0 || endptr != text + 1) {
+    return -4;
+  }
+  if (strtoul("-1", NULL, 10) != 4294967295) {
+    return -5;
+  }
   return 0;
 }
 
@@ -904,6 +991,288 @@ int test_CFMutableString() {
   return 0;
 }
 
+jmp_buf setjmp_test_buf;
+
+int setjmp_compar(const void *a, const void *b) {
+  // Abandon the sort from inside qsort()'s callback.
+  longjmp(setjmp_test_buf, 2);
+}
+
+int test_setjmp() {
+  volatile int jumps = 0;
+
+  // Within the same function. longjmp() can't make setjmp() return 0, so it
+  // returns 1 instead.
+  int res = setjmp(setjmp_test_buf);
+  if (res == 0) {
+    jumps++;
+    longjmp(setjmp_test_buf, 0);
+  }
+  if (res != 1 || jumps != 1) {
+    return -1;
+  }
+
+  // Across host frames: qsort() is implemented by touchHLE and calls back into
+  // the app.
+  int arr[] = {3, 2, 1};
+  res = setjmp(setjmp_test_buf);
+  if (res == 0) {
+    jumps++;
+    qsort(arr, 3, sizeof(int), &setjmp_compar);
+    return -2;
+  }
+  if (res != 2 || jumps != 2) {
+    return -3;
+  }
+  // The unwound host function can still be used afterwards.
+  if (sort_and_check(3, arr, (int[]){1, 2, 3}) != 0) {
+    return -4;
+  }
+  return 0;
+}
+
+pthread_mutex_t cond_mutex = PTHREAD_MUTEX_INITIALIZER;
+pthread_cond_t cond = PTHREAD_COND_INITIALIZER;
+int cond_ready = 0;
+
+void *cond_thread_func(void *arg) {
+  pthread_mutex_lock(&cond_mutex);
+  cond_ready = 1;
+  pthread_cond_signal(&cond);
+  pthread_mutex_unlock(&cond_mutex);
+  return NULL;
+}
+
+int test_pthread_cond() {
+  pthread_mutex_lock(&cond_mutex);
+
+  // A deadline in the past times out immediately.
+  struct timespec past = {0, 0};
+  if (pthread_cond_timedwait(&cond, &cond_mutex, &past) != ETIMEDOUT) {
+    return -1;
+  }
+
+  pthread_t thread;
+  pthread_create(&thread, NULL, &cond_thread_func, NULL);
+  while (!cond_ready) {
+    if (pthread_cond_wait(&cond, &cond_mutex) != 0) {
+      return -2;
+    }
+  }
+  pthread_mutex_unlock(&cond_mutex);
+  pthread_join(thread, NULL);
+
+  if (pthread_cond_destroy(&cond) != 0) {
+    return -3;
+  }
+  return 0;
+}
+
+pthread_rwlock_t rwlock = PTHREAD_RWLOCK_INITIALIZER;
+int rwlock_shared = 0;
+
+void *rwlock_thread_func(void *arg) {
+  pthread_rwlock_rdlock(&rwlock);
+  *(int *)arg = rwlock_shared;
+  pthread_rwlock_unlock(&rwlock);
+  return NULL;
+}
+
+int test_pthread_rwlock() {
+  // Multiple readers are allowed, but not a writer at the same time.
+  if (pthread_rwlock_rdlock(&rwlock) != 0 ||
+      pthread_rwlock_tryrdlock(&rwlock) != 0) {
+    return -1;
+  }
+  if (pthread_rwlock_trywrlock(&rwlock) != EBUSY) {
+    return -2;
+  }
+  pthread_rwlock_unlock(&rwlock);
+  pthread_rwlock_unlock(&rwlock);
+
+  // A reader has to wait for the writer to finish.
+  if (pthread_rwlock_wrlock(&rwlock) != 0) {
+    return -3;
+  }
+  int result = 0;
+  pthread_t thread;
+  pthread_create(&thread, NULL, &rwlock_thread_func, &result);
+  usleep(100);
+  rwlock_shared = 1;
+  pthread_rwlock_unlock(&rwlock);
+  pthread_join(thread, NULL);
+  if (result != 1) {
+    return -4;
+  }
+
+  if (pthread_rwlock_destroy(&rwlock) != 0) {
+    return -5;
+  }
+  return 0;
+}
+
+void *exit_thread_func(void *arg) {
+  pthread_exit(arg);
+  return NULL;
+}
+
+void *cancel_thread_func(void *arg) {
+  while (1) {
+    usleep(100);
+  }
+  return NULL;
+}
+
+pthread_mutex_t cancel_mutex = PTHREAD_MUTEX_INITIALIZER;
+pthread_cond_t cancel_cond = PTHREAD_COND_INITIALIZER;
+
+void *cancel_blocked_thread_func(void *arg) {
+  if (arg) {
+    pthread_mutex_lock(&cancel_mutex);
+    while (1) {
+      pthread_cond_wait(&cancel_cond, &cancel_mutex);
+    }
+  }
+  // Nothing will wake this thread except the cancellation.
+  usleep(100000000);
+  return NULL;
+}
+
+int test_pthread_exit_cancel() {
+  if (!pthread_equal(pthread_self(), pthread_self())) {
+    return -1;
+  }
+
+  pthread_attr_t attr;
+  size_t stacksize = 0;
+  pthread_attr_init(&attr);
+  if (pthread_attr_setstacksize(&attr, 0x10000) != 0 ||
+      pthread_attr_getstacksize(&attr, &stacksize) != 0 ||
+      stacksize != 0x10000) {
+    return -2;
+  }
+
+  pthread_t thread;
+  void *retval = NULL;
+  pthread_create(&thread, &attr, &exit_thread_func, (void *)42);
+  pthread_attr_destroy(&attr);
+  if (pthread_equal(thread, pthread_self())) {
+    return -3;
+  }
+  pthread_join(thread, &retval);
+  if (retval != (void *)42) {
+    return -4;
+  }
+
+  // The thread exits at its next cancellation point (usleep()).
+  pthread_create(&thread, NULL, &cancel_thread_func, NULL);
+  usleep(200);
+  pthread_cancel(thread);
+  pthread_join(thread, &retval);
+  if (retval != PTHREAD_CANCELED) {
+    return -5;
+  }
+
+  // A thread that's already blocked at a cancellation point is woken up.
+  for (int wait_on_cond = 0; wait_on_cond < 2; wait_on_cond++) {
+    retval = NULL;
+    pthread_create(&thread, NULL, &cancel_blocked_thread_func,
+                   (void *)wait_on_cond);
+    usleep(200);
+    pthread_cancel(thread);
+    pthread_join(thread, &retval);
+    if (retval != PTHREAD_CANCELED) {
+      return -6 - wait_on_cond;
+    }
+  }
+  return 0;
+}
+
+void *errno_thread_func(void *arg) {
+  // errno is per-thread, so this thread shouldn't see the main thread's.
+  return (void *)errno;
+}
+
+int test_errno_strerror() {
+  errno = 0;
+  FILE *fake_file = fopen("/var/mobile/does-not-exist", "r");
+  if (fake_file || errno != ENOENT) {
+    return -1;
+  }
+
+  pthread_t thread;
+  void *retval = NULL;
+  pthread_create(&thread, NULL, &errno_thread_func, NULL);
+  pthread_join(thread, &retval);
+  if (retval != (void *)0 || errno != ENOENT) {
+    return -2;
+  }
+
+  errno = 0;
+  if (chdir("/var/mobile/does-not-exist") != -1 || errno != ENOENT) {
+    return -3;
+  }
+  char buf[16];
+  errno = 0;
+  if (getcwd(buf, 0) || errno != EINVAL) {
+    return -4;
+  }
+  errno = 0;
+  if (getcwd(buf, 1) || errno != ERANGE) {
+    return -5;
+  }
+
+  errno = 0;
+  if (sem_open("errno_test", 0) != SEM_FAILED || errno != ENOENT) {
+    return -6;
+  }
+  sem_t *sem = sem_open("errno_test", O_CREAT, 0644, 1);
+  errno = 0;
+  if (sem_open("errno_test", O_CREAT | O_EXCL, 0644, 1) != SEM_FAILED ||
+      errno != EEXIST) {
+    return -7;
+  }
+  sem_close(sem);
+  sem_unlink("errno_test");
+
+  if (strcmp(strerror(ENOENT), "No such file or directory") ||
+      strcmp(strerror(0), "Undefined error: 0")) {
+    return -8;
+  }
+  errno = 0;
+  if (strcmp(strerror(1000), "Unknown error: 1000") || errno != EINVAL) {
+    return -9;
+  }
+  if (strerror_r(ETIMEDOUT, buf, sizeof buf) != ERANGE ||
+      strcmp(buf, "Operation timed")) {
+    return -10;
+  }
+  if (strerror_r(EEXIST, buf, sizeof buf) != 0 || strcmp(buf, "File exists")) {
+    return -11;
+  }
+
+  errno = 0;
+  if (calloc(0x10000, 0x10000) || errno != ENOMEM) {
+    return -12;
+  }
+  errno = 0;
+  if (strtol("-2147483648", NULL, 10) != -2147483648 || errno != 0) {
+    return -13;
+  }
+  if (strtol("2147483648", NULL, 10) != 2147483647 || errno != ERANGE) {
+    return -14;
+  }
+  errno = 0;
+  if (strtoul("0x100000000", NULL, 0) != 4294967295 || errno != ERANGE) {
+    return -15;
+  }
+  errno = 0;
+  if (strtod("1e999", NULL) != __builtin_inf() || errno != ERANGE) {
+    return -16;
+  }
+  return 0;
+}
+
 #define FUNC_DEF(func)                                                         \
   { &func, #func }
 struct {
@@ -933,6 +1302,17 @@ struct {
     FUNC_DEF(test_strcspn),
     FUNC_DEF(test_mbstowcs),
     FUNC_DEF(test_CFMutableString),
+    FUNC_DEF(test_setjmp),
+    FUNC_DEF(test_pthread_cond),
+    FUNC_DEF(test_pthread_rwlock),
+    FUNC_DEF(test_pthread_exit_cancel),
+    FUNC_DEF(test_errno_strerror),
+    FUNC_DEF(test_cxx_throw_catch),
+    FUNC_DEF(test_cxx_catch_by_base_class),
+    FUNC_DEF(test_cxx_cleanups),
+    FUNC_DEF(test_cxx_rethrow),
+    FUNC_DEF(test_cxx_exception_in_callback),
+    FUNC_DEF(test_cxx_exception_in_thread),
 };
 
 // Because no libc is linked into this executable, there is no libc entry point
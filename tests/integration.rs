@@ -1,9 +1,11 @@
 use std::env;
 use std::env::current_dir;
 use std::error::Error;
-use std::io::Write;
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::sync::Once;
 
 // adapted from `assert_cmd` crate
 fn target_dir() -> PathBuf {
@@ -42,6 +44,8 @@ fn build_test_app(tests_dir: &Path, test_app_path: &Path) -> Result<(), Box<dyn
 
     let test_bin_path = test_app_path.join("TestApp");
 
+    let dylibs_dir = tests_dir.parent().unwrap().join("touchHLE_dylibs");
+
     eprintln!("Building {} for iPhone OS 2...", test_bin_path.display());
 
     let mut cmd = Command::new(clang_path);
@@ -71,6 +75,11 @@ fn build_test_app(tests_dir: &Path, test_app_path: &Path) -> Result<(), Box<dyn
         .arg("-Wl,-e,_main,-undefined,dynamic_lookup")
         // Input
         .arg(tests_dir.join("TestApp_source").join("main.c"))
+        .arg(tests_dir.join("TestApp_source").join("cxx_exceptions.cpp"))
+        // The C++ runtime. Linking against the dylibs touchHLE bundles makes
+        // the app load them, like an app built with Apple's tools would.
+        .arg(dylibs_dir.join("libstdc++.6.0.9.dylib"))
+        .arg(dylibs_dir.join("libgcc_s.1.dylib"))
         // Write the output to the bundle.
         .arg("-o")
         .arg(&test_bin_path)
@@ -87,18 +96,32 @@ fn build_test_app(tests_dir: &Path, test_app_path: &Path) -> Result<(), Box<dyn
     Ok(())
 }
 
-#[test]
-fn run_test_app() -> Result<(), Box<dyn Error>> {
-    let tests_dir = current_dir()?.join("tests");
+/// Build TestApp, if that hasn't already been done by another test, and get the
+/// path to its bundle.
+fn test_app_path() -> PathBuf {
+    static BUILD_TEST_APP: Once = Once::new();
+
+    let tests_dir = current_dir().unwrap().join("tests");
 
     let test_app_path = tests_dir.join("TestApp.app");
 
-    build_test_app(&tests_dir, &test_app_path)?;
+    BUILD_TEST_APP.call_once(|| build_test_app(&tests_dir, &test_app_path).unwrap());
+
+    test_app_path
+}
 
+fn touchhle_command() -> Command {
     let binary_name = "touchHLE";
     let binary_path = target_dir().join(format!("{}{}", binary_name, env::consts::EXE_SUFFIX));
 
-    let mut cmd = Command::new(binary_path);
+    Command::new(binary_path)
+}
+
+#[test]
+fn run_test_app() -> Result<(), Box<dyn Error>> {
+    let test_app_path = test_app_path();
+
+    let mut cmd = touchhle_command();
 
     let output = cmd
         .arg(test_app_path)
@@ -120,3 +143,277 @@ fn run_test_app() -> Result<(), Box<dyn Error>> {
 
     Ok(())
 }
+
+/// Run an app in touchHLE (or the app picker, if `app_path` is [None]) until
+/// the last of `frames` has been presented, optionally replaying input recorded
+/// with `--record-input=`, and save screenshots of each of `frames`. The
+/// screenshots are written to a directory in the target directory named after
+/// `name`, and their paths are returned in the same order as `frames`.
+///
+/// `gles1` is the `--gles1=` implementation to use. Only the software
+/// implementation gives the same result on every system, and only it works
+/// without a display, so the others need a real OpenGL driver.
+fn capture_frames(
+    name: &str,
+    app_path: Option<&Path>,
+    gles1: &str,
+    extra_args: &[&str],
+    replay_input: Option<&Path>,
+    frames: &[u64],
+) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let output_dir = target_dir().join("golden_frames").join(name);
+    // Make sure stale screenshots from a previous run can't be mistaken for
+    // new ones.
+    let _ = std::fs::remove_dir_all(&output_dir);
+    std::fs::create_dir_all(&output_dir)?;
+
+    let frame_list: Vec<String> = frames.iter().map(|frame| frame.to_string()).collect();
+
+    let mut cmd = touchhle_command();
+    if let Some(app_path) = app_path {
+        cmd.arg(app_path);
+    }
+    cmd.arg(format!("--gles1={}", gles1))
+        .arg(format!("--screenshot-frames={}", frame_list.join(",")))
+        .arg(format!("--screenshot-dir={}", output_dir.display()))
+        .arg(format!(
+            "--exit-after-frames={}",
+            frames.iter().max().unwrap()
+        ))
+        .args(extra_args)
+        // Like the dummy video driver below, this avoids sound playing during
+        // testing and works in CI.
+        .env("ALSOFT_DRIVERS", "null");
+    if gles1 == "software" {
+        // Like headless mode, this avoids a window appearing and works in CI,
+        // but unlike headless mode, there is still a window to present to.
+        cmd.env("SDL_VIDEODRIVER", "dummy");
+    }
+    if let Some(replay_input) = replay_input {
+        cmd.arg(format!("--replay-input={}", replay_input.display()));
+    }
+
+    let output = cmd.output().expect("failed to execute touchHLE process");
+
+    std::io::stdout().write_all(&output.stdout).unwrap();
+    std::io::stderr().write_all(&output.stderr).unwrap();
+
+    assert!(output.status.success());
+
+    let paths: Vec<PathBuf> = frames
+        .iter()
+        .map(|frame| output_dir.join(format!("frame_{}.png", frame)))
+        .collect();
+    for path in &paths {
+        assert!(path.exists(), "Missing screenshot {}", path.display());
+    }
+    Ok(paths)
+}
+
+/// How different a screenshot may be from its golden image.
+#[derive(Copy, Clone, Debug)]
+struct Tolerance {
+    /// Largest [pixel_difference] for pixels to be considered the same.
+    pixel_threshold: f32,
+    /// Largest fraction of pixels that may be different.
+    max_different_pixels: f32,
+}
+
+/// Tolerance for screenshots rendered with the software OpenGL ES
+/// implementation. It should be deterministic, but floating-point results
+/// might still differ slightly between host architectures.
+const SOFTWARE_TOLERANCE: Tolerance = Tolerance {
+    pixel_threshold: 0.01,
+    max_different_pixels: 0.001,
+};
+
+/// Tolerance for screenshots rendered with a host OpenGL driver, which is free
+/// to rasterize and filter slightly differently from the one that was used to
+/// capture the golden image.
+const DRIVER_TOLERANCE: Tolerance = Tolerance {
+    pixel_threshold: 0.05,
+    max_different_pixels: 0.01,
+};
+
+/// Load a PNG file as RGB8 pixel data.
+fn load_png(path: &Path) -> Result<(u32, u32, Vec<u8>), Box<dyn Error>> {
+    let mut decoder = png::Decoder::new(File::open(path)?);
+    // Expand palettes and reduce 16-bit channels, so that there's always one
+    // byte per channel.
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info()?;
+    let mut pixels = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut pixels)?;
+    pixels.truncate(info.buffer_size());
+
+    let pixels = match info.color_type {
+        png::ColorType::Rgb => pixels,
+        png::ColorType::Rgba => pixels.chunks(4).flat_map(|p| &p[..3]).copied().collect(),
+        png::ColorType::Grayscale => pixels.iter().flat_map(|&l| [l, l, l]).collect(),
+        png::ColorType::GrayscaleAlpha => pixels.chunks(2).flat_map(|p| [p[0]; 3]).collect(),
+        png::ColorType::Indexed => unreachable!(),
+    };
+    Ok((info.width, info.height, pixels))
+}
+
+/// Write RGB8 pixel data to a PNG file.
+fn write_png(path: &Path, width: u32, height: u32, pixels: &[u8]) -> Result<(), Box<dyn Error>> {
+    let file = BufWriter::new(File::create(path)?);
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.write_header()?.write_image_data(pixels)?;
+    Ok(())
+}
+
+/// Perceptual difference between two RGB8 pixels, where 0.0 means identical
+/// and black versus white is about 0.93. This is based on distance in the YIQ
+/// colour space (as used by the pixelmatch library), so changes in brightness
+/// count for more than changes in hue.
+fn pixel_difference(a: &[u8], b: &[u8]) -> f32 {
+    let [r, g, b] = [0, 1, 2].map(|i| a[i] as f32 - b[i] as f32);
+    let y = r * 0.298_895 + g * 0.586_622 + b * 0.114_482;
+    let i = r * 0.595_978 - g * 0.274_176 - b * 0.321_802;
+    let q = r * 0.211_470 - g * 0.522_617 + b * 0.311_147;
+    (0.5053 * y * y + 0.299 * i * i + 0.1957 * q * q) / 35215.0
+}
+
+/// Compare a screenshot against `tests/goldens/<golden_name>.png`, panicking
+/// if they differ by more than the tolerance. When they do differ, a diff
+/// image is written next to the screenshot, with the differing pixels in red.
+///
+/// If the `TOUCHHLE_UPDATE_GOLDENS` environment variable is set, the golden
+/// image is replaced with the screenshot instead.
+fn assert_frame_matches_golden(screenshot_path: &Path, golden_name: &str, tolerance: Tolerance) {
+    let golden_path = current_dir()
+        .unwrap()
+        .join("tests")
+        .join("goldens")
+        .join(format!("{}.png", golden_name));
+
+    if env::var_os("TOUCHHLE_UPDATE_GOLDENS").is_some() {
+        std::fs::create_dir_all(golden_path.parent().unwrap()).unwrap();
+        std::fs::copy(screenshot_path, &golden_path).unwrap();
+        eprintln!("Updated golden image {}.", golden_path.display());
+        return;
+    }
+
+    let (width, height, actual) = load_png(screenshot_path).unwrap();
+    let (golden_width, golden_height, golden) = load_png(&golden_path).unwrap_or_else(|e| {
+        panic!(
+            "Couldn't load golden image {}: {}. Set TOUCHHLE_UPDATE_GOLDENS=1 to create it.",
+            golden_path.display(),
+            e
+        )
+    });
+    assert_eq!(
+        (width, height),
+        (golden_width, golden_height),
+        "Screenshot {} has different dimensions from golden image {}",
+        screenshot_path.display(),
+        golden_path.display()
+    );
+
+    let mut different_pixels = 0;
+    let mut diff = Vec::with_capacity(golden.len());
+    for (actual, golden) in actual.chunks(3).zip(golden.chunks(3)) {
+        if pixel_difference(actual, golden) > tolerance.pixel_threshold {
+            different_pixels += 1;
+            diff.extend_from_slice(&[255, 0, 0]);
+        } else {
+            // Faded greyscale version of the golden image, for context.
+            let luma =
+                golden[0] as f32 * 0.299 + golden[1] as f32 * 0.587 + golden[2] as f32 * 0.114;
+            let faded = (255.0 - (255.0 - luma) * 0.2) as u8;
+            diff.extend_from_slice(&[faded, faded, faded]);
+        }
+    }
+
+    let different_fraction = different_pixels as f32 / (width * height) as f32;
+    if different_fraction > tolerance.max_different_pixels {
+        let diff_path = screenshot_path.with_extension("diff.png");
+        write_png(&diff_path, width, height, &diff).unwrap();
+        panic!(
+            "Screenshot {} differs from golden image {} ({} of {} pixels differ, tolerance: {:?}). Diff image written to {}.",
+            screenshot_path.display(),
+            golden_path.display(),
+            different_pixels,
+            width * height,
+            tolerance,
+            diff_path.display()
+        );
+    }
+}
+
+#[test]
+fn splash_screen_goldens() -> Result<(), Box<dyn Error>> {
+    // TestApp has a launch image (Default.png), which is presented as the
+    // first frame, so this checks the software rasterizer's texturing and
+    // present::present_frame's rotation without needing the app to draw
+    // anything.
+    let test_app_path = test_app_path();
+
+    for (name, args) in [
+        ("splash_portrait", &[][..]),
+        ("splash_landscape_left", &["--landscape-left"][..]),
+        ("splash_landscape_right", &["--landscape-right"][..]),
+    ] {
+        let screenshots = capture_frames(name, Some(&test_app_path), "software", args, None, &[1])?;
+        assert_frame_matches_golden(&screenshots[0], name, SOFTWARE_TOLERANCE);
+    }
+
+    Ok(())
+}
+
+#[test]
+#[ignore = "needs an OpenGL 2.1 driver"]
+fn splash_screen_goldens_gles1_on_gl2() -> Result<(), Box<dyn Error>> {
+    // Same as splash_screen_goldens, but with the OpenGL ES 1.1 on OpenGL 2.1
+    // implementation that's used by default on desktop. The golden images are
+    // separate from the software ones, since the two don't match exactly.
+    let test_app_path = test_app_path();
+
+    for (name, args) in [
+        ("splash_portrait_gles1_on_gl2", &[][..]),
+        (
+            "splash_landscape_left_gles1_on_gl2",
+            &["--landscape-left"][..],
+        ),
+    ] {
+        let screenshots =
+            capture_frames(name, Some(&test_app_path), "gles1_on_gl2", args, None, &[1])?;
+        assert_frame_matches_golden(&screenshots[0], name, DRIVER_TOLERANCE);
+    }
+
+    Ok(())
+}
+
+#[test]
+fn app_picker_copyright_info_golden() -> Result<(), Box<dyn Error>> {
+    // The app picker is built from UIKit views, so this checks Core Animation
+    // compositing (layer backgrounds, UILabel text, UIButton images). The input
+    // recording taps the "Copyright info" button, which shows an opaque page of
+    // text over the rest of the picker. The screenshot is taken once that's
+    // visible, so it doesn't include the version number label, which would make
+    // the golden image change with every commit.
+    let tests_dir = current_dir().unwrap().join("tests");
+    let replay_input = tests_dir
+        .join("inputs")
+        .join("app_picker_copyright_info.txt");
+
+    let screenshots = capture_frames(
+        "app_picker_copyright_info",
+        None,
+        "software",
+        &[],
+        Some(&replay_input),
+        &[20],
+    )?;
+    assert_frame_matches_golden(
+        &screenshots[0],
+        "app_picker_copyright_info",
+        SOFTWARE_TOLERANCE,
+    );
+
+    Ok(())
+}